@@ -0,0 +1,123 @@
+//! Throughput of the block receive path: piece messages read off a socket and
+//! copied into their piece, as a peer does while downloading.
+
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use rbittorrent::{
+    message::{Message, Piece},
+    pool::BlockPool,
+};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+    runtime::Runtime,
+};
+
+const BLOCK_SIZE: usize = 16384;
+const PIECE_LENGTH: usize = 256 * 1024;
+/// blocks streamed per iteration, 4 MiB
+const BLOCKS: usize = 256;
+
+fn encoded_blocks() -> Vec<u8> {
+    let block = vec![0xab; BLOCK_SIZE];
+    (0..BLOCKS)
+        .flat_map(|i| {
+            let index = (i * BLOCK_SIZE / PIECE_LENGTH) as u32;
+            let begin = (i * BLOCK_SIZE % PIECE_LENGTH) as u32;
+            let mut msg = Vec::with_capacity(13 + BLOCK_SIZE);
+            msg.extend((9 + BLOCK_SIZE as u32).to_be_bytes());
+            msg.push(7);
+            msg.extend(index.to_be_bytes());
+            msg.extend(begin.to_be_bytes());
+            msg.extend_from_slice(&block);
+            msg
+        })
+        .collect()
+}
+
+async fn tcp_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (server, _) = listener.accept().await.unwrap();
+    (client, server)
+}
+
+/// stream [`BLOCKS`] blocks to a reader which takes its buffers from `pool`, if any
+fn bench_blocks(c: &mut Criterion, name: &str, pool: Option<Arc<BlockPool>>) {
+    let rt = Runtime::new().unwrap();
+    let encoded = encoded_blocks();
+    let (mut reader, mut writer) = rt.block_on(tcp_pair());
+    let mut piece = vec![0; PIECE_LENGTH];
+
+    let mut group = c.benchmark_group("receive");
+    group.throughput(Throughput::Elements(BLOCKS as u64));
+    group.bench_function(name, |b| {
+        b.iter(|| {
+            rt.block_on(async {
+                let sender = async {
+                    writer.write_all(&encoded).await.unwrap();
+                };
+                let receiver = async {
+                    for _ in 0..BLOCKS {
+                        let msg = Message::from_stream_pooled(&mut reader, pool.as_ref()).await;
+                        match msg.unwrap() {
+                            Message::Piece(block) => {
+                                let begin = block.begin as usize;
+                                piece[begin..begin + block.piece.len()]
+                                    .copy_from_slice(&block.piece);
+                            }
+                            other => panic!("unexpected {:?}", other),
+                        }
+                    }
+                };
+                tokio::join!(sender, receiver);
+            })
+        })
+    });
+    group.finish();
+}
+
+/// turning a message body read off the socket into a block, without the socket.
+/// `copied` is how bodies were handled before the pool, `pooled` how peers do now
+fn decode_blocks(c: &mut Criterion) {
+    let body = &encoded_blocks()[4..13 + BLOCK_SIZE];
+    let pool = Arc::new(BlockPool::new(64, 9 + BLOCK_SIZE));
+    let mut piece = vec![0; PIECE_LENGTH];
+
+    let mut group = c.benchmark_group("decode");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("copied", |b| {
+        b.iter(|| {
+            let mut read = vec![0; body.len()];
+            read.copy_from_slice(body);
+            let block = Piece::from_bytes(&read[1..]).unwrap();
+            piece[..block.piece.len()].copy_from_slice(&block.piece);
+        })
+    });
+    group.bench_function("pooled", |b| {
+        b.iter(|| {
+            let mut read = pool.try_checkout().unwrap();
+            read.vec_mut().resize(body.len(), 0);
+            read.copy_from_slice(body);
+            let block = Piece::from_block(read).unwrap();
+            piece[..block.piece.len()].copy_from_slice(&block.piece);
+        })
+    });
+    group.finish();
+}
+
+fn receive_blocks(c: &mut Criterion) {
+    bench_blocks(c, "blocks", None);
+    // as a peer reads them, into buffers of the shared pool
+    bench_blocks(
+        c,
+        "pooled_blocks",
+        Some(Arc::new(BlockPool::new(64, 9 + BLOCK_SIZE))),
+    );
+}
+
+criterion_group!(benches, decode_blocks, receive_blocks);
+criterion_main!(benches);
@@ -5,7 +5,9 @@ use std::path::PathBuf;
 async fn main() -> anyhow::Result<()> {
     env_logger::init();
     let path = PathBuf::from("./tests/debian-12.5.0-amd64-netinst.iso.torrent");
-    let client = TorrentClientBuilder::new().add_torrent_path(path)?.build();
-    client.send_request().await?;
+    let client = TorrentClientBuilder::new()
+        .add_torrent_path(path)?
+        .build()?;
+    client.run_until_signal().await?;
     Ok(())
 }
@@ -0,0 +1,122 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use crate::{message::Piece, peer::Peer, task::Task};
+
+#[derive(Debug)]
+struct PartialPiece {
+    task: Task,
+    blocks: Vec<Piece>,
+    /// the peer each block came from, in the order of `blocks`
+    sources: Vec<SocketAddr>,
+    received: u32,
+}
+
+/// Blocks of the pieces in flight, shared by every peer so a piece can be put
+/// together from blocks delivered by different peers.
+#[derive(Debug, Clone, Default)]
+pub struct PieceAssembly(Arc<Mutex<HashMap<u32, PartialPiece>>>);
+
+impl PieceAssembly {
+    /// start collecting the blocks of `task`, keeping the blocks already received for it
+    pub fn start(&self, task: Task) {
+        self.0
+            .lock()
+            .unwrap()
+            .entry(task.index)
+            .or_insert_with(|| PartialPiece {
+                task,
+                blocks: Vec::with_capacity(task.piece_length.div_ceil(Peer::BLOCK_SIZE) as usize),
+                sources: Vec::new(),
+                received: 0,
+            });
+    }
+
+    /// add a block delivered by `from`, handing out the task, all of its blocks and
+    /// the peers which contributed them once the piece is complete.
+    /// Blocks of pieces nobody collects and blocks received before are dropped
+    pub fn insert(
+        &self,
+        block: Piece,
+        from: SocketAddr,
+    ) -> Option<(Task, Vec<Piece>, Vec<SocketAddr>)> {
+        let mut pieces = self.0.lock().unwrap();
+        let index = block.index;
+        let piece = pieces.get_mut(&index)?;
+        if piece.blocks.iter().any(|b| b.begin == block.begin) {
+            return None;
+        }
+        piece.received += block.piece.len() as u32;
+        piece.blocks.push(block);
+        piece.sources.push(from);
+        if piece.received < piece.task.piece_length {
+            return None;
+        }
+        let mut piece = pieces.remove(&index)?;
+        piece.sources.sort_unstable();
+        piece.sources.dedup();
+        Some((piece.task, piece.blocks, piece.sources))
+    }
+
+    /// whether the piece is still being collected
+    pub fn contains(&self, index: u32) -> bool {
+        self.0.lock().unwrap().contains_key(&index)
+    }
+
+    /// bytes received so far for the piece
+    pub fn received(&self, index: u32) -> u32 {
+        self.0
+            .lock()
+            .unwrap()
+            .get(&index)
+            .map_or(0, |piece| piece.received)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_from_two_peers_make_one_piece() {
+        let (first, second): (SocketAddr, SocketAddr) = (
+            "10.0.0.1:6881".parse().unwrap(),
+            "10.0.0.2:6881".parse().unwrap(),
+        );
+        let block = Peer::BLOCK_SIZE;
+        let task = Task {
+            index: 3,
+            piece_hash: [0; 20],
+            piece_length: 2 * block + 100,
+        };
+        let assembly = PieceAssembly::default();
+        // blocks of a piece nobody collects are dropped
+        assert!(assembly.insert(Piece::new(3, 0, &[1; 10]), first).is_none());
+        assembly.start(task);
+        assert!(assembly
+            .insert(Piece::new(3, 0, &vec![1; block as usize]), first)
+            .is_none());
+        assert!(assembly
+            .insert(Piece::new(3, 2 * block, &[3; 100]), second)
+            .is_none());
+        // the same block again from the other peer counts once
+        assert!(assembly
+            .insert(Piece::new(3, 0, &vec![1; block as usize]), second)
+            .is_none());
+        assert_eq!(assembly.received(3), block + 100);
+        // a peer taking the piece over keeps what was received
+        assembly.start(task);
+        let (done, blocks, sources) = assembly
+            .insert(Piece::new(3, block, &vec![2; block as usize]), first)
+            .unwrap();
+        assert_eq!(done.index, 3);
+        let mut begins: Vec<u32> = blocks.iter().map(|b| b.begin).collect();
+        begins.sort_unstable();
+        assert_eq!(begins, vec![0, block, 2 * block]);
+        assert_eq!(sources, vec![first, second]);
+        assert!(!assembly.contains(3));
+    }
+}
@@ -0,0 +1,322 @@
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Download and upload limits in bytes per second, `None` for no limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RateLimits {
+    pub download: Option<u64>,
+    pub upload: Option<u64>,
+}
+
+impl RateLimits {
+    pub const UNLIMITED: Self = Self {
+        download: None,
+        upload: None,
+    };
+
+    pub fn new(download: Option<u64>, upload: Option<u64>) -> Self {
+        Self { download, upload }
+    }
+}
+
+/// Tokens of a rate limit, refilled continuously and holding up to a second's worth.
+///
+/// Taking more than is there runs into debt, which the taker sleeps off, so a
+/// single block larger than the rate still goes through.
+#[derive(Debug)]
+pub struct TokenBucket(Mutex<BucketState>);
+
+#[derive(Debug)]
+struct BucketState {
+    rate: Option<u64>,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Default for TokenBucket {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl TokenBucket {
+    /// a bucket for `rate` bytes per second, `None` or 0 for no limit
+    pub fn new(rate: Option<u64>) -> Self {
+        let rate = rate.filter(|rate| *rate > 0);
+        Self(Mutex::new(BucketState {
+            rate,
+            tokens: rate.unwrap_or_default() as f64,
+            refilled: Instant::now(),
+        }))
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.0.lock().unwrap().rate
+    }
+
+    /// change the rate, keeping what is left of the tokens up to the new burst
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut state = self.0.lock().unwrap();
+        state.refill();
+        state.rate = rate.filter(|rate| *rate > 0);
+        state.tokens = state.tokens.min(state.rate.unwrap_or_default() as f64);
+    }
+
+    /// take `bytes` tokens, waiting until the rate allows them
+    pub async fn take(&self, bytes: u64) {
+        let wait = {
+            let mut state = self.0.lock().unwrap();
+            let Some(rate) = state.rate else {
+                return;
+            };
+            state.refill();
+            state.tokens -= bytes as f64;
+            if state.tokens >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-state.tokens / rate as f64)
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
+
+impl BucketState {
+    fn refill(&mut self) {
+        let now = Instant::now();
+        if let Some(rate) = self.rate {
+            let earned = (now - self.refilled).as_secs_f64() * rate as f64;
+            self.tokens = (self.tokens + earned).min(rate as f64);
+        }
+        self.refilled = now;
+    }
+}
+
+/// The global token buckets every peer of a torrent takes from for the blocks it
+/// receives and sends. Share one between torrents to limit them together.
+#[derive(Debug, Default)]
+pub struct Bandwidth {
+    pub download: TokenBucket,
+    pub upload: TokenBucket,
+}
+
+impl Bandwidth {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            download: TokenBucket::new(limits.download),
+            upload: TokenBucket::new(limits.upload),
+        }
+    }
+
+    /// the limits in effect
+    pub fn limits(&self) -> RateLimits {
+        RateLimits::new(self.download.rate(), self.upload.rate())
+    }
+
+    pub fn apply(&self, limits: RateLimits) {
+        self.download.set_rate(limits.download);
+        self.upload.set_rate(limits.upload);
+    }
+}
+
+/// The time of day a [`BandwidthSchedule`] goes by, swapped out in tests.
+pub trait Clock: Debug + Send + Sync {
+    /// time since midnight
+    fn time_of_day(&self) -> Duration;
+}
+
+/// The local time of the system.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LocalClock;
+
+impl Clock for LocalClock {
+    #[cfg(target_os = "linux")]
+    fn time_of_day(&self) -> Duration {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        let secs = now.as_secs() as libc::time_t;
+        // SAFETY: localtime_r only writes the zeroed tm it is handed
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        if unsafe { libc::localtime_r(&secs, &mut tm) }.is_null() {
+            return Duration::from_secs(now.as_secs() % 86400);
+        }
+        let secs = tm.tm_hour as u64 * 3600 + tm.tm_min as u64 * 60 + tm.tm_sec as u64;
+        Duration::from_secs(secs) + Duration::from_nanos(now.subsec_nanos() as u64)
+    }
+
+    /// UTC, local time needs the time zone database of the platform
+    #[cfg(not(target_os = "linux"))]
+    fn time_of_day(&self) -> Duration {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        Duration::from_secs(now.as_secs() % 86400)
+    }
+}
+
+/// Limits for a range of the day, from `from` up to `to` since midnight. A range
+/// ending before it starts runs over midnight, e.g. from 23:00 to 06:00.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScheduleRule {
+    pub from: Duration,
+    pub to: Duration,
+    pub limits: RateLimits,
+}
+
+impl ScheduleRule {
+    pub fn covers(&self, time_of_day: Duration) -> bool {
+        match self.from <= self.to {
+            true => (self.from..self.to).contains(&time_of_day),
+            false => time_of_day >= self.from || time_of_day < self.to,
+        }
+    }
+}
+
+/// Rate limits by the time of day, see
+/// [`crate::TorrentClientBuilder::set_bandwidth_schedule`].
+#[derive(Debug, Clone)]
+pub struct BandwidthSchedule {
+    rules: Vec<ScheduleRule>,
+    default: RateLimits,
+    clock: Arc<dyn Clock>,
+}
+
+impl BandwidthSchedule {
+    /// how often the schedule is looked at while downloading
+    pub const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+    /// a schedule applying `default` outside of its rules
+    pub fn new(default: RateLimits) -> Self {
+        Self {
+            rules: vec![],
+            default,
+            clock: Arc::new(LocalClock),
+        }
+    }
+
+    /// apply `limits` from `from` to `to` since midnight. The first rule covering
+    /// a time wins
+    pub fn add_rule(mut self, from: Duration, to: Duration, limits: RateLimits) -> Self {
+        self.rules.push(ScheduleRule { from, to, limits });
+        self
+    }
+
+    /// tell the time with `clock` instead of the local time
+    pub fn set_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn limits_at(&self, time_of_day: Duration) -> RateLimits {
+        self.rules
+            .iter()
+            .find(|rule| rule.covers(time_of_day))
+            .map_or(self.default, |rule| rule.limits)
+    }
+
+    /// the limits for the time of day now
+    pub fn current_limits(&self) -> RateLimits {
+        self.limits_at(self.clock.time_of_day())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, SeedBehavior};
+
+    /// a clock which is set by hand
+    #[derive(Debug, Default)]
+    struct MockClock(Mutex<Duration>);
+
+    impl MockClock {
+        fn set(&self, hours: u64, minutes: u64, seconds: u64) {
+            *self.0.lock().unwrap() = Duration::from_secs(hours * 3600 + minutes * 60 + seconds);
+        }
+    }
+
+    impl Clock for MockClock {
+        fn time_of_day(&self) -> Duration {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    fn hours(hours: u64) -> Duration {
+        Duration::from_secs(hours * 3600)
+    }
+
+    #[test]
+    fn limits_change_at_schedule_boundaries() {
+        let clock = Arc::new(MockClock::default());
+        let day = RateLimits::new(Some(100_000), Some(20_000));
+        let night = RateLimits::UNLIMITED;
+        let default = RateLimits::new(Some(1_000_000), None);
+        let schedule = BandwidthSchedule::new(default)
+            .add_rule(hours(9), hours(17), day)
+            .add_rule(hours(23), hours(6), night)
+            .set_clock(clock.clone());
+
+        clock.set(8, 59, 59);
+        assert_eq!(schedule.current_limits(), default);
+        clock.set(9, 0, 0);
+        assert_eq!(schedule.current_limits(), day);
+        clock.set(16, 59, 59);
+        assert_eq!(schedule.current_limits(), day);
+        clock.set(17, 0, 0);
+        assert_eq!(schedule.current_limits(), default);
+        // the night runs over midnight
+        clock.set(23, 30, 0);
+        assert_eq!(schedule.current_limits(), night);
+        clock.set(0, 0, 0);
+        assert_eq!(schedule.current_limits(), night);
+        clock.set(6, 0, 0);
+        assert_eq!(schedule.current_limits(), default);
+    }
+
+    #[tokio::test]
+    async fn schedule_drives_the_client_bandwidth() {
+        let clock = Arc::new(MockClock::default());
+        let day = RateLimits::new(Some(50_000), None);
+        let schedule = BandwidthSchedule::new(RateLimits::UNLIMITED)
+            .add_rule(hours(9), hours(17), day)
+            .set_clock(clock.clone());
+        let content = testutil::content(100_000);
+        let client = testutil::seeded(&content, 32768, SeedBehavior::default())
+            .await
+            .set_bandwidth_schedule(schedule)
+            .build()
+            .unwrap();
+        clock.set(8, 0, 0);
+        assert_eq!(
+            client.apply_bandwidth_schedule(),
+            Some(RateLimits::UNLIMITED)
+        );
+        assert_eq!(client.bandwidth.limits(), RateLimits::UNLIMITED);
+
+        // the schedule is applied as the download starts
+        clock.set(10, 0, 0);
+        let start = Instant::now();
+        assert_eq!(client.download_to_vec().await.unwrap(), content);
+        assert_eq!(client.bandwidth.limits(), day);
+        // a second's worth right away, the other half at the limit
+        assert!(start.elapsed() >= Duration::from_millis(900));
+    }
+
+    #[tokio::test]
+    async fn token_bucket_holds_to_its_rate() {
+        let bucket = TokenBucket::new(Some(100_000));
+        let start = Instant::now();
+        // a second's worth goes through at once, the rest waits for its tokens
+        bucket.take(100_000).await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+        bucket.take(50_000).await;
+        assert!(start.elapsed() >= Duration::from_millis(450));
+        bucket.set_rate(None);
+        let unlimited = Instant::now();
+        bucket.take(10_000_000).await;
+        assert!(unlimited.elapsed() < Duration::from_millis(100));
+    }
+}
@@ -1,11 +1,91 @@
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{anyhow, Result};
 use bytes::Bytes;
 use serde::{Deserialize, Serialize};
 use sha1::Digest;
 
+use crate::{files::TorrentFile, task};
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct BencodeTorrent {
     pub announce: String,
+    #[serde(rename = "announce-list")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub announce_list: Option<Vec<Vec<String>>>,
     pub info: BencodeInfo,
+    #[serde(rename = "url-list")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url_list: Option<UrlList>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub httpseeds: Option<Vec<String>>,
+    /// kept as bytes, old torrents encode it in whatever codepage their creator used
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<Bytes>,
+    #[serde(rename = "comment.utf-8")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment_utf8: Option<Bytes>,
+    #[serde(rename = "created by")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_by: Option<Bytes>,
+    #[serde(rename = "created by.utf-8")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_by_utf8: Option<Bytes>,
+    #[serde(rename = "creation date")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub creation_date: Option<i64>,
+}
+
+impl BencodeTorrent {
+    /// every tracker of the torrent, tier by tier (BEP 12), starting from `announce`
+    pub fn trackers(&self) -> Vec<String> {
+        let mut trackers = vec![self.announce.clone()];
+        for url in self.announce_list.iter().flatten().flatten() {
+            if !trackers.contains(url) {
+                trackers.push(url.clone());
+            }
+        }
+        trackers
+    }
+
+    /// the comment for display, `comment.utf-8` if the torrent has it
+    pub fn comment(&self) -> Option<String> {
+        prefer_utf8(&self.comment_utf8, &self.comment)
+    }
+
+    /// the program which created the torrent, `created by.utf-8` if the torrent has it
+    pub fn created_by(&self) -> Option<String> {
+        prefer_utf8(&self.created_by_utf8, &self.created_by)
+    }
+}
+
+/// the `.utf-8` variant of a key if present and valid, otherwise the legacy one
+fn prefer_utf8(utf8: &Option<Bytes>, legacy: &Option<Bytes>) -> Option<String> {
+    utf8.as_deref()
+        .and_then(|value| std::str::from_utf8(value).ok())
+        .map(str::to_string)
+        .or_else(|| {
+            legacy
+                .as_deref()
+                .map(|value| String::from_utf8_lossy(value).into_owned())
+        })
+}
+
+/// `url-list` is either a single url or a list of them (BEP 19)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum UrlList {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl UrlList {
+    pub fn into_vec(self) -> Vec<String> {
+        match self {
+            Self::One(url) => vec![url],
+            Self::Many(urls) => urls,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -13,11 +93,124 @@ pub struct BencodeInfo {
     pub pieces: Bytes,
     #[serde(rename = "piece length")]
     pub piece_length: u32,
-    pub length: u32,
+    /// length of the single file, absent when `files` is given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub length: Option<u32>,
+    /// the files of a multi-file torrent, laid out one after another
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub files: Option<Vec<BencodeFile>>,
     pub name: String,
+    /// 1 for private torrents (BEP 27), which only use the trackers they name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<i64>,
+}
+
+/// A file of a multi-file torrent, `path` is relative to the directory `name`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BencodeFile {
+    pub length: u32,
+    pub path: Vec<String>,
 }
 
 impl BencodeInfo {
+    /// length of the content, the sum of all files for multi-file torrents
+    pub fn total_length(&self) -> Result<u32> {
+        match (&self.files, self.length) {
+            (Some(files), _) => files.iter().try_fold(0u32, |total, file| {
+                total
+                    .checked_add(file.length)
+                    .ok_or_else(|| anyhow!("torrent {} is too large", self.name))
+            }),
+            (None, Some(length)) => Ok(length),
+            (None, None) => Err(anyhow!(
+                "torrent {} has neither length nor files",
+                self.name
+            )),
+        }
+    }
+
+    /// the files of the torrent, a multi-file torrent lives in the directory `name`
+    pub fn files(&self) -> Result<Vec<TorrentFile>> {
+        self.check_name()?;
+        let Some(files) = &self.files else {
+            return Ok(vec![TorrentFile {
+                path: PathBuf::from(&self.name),
+                offset: 0,
+                length: self.total_length()? as u64,
+            }]);
+        };
+        let mut offset = 0;
+        files
+            .iter()
+            .map(|file| {
+                let mut path = PathBuf::from(&self.name);
+                for part in file.path.iter() {
+                    // keep every file inside the torrent directory
+                    if !is_plain_component(part) {
+                        return Err(anyhow!(
+                            "invalid file path {:?} in torrent {}",
+                            file.path,
+                            self.name
+                        ));
+                    }
+                    path.push(part);
+                }
+                if file.path.is_empty() {
+                    return Err(anyhow!("empty file path in torrent {}", self.name));
+                }
+                let torrent_file = TorrentFile {
+                    path,
+                    offset,
+                    length: file.length as u64,
+                };
+                offset += file.length as u64;
+                Ok(torrent_file)
+            })
+            .collect()
+    }
+
+    /// `name` becomes a file or directory in the working directory, so it must not
+    /// reach anywhere else through separators, `..` or an absolute path
+    pub fn check_name(&self) -> Result<()> {
+        if !is_plain_component(&self.name) {
+            return Err(anyhow!("invalid torrent name {:?}", self.name));
+        }
+        Ok(())
+    }
+
+    /// `pieces` has to hold one 20 byte hash for every piece of the content
+    pub fn check_pieces(&self) -> Result<()> {
+        if self.piece_length == 0 {
+            return Err(anyhow!("torrent {} has a piece length of 0", self.name));
+        }
+        if !self.pieces.len().is_multiple_of(20) {
+            return Err(anyhow!(
+                "pieces of torrent {} are {} bytes, not a multiple of 20",
+                self.name,
+                self.pieces.len()
+            ));
+        }
+        let length = self.total_length()?;
+        let expected = task::piece_count(length, self.piece_length) as usize;
+        if self.pieces.len() / 20 != expected {
+            return Err(anyhow!(
+                "torrent {} has {} piece hashes, but {} bytes in pieces of {} take {}",
+                self.name,
+                self.pieces.len() / 20,
+                length,
+                self.piece_length,
+                expected
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.private == Some(1)
+    }
+
+    /// the hash of this dictionary as we encode it. Keys we do not model are lost,
+    /// so for a loaded torrent hash the original bytes, see [`dict_value`]
     pub fn hash(&self) -> [u8; 20] {
         let buf = serde_bencode::to_bytes(self).unwrap();
         let mut hasher = sha1::Sha1::new();
@@ -25,3 +218,256 @@ impl BencodeInfo {
         hasher.finalize().into()
     }
 }
+
+/// whether `part` names a single entry of a directory, on any platform
+fn is_plain_component(part: &str) -> bool {
+    let mut components = Path::new(part).components();
+    !part.contains(['/', '\\', '\0'])
+        && matches!(components.next(), Some(Component::Normal(_)))
+        && components.next().is_none()
+}
+
+/// skip a UTF-8 BOM and whitespace some servers put in front of the bencode
+pub fn trim_start(buf: &[u8]) -> Result<&[u8]> {
+    let buf = buf.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(buf);
+    let start = buf
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(buf.len());
+    let buf = &buf[start..];
+    match buf.first() {
+        Some(b'd' | b'l' | b'i' | b'0'..=b'9') => Ok(buf),
+        Some(b) => Err(anyhow!(
+            "not bencode, starts with byte {:#04x} instead of d, l, i or a digit",
+            b
+        )),
+        None => Err(anyhow!("not bencode, the data is empty")),
+    }
+}
+
+/// lists and dictionaries nested deeper than this are refused by [`value_len`]
+pub const MAX_DEPTH: usize = 64;
+
+/// length in bytes of the first bencoded value at the start of `buf`
+pub fn value_len(buf: &[u8]) -> Result<usize> {
+    fn scan(buf: &[u8], pos: usize, depth: usize) -> Option<usize> {
+        match *buf.get(pos)? {
+            b'i' => {
+                let end = pos + buf[pos..].iter().position(|&b| b == b'e')?;
+                Some(end + 1)
+            }
+            // the nesting is attacker controlled, don't let it exhaust the stack
+            b'l' | b'd' if depth < MAX_DEPTH => {
+                let mut pos = pos + 1;
+                while *buf.get(pos)? != b'e' {
+                    pos = scan(buf, pos, depth + 1)?;
+                }
+                Some(pos + 1)
+            }
+            b'0'..=b'9' => {
+                let colon = pos + buf[pos..].iter().position(|&b| b == b':')?;
+                let len: usize = std::str::from_utf8(&buf[pos..colon]).ok()?.parse().ok()?;
+                let end = colon.checked_add(1)?.checked_add(len)?;
+                (end <= buf.len()).then_some(end)
+            }
+            _ => None,
+        }
+    }
+    scan(buf, 0, 0).ok_or_else(|| anyhow!("malformed bencode value"))
+}
+
+/// fields we keep as `u32`, an integer beyond that is as unusable as one beyond `i64`
+const U32_FIELDS: [&[u8]; 2] = [b"length", b"piece length"];
+
+/// check every integer of the bencoded `buf` fits an `i64`, or a `u32` for the
+/// fields modeled as such, naming the field of the first one which does not.
+/// Otherwise the error of the decoder does not tell which value is at fault
+pub fn check_integers(buf: &[u8]) -> Result<()> {
+    fn check(buf: &[u8], pos: usize, key: Option<&[u8]>) -> Result<usize> {
+        let malformed = || anyhow!("malformed bencode at byte {}", pos);
+        match *buf.get(pos).ok_or_else(malformed)? {
+            b'i' => {
+                let end = pos
+                    + buf[pos..]
+                        .iter()
+                        .position(|&b| b == b'e')
+                        .ok_or_else(malformed)?;
+                let digits = std::str::from_utf8(&buf[pos + 1..end]).map_err(|_| malformed())?;
+                let in_range = match key {
+                    Some(key) if U32_FIELDS.contains(&key) => digits.parse::<u32>().is_ok(),
+                    _ => digits.parse::<i64>().is_ok(),
+                };
+                if !in_range {
+                    let field = key.map_or("?".into(), String::from_utf8_lossy);
+                    return Err(anyhow!(
+                        "malformed torrent: integer {} out of range for `{}`",
+                        digits,
+                        field
+                    ));
+                }
+                Ok(end + 1)
+            }
+            b'l' => {
+                let mut pos = pos + 1;
+                while *buf.get(pos).ok_or_else(malformed)? != b'e' {
+                    pos = check(buf, pos, key)?;
+                }
+                Ok(pos + 1)
+            }
+            b'd' => {
+                let mut pos = pos + 1;
+                while *buf.get(pos).ok_or_else(malformed)? != b'e' {
+                    let key_end = pos + value_len(&buf[pos..])?;
+                    let colon = pos
+                        + buf[pos..key_end]
+                            .iter()
+                            .position(|&b| b == b':')
+                            .ok_or_else(malformed)?;
+                    pos = check(buf, key_end, Some(&buf[colon + 1..key_end]))?;
+                }
+                Ok(pos + 1)
+            }
+            _ => Ok(pos + value_len(&buf[pos..])?),
+        }
+    }
+    check(buf, 0, None).map(|_| ())
+}
+
+/// a copy of the bencoded `buf` with the fields modeled as `u32` turned into
+/// integers where they hold a numeric string, as some broken generators write them
+pub fn integer_strings(buf: &[u8]) -> Result<Vec<u8>> {
+    fn rewrite(buf: &[u8], pos: usize, key: Option<&[u8]>, out: &mut Vec<u8>) -> Result<usize> {
+        let malformed = || anyhow!("malformed bencode at byte {}", pos);
+        match *buf.get(pos).ok_or_else(malformed)? {
+            b'l' | b'd' => {
+                let dict = buf[pos] == b'd';
+                out.push(buf[pos]);
+                let mut pos = pos + 1;
+                while *buf.get(pos).ok_or_else(malformed)? != b'e' {
+                    let key = match dict {
+                        true => {
+                            let key_end = pos + value_len(&buf[pos..])?;
+                            let colon = pos
+                                + buf[pos..key_end]
+                                    .iter()
+                                    .position(|&b| b == b':')
+                                    .ok_or_else(malformed)?;
+                            out.extend_from_slice(&buf[pos..key_end]);
+                            let key = &buf[colon + 1..key_end];
+                            pos = key_end;
+                            Some(key)
+                        }
+                        false => key,
+                    };
+                    pos = rewrite(buf, pos, key, out)?;
+                }
+                out.push(b'e');
+                Ok(pos + 1)
+            }
+            b'0'..=b'9' if key.is_some_and(|key| U32_FIELDS.contains(&key)) => {
+                let end = pos + value_len(&buf[pos..])?;
+                let colon = pos + buf[pos..end].iter().position(|&b| b == b':').unwrap();
+                let value = &buf[colon + 1..end];
+                match std::str::from_utf8(value).map(|value| value.trim().parse::<u64>()) {
+                    Ok(Ok(number)) => out.extend_from_slice(format!("i{}e", number).as_bytes()),
+                    _ => out.extend_from_slice(&buf[pos..end]),
+                }
+                Ok(end)
+            }
+            _ => {
+                let end = pos + value_len(&buf[pos..])?;
+                out.extend_from_slice(&buf[pos..end]);
+                Ok(end)
+            }
+        }
+    }
+    let mut out = Vec::with_capacity(buf.len());
+    rewrite(buf, 0, None, &mut out)?;
+    Ok(out)
+}
+
+/// the raw bytes of the value under `key` in the dictionary `buf`, as they were
+/// encoded, e.g. the `info` dictionary whose hash identifies the torrent
+pub fn dict_value<'a>(buf: &'a [u8], key: &[u8]) -> Result<Option<&'a [u8]>> {
+    if buf.first() != Some(&b'd') {
+        return Err(anyhow!("bencode value is not a dictionary"));
+    }
+    let mut pos = 1;
+    while buf.get(pos) != Some(&b'e') {
+        let key_end = pos + value_len(&buf[pos..])?;
+        let colon = pos
+            + buf[pos..key_end]
+                .iter()
+                .position(|&b| b == b':')
+                .ok_or_else(|| anyhow!("dictionary key is not a string"))?;
+        let value_end = key_end + value_len(&buf[key_end..])?;
+        if &buf[colon + 1..key_end] == key {
+            return Ok(Some(&buf[key_end..value_end]));
+        }
+        pos = value_end;
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leading_bom_and_whitespace_are_skipped() {
+        assert_eq!(trim_start(b"\xEF\xBB\xBF\n d1:ai1ee").unwrap(), b"d1:ai1ee");
+        assert_eq!(trim_start(b"4:spam").unwrap(), b"4:spam");
+        let err = trim_start(b"\xEF\xBB\xBF<html>").unwrap_err();
+        assert!(err.to_string().contains("0x3c"), "{}", err);
+        assert!(trim_start(b" \t").is_err());
+    }
+
+    #[test]
+    fn out_of_range_integers_name_their_field() {
+        let torrent = |date: &str, length: &str| {
+            format!(
+                "d8:announce3:url13:creation datei{}e4:infod6:lengthi{}e4:name1:a\
+                 12:piece lengthi16384e6:pieces0:ee",
+                date, length
+            )
+        };
+        assert!(check_integers(torrent("1700000000", "5").as_bytes()).is_ok());
+        let err = check_integers(torrent("1700000000", "5000000000").as_bytes()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "malformed torrent: integer 5000000000 out of range for `length`"
+        );
+        let err = check_integers(torrent("99999999999999999999", "5").as_bytes()).unwrap_err();
+        assert!(err.to_string().contains("`creation date`"), "{}", err);
+        // and so parsing the torrent fails with it
+        let err = crate::Torrent::from_bytes(torrent("1700000000", "-1").as_bytes()).unwrap_err();
+        assert!(
+            err.to_string().contains("out of range for `length`"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn value_len_of_nested_values() {
+        assert_eq!(value_len(b"d3:fooli1e4:spamee5:extra").unwrap(), 18);
+        assert_eq!(value_len(b"i42e").unwrap(), 4);
+        assert!(value_len(b"5:abc").is_err());
+        assert!(value_len(b"li1e").is_err());
+    }
+
+    #[test]
+    fn value_len_refuses_overflowing_lengths() {
+        let buf = format!("{}:x", usize::MAX);
+        assert!(value_len(buf.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn value_len_refuses_deep_nesting() {
+        let deep = |depth: usize| [vec![b'l'; depth], vec![b'e'; depth]].concat();
+        assert!(value_len(&deep(MAX_DEPTH)).is_ok());
+        assert!(value_len(&deep(MAX_DEPTH + 1)).is_err());
+        // far beyond what the stack could take
+        assert!(value_len(&deep(1_000_000)).is_err());
+    }
+}
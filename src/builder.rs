@@ -1,24 +1,111 @@
-use std::{path::Path, sync::Arc};
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{atomic::AtomicU64, Arc, Mutex},
+    time::Duration,
+};
 
-use anyhow::Result;
-use crossbeam::queue::ArrayQueue;
+use anyhow::{anyhow, Result};
 use indicatif::{ProgressBar, ProgressStyle};
+use log::{info, warn};
+use tokio::sync::{broadcast, watch, Semaphore};
 
-use crate::{bencode::BencodeTorrent, message::Bitfield, torrent::TorrentClient};
+use crate::{
+    bandwidth::{Bandwidth, BandwidthSchedule},
+    files::TorrentFile,
+    http,
+    magnet::MagnetLink,
+    message::{Bitfield, Extension},
+    metadata::{MetadataCache, MetadataFetcher},
+    metainfo::Torrent,
+    peer::{Peer, PeerIdFilter, SocketOptions},
+    picker::{PiecePicker, Sequential},
+    pool::BlockPool,
+    retry::RetryPolicy,
+    storage::{
+        AssemblyOrder, CacheStorage, DiskQuota, FileStorage, MissingPiece, MultiFileStorage,
+        SerialWriter, Storage,
+    },
+    task::{self, TaskQueue},
+    torrent::TorrentClient,
+    tracker::{AnnounceRequest, TrackerFilter},
+    webseed::WebSeedPolicy,
+};
 
 #[derive(Debug, Default)]
 pub struct TorrentClientBuilder {
     announce: Option<String>,
+    trackers: Vec<String>,
     info_hash: Option<[u8; 20]>,
     piece_hashes: Option<Vec<[u8; 20]>>,
     piece_length: Option<u32>,
     length: Option<u32>,
+    files: Option<Vec<TorrentFile>>,
     name: Option<String>,
     id: Option<[u8; 20]>,
     port: Option<u16>,
+    control_file: bool,
+    reannounce_attempts: u32,
+    prefer_local_peers: bool,
+    prefer_known_peers: bool,
+    web_seeds: Vec<String>,
+    http_seeds: Vec<String>,
+    webseed_policy: WebSeedPolicy,
+    webseed_timeout: Option<Duration>,
+    metadata_cache_dir: Option<PathBuf>,
+    storage: Option<Arc<dyn Storage>>,
+    retry_policy: RetryPolicy,
+    block_pool_size: Option<usize>,
+    seed_ratio_limit: Option<f64>,
+    seed_time_limit: Option<Duration>,
+    assemble: Option<bool>,
+    max_piece_time: Option<Duration>,
+    min_peer_rate: Option<u64>,
+    keep_alive_interval: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    bandwidth: Option<Arc<Bandwidth>>,
+    bandwidth_schedule: Option<BandwidthSchedule>,
+    socket_options: SocketOptions,
+    disk_quota: Option<u64>,
+    max_pending_connects: Option<usize>,
+    external_ip: Option<IpAddr>,
+    announce_jitter: Option<f64>,
+    verify_limit: Option<Arc<Semaphore>>,
+    private: bool,
+    comment: Option<String>,
+    created_by: Option<String>,
+    extra_trackers: Vec<String>,
+    initial_peers: Vec<SocketAddr>,
+    announce_param_order: Option<Vec<String>>,
+    tracker_filter: Option<TrackerFilter>,
+    peer_id_filter: Option<PeerIdFilter>,
+    extensions: Option<Vec<Extension>>,
+    stall_window: Option<Duration>,
+    lenient: bool,
+    serial_writes: bool,
+    max_piece_failures: Option<u32>,
+    slow_peer_rate: Option<u64>,
+    missing_piece: Option<MissingPiece>,
+    piece_cache: bool,
+    assembly_order: Option<AssemblyOrder>,
+    piece_picker: Option<Arc<dyn PiecePicker>>,
+    completed_dir: Option<PathBuf>,
+    task_queue_capacity: Option<usize>,
+    metadata_only: bool,
+    pipeline_depth: Option<usize>,
+    piece_affinity: bool,
+    port_mapping: bool,
+    gateway: Option<SocketAddr>,
+    max_response_size: Option<usize>,
 }
 
 impl TorrentClientBuilder {
+    const DEFAULT_PEER_ID: [u8; 20] = *b"-RT0001-123456012345";
+    const DEFAULT_PORT: u16 = 6881;
+    const DEFAULT_VERIFY_LIMIT: usize = 2;
+    const MIN_PIECE_LENGTH: u32 = 16 * 1024;
+    const MAX_PIECE_LENGTH: u32 = 64 * 1024 * 1024;
+
     pub fn new() -> Self {
         Default::default()
     }
@@ -32,32 +119,114 @@ impl TorrentClientBuilder {
     }
 
     pub async fn add_torrent_url(self, url: &str) -> Result<Self> {
-        let client = reqwest::ClientBuilder::new()
-            .user_agent("rbittorrent/v0.1.3")
-            .build()?;
-        let bytes = client.get(url).send().await?.bytes().await?;
+        let client = http::client()?;
+        let res = client.get(url).send().await?.error_for_status()?;
+        let bytes = http::read_body(res, self.max_response_size()).await?;
         self.add_torrent_bytes(&bytes)
     }
 
-    pub fn add_torrent_bytes(mut self, bytes: &[u8]) -> Result<Self> {
-        let torrent: BencodeTorrent = serde_bencode::from_bytes(bytes)?;
-        let info_hash = torrent.info.hash();
-        let piece_hashes = {
-            torrent
-                .info
-                .pieces
-                .chunks(20)
-                .map(|chunk| chunk[..20].try_into().unwrap())
-                .collect()
+    pub fn add_torrent_bytes(self, bytes: &[u8]) -> Result<Self> {
+        let torrent = match self.lenient {
+            true => Torrent::from_bytes_lenient(bytes)?,
+            false => Torrent::from_bytes(bytes)?,
+        };
+        self.add_torrent(torrent)
+    }
+
+    /// accept `length` and `piece length` written as numeric strings, as some broken
+    /// generators do. Set it before adding the torrent, strict by default
+    pub fn set_lenient(mut self, lenient: bool) -> Self {
+        self.lenient = lenient;
+        self
+    }
+
+    /// download a parsed torrent, with its trackers and web seeds
+    pub fn add_torrent(mut self, torrent: Torrent) -> Result<Self> {
+        self.trackers = torrent.trackers.clone();
+        self.comment = torrent.comment.clone();
+        self.created_by = torrent.created_by.clone();
+        self.announce = torrent.announce.clone();
+        self.web_seeds = torrent.web_seeds.clone();
+        self.http_seeds = torrent.http_seeds.clone();
+        Ok(self.set_info(torrent))
+    }
+
+    /// load a bare info dictionary, as resolved from a magnet link
+    pub fn add_info_bytes(self, bytes: &[u8]) -> Result<Self> {
+        let torrent = match self.lenient {
+            true => Torrent::from_info_bytes_lenient(bytes)?,
+            false => Torrent::from_info_bytes(bytes)?,
         };
-        self.announce = Some(torrent.announce);
-        self.name = Some(torrent.info.name);
-        self.length = Some(torrent.info.length);
-        self.piece_length = Some(torrent.info.piece_length);
-        self.info_hash = Some(info_hash);
-        self.piece_hashes = Some(piece_hashes);
+        Ok(self.set_info(torrent))
+    }
+
+    /// resolve the info dictionary of a magnet link, from the metadata cache or from peers
+    pub async fn add_magnet(mut self, uri: &str) -> Result<Self> {
+        let magnet = MagnetLink::parse(uri)?;
+        self.announce = magnet.trackers.first().cloned();
+        self.trackers = magnet.trackers.clone();
+        self.initial_peers.extend(magnet.peers.iter().copied());
+        let cache = self
+            .metadata_cache_dir
+            .as_ref()
+            .map(MetadataCache::new)
+            .unwrap_or_default();
+        if let Some(info) = cache.load(&magnet.info_hash) {
+            info!("load metadata of {} from cache", uri);
+            return self.add_info_bytes(&info);
+        }
+
+        let peer_id = self.id.unwrap_or(Self::DEFAULT_PEER_ID);
+        let mut peers: Vec<SocketAddr> = magnet.peers.clone();
+        for tracker in magnet.trackers.iter() {
+            if !self.allows_tracker(tracker) {
+                info!("skip filtered tracker {}", tracker);
+                continue;
+            }
+            let request = AnnounceRequest {
+                info_hash: magnet.info_hash,
+                peer_id,
+                port: self.port.unwrap_or(Self::DEFAULT_PORT),
+                uploaded: 0,
+                downloaded: 0,
+                left: 0,
+                event: None,
+                param_order: self.announce_param_order.clone(),
+                max_response_size: self.max_response_size(),
+            };
+            match request.send(tracker).await {
+                Ok(report) => peers.extend(report.resolve_peers().await),
+                Err(err) => info!("tracker {} failed: {}", tracker, err),
+            }
+        }
+        if peers.is_empty() {
+            return Err(anyhow!("no peers found to fetch the metadata of {}", uri));
+        }
+        let info = MetadataFetcher::new(magnet.info_hash, peer_id)
+            .fetch(&peers)
+            .await
+            .map_err(|err| {
+                anyhow!(
+                    "failed to fetch the metadata of {} from {} peers: {}",
+                    uri,
+                    peers.len(),
+                    err
+                )
+            })?;
+        cache.store(&magnet.info_hash, &info)?;
+        self.add_info_bytes(&info)
+    }
 
-        Ok(self)
+    fn set_info(mut self, torrent: Torrent) -> Self {
+        self.private = torrent.private;
+        self.length = Some(torrent.length);
+        // single-file torrents are laid out by `name` and `length` alone
+        self.files = torrent.multi_file.then_some(torrent.files);
+        self.name = Some(torrent.name);
+        self.piece_length = Some(torrent.piece_length);
+        self.info_hash = Some(torrent.info_hash);
+        self.piece_hashes = Some(torrent.piece_hashes);
+        self
     }
 
     #[allow(unused)]
@@ -72,31 +241,1027 @@ impl TorrentClientBuilder {
         self
     }
 
+    /// where info dictionaries resolved from magnet links are cached
+    pub fn set_metadata_cache_dir<T>(mut self, dir: T) -> Self
+    where
+        T: AsRef<Path>,
+    {
+        self.metadata_cache_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// how peers choose the next piece among the queued ones, [`Sequential`] by
+    /// default. See [`crate::picker`] for the built-in pickers
+    pub fn set_piece_picker<P>(mut self, picker: P) -> Self
+    where
+        P: PiecePicker + 'static,
+    {
+        self.piece_picker = Some(Arc::new(picker));
+        self
+    }
+
+    /// how many pieces may be queued for download at once, unbounded by default.
+    /// Pieces handed back by peers or failing their hash are always requeued
+    pub fn set_task_queue_capacity(mut self, capacity: usize) -> Self {
+        self.task_queue_capacity = Some(capacity);
+        self
+    }
+
+    /// give up on the download once a piece failed its hash check `limit` times,
+    /// rather than fetching it again forever. 10 by default
+    pub fn set_max_piece_failures(mut self, limit: u32) -> Self {
+        self.max_piece_failures = Some(limit);
+        self
+    }
+
+    /// re-announce right away when nothing was downloaded for `window`, instead of
+    /// waiting for the tracker interval while every peer chokes us. Off by default
+    pub fn set_stall_window(mut self, window: Duration) -> Self {
+        self.stall_window = Some(window);
+        self
+    }
+
+    /// how failing tracker announces and peer connections are retried, 3 attempts
+    /// a second apart and doubling by default
+    pub fn set_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
+    /// how often an unreachable tracker or peer is tried again, a shorthand for
+    /// [`RetryPolicy::max_attempts`] of [`Self::set_retry_policy`]
+    pub fn set_tracker_retries(mut self, retries: u32) -> Self {
+        self.retry_policy.max_attempts = retries.saturating_add(1);
+        self
+    }
+
+    /// delay before the first retry, doubled on every further retry, a shorthand
+    /// for [`RetryPolicy::base_delay`] of [`Self::set_retry_policy`]
+    pub fn set_tracker_backoff(mut self, backoff: Duration) -> Self {
+        self.retry_policy.base_delay = backoff;
+        self
+    }
+
+    /// how many block buffers peers may hold at once, 4096 (64 MiB) by default.
+    /// Peers wait for a returned buffer once they are all out, so it should leave
+    /// room for a piece in progress at every peer
+    pub fn set_block_pool_size(mut self, size: usize) -> Self {
+        self.block_pool_size = Some(size);
+        self
+    }
+
+    /// once complete, keep seeding to the peers connecting to our port until uploaded /
+    /// downloaded reaches `ratio`. Needs a time limit as well, as the ratio may never be reached
+    pub fn set_seed_ratio_limit(mut self, ratio: f64) -> Self {
+        self.seed_ratio_limit = Some(ratio);
+        self
+    }
+
+    /// once complete, keep seeding for at most `limit`
+    pub fn set_seed_time_limit(mut self, limit: Duration) -> Self {
+        self.seed_time_limit = Some(limit);
+        self
+    }
+
+    /// drop a peer which has not finished its piece within `limit`, the piece is requeued
+    pub fn set_max_piece_time(mut self, limit: Duration) -> Self {
+        self.max_piece_time = Some(limit);
+        self
+    }
+
+    /// drop a peer delivering its piece below `bytes_per_sec`, the piece is requeued
+    pub fn set_min_peer_rate(mut self, bytes_per_sec: u64) -> Self {
+        self.min_peer_rate = Some(bytes_per_sec);
+        self
+    }
+
+    /// send a keep-alive to a peer we told nothing for `interval`, 2 minutes by default
+    pub fn set_keep_alive_interval(mut self, interval: Duration) -> Self {
+        self.keep_alive_interval = Some(interval);
+        self
+    }
+
+    /// drop a peer which neither sent nor asked for a block within `limit`, making room
+    /// for better ones. 5 minutes by default
+    pub fn set_idle_timeout(mut self, limit: Duration) -> Self {
+        self.idle_timeout = Some(limit);
+        self
+    }
+
+    /// halve the request window of a peer delivering its piece below `bytes_per_sec`
+    /// and grow it back once it speeds up, so a slow peer holds fewer blocks before
+    /// [`Self::set_min_peer_rate`] drops it. Meant to be above that minimum
+    pub fn set_slow_peer_rate(mut self, bytes_per_sec: u64) -> Self {
+        self.slow_peer_rate = Some(bytes_per_sec);
+        self
+    }
+
+    /// the token buckets limiting the rate blocks are received and sent at, unlimited
+    /// by default. Share one between torrents to limit them together
+    pub fn set_bandwidth(mut self, bandwidth: Arc<Bandwidth>) -> Self {
+        self.bandwidth = Some(bandwidth);
+        self
+    }
+
+    /// change the limits of the bandwidth by the time of day while downloading,
+    /// looked at every [`BandwidthSchedule::CHECK_INTERVAL`]
+    pub fn set_bandwidth_schedule(mut self, schedule: BandwidthSchedule) -> Self {
+        self.bandwidth_schedule = Some(schedule);
+        self
+    }
+
+    /// set TCP_NODELAY on peer connections, on by default
+    pub fn set_tcp_nodelay(mut self, nodelay: bool) -> Self {
+        self.socket_options.nodelay = nodelay;
+        self
+    }
+
+    /// receive buffer size (SO_RCVBUF) of peer connections, the system default if unset
+    pub fn set_recv_buffer_size(mut self, size: usize) -> Self {
+        self.socket_options.recv_buffer_size = Some(size.try_into().unwrap_or(u32::MAX));
+        self
+    }
+
+    /// send buffer size (SO_SNDBUF) of peer connections, the system default if unset
+    pub fn set_send_buffer_size(mut self, size: usize) -> Self {
+        self.socket_options.send_buffer_size = Some(size.try_into().unwrap_or(u32::MAX));
+        self
+    }
+
+    /// never let the storage take more than `bytes` of disk, downloading pauses
+    /// until space frees up when the quota is reached. The output files are
+    /// preallocated, so only a storage of cache files may take less than the content
+    pub fn set_disk_quota(mut self, bytes: u64) -> Self {
+        self.disk_quota = Some(bytes);
+        self
+    }
+
+    /// write every piece from one dedicated thread, in the order the pieces were
+    /// completed, instead of from the thread which hashed it. Off by default
+    pub fn set_serial_writes(mut self, enable: bool) -> Self {
+        self.serial_writes = enable;
+        self
+    }
+
+    /// keep pieces somewhere else than the output file itself, or the per-piece cache
+    /// files of a multi-file torrent
+    pub fn set_storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    /// what assembling the cache files does about a piece which is not cached,
+    /// failing by default. It takes the piece cache, see [`Self::set_piece_cache`]
+    pub fn set_missing_piece(mut self, on_missing: MissingPiece) -> Self {
+        self.missing_piece = Some(on_missing);
+        self
+    }
+
+    /// keep every verified piece in a cache file of its own and assemble the output
+    /// from them once the download is complete, instead of writing to the output
+    pub fn set_piece_cache(mut self, enable: bool) -> Self {
+        self.piece_cache = enable;
+        self
+    }
+
+    /// the order the piece cache assembles a single-file output in, ascending by
+    /// default. Every piece lands at its own offset either way, so this is there to
+    /// check exactly that. It takes the piece cache as well
+    pub fn set_assembly_order(mut self, order: AssemblyOrder) -> Self {
+        self.assembly_order = Some(order);
+        self
+    }
+
+    /// move the output, the file or the directory of a multi-file torrent, into `dir`
+    /// once the download is complete, e.g. out of a directory of incomplete downloads.
+    /// It keeps an existing entry of the same name, renaming the moved one
+    pub fn set_completed_dir<T>(mut self, dir: T) -> Self
+    where
+        T: AsRef<Path>,
+    {
+        self.completed_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// whether a finished download is assembled into the output right away, true by default.
+    /// When disabled the verified pieces stay in the storage until [`TorrentClient::assemble`]
+    pub fn set_assemble(mut self, assemble: bool) -> Self {
+        self.assemble = Some(assemble);
+        self
+    }
+
+    /// only resolve the metadata, e.g. of a magnet link to look at its files. Running
+    /// the client then returns right away without asking anyone for content
+    pub fn set_metadata_only(mut self, metadata_only: bool) -> Self {
+        self.metadata_only = metadata_only;
+        self
+    }
+
+    /// keep a binary control file with the download progress next to the output
+    pub fn set_control_file(mut self, enable: bool) -> Self {
+        self.control_file = enable;
+        self
+    }
+
+    /// re-announce up to `attempts` times when every peer leaves before the download is complete
+    pub fn set_reannounce_attempts(mut self, attempts: u32) -> Self {
+        self.reannounce_attempts = attempts;
+        self
+    }
+
+    /// connect to peers on the local network before public ones
+    pub fn set_prefer_local_peers(mut self, prefer: bool) -> Self {
+        self.prefer_local_peers = prefer;
+        self
+    }
+
+    /// on re-announce, connect to peers which connected before ahead of those which failed
+    pub fn set_prefer_known_peers(mut self, prefer: bool) -> Self {
+        self.prefer_known_peers = prefer;
+        self
+    }
+
+    /// announce to `trackers` as well as the ones of the torrent, refused by
+    /// [`TorrentClientBuilder::build`] for a private torrent
+    pub fn add_trackers(mut self, trackers: Vec<String>) -> Self {
+        self.extra_trackers.extend(trackers);
+        self
+    }
+
+    /// announce only to trackers whose url `filter` returns true for, whether they
+    /// come from `announce`, `announce-list`, a magnet link or [`Self::add_trackers`].
+    /// Set it before [`Self::add_magnet`] to apply it to the metadata lookup as well
+    pub fn set_tracker_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        self.tracker_filter = Some(TrackerFilter::new(filter));
+        self
+    }
+
+    /// keep only peers whose id `filter` returns true for, the others are
+    /// disconnected right after the handshake. See [`crate::stats::client_name`]
+    /// to decode the client and version from an id
+    pub fn set_peer_id_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&[u8; 20]) -> bool + Send + Sync + 'static,
+    {
+        self.peer_id_filter = Some(PeerIdFilter::new(filter));
+        self
+    }
+
+    /// the extensions advertised to peers while downloading, all of [`Extension::ALL`]
+    /// by default. Messages of the others are ignored, and without any extension the
+    /// extension protocol itself is not advertised
+    pub fn set_extensions(mut self, extensions: Vec<Extension>) -> Self {
+        self.extensions = Some(extensions);
+        self
+    }
+
+    fn allows_tracker(&self, tracker: &str) -> bool {
+        self.tracker_filter
+            .as_ref()
+            .is_none_or(|filter| filter.allows(tracker))
+    }
+
+    /// send the announce parameters in this order, e.g. `["info_hash", "peer_id", "port"]`,
+    /// unlisted ones follow in the usual order
+    pub fn set_announce_param_order(mut self, order: Vec<String>) -> Self {
+        self.announce_param_order = Some(order);
+        self
+    }
+
+    /// peers to connect to in the first round besides those of the trackers
+    pub fn add_peers(mut self, peers: Vec<SocketAddr>) -> Self {
+        self.initial_peers.extend(peers);
+        self
+    }
+
+    /// our public address, peers announced at it with our port are skipped.
+    /// Otherwise it is learned from trackers reporting `external ip`
+    pub fn set_external_ip(mut self, ip: IpAddr) -> Self {
+        self.external_ip = Some(ip);
+        self
+    }
+
+    /// spread re-announces randomly by this fraction of the tracker interval, 0.1 by default
+    pub fn set_announce_jitter(mut self, jitter: f64) -> Self {
+        self.announce_jitter = Some(jitter);
+        self
+    }
+
+    /// permits for hashing stored pieces on resume, one per piece being hashed.
+    /// Share one semaphore between torrents to bound their verification together
+    pub fn set_verify_limit(mut self, limit: Arc<Semaphore>) -> Self {
+        self.verify_limit = Some(limit);
+        self
+    }
+
+    /// keep each peer on a contiguous run of pieces: after completing a piece a peer
+    /// takes the one right after it if still queued, which keeps writes and reads of
+    /// the output close together when streaming
+    pub fn set_piece_affinity(mut self, affinity: bool) -> Self {
+        self.piece_affinity = affinity;
+        self
+    }
+
+    /// ask the gateway of the local network to forward the listen port (NAT-PMP) and
+    /// announce the external port it maps, removing the mapping once done. Off by default
+    pub fn set_port_mapping(mut self, port_mapping: bool) -> Self {
+        self.port_mapping = port_mapping;
+        self
+    }
+
+    /// the largest tracker response or torrent file read over HTTP, 4 MiB by default.
+    /// Set it before [`TorrentClientBuilder::add_torrent_url`] to apply it there
+    pub fn set_max_response_size(mut self, bytes: usize) -> Self {
+        self.max_response_size = Some(bytes);
+        self
+    }
+
+    fn max_response_size(&self) -> usize {
+        self.max_response_size.unwrap_or(http::MAX_BODY)
+    }
+
+    /// the NAT-PMP gateway to ask for the port mapping instead of the default route
+    pub fn set_gateway(mut self, gateway: SocketAddr) -> Self {
+        self.gateway = Some(gateway);
+        self
+    }
+
+    /// how many block requests may be in flight to a single peer, 5 by default
+    pub fn set_pipeline_depth(mut self, depth: usize) -> Self {
+        self.pipeline_depth = Some(depth);
+        self
+    }
+
+    /// how many peer connection attempts may be pending at once, 32 by default
+    pub fn set_max_pending_connects(mut self, max: usize) -> Self {
+        self.max_pending_connects = Some(max);
+        self
+    }
+
+    /// choose whether web seeds compete with peers from the start or only back them up
+    pub fn set_webseed_policy(mut self, policy: WebSeedPolicy) -> Self {
+        self.webseed_policy = policy;
+        self
+    }
+
+    /// how long peers get before web seeds step in under [`WebSeedPolicy::Fallback`]
+    pub fn set_webseed_timeout(mut self, timeout: Duration) -> Self {
+        self.webseed_timeout = Some(timeout);
+        self
+    }
+
+    /// whether the loaded torrent uses a power-of-two piece length
+    pub fn is_standard_piece_length(&self) -> bool {
+        self.piece_length.is_some_and(u32::is_power_of_two)
+    }
+
     fn piece_num(&self) -> u32 {
-        self.length.unwrap() / self.piece_length.unwrap()
+        task::piece_count(self.length.unwrap(), self.piece_length.unwrap())
+    }
+
+    /// reject piece lengths which would break the piece arithmetic or are implausible,
+    /// and warn about a non-standard one
+    fn validate(&self) -> Result<()> {
+        let (Some(piece_length), Some(length), Some(piece_hashes)) =
+            (self.piece_length, self.length, self.piece_hashes.as_ref())
+        else {
+            return Err(anyhow!("no torrent loaded into the builder"));
+        };
+        if piece_length == 0 {
+            return Err(anyhow!("piece length must not be zero"));
+        }
+        // allowed, but blocks will not line up with such pieces
+        if !piece_length.is_power_of_two() {
+            warn!(
+                "piece length {} is not a power of two, blocks will not align to pieces",
+                piece_length
+            );
+        }
+        if !(Self::MIN_PIECE_LENGTH..=Self::MAX_PIECE_LENGTH).contains(&piece_length) {
+            return Err(anyhow!(
+                "piece length {} is outside of {}..={}",
+                piece_length,
+                Self::MIN_PIECE_LENGTH,
+                Self::MAX_PIECE_LENGTH
+            ));
+        }
+        if piece_length > length && piece_hashes.len() > 1 {
+            return Err(anyhow!(
+                "piece length {} is larger than the total length {}",
+                piece_length,
+                length
+            ));
+        }
+        Ok(())
     }
 
-    pub fn build(self) -> TorrentClient {
+    pub fn build(mut self) -> Result<TorrentClient> {
+        self.validate()?;
+        if self.seed_ratio_limit.is_some() && self.seed_time_limit.is_none() {
+            return Err(anyhow!(
+                "a seed ratio limit needs a seed time limit as well"
+            ));
+        }
+        // blocks stay checked out until their piece is complete
+        let piece_blocks = self.piece_length.unwrap().div_ceil(Peer::BLOCK_SIZE) as usize;
+        if self.block_pool_size.is_some_and(|size| size < piece_blocks) {
+            return Err(anyhow!(
+                "a block pool of {} buffers cannot hold a piece of {} blocks",
+                self.block_pool_size.unwrap(),
+                piece_blocks
+            ));
+        }
+        if !self.extra_trackers.is_empty() {
+            if self.private {
+                return Err(anyhow!(
+                    "{} is a private torrent, it cannot use other trackers",
+                    self.name.unwrap_or_default()
+                ));
+            }
+            for tracker in std::mem::take(&mut self.extra_trackers) {
+                if !self.trackers.contains(&tracker) {
+                    self.trackers.push(tracker);
+                }
+            }
+        }
+        if self.tracker_filter.is_some() {
+            let trackers = std::mem::take(&mut self.trackers);
+            for tracker in trackers {
+                if self.allows_tracker(&tracker) {
+                    self.trackers.push(tracker);
+                } else {
+                    info!("skip filtered tracker {}", tracker);
+                }
+            }
+            if !self
+                .announce
+                .as_ref()
+                .is_some_and(|announce| self.allows_tracker(announce))
+            {
+                self.announce = self.trackers.first().cloned();
+            }
+        }
         let piece_num = self.piece_num();
-        let task_queue = ArrayQueue::new(piece_num as usize);
+        let task_queue = TaskQueue::new(self.task_queue_capacity.unwrap_or(usize::MAX));
         let pb = {
             let pb = ProgressBar::new(self.length.unwrap() as _);
             pb.set_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
         .unwrap());
             pb
         };
-        TorrentClient {
-            announce: self.announce.unwrap(),
+        let name = Arc::new(self.name.unwrap());
+        // the output files are preallocated, only written pieces could make room
+        let cached = self.storage.is_none() && (self.piece_cache || self.metadata_only);
+        if self.missing_piece.is_some() && !cached {
+            return Err(anyhow!(
+                "a missing piece policy takes the piece cache, see set_piece_cache"
+            ));
+        }
+        if self.assembly_order.is_some() && !cached {
+            return Err(anyhow!(
+                "an assembly order takes the piece cache, see set_piece_cache"
+            ));
+        }
+        let preallocated = self.storage.is_none() && !cached;
+        if let Some(quota) = self.disk_quota.filter(|_| preallocated) {
+            if quota < self.length.unwrap() as u64 {
+                return Err(anyhow!(
+                    "a disk quota of {} bytes cannot hold the output of {} bytes",
+                    quota,
+                    self.length.unwrap()
+                ));
+            }
+        }
+        let storage: Arc<dyn Storage> = match self.storage {
+            Some(storage) => storage,
+            None if cached => {
+                let storage = CacheStorage::new(name.clone(), piece_num)
+                    .with_piece_length(self.piece_length.unwrap())
+                    .with_length(self.length.unwrap() as u64)
+                    .with_missing_piece(self.missing_piece.unwrap_or_default())
+                    .with_assembly_order(self.assembly_order.unwrap_or_default());
+                match self.files.clone() {
+                    Some(files) => Arc::new(storage.with_files(files)),
+                    None => Arc::new(storage),
+                }
+            }
+            // verified pieces go straight to their offset in the output, which holds
+            // whatever was verified before a crash for the resume to hash again
+            None if self.files.is_none() => Arc::new(FileStorage::new(
+                name.as_str(),
+                self.piece_length.unwrap(),
+                self.length.unwrap() as u64,
+            )?),
+            // each file has a handle of its own, pieces crossing files are split
+            None => Arc::new(MultiFileStorage::new(
+                self.files.clone().unwrap(),
+                self.piece_length.unwrap(),
+                self.length.unwrap() as u64,
+            )?),
+        };
+        let storage: Arc<dyn Storage> = match self.serial_writes {
+            true => Arc::new(SerialWriter::new(storage)?),
+            false => storage,
+        };
+        let files = self.files.unwrap_or_else(|| {
+            vec![TorrentFile {
+                path: PathBuf::from(name.as_str()),
+                offset: 0,
+                length: self.length.unwrap() as u64,
+            }]
+        });
+        Ok(TorrentClient {
+            announce: self.announce.unwrap_or_default(),
+            trackers: Mutex::new(self.trackers),
             info_hash: self.info_hash.unwrap(),
             piece_hashes: self.piece_hashes.unwrap(),
             piece_length: self.piece_length.unwrap(),
-            name: Arc::new(self.name.unwrap()),
+            name,
             length: self.length.unwrap(),
-            id: self.id.unwrap_or(*b"-RT0001-123456012345"),
-            port: self.port.unwrap_or(6881),
+            files,
+            id: self.id.unwrap_or(Self::DEFAULT_PEER_ID),
+            port: self.port.unwrap_or(Self::DEFAULT_PORT),
             task_queue: Arc::new(task_queue),
-            bitfield: Bitfield::new(piece_num),
+            bitfield: Arc::new(Mutex::new(Bitfield::new(piece_num))),
             pb,
+            downloaded: Arc::new(AtomicU64::new(0)),
+            uploaded: Arc::new(AtomicU64::new(0)),
+            control_file: self.control_file,
+            reannounce_attempts: self.reannounce_attempts,
+            prefer_local_peers: self.prefer_local_peers,
+            prefer_known_peers: self.prefer_known_peers,
+            peer_history: Default::default(),
+            external_ip: Arc::new(Mutex::new(self.external_ip)),
+            // until a tracker tells us its interval
+            announce_interval: Mutex::new(Duration::from_secs(30 * 60)),
+            announce_jitter: self.announce_jitter.unwrap_or(0.1),
+            state: Default::default(),
+            private: self.private,
+            comment: self.comment,
+            created_by: self.created_by,
+            initial_peers: self.initial_peers,
+            announce_param_order: self.announce_param_order,
+            tracker_filter: self.tracker_filter,
+            peer_id_filter: self.peer_id_filter,
+            tracker_stats: Default::default(),
+            extensions: self.extensions.unwrap_or_else(|| Extension::ALL.to_vec()),
+            stall_window: self.stall_window,
+            piece_failures: Default::default(),
+            max_piece_failures: self.max_piece_failures.unwrap_or(10),
+            piece_picker: self.piece_picker.unwrap_or_else(|| Arc::new(Sequential)),
+            completed_dir: self.completed_dir,
+            message_totals: Default::default(),
+            metadata_only: self.metadata_only,
+            pipeline_depth: self.pipeline_depth.unwrap_or(5).max(1),
+            piece_affinity: self.piece_affinity,
+            port_mapping: self.port_mapping,
+            gateway: self.gateway,
+            mapping: Mutex::new(None),
+            max_response_size: self.max_response_size.unwrap_or(http::MAX_BODY),
+            rate_window: Default::default(),
+            verify_limit: self
+                .verify_limit
+                .unwrap_or_else(|| Arc::new(Semaphore::new(Self::DEFAULT_VERIFY_LIMIT))),
+            connect_permits: Arc::new(Semaphore::new(
+                self.max_pending_connects.unwrap_or(32).max(1),
+            )),
+            disk_quota: self
+                .disk_quota
+                .map(|limit| Arc::new(DiskQuota::new(limit, storage.clone()))),
+            web_seeds: self.web_seeds,
+            http_seeds: self.http_seeds,
+            webseed_policy: self.webseed_policy,
+            webseed_timeout: self.webseed_timeout.unwrap_or(Duration::from_secs(30)),
+            availability: Arc::new(Mutex::new(vec![0; piece_num as usize])),
+            blocks_completed: Arc::new(AtomicU64::new(0)),
+            pieces_checked: Arc::new(AtomicU64::new(0)),
+            shutdown: watch::Sender::new(false),
+            storage,
+            retry_policy: self.retry_policy,
+            peer_stats: Default::default(),
+            block_pool: Arc::new(BlockPool::new(
+                self.block_pool_size.unwrap_or(4096),
+                // a piece message carries 9 bytes of header besides the block
+                Peer::BLOCK_SIZE as usize + 9,
+            )),
+            seed_ratio_limit: self.seed_ratio_limit,
+            seed_time_limit: self.seed_time_limit,
+            assemble: self.assemble.unwrap_or(true),
+            max_piece_time: self.max_piece_time,
+            min_peer_rate: self.min_peer_rate,
+            slow_peer_rate: self.slow_peer_rate,
+            keep_alive_interval: self
+                .keep_alive_interval
+                .unwrap_or(Duration::from_secs(2 * 60)),
+            idle_timeout: self.idle_timeout.unwrap_or(Duration::from_secs(5 * 60)),
+            bandwidth: self.bandwidth.unwrap_or_default(),
+            bandwidth_schedule: self.bandwidth_schedule,
+            socket_options: self.socket_options,
+            assembly: Default::default(),
+            events: broadcast::Sender::new(1024),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{storage::MemoryStorage, testutil};
+
+    #[test]
+    fn non_power_of_two_piece_length_only_warns() {
+        // 48 KiB, within the sane range but not a power of two
+        let content = testutil::content(100_000);
+        let builder = testutil::builder(&content, 48 * 1024);
+        assert!(!builder.is_standard_piece_length());
+        let client = builder.build().unwrap();
+        assert!(!client.is_standard_piece_length());
+        assert_eq!(client.piece_hashes.len(), 3);
+    }
+
+    #[test]
+    fn torrent_bytes_with_a_bom() {
+        let content = testutil::content(1000);
+        let mut bytes = b"\xEF\xBB\xBF \r\n".to_vec();
+        bytes.extend(testutil::torrent_bytes("bom", &content, 32768));
+        let client = TorrentClientBuilder::new()
+            .add_torrent_bytes(&bytes)
+            .unwrap()
+            .set_storage(Arc::new(MemoryStorage::new()))
+            .build()
+            .unwrap();
+        assert_eq!(
+            client.info_hash,
+            testutil::torrent("bom", &content, 32768).info_hash
+        );
+    }
+
+    #[test]
+    fn info_hash_covers_unmodeled_info_keys() {
+        let mut bytes = b"d8:announce".to_vec();
+        bytes.extend(
+            format!(
+                "{}:{}",
+                testutil::DEAD_TRACKER.len(),
+                testutil::DEAD_TRACKER
+            )
+            .bytes(),
+        );
+        bytes.extend(b"4:infod6:lengthi5e4:name4:file12:piece lengthi16384e6:pieces20:");
+        bytes.extend([b'a'; 20]);
+        // not a key we model, but part of what the hash identifies
+        bytes.extend(b"6:source3:rbtee");
+        let client = TorrentClientBuilder::new()
+            .add_torrent_bytes(&bytes)
+            .unwrap()
+            .set_storage(Arc::new(MemoryStorage::new()))
+            .build()
+            .unwrap();
+        let hex: String = client
+            .info_hash
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        assert_eq!(hex, "28e45ee5332b8a4f7c583e6b5954948d81df2f1c");
+    }
+
+    #[test]
+    fn piece_hash_count_must_match_the_length() {
+        let content = testutil::content(100_000);
+        // four pieces of content, but only three hashes
+        let mut info = testutil::info("short", &content, 32768);
+        info.pieces = info.pieces.slice(..60);
+        let err = TorrentClientBuilder::new()
+            .add_torrent_bytes(&serde_bencode::to_bytes(&testutil::torrent_of(info)).unwrap())
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("3 piece hashes, but 100000 bytes in pieces of 32768 take 4"),
+            "{}",
+            err
+        );
+        // the files of a multi-file torrent add up to one piece too many
+        let files = [("a.bin", 70000), ("b.bin", 70000)];
+        let info = testutil::multi_info("multi", &files, &content, 32768);
+        let err = TorrentClientBuilder::new()
+            .add_torrent_bytes(&serde_bencode::to_bytes(&testutil::torrent_of(info)).unwrap())
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("4 piece hashes, but 140000"),
+            "{}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn piece_cache_assembles_the_files() {
+        let name = testutil::unique_name("piece-cache");
+        let _output = testutil::Scratch::output(&name);
+        let content = testutil::content(100_000);
+        let files = [("a.bin", 40000), ("b.bin", 60000)];
+        let info = testutil::multi_info(&name, &files, &content, 32768);
+        let bytes = serde_bencode::to_bytes(&testutil::torrent_of(info)).unwrap();
+        let info_hash = crate::Torrent::from_bytes(&bytes).unwrap().info_hash;
+        let builder = || {
+            TorrentClientBuilder::new()
+                .add_torrent_bytes(&bytes)
+                .unwrap()
+                .set_retry_policy(crate::RetryPolicy::none())
+                .set_missing_piece(MissingPiece::ZeroFill)
+        };
+        // without the piece cache nothing would ever be missing
+        let err = builder().build().unwrap_err();
+        assert!(err.to_string().contains("set_piece_cache"), "{}", err);
+        assert!(!std::path::Path::new(&name).exists());
+
+        let seed = testutil::seed(content.clone(), 32768, info_hash, Default::default()).await;
+        let client = builder()
+            .set_piece_cache(true)
+            .add_peers(vec![seed])
+            .build()
+            .unwrap();
+        client.send_request().await.unwrap();
+        let output = std::path::Path::new(&name);
+        assert_eq!(
+            std::fs::read(output.join("a.bin")).unwrap(),
+            content[..40000]
+        );
+        assert_eq!(
+            std::fs::read(output.join("b.bin")).unwrap(),
+            content[40000..]
+        );
+        assert!(!std::path::Path::new(&format!("{}.cache", name)).exists());
+    }
+
+    #[tokio::test]
+    async fn assembly_order_leaves_the_output_as_it_is() {
+        let content = testutil::content(100_000);
+        for order in [AssemblyOrder::Ascending, AssemblyOrder::Descending] {
+            let name = testutil::unique_name("order");
+            let _output = testutil::Scratch::output(&name);
+            let bytes = testutil::torrent_bytes(&name, &content, 32768);
+            let info_hash = crate::Torrent::from_bytes(&bytes).unwrap().info_hash;
+            let builder = || {
+                TorrentClientBuilder::new()
+                    .add_torrent_bytes(&bytes)
+                    .unwrap()
+                    .set_retry_policy(crate::RetryPolicy::none())
+                    .set_assembly_order(order)
+            };
+            let err = builder().build().unwrap_err();
+            assert!(err.to_string().contains("set_piece_cache"), "{}", err);
+
+            let seed = testutil::seed(content.clone(), 32768, info_hash, Default::default()).await;
+            let client = builder()
+                .set_piece_cache(true)
+                .add_peers(vec![seed])
+                .build()
+                .unwrap();
+            client.send_request().await.unwrap();
+            // the short final piece included, every piece at its own offset
+            assert!(std::fs::read(&name).unwrap() == content, "{:?}", order);
+            assert!(client.verify_output().unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn utf8_comment_is_preferred() {
+        let content = testutil::content(1000);
+        let mut torrent = testutil::torrent_of(testutil::info("comment", &content, 32768));
+        // the legacy key in latin-1, as old clients wrote it
+        torrent.comment = Some(b"caf\xe9"[..].into());
+        torrent.comment_utf8 = Some("café".as_bytes().into());
+        torrent.created_by = Some(b"rbt 1.0"[..].into());
+        let client = testutil::builder_of(&torrent).build().unwrap();
+        assert_eq!(client.comment.as_deref(), Some("café"));
+        // without a .utf-8 variant the legacy key is used
+        assert_eq!(client.created_by.as_deref(), Some("rbt 1.0"));
+        let parsed = crate::Torrent::from_bytes(&serde_bencode::to_bytes(&torrent).unwrap());
+        assert_eq!(parsed.unwrap().comment.as_deref(), Some("café"));
+    }
+
+    #[test]
+    fn lenient_mode_takes_numeric_string_lengths() {
+        let content = testutil::content(1000);
+        let mut bytes = testutil::torrent_bytes("rbt-test-memory", &content, 32768);
+        // the piece hashes are binary, so replace the integers byte by byte
+        let mut replace = |from: &[u8], to: &[u8]| {
+            let at = bytes.windows(from.len()).position(|w| w == from).unwrap();
+            bytes.splice(at..at + from.len(), to.iter().copied());
+        };
+        replace(b"6:lengthi1000e", b"6:length4:1000");
+        replace(b"12:piece lengthi32768e", b"12:piece length5:32768");
+        let err = TorrentClientBuilder::new()
+            .add_torrent_bytes(&bytes)
+            .unwrap_err();
+        assert!(err.to_string().contains("u32"), "{}", err);
+        let client = TorrentClientBuilder::new()
+            .set_lenient(true)
+            .add_torrent_bytes(&bytes)
+            .unwrap()
+            .set_storage(Arc::new(MemoryStorage::new()))
+            .build()
+            .unwrap();
+        assert_eq!(client.length, 1000);
+        assert_eq!(client.piece_length, 32768);
+    }
+
+    #[test]
+    fn disk_quota_must_hold_preallocated_output() {
+        let name = testutil::unique_name("quota");
+        let _output = testutil::Scratch::output(&name);
+        let content = testutil::content(100_000);
+        let bytes = testutil::torrent_bytes(&name, &content, 32768);
+        let builder = || {
+            TorrentClientBuilder::new()
+                .add_torrent_bytes(&bytes)
+                .unwrap()
+        };
+        let err = builder().set_disk_quota(65536).build().unwrap_err();
+        assert!(err.to_string().contains("disk quota"), "{}", err);
+        assert!(!std::path::Path::new(&name).exists());
+        assert!(builder().set_disk_quota(100_000).build().is_ok());
+        // pieces kept elsewhere may take less
+        assert!(builder()
+            .set_storage(Arc::new(MemoryStorage::new()))
+            .set_disk_quota(65536)
+            .build()
+            .is_ok());
+    }
+
+    #[test]
+    fn implausible_piece_lengths_are_rejected() {
+        let content = testutil::content(100_000);
+        let build = |piece_length: u32| {
+            // hashes of zero length pieces cannot be worked out, keep some
+            let hashed_with = if piece_length == 0 {
+                32768
+            } else {
+                piece_length
+            };
+            let mut torrent = testutil::torrent_of(testutil::info("pieces", &content, hashed_with));
+            torrent.info.piece_length = piece_length;
+            TorrentClientBuilder::new()
+                .add_torrent_bytes(&serde_bencode::to_bytes(&torrent).unwrap())?
+                .set_storage(Arc::new(MemoryStorage::new()))
+                .build()
+        };
+        assert!(build(32768).is_ok());
+        for piece_length in [0, 1024, 128 * 1024 * 1024] {
+            let err = build(piece_length).unwrap_err();
+            assert!(err.to_string().contains("piece length"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn power_of_two_piece_length_is_standard() {
+        let client = testutil::client(&testutil::content(100_000), 32 * 1024);
+        assert!(client.is_standard_piece_length());
+    }
+
+    fn magnet(info_hash: &[u8; 20]) -> String {
+        let hex: String = info_hash.iter().map(|b| format!("{:02x}", b)).collect();
+        format!("magnet:?xt=urn:btih:{}&dn=cached", hex)
+    }
+
+    #[tokio::test]
+    async fn magnet_from_the_metadata_cache() {
+        let dir = testutil::Scratch::dir("metadata-cache");
+        let content = testutil::content(100_000);
+        let info = serde_bencode::to_bytes(&testutil::info("cached", &content, 32768)).unwrap();
+        let torrent = Torrent::from_info_bytes(&info).unwrap();
+        MetadataCache::new(dir.path())
+            .store(&torrent.info_hash, &info)
+            .unwrap();
+        // neither trackers nor peers, the cache is the only way to the metadata
+        let client = TorrentClientBuilder::new()
+            .set_metadata_cache_dir(dir.path())
+            .add_magnet(&magnet(&torrent.info_hash))
+            .await
+            .unwrap()
+            .set_storage(Arc::new(MemoryStorage::new()))
+            .build()
+            .unwrap();
+        assert_eq!(client.info_hash, torrent.info_hash);
+        assert_eq!(client.name.as_str(), "cached");
+        assert_eq!(client.piece_hashes.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn magnet_peer_hints_become_initial_peers() {
+        let dir = testutil::Scratch::dir("metadata-cache");
+        let content = testutil::content(1000);
+        let info = serde_bencode::to_bytes(&testutil::info("cached", &content, 32768)).unwrap();
+        let info_hash = Torrent::from_info_bytes(&info).unwrap().info_hash;
+        MetadataCache::new(dir.path())
+            .store(&info_hash, &info)
+            .unwrap();
+        let uri = format!(
+            "{}&x.pe=10.0.0.5:6881&x.pe=%5B::1%5D:51413&x.pe=peer.lan:6881",
+            magnet(&info_hash)
+        );
+        let client = TorrentClientBuilder::new()
+            .set_metadata_cache_dir(dir.path())
+            .add_magnet(&uri)
+            .await
+            .unwrap()
+            .set_storage(Arc::new(MemoryStorage::new()))
+            .build()
+            .unwrap();
+        // host names are skipped, only ip:port hints are used
+        assert_eq!(
+            client.initial_peers,
+            vec![
+                "10.0.0.5:6881".parse().unwrap(),
+                "[::1]:51413".parse().unwrap()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn magnet_without_cached_metadata_needs_peers() {
+        let dir = testutil::Scratch::dir("metadata-cache");
+        let content = testutil::content(1000);
+        let info = serde_bencode::to_bytes(&testutil::info("cached", &content, 32768)).unwrap();
+        let info_hash = Torrent::from_info_bytes(&info).unwrap().info_hash;
+        // stored under another hash, e.g. a corrupted cache entry
+        let mut other = info_hash;
+        other[0] ^= 1;
+        MetadataCache::new(dir.path()).store(&other, &info).unwrap();
+        let result = TorrentClientBuilder::new()
+            .set_metadata_cache_dir(dir.path())
+            .add_magnet(&magnet(&other))
+            .await;
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn names_reaching_outside_the_output_are_rejected() {
+        let content = testutil::content(1000);
+        let escaped = testutil::unique_name("escaped");
+        let outside = format!("../{}", escaped);
+        for name in [
+            outside.as_str(),
+            "..",
+            "/tmp/rbt-absolute",
+            "a/b",
+            "..\\x",
+            "",
+        ] {
+            let bytes = testutil::torrent_bytes(name, &content, 32768);
+            assert!(
+                TorrentClientBuilder::new()
+                    .add_torrent_bytes(&bytes)
+                    .is_err(),
+                "{:?}",
+                name
+            );
+        }
+        for path in ["../x", "sub/../../x", "/etc/x"] {
+            let info = testutil::multi_info("multi", &[("a", 500), (path, 500)], &content, 32768);
+            let bytes = serde_bencode::to_bytes(&testutil::torrent_of(info)).unwrap();
+            assert!(
+                TorrentClientBuilder::new()
+                    .add_torrent_bytes(&bytes)
+                    .is_err(),
+                "{:?}",
+                path
+            );
+        }
+        assert!(!Path::new(&outside).exists());
+    }
+
+    #[test]
+    fn seed_ratio_limit_needs_a_time_limit() {
+        let content = testutil::content(1000);
+        let result = testutil::builder(&content, 32768)
+            .set_seed_ratio_limit(1.0)
+            .build();
+        assert!(result.is_err());
+        assert!(testutil::builder(&content, 32768)
+            .set_seed_ratio_limit(1.0)
+            .set_seed_time_limit(Duration::from_secs(60))
+            .build()
+            .is_ok());
+    }
+
+    #[test]
+    fn block_pool_must_hold_a_piece() {
+        let content = testutil::content(1000);
+        for size in [0, 1] {
+            let result = testutil::builder(&content, 32768)
+                .set_block_pool_size(size)
+                .build();
+            assert!(result.is_err());
         }
+        assert!(testutil::builder(&content, 32768)
+            .set_block_pool_size(2)
+            .build()
+            .is_ok());
     }
 }
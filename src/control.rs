@@ -0,0 +1,164 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use bytes::{Buf, BufMut, BytesMut};
+
+use crate::message::Bitfield;
+
+/// A versioned binary snapshot of download progress, written next to the output.
+///
+/// Layout (all integers big endian):
+///
+/// | field        | size          |
+/// |--------------|---------------|
+/// | magic `RBTC` | 4             |
+/// | version      | 1             |
+/// | info_hash    | 20            |
+/// | piece_length | 4             |
+/// | length       | 4             |
+/// | downloaded   | 8             |
+/// | uploaded     | 8             |
+/// | bitfield len | 4             |
+/// | bitfield     | bitfield len  |
+#[derive(Debug, Clone)]
+pub struct ControlFile {
+    pub info_hash: [u8; 20],
+    pub piece_length: u32,
+    pub length: u32,
+    /// totals across every run, restored on resume so announces and the seed
+    /// ratio keep counting where the last run stopped
+    pub downloaded: u64,
+    pub uploaded: u64,
+    pub bitfield: Bitfield,
+}
+
+impl ControlFile {
+    pub const MAGIC: &'static [u8; 4] = b"RBTC";
+    pub const VERSION: u8 = 1;
+    const HEADER_LEN: usize = 4 + 1 + 20 + 4 + 4 + 8 + 8 + 4;
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let bitfield = self.bitfield.as_bytes();
+        let mut buf = BytesMut::with_capacity(Self::HEADER_LEN + bitfield.len());
+        buf.put_slice(Self::MAGIC);
+        buf.put_u8(Self::VERSION);
+        buf.put_slice(&self.info_hash);
+        buf.put_u32(self.piece_length);
+        buf.put_u32(self.length);
+        buf.put_u64(self.downloaded);
+        buf.put_u64(self.uploaded);
+        buf.put_u32(bitfield.len() as u32);
+        buf.put_slice(bitfield);
+        buf.to_vec()
+    }
+
+    pub fn from_bytes(mut buf: &[u8]) -> Result<Self> {
+        if buf.len() < Self::HEADER_LEN || &buf[..4] != Self::MAGIC {
+            return Err(anyhow!("not a control file"));
+        }
+        buf.advance(4);
+        let version = buf.get_u8();
+        if version != Self::VERSION {
+            return Err(anyhow!("unsupported control file version: {}", version));
+        }
+        let info_hash = buf[..20].try_into().unwrap();
+        buf.advance(20);
+        let piece_length = buf.get_u32();
+        let length = buf.get_u32();
+        let downloaded = buf.get_u64();
+        let uploaded = buf.get_u64();
+        let bitfield_len = buf.get_u32() as usize;
+        if buf.len() != bitfield_len {
+            return Err(anyhow!(
+                "control file bitfield is {} bytes, expected {}",
+                buf.len(),
+                bitfield_len
+            ));
+        }
+        Ok(Self {
+            info_hash,
+            piece_length,
+            length,
+            downloaded,
+            uploaded,
+            bitfield: Bitfield::from(buf),
+        })
+    }
+
+    pub fn save<T>(&self, path: T) -> Result<()>
+    where
+        T: AsRef<Path>,
+    {
+        // write to a sibling first so a crash never leaves a torn control file
+        let tmp = path.as_ref().with_extension("rbt.tmp");
+        std::fs::write(&tmp, self.as_bytes())?;
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    pub fn load<T>(path: T) -> Result<Self>
+    where
+        T: AsRef<Path>,
+    {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::Scratch;
+
+    fn sample() -> ControlFile {
+        let mut bitfield = Bitfield::new(2);
+        [0, 3, 9]
+            .into_iter()
+            .for_each(|index| bitfield.set_piece(index));
+        ControlFile {
+            info_hash: [7; 20],
+            piece_length: 16384,
+            length: 170_000,
+            downloaded: 1 << 40,
+            uploaded: 12345,
+            bitfield,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let control = sample();
+        let bytes = control.as_bytes();
+        assert_eq!(&bytes[..4], ControlFile::MAGIC);
+        assert_eq!(bytes[4], ControlFile::VERSION);
+        let back = ControlFile::from_bytes(&bytes).unwrap();
+        assert_eq!(back.info_hash, control.info_hash);
+        assert_eq!(back.piece_length, control.piece_length);
+        assert_eq!(back.length, control.length);
+        assert_eq!(back.downloaded, control.downloaded);
+        assert_eq!(back.uploaded, control.uploaded);
+        assert_eq!(back.bitfield.as_bytes(), control.bitfield.as_bytes());
+    }
+
+    #[test]
+    fn round_trip_through_a_file() {
+        let dir = Scratch::dir("control");
+        let path = dir.join("out.rbt");
+        sample().save(&path).unwrap();
+        assert!(!dir.join("out.rbt.tmp").exists());
+        let back = ControlFile::load(&path).unwrap();
+        assert_eq!(back.bitfield.as_bytes(), sample().bitfield.as_bytes());
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let bytes = sample().as_bytes();
+        let mut magic = bytes.clone();
+        magic[0] = b'X';
+        assert!(ControlFile::from_bytes(&magic).is_err());
+        let mut version = bytes.clone();
+        version[4] = ControlFile::VERSION + 1;
+        assert!(ControlFile::from_bytes(&version).is_err());
+        assert!(ControlFile::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(ControlFile::from_bytes(&bytes[..10]).is_err());
+    }
+}
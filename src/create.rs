@@ -0,0 +1,154 @@
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use sha1::Digest;
+
+use crate::bencode::{BencodeInfo, BencodeTorrent};
+
+/// Creates a `.torrent` for a single file, hashing its pieces on several threads.
+#[derive(Debug, Clone)]
+pub struct TorrentCreator {
+    path: PathBuf,
+    announce: String,
+    piece_length: u32,
+    threads: usize,
+}
+
+impl TorrentCreator {
+    const DEFAULT_PIECE_LENGTH: u32 = 256 * 1024;
+
+    pub fn new<T>(path: T, announce: &str) -> Self
+    where
+        T: AsRef<Path>,
+    {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            announce: announce.to_string(),
+            piece_length: Self::DEFAULT_PIECE_LENGTH,
+            threads: std::thread::available_parallelism().map_or(1, NonZeroUsize::get),
+        }
+    }
+
+    pub fn set_piece_length(mut self, piece_length: u32) -> Self {
+        self.piece_length = piece_length;
+        self
+    }
+
+    /// how many threads hash pieces, 1 hashes them one after another.
+    /// Every thread takes one contiguous batch of pieces
+    pub fn set_threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// the `pieces` blob, the SHA-1 of every piece in order
+    pub fn hash_pieces(&self) -> Result<Vec<u8>> {
+        if self.piece_length == 0 {
+            return Err(anyhow!("piece length must not be zero"));
+        }
+        let length = std::fs::metadata(&self.path)?.len();
+        let piece_num = length.div_ceil(self.piece_length as u64);
+        let batch = piece_num.div_ceil(self.threads as u64).max(1);
+        let batches = std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..piece_num)
+                .step_by(batch as usize)
+                .map(|first| {
+                    let last = (first + batch).min(piece_num);
+                    scope.spawn(move || self.hash_batch(first, last, length))
+                })
+                .collect();
+            workers
+                .into_iter()
+                .map(|worker| {
+                    worker
+                        .join()
+                        .map_err(|_| anyhow!("hashing thread panicked"))?
+                })
+                .collect::<Result<Vec<_>>>()
+        })?;
+        Ok(batches.concat())
+    }
+
+    /// hashes of the pieces `first..last`
+    fn hash_batch(&self, first: u64, last: u64, length: u64) -> Result<Vec<u8>> {
+        let piece_length = self.piece_length as u64;
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(first * piece_length))?;
+        let mut buf = vec![0; self.piece_length as usize];
+        let mut hashes = Vec::with_capacity(((last - first) * 20) as usize);
+        for index in first..last {
+            let len = (length - index * piece_length).min(piece_length) as usize;
+            file.read_exact(&mut buf[..len])?;
+            hashes.extend_from_slice(&sha1::Sha1::digest(&buf[..len]));
+        }
+        Ok(hashes)
+    }
+
+    /// the bencoded `.torrent`
+    pub fn create(&self) -> Result<Vec<u8>> {
+        let length = std::fs::metadata(&self.path)?.len();
+        let name = self
+            .path
+            .file_name()
+            .ok_or_else(|| anyhow!("{:?} does not name a file", self.path))?
+            .to_string_lossy()
+            .into_owned();
+        let torrent = BencodeTorrent {
+            announce: self.announce.clone(),
+            announce_list: None,
+            info: BencodeInfo {
+                pieces: Bytes::from(self.hash_pieces()?),
+                piece_length: self.piece_length,
+                length: Some(
+                    length
+                        .try_into()
+                        .map_err(|_| anyhow!("{:?} is too large", self.path))?,
+                ),
+                files: None,
+                name,
+                private: None,
+            },
+            url_list: None,
+            httpseeds: None,
+            comment: None,
+            comment_utf8: None,
+            created_by: None,
+            created_by_utf8: None,
+            creation_date: None,
+        };
+        Ok(serde_bencode::to_bytes(&torrent)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    #[test]
+    fn parallel_hashing_matches_serial() {
+        let scratch = testutil::Scratch::dir("create");
+        let path = scratch.join("big.bin");
+        // a short final piece ends the last batch
+        let content = testutil::content(4 * 1024 * 1024 + 1234);
+        std::fs::write(&path, &content).unwrap();
+        let creator = TorrentCreator::new(&path, testutil::DEAD_TRACKER).set_piece_length(65536);
+        let serial = creator.clone().set_threads(1);
+        let parallel = creator.set_threads(7);
+        assert_eq!(
+            parallel.hash_pieces().unwrap(),
+            testutil::piece_hashes(&content, 65536)
+        );
+        assert_eq!(
+            serial.hash_pieces().unwrap(),
+            parallel.hash_pieces().unwrap()
+        );
+        assert_eq!(serial.create().unwrap(), parallel.create().unwrap());
+    }
+}
@@ -0,0 +1,43 @@
+use std::fmt::Display;
+
+/// Errors describing why a download could not finish.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RbitError {
+    /// every peer disconnected while pieces were still missing
+    Incomplete { remaining: u32 },
+    /// the trackers knew no peers and there is no web seed to download from
+    NoPeers,
+    /// the tracker answered with a `failure reason`, asking again will not help
+    TrackerFailure(String),
+    /// a session already manages a torrent with this info hash
+    DuplicateTorrent([u8; 20]),
+    /// the piece failed its hash check `attempts` times, see
+    /// [`crate::TorrentClientBuilder::set_max_piece_failures`]
+    PieceFailed { index: u32, attempts: u32 },
+}
+
+impl Display for RbitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Incomplete { remaining } => {
+                write!(f, "download incomplete, {} pieces remaining", remaining)
+            }
+            Self::NoPeers => write!(f, "no peers available"),
+            Self::TrackerFailure(reason) => write!(f, "tracker returned failure: {}", reason),
+            Self::DuplicateTorrent(info_hash) => {
+                write!(f, "torrent ")?;
+                for byte in info_hash {
+                    write!(f, "{:02x}", byte)?;
+                }
+                write!(f, " is already in the session")
+            }
+            Self::PieceFailed { index, attempts } => write!(
+                f,
+                "piece #{} failed verification {} times, giving up",
+                index, attempts
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RbitError {}
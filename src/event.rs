@@ -0,0 +1,19 @@
+use std::net::SocketAddr;
+
+/// Something that happened while downloading, see [`crate::TorrentClient::events`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    PeerConnected(SocketAddr),
+    PeerDisconnected(SocketAddr),
+    PieceVerified(u32),
+    /// a piece failed its hash check, `peers` are the peers which delivered its blocks
+    PieceFailed {
+        index: u32,
+        peers: Vec<SocketAddr>,
+    },
+    TrackerAnnounced {
+        tracker: String,
+        peers: usize,
+    },
+    DownloadComplete,
+}
@@ -0,0 +1,81 @@
+use std::{
+    fs, io,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+
+/// A file of the torrent and where it lives in the concatenated content.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TorrentFile {
+    pub path: PathBuf,
+    pub offset: u64,
+    pub length: u64,
+}
+
+impl TorrentFile {
+    /// byte range of the file in the concatenated content
+    pub fn range(&self) -> Range<u64> {
+        self.offset..self.offset + self.length
+    }
+
+    /// indices of the pieces holding part of the file
+    pub fn pieces(&self, piece_length: u32) -> Range<u32> {
+        let piece_length = piece_length as u64;
+        if self.length == 0 {
+            let index = (self.offset / piece_length) as u32;
+            return index..index;
+        }
+        let first = self.offset / piece_length;
+        let last = (self.offset + self.length - 1) / piece_length;
+        first as u32..last as u32 + 1
+    }
+}
+
+/// move the file or directory `src` into `dir`, returning where it ended up. An
+/// existing entry of the same name is kept and the moved one gets a ` (n)` suffix.
+/// Across file systems, where renaming fails, it is copied and then removed
+pub fn move_into(src: &Path, dir: &Path) -> Result<PathBuf> {
+    let name = src
+        .file_name()
+        .ok_or_else(|| anyhow!("{:?} has no file name", src))?;
+    fs::create_dir_all(dir)?;
+    let mut dest = dir.join(name);
+    let mut n = 1;
+    while dest.exists() {
+        let (stem, ext) = match (Path::new(name).file_stem(), Path::new(name).extension()) {
+            (Some(stem), Some(ext)) if src.is_file() => (stem, Some(ext)),
+            _ => (name, None),
+        };
+        let mut renamed = stem.to_os_string();
+        renamed.push(format!(" ({})", n));
+        if let Some(ext) = ext {
+            renamed.push(".");
+            renamed.push(ext);
+        }
+        dest = dir.join(renamed);
+        n += 1;
+    }
+    if fs::rename(src, &dest).is_err() {
+        copy_all(src, &dest)?;
+        match src.is_dir() {
+            true => fs::remove_dir_all(src)?,
+            false => fs::remove_file(src)?,
+        }
+    }
+    Ok(dest)
+}
+
+fn copy_all(src: &Path, dest: &Path) -> io::Result<()> {
+    if !src.is_dir() {
+        fs::copy(src, dest)?;
+        return fs::File::open(dest)?.sync_all();
+    }
+    fs::create_dir(dest)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        copy_all(&entry.path(), &dest.join(entry.file_name()))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,126 @@
+use anyhow::{anyhow, Result};
+use reqwest::{NoProxy, Proxy};
+
+/// environment variables consulted for each proxy kind, upper case first
+const HTTP_PROXY: [&str; 2] = ["HTTP_PROXY", "http_proxy"];
+const HTTPS_PROXY: [&str; 2] = ["HTTPS_PROXY", "https_proxy"];
+const ALL_PROXY: [&str; 2] = ["ALL_PROXY", "all_proxy"];
+
+const NO_PROXY: [&str; 2] = ["NO_PROXY", "no_proxy"];
+
+fn env_var<F>(var: &F, names: [&str; 2]) -> Option<String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    names
+        .into_iter()
+        .find_map(var)
+        .filter(|value| !value.is_empty())
+}
+
+/// The HTTP client used for trackers, web seeds and torrent downloads.
+///
+/// `HTTP_PROXY`, `HTTPS_PROXY`, `ALL_PROXY` and `NO_PROXY` are applied explicitly so
+/// every request honors them. Peer connections are plain TCP and never go through
+/// these proxies, they need a SOCKS proxy configured separately.
+pub fn client() -> Result<reqwest::Client> {
+    Ok(builder()?.build()?)
+}
+
+/// a client builder with the user agent and proxies of [`client`] applied
+pub fn builder() -> Result<reqwest::ClientBuilder> {
+    builder_with(|name| std::env::var(name).ok())
+}
+
+/// [`builder`] with the environment read through `var`
+fn builder_with<F>(var: F) -> Result<reqwest::ClientBuilder>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut builder = reqwest::ClientBuilder::new()
+        .user_agent(concat!("rbittorrent/", env!("CARGO_PKG_VERSION")));
+    let proxies = [
+        env_var(&var, HTTP_PROXY).map(Proxy::http),
+        env_var(&var, HTTPS_PROXY).map(Proxy::https),
+        env_var(&var, ALL_PROXY).map(Proxy::all),
+    ];
+    let no_proxy = env_var(&var, NO_PROXY);
+    for proxy in proxies.into_iter().flatten() {
+        let no_proxy = no_proxy.as_deref().and_then(NoProxy::from_string);
+        builder = builder.proxy(proxy?.no_proxy(no_proxy));
+    }
+    Ok(builder)
+}
+
+/// default cap on a response body read by [`read_body`], 4 MiB
+pub const MAX_BODY: usize = 4 << 20;
+
+/// read the body of `res`, failing as soon as it grows beyond `limit` bytes instead
+/// of buffering whatever a server cares to send
+pub async fn read_body(mut res: reqwest::Response, limit: usize) -> Result<Vec<u8>> {
+    let url = res.url().clone();
+    let too_large = || anyhow!("response of {} is larger than {} bytes", url, limit);
+    if res.content_length().is_some_and(|len| len > limit as u64) {
+        return Err(too_large());
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = res.chunk().await? {
+        if body.len() + chunk.len() > limit {
+            return Err(too_large());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::HttpStub;
+
+    fn env(vars: &[(&'static str, String)]) -> impl Fn(&str) -> Option<String> {
+        let vars = vars.to_vec();
+        move |name| {
+            vars.iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_go_through_the_proxy() {
+        let proxy = HttpStub::new(200, "text/plain", b"d8:intervali60ee".to_vec()).await;
+        let client = builder_with(env(&[("http_proxy", proxy.url(""))]))
+            .unwrap()
+            .build()
+            .unwrap();
+        let res = client
+            .get("http://tracker.invalid/announce?left=0")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.bytes().await.unwrap().as_ref(), b"d8:intervali60ee");
+        // a proxy is asked for the absolute url
+        assert_eq!(
+            proxy.requests(),
+            vec!["GET http://tracker.invalid/announce?left=0 HTTP/1.1"]
+        );
+    }
+
+    #[tokio::test]
+    async fn no_proxy_hosts_are_reached_directly() {
+        let proxy = HttpStub::new(502, "text/plain", vec![]).await;
+        let tracker = HttpStub::new(200, "text/plain", b"ok".to_vec()).await;
+        let client = builder_with(env(&[
+            ("ALL_PROXY", proxy.url("")),
+            ("NO_PROXY", "127.0.0.1".to_string()),
+        ]))
+        .unwrap()
+        .build()
+        .unwrap();
+        let res = client.get(tracker.url("/announce")).send().await.unwrap();
+        assert_eq!(res.status(), 200);
+        assert!(proxy.requests().is_empty());
+        assert_eq!(tracker.requests().len(), 1);
+    }
+}
@@ -1,9 +1,36 @@
+mod assembly;
+pub mod bandwidth;
 pub mod bencode;
 mod builder;
+pub mod control;
+pub mod create;
+mod error;
+pub mod event;
+pub mod files;
+mod http;
+pub mod magnet;
 pub mod message;
+pub mod metadata;
+pub mod metainfo;
 pub mod peer;
+pub mod picker;
+pub mod pool;
+pub mod portmap;
+pub mod retry;
+pub mod session;
+pub mod stats;
+pub mod storage;
 mod task;
+#[cfg(test)]
+mod testutil;
 mod torrent;
+pub mod tracker;
+mod verifier;
+mod webseed;
 
 pub use builder::TorrentClientBuilder;
-pub use torrent::TorrentClient;
+pub use error::RbitError;
+pub use metainfo::Torrent;
+pub use retry::RetryPolicy;
+pub use torrent::{TorrentClient, TorrentState};
+pub use webseed::WebSeedPolicy;
@@ -0,0 +1,87 @@
+use std::net::SocketAddr;
+
+use anyhow::{anyhow, Result};
+use log::warn;
+
+/// The parts of a `magnet:?xt=urn:btih:...` link needed to start a download.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MagnetLink {
+    pub info_hash: [u8; 20],
+    /// the `dn` display name
+    pub name: Option<String>,
+    /// the `tr` tracker urls
+    pub trackers: Vec<String>,
+    /// the `x.pe` peer addresses to connect to right away, host names are not resolved
+    pub peers: Vec<SocketAddr>,
+}
+
+impl MagnetLink {
+    pub fn parse(uri: &str) -> Result<Self> {
+        let url = url::Url::parse(uri)?;
+        if url.scheme() != "magnet" {
+            return Err(anyhow!("not a magnet link: {}", uri));
+        }
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = vec![];
+        let mut peers = vec![];
+        for (key, value) in url.query_pairs() {
+            match &*key {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(match hash.len() {
+                            32 => parse_base32(hash)?,
+                            _ => parse_hex(hash)?,
+                        });
+                    }
+                }
+                "dn" => name = Some(value.into_owned()),
+                "tr" => trackers.push(value.into_owned()),
+                "x.pe" => match value.parse() {
+                    Ok(addr) => peers.push(addr),
+                    Err(_) => warn!("skip x.pe peer {}, not an ip:port", value),
+                },
+                _ => {}
+            }
+        }
+        Ok(Self {
+            info_hash: info_hash.ok_or_else(|| anyhow!("magnet link has no btih info hash"))?,
+            name,
+            trackers,
+            peers,
+        })
+    }
+}
+
+fn parse_hex(hash: &str) -> Result<[u8; 20]> {
+    if hash.len() != 40 {
+        return Err(anyhow!("invalid btih info hash: {}", hash));
+    }
+    let mut buf = [0u8; 20];
+    for (i, byte) in buf.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hash[2 * i..2 * i + 2], 16)
+            .map_err(|_| anyhow!("invalid btih info hash: {}", hash))?;
+    }
+    Ok(buf)
+}
+
+/// the 32 character base32 form (RFC 4648, no padding) some clients still emit
+fn parse_base32(hash: &str) -> Result<[u8; 20]> {
+    let mut buf = [0u8; 20];
+    let (mut bits, mut acc, mut pos) = (0u32, 0u64, 0);
+    for c in hash.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return Err(anyhow!("invalid btih info hash: {}", hash)),
+        };
+        acc = (acc << 5) | value as u64;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            buf[pos] = (acc >> bits) as u8;
+            pos += 1;
+        }
+    }
+    Ok(buf)
+}
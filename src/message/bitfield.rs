@@ -13,16 +13,40 @@ impl Bitfield {
     pub fn has_piece(&self, index: u32) -> bool {
         let byte_index = index / 8;
         let offset = index % 8;
-        self.0[byte_index as usize] >> (7 - offset) & 1 != 0
+        self.0
+            .get(byte_index as usize)
+            .is_some_and(|byte| byte >> (7 - offset) & 1 != 0)
     }
 
-    #[allow(unused)]
     pub fn set_piece(&mut self, index: u32) {
         let byte_index = index / 8;
         let offset = index % 8;
         self.0[byte_index as usize] |= 1 << (7 - offset);
     }
 
+    pub fn clear_piece(&mut self, index: u32) {
+        let byte_index = index / 8;
+        let offset = index % 8;
+        self.0[byte_index as usize] &= !(1 << (7 - offset));
+    }
+
+    /// number of pieces set
+    pub fn count(&self) -> u32 {
+        self.0.iter().map(|byte| byte.count_ones()).sum()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// whether this is exactly the size for `piece_count` pieces, with the spare bits clear
+    pub fn fits(&self, piece_count: u32) -> bool {
+        if self.len() != piece_count.div_ceil(8) {
+            return false;
+        }
+        (piece_count..self.len() * 8).all(|index| !self.has_piece(index))
+    }
+
     #[allow(clippy::len_without_is_empty)]
     pub fn len(&self) -> u32 {
         self.0.len() as u32
@@ -0,0 +1,226 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+use bytes::{BufMut, BytesMut};
+use serde::{Deserialize, Serialize};
+
+use crate::bencode;
+
+/// A message of the extension protocol (BEP 10), `id` 0 is the extended handshake.
+#[derive(Debug, Clone)]
+pub struct Extended {
+    pub id: u8,
+    pub payload: Vec<u8>,
+}
+
+impl Extended {
+    pub const HANDSHAKE_ID: u8 = 0;
+
+    pub fn new(id: u8, payload: &[u8]) -> Self {
+        Self {
+            id,
+            payload: payload.to_vec(),
+        }
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self> {
+        let (id, payload) = buf
+            .split_first()
+            .ok_or_else(|| anyhow!("extended message without an id"))?;
+        Ok(Self {
+            id: *id,
+            payload: payload.to_vec(),
+        })
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut buf = BytesMut::with_capacity(6 + self.payload.len());
+        buf.put_u32(2 + self.payload.len() as u32);
+        buf.put_u8(20);
+        buf.put_u8(self.id);
+        buf.put_slice(&self.payload);
+        buf.to_vec()
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExtendedHandshake {
+    /// extension name to the message id the sender wants to receive it on
+    #[serde(default)]
+    pub m: BTreeMap<String, i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<i64>,
+}
+
+impl ExtendedHandshake {
+    pub fn from_bytes(buf: &[u8]) -> Result<Self> {
+        Ok(serde_bencode::from_bytes(buf)?)
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        serde_bencode::to_bytes(self).unwrap()
+    }
+
+    /// our handshake, advertising each of `extensions` on its id
+    pub fn advertising(extensions: &[Extension]) -> Self {
+        Self {
+            m: extensions
+                .iter()
+                .map(|ext| (ext.name().to_string(), ext.id() as i64))
+                .collect(),
+            metadata_size: None,
+        }
+    }
+
+    /// id the peer expects for the given extension
+    pub fn id_of(&self, name: &str) -> Option<u8> {
+        self.m
+            .get(name)
+            .and_then(|id| u8::try_from(*id).ok())
+            .filter(|id| *id != 0)
+    }
+}
+
+/// Extensions spoken while downloading, on the ids peers send them to us with.
+/// A new one is added here and handled in the peer's extended message handling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Extension {
+    /// lt_donthave (BEP 54), a peer lost a piece it announced before
+    DontHave,
+}
+
+impl Extension {
+    pub const ALL: [Self; 1] = [Self::DontHave];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::DontHave => "lt_donthave",
+        }
+    }
+
+    /// the id we ask peers to use when sending us this extension
+    pub fn id(&self) -> u8 {
+        match self {
+            Self::DontHave => 1,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|ext| ext.id() == id)
+    }
+}
+
+/// The payload of an lt_donthave message, the index of the piece given up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DontHave {
+    pub index: u32,
+}
+
+impl DontHave {
+    pub fn from_bytes(buf: &[u8]) -> Result<Self> {
+        let index = buf
+            .try_into()
+            .map_err(|_| anyhow!("lt_donthave payload of {} bytes", buf.len()))?;
+        Ok(Self {
+            index: u32::from_be_bytes(index),
+        })
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        self.index.to_be_bytes().to_vec()
+    }
+}
+
+/// The ut_metadata (BEP 9) message types.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetadataMessageType {
+    Request,
+    Data,
+    Reject,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct MetadataHeader {
+    msg_type: i64,
+    piece: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_size: Option<i64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MetadataMessage {
+    pub msg_type: MetadataMessageType,
+    pub piece: u32,
+    pub total_size: Option<u32>,
+    pub data: Vec<u8>,
+}
+
+impl MetadataMessage {
+    pub fn request(piece: u32) -> Self {
+        Self {
+            msg_type: MetadataMessageType::Request,
+            piece,
+            total_size: None,
+            data: vec![],
+        }
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self> {
+        // a data message carries the raw metadata piece right after the dictionary
+        let header_len = bencode::value_len(buf)?;
+        let header: MetadataHeader = serde_bencode::from_bytes(&buf[..header_len])?;
+        let msg_type = match header.msg_type {
+            0 => MetadataMessageType::Request,
+            1 => MetadataMessageType::Data,
+            2 => MetadataMessageType::Reject,
+            other => return Err(anyhow!("unknown ut_metadata message type: {}", other)),
+        };
+        Ok(Self {
+            msg_type,
+            piece: u32::try_from(header.piece)?,
+            total_size: header.total_size.map(u32::try_from).transpose()?,
+            data: buf[header_len..].to_vec(),
+        })
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let header = MetadataHeader {
+            msg_type: match self.msg_type {
+                MetadataMessageType::Request => 0,
+                MetadataMessageType::Data => 1,
+                MetadataMessageType::Reject => 2,
+            },
+            piece: self.piece as i64,
+            total_size: self.total_size.map(|size| size as i64),
+        };
+        let mut buf = serde_bencode::to_bytes(&header).unwrap();
+        buf.extend_from_slice(&self.data);
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extended_without_an_id_is_an_error() {
+        assert!(Extended::from_bytes(&[]).is_err());
+        let ext = Extended::from_bytes(&[3, 1, 2]).unwrap();
+        assert_eq!((ext.id, ext.payload), (3, vec![1, 2]));
+    }
+
+    #[test]
+    fn metadata_data_message_round_trip() {
+        let msg = MetadataMessage {
+            msg_type: MetadataMessageType::Data,
+            piece: 2,
+            total_size: Some(40000),
+            data: b"d4:name".to_vec(),
+        };
+        let back = MetadataMessage::from_bytes(&msg.as_bytes()).unwrap();
+        assert_eq!(back.msg_type, MetadataMessageType::Data);
+        assert_eq!((back.piece, back.total_size), (2, Some(40000)));
+        assert_eq!(back.data, b"d4:name");
+    }
+}
@@ -1,7 +1,10 @@
 use bytes::{BufMut, BytesMut};
 
+use super::MessageError;
+
 #[derive(Debug, Clone, Copy)]
 pub struct HandShake {
+    pub reserved: [u8; 8],
     pub info_hash: [u8; 20],
     pub peer_id: [u8; 20],
 }
@@ -9,27 +12,70 @@ pub struct HandShake {
 impl HandShake {
     pub fn new(info_hash: &[u8], peer_id: &[u8]) -> Self {
         Self {
+            reserved: [0u8; 8],
             info_hash: info_hash.try_into().unwrap(),
             peer_id: peer_id.try_into().unwrap(),
         }
     }
 
-    pub fn from_bytes(buf: &[u8]) -> Self {
-        assert!(buf.len() >= 68);
-        assert!(buf[0] == 19);
-        assert!(buf[1..20] == *b"BitTorrent protocol");
+    /// advertise support for the extension protocol (BEP 10)
+    pub fn with_extension_protocol(mut self) -> Self {
+        self.reserved[5] |= 0x10;
+        self
+    }
+
+    pub fn supports_extension_protocol(&self) -> bool {
+        self.reserved[5] & 0x10 != 0
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, MessageError> {
+        if buf.len() < 68 || buf[0] != 19 || buf[1..20] != *b"BitTorrent protocol" {
+            return Err(MessageError::HandShakeError);
+        }
+        let reserved = buf[20..28].try_into().unwrap();
         let info_hash = buf[28..48].try_into().unwrap();
         let peer_id = buf[48..68].try_into().unwrap();
-        Self { info_hash, peer_id }
+        Ok(Self {
+            reserved,
+            info_hash,
+            peer_id,
+        })
     }
 
     pub fn as_bytes(&self) -> Vec<u8> {
         let mut buf = BytesMut::with_capacity(68);
         buf.put_u8(19); // len of pstr
         buf.put_slice(b"BitTorrent protocol");
-        buf.put_slice(&[0u8; 8]);
+        buf.put_slice(&self.reserved);
         buf.put_slice(&self.info_hash);
         buf.put_slice(&self.peer_id);
         buf.to_vec()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let handshake = HandShake::new(&[1; 20], &[2; 20]).with_extension_protocol();
+        let back = HandShake::from_bytes(&handshake.as_bytes()).unwrap();
+        assert_eq!(back.info_hash, [1; 20]);
+        assert_eq!(back.peer_id, [2; 20]);
+        assert!(back.supports_extension_protocol());
+    }
+
+    #[test]
+    fn malformed_handshakes_are_errors() {
+        let bytes = HandShake::new(&[1; 20], &[2; 20]).as_bytes();
+        assert!(HandShake::from_bytes(&bytes[..67]).is_err());
+        assert!(HandShake::from_bytes(&[]).is_err());
+        let mut protocol = bytes.clone();
+        protocol[1..20].copy_from_slice(b"BitTorrent protocoL");
+        assert!(HandShake::from_bytes(&protocol).is_err());
+        let mut pstrlen = bytes;
+        pstrlen[0] = 18;
+        assert!(HandShake::from_bytes(&pstrlen).is_err());
+    }
+}
@@ -1,10 +1,13 @@
 mod bitfield;
+mod extended;
 mod handshake;
 mod request;
-use std::{fmt::Display, time::Duration};
+use std::{fmt::Display, sync::Arc, time::Duration};
 
+use crate::pool::{BlockPool, PooledBlock};
 use anyhow::Result;
 pub use bitfield::Bitfield;
+pub use extended::*;
 pub use handshake::HandShake;
 pub use request::*;
 use tokio::{io::AsyncReadExt, net::TcpStream, time::timeout};
@@ -15,21 +18,26 @@ pub enum Message {
     UnChoke,
     Interested,
     NotInterested,
-    #[allow(unused)]
-    Have(u8),
+    Have(u32),
     Bitfield(Bitfield),
     Request(Request),
     Piece(Piece),
     #[allow(unused)]
     Cancel(Cancel),
+    Extended(Extended),
     KeepAlive,
     HandShake(HandShake),
 }
 
+#[derive(Debug)]
 pub enum MessageError {
     Timeout,
     ReadError,
     HandShakeError,
+    /// a message whose body does not fit its id
+    Malformed(String),
+    /// the block pool was closed while waiting for a buffer
+    PoolClosed,
 }
 
 impl Display for MessageError {
@@ -38,53 +46,111 @@ impl Display for MessageError {
             Self::Timeout => f.write_str("timeout"),
             Self::ReadError => f.write_str("read error"),
             Self::HandShakeError => f.write_str("handshake error"),
+            Self::Malformed(reason) => write!(f, "malformed message: {}", reason),
+            Self::PoolClosed => f.write_str("block pool closed"),
         }
     }
 }
 
 impl Message {
+    /// the longest message body accepted, a block with room to spare or the
+    /// bitfield of a torrent with millions of pieces
+    pub const MAX_LENGTH: usize = 2 * 1024 * 1024;
+    /// how long a peer may keep us waiting for a message, or for the rest of one
+    pub const READ_TIMEOUT: Duration = Duration::from_secs(3);
+
     pub async fn from_stream(stream: &mut TcpStream) -> Result<Self, MessageError> {
-        let dw = timeout(Duration::from_secs(3), stream.read_u32())
+        Self::from_stream_pooled(stream, None).await
+    }
+
+    /// like [`Message::from_stream`], reading message bodies into buffers of `pool`
+    pub async fn from_stream_pooled(
+        stream: &mut TcpStream,
+        pool: Option<&Arc<BlockPool>>,
+    ) -> Result<Self, MessageError> {
+        let dw = timeout(Self::READ_TIMEOUT, stream.read_u32())
             .await
             .map_err(|_| MessageError::Timeout)?
             .map_err(|_| MessageError::ReadError)?;
         let mut buf = dw.to_be_bytes().to_vec();
         let msg = if buf[0] == 19 && buf[1..4] == *b"Bit" {
             let mut other = vec![0; 64];
-            timeout(Duration::from_secs(3), stream.read_exact(&mut other))
+            timeout(Self::READ_TIMEOUT, stream.read_exact(&mut other))
                 .await
                 .map_err(|_| MessageError::Timeout)?
                 .map_err(|_| MessageError::HandShakeError)?; // exit if
             buf.extend(other);
-            Self::HandShake(HandShake::from_bytes(&buf))
+            Self::HandShake(HandShake::from_bytes(&buf)?)
         } else {
             let length = dw as usize;
-            let mut other = vec![0; length];
-            timeout(Duration::from_secs(3), stream.read_exact(&mut other))
+            if length > Self::MAX_LENGTH {
+                return Err(MessageError::Malformed(format!(
+                    "length of {} bytes is over the limit of {}",
+                    length,
+                    Self::MAX_LENGTH
+                )));
+            }
+            let mut other = match pool {
+                Some(pool) if length <= pool.buf_size() => {
+                    pool.checkout().await.ok_or(MessageError::PoolClosed)?
+                }
+                _ => PooledBlock::default(),
+            };
+            other.vec_mut().resize(length, 0);
+            timeout(Self::READ_TIMEOUT, stream.read_exact(&mut other))
                 .await
                 .map_err(|_| MessageError::Timeout)?
                 .map_err(|_| MessageError::ReadError)?;
-            buf.extend(other);
-            Self::from(&buf[4..4 + length])
+            // blocks are the hot path, reuse the read buffer instead of copying it
+            if other.first() == Some(&7) {
+                Self::Piece(Piece::from_block(other)?)
+            } else {
+                Self::from(&other)?
+            }
         };
         Ok(msg)
     }
 
-    fn from(buf: &[u8]) -> Self {
+    fn from(buf: &[u8]) -> Result<Self, MessageError> {
         if buf.is_empty() {
-            return Self::KeepAlive;
+            return Ok(Self::KeepAlive);
         }
-        match buf[0] {
+        let msg = match buf[0] {
             0 => Self::Choke,
             1 => Self::UnChoke,
             2 => Self::Interested,
             3 => Self::NotInterested,
-            4 => Self::Have(buf[1]),
+            4 => Self::Have(u32::from_be_bytes(buf[1..].try_into().map_err(|_| {
+                MessageError::Malformed(format!("have of {} bytes", buf.len() - 1))
+            })?)),
             5 => Self::Bitfield(Bitfield::from(&buf[1..])),
-            6 => Self::Request(Request::from_bytes(&buf[1..])),
-            7 => Self::Piece(Piece::from_bytes(&buf[1..])),
-            8 => Self::Cancel(Cancel::from_bytes(&buf[1..])),
-            _ => unreachable!(),
+            6 => Self::Request(Request::from_bytes(&buf[1..])?),
+            7 => Self::Piece(Piece::from_bytes(&buf[1..])?),
+            8 => Self::Cancel(Cancel::from_bytes(&buf[1..])?),
+            20 => Self::Extended(
+                Extended::from_bytes(&buf[1..])
+                    .map_err(|err| MessageError::Malformed(err.to_string()))?,
+            ),
+            id => return Err(MessageError::Malformed(format!("unknown id {}", id))),
+        };
+        Ok(msg)
+    }
+
+    /// name of the message kind, as counted in [`crate::stats::MessageCounts`]
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Choke => "choke",
+            Self::UnChoke => "unchoke",
+            Self::Interested => "interested",
+            Self::NotInterested => "not interested",
+            Self::Have(_) => "have",
+            Self::Bitfield(_) => "bitfield",
+            Self::Request(_) => "request",
+            Self::Piece(_) => "piece",
+            Self::Cancel(_) => "cancel",
+            Self::Extended(_) => "extended",
+            Self::KeepAlive => "keep-alive",
+            Self::HandShake(_) => "handshake",
         }
     }
 
@@ -99,6 +165,7 @@ impl Message {
             Self::Request(_) => 6,
             Self::Piece(_) => 7,
             Self::Cancel(_) => 8,
+            Self::Extended(_) => 20,
             _ => unreachable!(),
         }
     }
@@ -106,12 +173,26 @@ impl Message {
     pub fn as_bytes(&self) -> Vec<u8> {
         match self {
             Self::HandShake(handshake) => handshake.as_bytes(),
-            Self::KeepAlive => vec![],
+            Self::KeepAlive => 0_u32.to_be_bytes().to_vec(),
             Self::Choke | Self::UnChoke | Self::Interested | Self::NotInterested => {
                 no_body_message(self.as_u8())
             }
+            Self::Have(index) => {
+                let mut bytes = 5_u32.to_be_bytes().to_vec();
+                bytes.push(self.as_u8());
+                bytes.extend_from_slice(&index.to_be_bytes());
+                bytes
+            }
+            Self::Bitfield(bitfield) => {
+                let mut bytes = (1 + bitfield.len()).to_be_bytes().to_vec();
+                bytes.push(self.as_u8());
+                bytes.extend_from_slice(bitfield.as_bytes());
+                bytes
+            }
             Self::Request(request) => request.as_bytes(),
-            _ => todo!(),
+            Self::Piece(piece) => piece.as_bytes(),
+            Self::Cancel(cancel) => cancel.as_bytes(),
+            Self::Extended(extended) => extended.as_bytes(),
         }
     }
 }
@@ -121,3 +202,141 @@ fn no_body_message(code: u8) -> Vec<u8> {
     bytes.push(code);
     bytes
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn wrong_protocol_handshake_is_an_error() {
+        let (mut ours, mut theirs) = testutil::tcp_pair().await;
+        let mut bytes = HandShake::new(&[1; 20], &[2; 20]).as_bytes();
+        bytes[10] = b'x';
+        theirs.write_all(&bytes).await.unwrap();
+        assert!(matches!(
+            Message::from_stream(&mut ours).await,
+            Err(MessageError::HandShakeError)
+        ));
+    }
+
+    #[tokio::test]
+    async fn truncated_handshake_is_an_error() {
+        let (mut ours, mut theirs) = testutil::tcp_pair().await;
+        let bytes = HandShake::new(&[1; 20], &[2; 20]).as_bytes();
+        theirs.write_all(&bytes[..30]).await.unwrap();
+        drop(theirs);
+        assert!(matches!(
+            Message::from_stream(&mut ours).await,
+            Err(MessageError::HandShakeError)
+        ));
+    }
+
+    #[test]
+    fn malformed_bodies_are_errors() {
+        for body in [
+            &[9][..],
+            &[4, 0, 0, 1],
+            &[6, 0, 0, 0, 1, 0, 0, 0, 0],
+            &[8, 0, 0, 0, 1],
+            &[7, 0, 0, 0, 1, 0],
+        ] {
+            assert!(
+                matches!(Message::from(body), Err(MessageError::Malformed(_))),
+                "{:?}",
+                body
+            );
+        }
+        let mut request = Request::new(1, 2, 3).as_bytes();
+        request.push(0);
+        assert!(Message::from(&request[4..]).is_err());
+        assert!(matches!(
+            Message::from(&Request::new(1, 2, 3).as_bytes()[4..]),
+            Ok(Message::Request(Request {
+                index: 1,
+                begin: 2,
+                length: 3
+            }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn every_message_round_trips() {
+        let mut bitfield = Bitfield::new(2);
+        [0, 5, 9]
+            .into_iter()
+            .for_each(|index| bitfield.set_piece(index));
+        let messages = [
+            Message::KeepAlive,
+            Message::Choke,
+            Message::UnChoke,
+            Message::Interested,
+            Message::NotInterested,
+            Message::Have(0x01020304),
+            Message::Bitfield(bitfield),
+            Message::Request(Request::new(1, 16384, 16384)),
+            Message::Piece(Piece::new(3, 32768, &[7; 100])),
+            Message::Cancel(Cancel::new(1, 16384, 16384)),
+            Message::Extended(Extended::new(1, &[0, 0, 0, 2])),
+        ];
+        let (mut ours, mut theirs) = testutil::tcp_pair().await;
+        for msg in messages.iter() {
+            let bytes = msg.as_bytes();
+            // the length prefix covers the id and the body
+            assert_eq!(
+                u32::from_be_bytes(bytes[..4].try_into().unwrap()) as usize,
+                bytes.len() - 4
+            );
+            let back = Message::from(&bytes[4..]).unwrap();
+            assert_eq!(back.name(), msg.name());
+            assert_eq!(back.as_bytes(), bytes);
+            theirs.write_all(&bytes).await.unwrap();
+        }
+        // and the same off the wire, blocks going through the pool
+        let pool = Arc::new(BlockPool::new(4, 16384 + 9));
+        for msg in messages.iter() {
+            let back = Message::from_stream_pooled(&mut ours, Some(&pool))
+                .await
+                .unwrap();
+            assert_eq!(back.as_bytes(), msg.as_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn short_pooled_piece_is_an_error() {
+        let (mut ours, mut theirs) = testutil::tcp_pair().await;
+        theirs
+            .write_all(&[0, 0, 0, 5, 7, 0, 0, 0, 1])
+            .await
+            .unwrap();
+        let pool = Arc::new(BlockPool::new(4, 16384 + 9));
+        assert!(matches!(
+            Message::from_stream_pooled(&mut ours, Some(&pool)).await,
+            Err(MessageError::Malformed(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn overlong_messages_are_refused() {
+        let (mut ours, mut theirs) = testutil::tcp_pair().await;
+        let length = Message::MAX_LENGTH as u32 + 1;
+        theirs.write_all(&length.to_be_bytes()).await.unwrap();
+        // refused from the length alone, without waiting for the body
+        assert!(matches!(
+            Message::from_stream(&mut ours).await,
+            Err(MessageError::Malformed(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn handshake_is_read_from_the_stream() {
+        let (mut ours, mut theirs) = testutil::tcp_pair().await;
+        let bytes = HandShake::new(&[1; 20], &[2; 20]).as_bytes();
+        theirs.write_all(&bytes).await.unwrap();
+        match Message::from_stream(&mut ours).await {
+            Ok(Message::HandShake(handshake)) => assert_eq!(handshake.peer_id, [2; 20]),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
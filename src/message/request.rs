@@ -1,5 +1,8 @@
 use bytes::{BufMut, BytesMut};
 
+use super::MessageError;
+use crate::pool::PooledBlock;
+
 #[derive(Debug, Clone, Copy)]
 pub struct Request {
     pub index: u32,
@@ -26,16 +29,13 @@ impl Request {
         buf.to_vec()
     }
 
-    pub fn from_bytes(buf: &[u8]) -> Self {
-        assert!(buf.len() == 12);
-        let index = u32::from_be_bytes(buf[..4].try_into().unwrap());
-        let begin = u32::from_be_bytes(buf[4..8].try_into().unwrap());
-        let length = u32::from_be_bytes(buf[8..12].try_into().unwrap());
-        Self {
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, MessageError> {
+        let [index, begin, length] = fields(buf, "request")?;
+        Ok(Self {
             index,
             begin,
             length,
-        }
+        })
     }
 }
 
@@ -44,27 +44,46 @@ pub struct Piece {
     pub index: u32,
     #[allow(unused)]
     pub begin: u32,
-    pub piece: Vec<u8>,
+    pub piece: PooledBlock,
 }
 
 impl Piece {
-    #[allow(unused)]
     pub fn new(index: u32, begin: u32, piece: &[u8]) -> Self {
         Self {
             index,
             begin,
-            piece: piece.to_vec(),
+            piece: piece.to_vec().into(),
         }
     }
 
-    pub fn from_bytes(buf: &[u8]) -> Self {
-        let index = u32::from_be_bytes(buf[..4].try_into().unwrap());
-        let begin = u32::from_be_bytes(buf[4..8].try_into().unwrap());
-        Self {
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, MessageError> {
+        let ([index, begin], piece) = split_piece(buf)?;
+        Ok(Self {
             index,
             begin,
-            piece: buf[8..].to_vec(),
-        }
+            piece: piece.to_vec().into(),
+        })
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut buf = BytesMut::with_capacity(13 + self.piece.len());
+        buf.put_u32(9 + self.piece.len() as u32);
+        buf.put_u8(7);
+        buf.put_u32(self.index);
+        buf.put_u32(self.begin);
+        buf.put_slice(&self.piece);
+        buf.to_vec()
+    }
+
+    /// take over a whole message body (id included) without copying the block
+    pub fn from_block(mut buf: PooledBlock) -> Result<Self, MessageError> {
+        let ([index, begin], _) = split_piece(buf.get(1..).unwrap_or_default())?;
+        buf.skip(9);
+        Ok(Self {
+            index,
+            begin,
+            piece: buf,
+        })
     }
 }
 
@@ -86,15 +105,49 @@ impl Cancel {
         }
     }
 
-    pub fn from_bytes(buf: &[u8]) -> Self {
-        assert!(buf.len() == 12);
-        let index = u32::from_be_bytes(buf[..4].try_into().unwrap());
-        let begin = u32::from_be_bytes(buf[4..8].try_into().unwrap());
-        let length = u32::from_be_bytes(buf[8..12].try_into().unwrap());
-        Self {
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        buf.put_u32(13);
+        buf.put_u8(8);
+        buf.put_u32(self.index);
+        buf.put_u32(self.begin);
+        buf.put_u32(self.length);
+        buf.to_vec()
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, MessageError> {
+        let [index, begin, length] = fields(buf, "cancel")?;
+        Ok(Self {
             index,
             begin,
             length,
-        }
+        })
+    }
+}
+
+/// the big endian u32 fields making up the whole of `buf`
+fn fields<const N: usize>(buf: &[u8], name: &str) -> Result<[u32; N], MessageError> {
+    if buf.len() != N * 4 {
+        return Err(MessageError::Malformed(format!(
+            "{} of {} bytes instead of {}",
+            name,
+            buf.len(),
+            N * 4
+        )));
+    }
+    Ok(std::array::from_fn(|i| {
+        u32::from_be_bytes(buf[i * 4..i * 4 + 4].try_into().unwrap())
+    }))
+}
+
+/// index and begin of a piece message, followed by its block
+fn split_piece(buf: &[u8]) -> Result<([u32; 2], &[u8]), MessageError> {
+    if buf.len() < 8 {
+        return Err(MessageError::Malformed(format!(
+            "piece of {} bytes has no room for its index and offset",
+            buf.len()
+        )));
     }
+    let (header, block) = buf.split_at(8);
+    Ok((fields(header, "piece header")?, block))
 }
@@ -0,0 +1,566 @@
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use log::{info, trace};
+use sha1::Digest;
+use tokio::{io::AsyncWriteExt, net::TcpStream, time::timeout};
+
+use crate::message::{
+    Extended, ExtendedHandshake, HandShake, Message, MetadataMessage, MetadataMessageType,
+};
+
+/// Reassembles the info dictionary from the 16 KiB pieces of the ut_metadata extension.
+#[derive(Debug, Clone)]
+pub struct MetadataBuffer {
+    size: usize,
+    pieces: Vec<Option<Vec<u8>>>,
+    /// how often each piece was requested, across peers
+    attempts: Vec<u32>,
+}
+
+impl MetadataBuffer {
+    pub const PIECE_SIZE: usize = 2_usize.pow(14);
+    /// how often a piece is requested before the fetch is given up
+    pub const MAX_ATTEMPTS: u32 = 3;
+
+    pub fn new(size: usize) -> Self {
+        let piece_num = size.div_ceil(Self::PIECE_SIZE);
+        Self {
+            size,
+            pieces: vec![None; piece_num],
+            attempts: vec![0; piece_num],
+        }
+    }
+
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    pub fn piece_num(&self) -> u32 {
+        self.pieces.len() as u32
+    }
+
+    fn piece_len(&self, piece: u32) -> usize {
+        let begin = piece as usize * Self::PIECE_SIZE;
+        Self::PIECE_SIZE.min(self.size - begin)
+    }
+
+    /// indexes of the pieces which are not received yet
+    pub fn missing(&self) -> impl Iterator<Item = u32> + '_ {
+        self.pieces
+            .iter()
+            .enumerate()
+            .filter(|(_, piece)| piece.is_none())
+            .map(|(index, _)| index as u32)
+    }
+
+    /// count a request for `piece`
+    pub fn requested(&mut self, piece: u32) {
+        if let Some(attempts) = self.attempts.get_mut(piece as usize) {
+            *attempts += 1;
+        }
+    }
+
+    /// whether a missing piece was requested [`Self::MAX_ATTEMPTS`] times already
+    pub fn is_exhausted(&self) -> bool {
+        self.missing()
+            .any(|piece| self.attempts[piece as usize] >= Self::MAX_ATTEMPTS)
+    }
+
+    pub fn insert(&mut self, piece: u32, data: Vec<u8>) -> Result<()> {
+        if piece >= self.piece_num() {
+            return Err(anyhow!("metadata piece #{} is out of range", piece));
+        }
+        if data.len() != self.piece_len(piece) {
+            return Err(anyhow!(
+                "metadata piece #{} has {} bytes, expected {}",
+                piece,
+                data.len(),
+                self.piece_len(piece)
+            ));
+        }
+        self.pieces[piece as usize] = Some(data);
+        Ok(())
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.pieces.iter().all(Option::is_some)
+    }
+
+    /// join all pieces and check them against the expected info hash
+    pub fn assemble(&self, info_hash: &[u8; 20]) -> Result<Vec<u8>> {
+        if !self.is_complete() {
+            return Err(anyhow!("metadata is incomplete"));
+        }
+        let info = self
+            .pieces
+            .iter()
+            .flatten()
+            .flatten()
+            .copied()
+            .collect::<Vec<_>>();
+        let mut hasher = sha1::Sha1::new();
+        hasher.update(&info);
+        let sum: [u8; 20] = hasher.finalize().into();
+        if sum != *info_hash {
+            return Err(anyhow!(
+                "metadata has a wrong hash, expected: {:x?}, found: {:x?}",
+                info_hash,
+                &sum
+            ));
+        }
+        Ok(info)
+    }
+}
+
+/// Fetches the info dictionary of a torrent from peers (BEP 9).
+#[derive(Debug, Clone)]
+pub struct MetadataFetcher {
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    /// how long a single peer gets to hand over the metadata
+    peer_timeout: Duration,
+}
+
+impl MetadataFetcher {
+    /// the id we ask peers to use when sending us ut_metadata messages
+    const UT_METADATA_ID: u8 = 1;
+    /// refuse to allocate for absurd metadata sizes announced by peers
+    const MAX_METADATA_SIZE: usize = 2_usize.pow(24);
+    pub const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+
+    pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
+        Self {
+            info_hash,
+            peer_id,
+            peer_timeout: Self::PEER_TIMEOUT,
+        }
+    }
+
+    /// give up on a peer which did not complete the metadata in `peer_timeout`,
+    /// [`Self::PEER_TIMEOUT`] by default
+    pub fn with_peer_timeout(mut self, peer_timeout: Duration) -> Self {
+        self.peer_timeout = peer_timeout;
+        self
+    }
+
+    /// try each peer in turn until the whole, verified info dictionary is in. Pieces
+    /// received from a peer which then drops out are kept, the next peer is only
+    /// asked for the missing ones
+    pub async fn fetch(&self, peers: &[SocketAddr]) -> Result<Vec<u8>> {
+        let mut buffer = None;
+        for addr in peers {
+            match self.fetch_within(*addr, &mut buffer).await {
+                Ok(info) => return Ok(info),
+                Err(err) => info!("skip metadata peer {}: {}", addr, err),
+            }
+            if let Some(metadata) = &buffer {
+                if metadata.is_exhausted() {
+                    return Err(anyhow!(
+                        "metadata pieces were requested {} times without success",
+                        MetadataBuffer::MAX_ATTEMPTS
+                    ));
+                }
+                info!(
+                    "keep {} of {} metadata pieces for the next peer",
+                    metadata.piece_num() as usize - metadata.missing().count(),
+                    metadata.piece_num()
+                );
+            }
+        }
+        Err(anyhow!("no peer could provide the metadata"))
+    }
+
+    pub async fn fetch_from(&self, addr: SocketAddr) -> Result<Vec<u8>> {
+        self.fetch_within(addr, &mut None).await
+    }
+
+    /// [`Self::fetch_into`], bounded by the peer timeout so a peer which trickles
+    /// keep-alives or stops answering cannot hold up the fetch
+    async fn fetch_within(
+        &self,
+        addr: SocketAddr,
+        buffer: &mut Option<MetadataBuffer>,
+    ) -> Result<Vec<u8>> {
+        timeout(self.peer_timeout, self.fetch_into(addr, buffer))
+            .await
+            .map_err(|_| anyhow!("no metadata within {:?}", self.peer_timeout))?
+    }
+
+    /// fetch the pieces missing from `buffer` from the peer at `addr`
+    async fn fetch_into(
+        &self,
+        addr: SocketAddr,
+        buffer: &mut Option<MetadataBuffer>,
+    ) -> Result<Vec<u8>> {
+        let mut stream = timeout(Duration::from_secs(3), TcpStream::connect(addr)).await??;
+        let handshake = HandShake::new(&self.info_hash, &self.peer_id).with_extension_protocol();
+        send(&mut stream, Message::HandShake(handshake)).await?;
+
+        let mut handshaked = false;
+        loop {
+            let msg = Message::from_stream(&mut stream)
+                .await
+                .map_err(|err| anyhow!("{}", err))?;
+            match msg {
+                Message::HandShake(handshake) => {
+                    if handshake.info_hash != self.info_hash {
+                        return Err(anyhow!("peer answered with another info hash"));
+                    }
+                    if !handshake.supports_extension_protocol() {
+                        return Err(anyhow!("peer does not support the extension protocol"));
+                    }
+                    let mut ours = ExtendedHandshake::default();
+                    ours.m
+                        .insert("ut_metadata".to_string(), Self::UT_METADATA_ID as i64);
+                    send(
+                        &mut stream,
+                        Message::Extended(Extended::new(Extended::HANDSHAKE_ID, &ours.as_bytes())),
+                    )
+                    .await?;
+                }
+                Message::Extended(ext) if ext.id == Extended::HANDSHAKE_ID => {
+                    let theirs = ExtendedHandshake::from_bytes(&ext.payload)?;
+                    let id = theirs
+                        .id_of("ut_metadata")
+                        .ok_or_else(|| anyhow!("peer does not support ut_metadata"))?;
+                    let size = theirs
+                        .metadata_size
+                        .and_then(|size| usize::try_from(size).ok())
+                        .filter(|size| *size > 0 && *size <= Self::MAX_METADATA_SIZE)
+                        .ok_or_else(|| anyhow!("peer announced no usable metadata_size"))?;
+                    if buffer
+                        .as_ref()
+                        .is_some_and(|metadata| metadata.len() != size)
+                    {
+                        info!("peer {} announced another metadata size, start over", addr);
+                        *buffer = None;
+                    }
+                    let metadata = buffer.get_or_insert_with(|| MetadataBuffer::new(size));
+                    let missing: Vec<_> = metadata.missing().collect();
+                    for piece in missing {
+                        metadata.requested(piece);
+                        let request = MetadataMessage::request(piece);
+                        send(
+                            &mut stream,
+                            Message::Extended(Extended::new(id, &request.as_bytes())),
+                        )
+                        .await?;
+                    }
+                    handshaked = true;
+                }
+                Message::Extended(ext) if ext.id == Self::UT_METADATA_ID => {
+                    let Some(metadata) = buffer.as_mut().filter(|_| handshaked) else {
+                        return Err(anyhow!("peer sent metadata before the extended handshake"));
+                    };
+                    let msg = MetadataMessage::from_bytes(&ext.payload)?;
+                    match msg.msg_type {
+                        MetadataMessageType::Data => {
+                            trace!("receive metadata piece #{} from peer: {}", msg.piece, addr);
+                            metadata.insert(msg.piece, msg.data)?;
+                            if metadata.is_complete() {
+                                // a bad piece cannot be told apart, so all of them go
+                                return metadata.assemble(&self.info_hash).inspect_err(|_| {
+                                    *buffer = None;
+                                });
+                            }
+                        }
+                        MetadataMessageType::Reject => {
+                            return Err(anyhow!("peer rejected metadata piece #{}", msg.piece));
+                        }
+                        // we are not serving metadata
+                        MetadataMessageType::Request => {}
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+async fn send(stream: &mut TcpStream, msg: Message) -> Result<()> {
+    stream.write_all(&msg.as_bytes()).await?;
+    Ok(())
+}
+
+/// Info dictionaries resolved from magnet links, stored on disk by info hash.
+#[derive(Debug, Clone)]
+pub struct MetadataCache {
+    dir: PathBuf,
+}
+
+impl Default for MetadataCache {
+    fn default() -> Self {
+        Self::new(std::env::temp_dir().join("rbittorrent-metadata"))
+    }
+}
+
+impl MetadataCache {
+    pub fn new<T>(dir: T) -> Self
+    where
+        T: AsRef<Path>,
+    {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    fn path(&self, info_hash: &[u8; 20]) -> PathBuf {
+        let hex = info_hash
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+        self.dir.join(format!("{}.info", hex))
+    }
+
+    /// the cached info dictionary, if there is one and it still matches the info hash
+    pub fn load(&self, info_hash: &[u8; 20]) -> Option<Vec<u8>> {
+        let info = std::fs::read(self.path(info_hash)).ok()?;
+        let mut hasher = sha1::Sha1::new();
+        hasher.update(&info);
+        let sum: [u8; 20] = hasher.finalize().into();
+        (sum == *info_hash).then_some(info)
+    }
+
+    pub fn store(&self, info_hash: &[u8; 20], info: &[u8]) -> Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        std::fs::write(self.path(info_hash), info)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{testutil, TorrentClientBuilder};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    /// the id the mock peer wants ut_metadata messages on
+    const THEIR_ID: u8 = 3;
+
+    /// an info dictionary spanning three metadata pieces
+    fn big_info() -> Vec<u8> {
+        let mut info = testutil::info("meta", &testutil::content(1000), 16);
+        info.pieces = bytes::Bytes::from(vec![9; 20 * 2000]);
+        serde_bencode::to_bytes(&info).unwrap()
+    }
+
+    fn info_hash(info: &[u8]) -> [u8; 20] {
+        sha1::Sha1::digest(info).into()
+    }
+
+    /// A mock peer serving an info dictionary over ut_metadata.
+    #[derive(Debug, Clone, Default)]
+    struct MetadataPeer {
+        info: Vec<u8>,
+        info_hash: [u8; 20],
+        /// reject every request
+        reject: bool,
+        /// never answer requests
+        stall: bool,
+        /// hang up after answering this many requests
+        hang_up_after: Option<usize>,
+        /// the id of every message received
+        log: Arc<Mutex<Vec<u8>>>,
+        /// the metadata pieces requested
+        requested: Arc<Mutex<Vec<u32>>>,
+    }
+
+    impl MetadataPeer {
+        fn new(info: Vec<u8>, info_hash: [u8; 20]) -> Self {
+            Self {
+                info,
+                info_hash,
+                ..Default::default()
+            }
+        }
+
+        async fn spawn(self) -> SocketAddr {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tokio::spawn(self.clone().serve(stream));
+                }
+            });
+            addr
+        }
+
+        async fn serve(self, mut stream: tokio::net::TcpStream) -> Result<()> {
+            let info = &self.info;
+            let mut handshake = [0; 68];
+            stream.read_exact(&mut handshake).await?;
+            let ours = HandShake::new(&self.info_hash, &[5; 20]).with_extension_protocol();
+            stream.write_all(&ours.as_bytes()).await?;
+            let mut ext = ExtendedHandshake::default();
+            ext.m.insert("ut_metadata".to_string(), THEIR_ID as i64);
+            ext.metadata_size = Some(info.len() as i64);
+            let msg = Extended::new(Extended::HANDSHAKE_ID, &ext.as_bytes());
+            stream.write_all(&Message::Extended(msg).as_bytes()).await?;
+            let mut answered = 0;
+            loop {
+                let length = stream.read_u32().await? as usize;
+                let mut body = vec![0; length];
+                stream.read_exact(&mut body).await?;
+                self.log.lock().unwrap().extend(body.first());
+                if body[..2] != [20, THEIR_ID] || self.stall {
+                    continue;
+                }
+                if self.hang_up_after == Some(answered) {
+                    return Ok(());
+                }
+                let request = MetadataMessage::from_bytes(&body[2..]).unwrap();
+                self.requested.lock().unwrap().push(request.piece);
+                let begin = request.piece as usize * MetadataBuffer::PIECE_SIZE;
+                let end = info.len().min(begin + MetadataBuffer::PIECE_SIZE);
+                let answer = MetadataMessage {
+                    msg_type: match self.reject {
+                        true => MetadataMessageType::Reject,
+                        false => MetadataMessageType::Data,
+                    },
+                    piece: request.piece,
+                    total_size: (!self.reject).then_some(info.len() as u32),
+                    data: match self.reject {
+                        true => vec![],
+                        false => info[begin..end].to_vec(),
+                    },
+                };
+                let msg = Extended::new(MetadataFetcher::UT_METADATA_ID, &answer.as_bytes());
+                stream.write_all(&Message::Extended(msg).as_bytes()).await?;
+                answered += 1;
+            }
+        }
+    }
+
+    /// a [`MetadataPeer`] rejecting every request if `reject`, or never answering
+    /// them if `stall`
+    async fn metadata_peer(
+        info: Vec<u8>,
+        info_hash: [u8; 20],
+        reject: bool,
+        stall: bool,
+    ) -> SocketAddr {
+        MetadataPeer {
+            reject,
+            stall,
+            ..MetadataPeer::new(info, info_hash)
+        }
+        .spawn()
+        .await
+    }
+
+    #[tokio::test]
+    async fn reassembles_multi_piece_metadata() {
+        let info = big_info();
+        assert_eq!(info.len().div_ceil(MetadataBuffer::PIECE_SIZE), 3);
+        let info_hash = info_hash(&info);
+        let peer = metadata_peer(info.clone(), info_hash, false, false).await;
+        let fetched = MetadataFetcher::new(info_hash, [1; 20])
+            .fetch_from(peer)
+            .await
+            .unwrap();
+        assert_eq!(fetched, info);
+    }
+
+    #[tokio::test]
+    async fn metadata_with_a_wrong_hash_is_refused() {
+        let info = big_info();
+        let info_hash = info_hash(&info);
+        let mut forged = info.clone();
+        *forged.last_mut().unwrap() ^= 1;
+        let peer = metadata_peer(forged, info_hash, false, false).await;
+        let err = MetadataFetcher::new(info_hash, [1; 20])
+            .fetch_from(peer)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("wrong hash"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn rejecting_and_stalled_peers_are_skipped() {
+        let info = big_info();
+        let info_hash = info_hash(&info);
+        let rejecting = metadata_peer(info.clone(), info_hash, true, false).await;
+        let stalled = metadata_peer(info.clone(), info_hash, false, true).await;
+        let serving = metadata_peer(info.clone(), info_hash, false, false).await;
+        let fetched = MetadataFetcher::new(info_hash, [1; 20])
+            .with_peer_timeout(Duration::from_millis(500))
+            .fetch(&[rejecting, stalled, serving])
+            .await
+            .unwrap();
+        assert_eq!(fetched, info);
+    }
+
+    #[tokio::test]
+    async fn metadata_only_requests_no_content() {
+        let content = testutil::content(100_000);
+        let info = serde_bencode::to_bytes(&testutil::info("meta", &content, 32768)).unwrap();
+        let info_hash = info_hash(&info);
+        let peer = MetadataPeer::new(info, info_hash);
+        let log = peer.log.clone();
+        let peer = peer.spawn().await;
+        let hex: String = info_hash.iter().map(|b| format!("{:02x}", b)).collect();
+        // an empty cache, so the metadata has to come from the peer
+        let cache = testutil::Scratch::dir("metadata-cache");
+        let client = TorrentClientBuilder::new()
+            .set_metadata_cache_dir(cache.path())
+            .set_metadata_only(true)
+            .add_magnet(&format!("magnet:?xt=urn:btih:{}&x.pe={}", hex, peer))
+            .await
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(client.info_hash, info_hash);
+        assert_eq!(client.name.as_str(), "meta");
+        assert_eq!(client.length, 100_000);
+        client.send_request().await.unwrap();
+        assert!(client.download_to_vec().await.is_err());
+        // nothing but the extension protocol went to the peer
+        let ids = log.lock().unwrap().clone();
+        assert!(!ids.is_empty());
+        assert!(ids.iter().all(|id| *id == 20), "{:?}", ids);
+        assert_eq!(client.queued_pieces(), 0);
+    }
+
+    #[tokio::test]
+    async fn metadata_fetch_survives_a_peer_leaving() {
+        let info = big_info();
+        let info_hash = info_hash(&info);
+        let leaving = MetadataPeer {
+            hang_up_after: Some(1),
+            ..MetadataPeer::new(info.clone(), info_hash)
+        };
+        let staying = MetadataPeer::new(info.clone(), info_hash);
+        let (left, stayed) = (leaving.requested.clone(), staying.requested.clone());
+        let peers = [leaving.spawn().await, staying.spawn().await];
+        let fetched = MetadataFetcher::new(info_hash, [1; 20])
+            .fetch(&peers)
+            .await
+            .unwrap();
+        assert_eq!(fetched, info);
+        // the piece from the peer which left is kept, the second one is only asked
+        // for the others
+        assert_eq!(*left.lock().unwrap(), vec![0]);
+        assert_eq!(*stayed.lock().unwrap(), vec![1, 2]);
+    }
+
+    #[test]
+    fn buffer_refuses_pieces_of_the_wrong_size() {
+        let mut buffer = MetadataBuffer::new(MetadataBuffer::PIECE_SIZE + 10);
+        assert!(buffer.insert(0, vec![0; 10]).is_err());
+        assert!(buffer.insert(1, vec![0; 11]).is_err());
+        assert!(buffer.insert(2, vec![0; 10]).is_err());
+        buffer.insert(1, vec![0; 10]).unwrap();
+        assert_eq!(buffer.missing().collect::<Vec<_>>(), vec![0]);
+    }
+}
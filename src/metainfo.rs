@@ -0,0 +1,196 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use sha1::Digest;
+
+use crate::{
+    bencode::{self, BencodeInfo, BencodeTorrent},
+    files::TorrentFile,
+    task,
+};
+
+/// A parsed and checked torrent, to look at without setting up a download.
+/// [`crate::TorrentClientBuilder::add_torrent`] downloads it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Torrent {
+    pub name: String,
+    /// total size of the content
+    pub length: u32,
+    pub piece_length: u32,
+    pub piece_hashes: Vec<[u8; 20]>,
+    pub info_hash: [u8; 20],
+    /// `announce`, absent for a bare info dictionary
+    pub announce: Option<String>,
+    /// every tracker, tier by tier, starting from `announce`
+    pub trackers: Vec<String>,
+    /// the files in the order they are laid out in the content
+    pub files: Vec<TorrentFile>,
+    /// whether the files live in the directory `name`, even if it is a single one
+    pub multi_file: bool,
+    pub private: bool,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    /// seconds since the Unix epoch
+    pub creation_date: Option<i64>,
+    pub web_seeds: Vec<String>,
+    pub http_seeds: Vec<String>,
+}
+
+impl Torrent {
+    pub fn from_path<T>(path: T) -> Result<Self>
+    where
+        T: AsRef<Path>,
+    {
+        Self::from_bytes(&std::fs::read(path.as_ref())?)
+    }
+
+    /// parse the content of a torrent file
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Self::parse(bytes, false)
+    }
+
+    /// like [`Self::from_bytes`], also taking `length` and `piece length` written as
+    /// numeric strings by some broken generators
+    pub fn from_bytes_lenient(bytes: &[u8]) -> Result<Self> {
+        Self::parse(bytes, true)
+    }
+
+    fn parse(bytes: &[u8], lenient: bool) -> Result<Self> {
+        let bytes = bencode::trim_start(bytes)?;
+        let decoded = match lenient {
+            true => bencode::integer_strings(bytes)?,
+            false => bytes.to_vec(),
+        };
+        bencode::check_integers(&decoded)?;
+        let torrent: BencodeTorrent = serde_bencode::from_bytes(&decoded)?;
+        // hash the info dictionary as encoded, with every key we do not model
+        let info = bencode::dict_value(bytes, b"info")?
+            .ok_or_else(|| anyhow!("torrent has no info dictionary"))?;
+        let mut parsed = Self::from_info(&torrent.info, sha1::Sha1::digest(info).into())?;
+        parsed.trackers = torrent.trackers();
+        parsed.comment = torrent.comment();
+        parsed.created_by = torrent.created_by();
+        parsed.creation_date = torrent.creation_date;
+        parsed.announce = Some(torrent.announce);
+        parsed.web_seeds = torrent
+            .url_list
+            .map(|urls| urls.into_vec())
+            .unwrap_or_default();
+        parsed.http_seeds = torrent.httpseeds.unwrap_or_default();
+        Ok(parsed)
+    }
+
+    /// parse a bare info dictionary, as resolved from a magnet link
+    pub fn from_info_bytes(bytes: &[u8]) -> Result<Self> {
+        Self::parse_info(bytes, false)
+    }
+
+    /// like [`Self::from_info_bytes`], with the leniency of [`Self::from_bytes_lenient`]
+    pub fn from_info_bytes_lenient(bytes: &[u8]) -> Result<Self> {
+        Self::parse_info(bytes, true)
+    }
+
+    fn parse_info(bytes: &[u8], lenient: bool) -> Result<Self> {
+        let decoded = match lenient {
+            true => bencode::integer_strings(bytes)?,
+            false => bytes.to_vec(),
+        };
+        bencode::check_integers(&decoded)?;
+        let info: BencodeInfo = serde_bencode::from_bytes(&decoded)?;
+        Self::from_info(&info, sha1::Sha1::digest(bytes).into())
+    }
+
+    fn from_info(info: &BencodeInfo, info_hash: [u8; 20]) -> Result<Self> {
+        info.check_pieces()?;
+        info.check_name()?;
+        Ok(Self {
+            name: info.name.clone(),
+            length: info.total_length()?,
+            piece_length: info.piece_length,
+            piece_hashes: info
+                .pieces
+                .chunks(20)
+                .map(|chunk| chunk.try_into().unwrap())
+                .collect(),
+            info_hash,
+            announce: None,
+            trackers: Vec::new(),
+            files: info.files()?,
+            multi_file: info.files.is_some(),
+            private: info.is_private(),
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            web_seeds: Vec::new(),
+            http_seeds: Vec::new(),
+        })
+    }
+
+    pub fn piece_count(&self) -> u32 {
+        task::piece_count(self.length, self.piece_length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::testutil;
+
+    #[test]
+    fn every_field_is_parsed() {
+        let content = testutil::content(100_000);
+        let files = [("a.bin", 40000), ("sub/b.bin", 60000)];
+        let mut info = testutil::multi_info("multi", &files, &content, 32768);
+        info.private = Some(1);
+        let mut torrent = testutil::torrent_of(info);
+        let backup = "http://backup.example/announce".to_string();
+        torrent.announce_list = Some(vec![
+            vec![testutil::DEAD_TRACKER.to_string()],
+            vec![backup.clone()],
+        ]);
+        torrent.comment = Some("a comment".as_bytes().into());
+        torrent.created_by = Some("rbt 1.0".as_bytes().into());
+        torrent.creation_date = Some(1_700_000_000);
+        torrent.httpseeds = Some(vec!["http://seed.example/".to_string()]);
+        let scratch = testutil::Scratch::dir("metainfo");
+        let path = scratch.join("multi.torrent");
+        std::fs::write(&path, serde_bencode::to_bytes(&torrent).unwrap()).unwrap();
+
+        let parsed = Torrent::from_path(&path).unwrap();
+        assert_eq!(parsed.name, "multi");
+        assert_eq!(parsed.length, 100_000);
+        assert_eq!(parsed.piece_length, 32768);
+        assert_eq!(
+            parsed.piece_hashes.concat(),
+            testutil::piece_hashes(&content, 32768)
+        );
+        let info = serde_bencode::to_bytes(&torrent.info).unwrap();
+        assert_eq!(parsed.info_hash, <[u8; 20]>::from(sha1::Sha1::digest(info)));
+        assert_eq!(parsed.announce.as_deref(), Some(testutil::DEAD_TRACKER));
+        assert_eq!(
+            parsed.trackers,
+            vec![testutil::DEAD_TRACKER.to_string(), backup]
+        );
+        let layout: Vec<_> = parsed
+            .files
+            .iter()
+            .map(|file| (file.path.clone(), file.offset, file.length))
+            .collect();
+        assert_eq!(
+            layout,
+            vec![
+                (PathBuf::from("multi/a.bin"), 0, 40000),
+                (PathBuf::from("multi/sub/b.bin"), 40000, 60000),
+            ]
+        );
+        assert!(parsed.multi_file);
+        assert!(parsed.private);
+        assert_eq!(parsed.comment.as_deref(), Some("a comment"));
+        assert_eq!(parsed.created_by.as_deref(), Some("rbt 1.0"));
+        assert_eq!(parsed.creation_date, Some(1_700_000_000));
+        assert!(parsed.web_seeds.is_empty());
+        assert_eq!(parsed.http_seeds, vec!["http://seed.example/".to_string()]);
+    }
+}
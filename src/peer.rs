@@ -1,88 +1,190 @@
 use std::{
-    fs::create_dir_all,
-    io::{Read, Write},
-    net::{Ipv4Addr, SocketAddrV4},
-    path::PathBuf,
-    sync::Arc,
-    time::Duration,
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
 
 use anyhow::{anyhow, Result};
-use bytes::Bytes;
-use crossbeam::queue::ArrayQueue;
 use indicatif::ProgressBar;
-use log::{info, trace};
-use serde::{Deserialize, Serialize};
-use sha1::Digest;
-use tokio::{io::AsyncWriteExt, net::TcpStream, time::timeout};
+use log::{info, trace, warn};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpSocket, TcpStream},
+    sync::{broadcast, watch, Semaphore},
+    task::{spawn_blocking, JoinHandle},
+    time::{interval_at, sleep_until, timeout},
+};
 
 use crate::{
-    message::{Bitfield, HandShake, Message, Piece, Request},
-    task::Task,
+    assembly::PieceAssembly,
+    bandwidth::Bandwidth,
+    event::Event,
+    message::{
+        Bitfield, DontHave, Extended, ExtendedHandshake, Extension, HandShake, Message, Piece,
+        Request,
+    },
+    picker::PiecePicker,
+    pool::BlockPool,
+    retry::RetryPolicy,
+    stats::{client_name, MessageCounts, PeerHistory, PeerStats},
+    task::{Task, TaskQueue},
+    torrent::TorrentClient,
+    verifier::PieceVerifier,
 };
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PeerState {
     Preparing,
+    /// unchoked, requests go out
     Busy,
+    /// choked after having been unchoked, requests wait for the next unchoke
+    Choked,
+}
+
+/// Decides by peer id which peers we keep talking to after the handshake, see
+/// [`crate::TorrentClientBuilder::set_peer_id_filter`].
+#[derive(Clone)]
+pub struct PeerIdFilter(Arc<dyn Fn(&PeerId) -> bool + Send + Sync>);
+
+type PeerId = [u8; 20];
+
+impl PeerIdFilter {
+    pub fn new<F>(filter: F) -> Self
+    where
+        F: Fn(&[u8; 20]) -> bool + Send + Sync + 'static,
+    {
+        Self(Arc::new(filter))
+    }
+
+    pub fn allows(&self, peer_id: &[u8; 20]) -> bool {
+        (self.0)(peer_id)
+    }
+}
+
+impl fmt::Debug for PeerIdFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PeerIdFilter")
+    }
+}
+
+/// Options applied to the socket of every peer connection.
+#[derive(Debug, Clone, Copy)]
+pub struct SocketOptions {
+    pub nodelay: bool,
+    pub recv_buffer_size: Option<u32>,
+    pub send_buffer_size: Option<u32>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        // the control messages are tiny, do not hold them back for coalescing
+        Self {
+            nodelay: true,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Peer {
-    pub ip: Ipv4Addr,
+    pub ip: IpAddr,
     pub port: u16,
     pub state: PeerState,
     pub id: Option<[u8; 20]>,
+    /// the info hash we sent in our handshake, the peer has to answer with the same
+    pub info_hash: [u8; 20],
     pub stream: Option<TcpStream>,
     pub bitfield: Option<Bitfield>,
-    pub task_queue: Arc<ArrayQueue<Task>>,
+    pub task_queue: Arc<TaskQueue>,
     pub current_task: Option<Task>,
     pub name: Arc<String>,
     pub pb: ProgressBar,
-    pub received_pieces: Vec<Piece>,
-}
-
-#[derive(Serialize, Deserialize)]
-pub struct TrackerReport {
-    interval: i64,
-    peers: Bytes,
+    pub assembly: PieceAssembly,
+    pub verifier: PieceVerifier,
+    pub verifications: Vec<JoinHandle<bool>>,
+    pub availability: Arc<Mutex<Vec<u16>>>,
+    pub shutdown: watch::Receiver<bool>,
+    pub stats: Arc<Mutex<HashMap<SocketAddr, PeerStats>>>,
+    pub block_pool: Arc<BlockPool>,
+    pub piece_started: Option<Instant>,
+    pub max_piece_time: Option<Duration>,
+    pub min_rate: Option<u64>,
+    pub keep_alive_interval: Duration,
+    pub idle_timeout: Duration,
+    /// when we last sent a message, a keep-alive goes out `keep_alive_interval` after
+    pub last_sent: Instant,
+    /// when a block last went either way, the peer is dropped `idle_timeout` after
+    pub last_useful: Instant,
+    /// the rate limits shared by every peer
+    pub bandwidth: Arc<Bandwidth>,
+    pub socket_options: SocketOptions,
+    pub pending_requests: Vec<Request>,
+    pub events: broadcast::Sender<Event>,
+    pub history: Arc<Mutex<HashMap<SocketAddr, PeerHistory>>>,
+    pub connect_permits: Arc<Semaphore>,
+    /// the extended handshake of the peer, if it speaks the extension protocol
+    pub extensions: Option<ExtendedHandshake>,
+    /// messages exchanged with this peer, kept in its stats once it is handshaked
+    pub messages: MessageCounts,
+    /// messages exchanged with peers which disconnected already
+    pub message_totals: Arc<Mutex<MessageCounts>>,
+    pub uploaded: Arc<AtomicU64>,
+    /// requests sent but not served yet, at most `window` of them
+    pub in_flight: Vec<Request>,
+    pub pipeline_depth: usize,
+    /// below this rate the request window shrinks, see
+    /// [`crate::TorrentClientBuilder::set_slow_peer_rate`]
+    pub slow_rate: Option<u64>,
+    /// how many requests may be in flight right now, up to `pipeline_depth`
+    pub window: usize,
+    pub window_changed: Option<Instant>,
+    /// prefer the piece right after `last_completed`, see
+    /// [`crate::TorrentClientBuilder::set_piece_affinity`]
+    pub piece_affinity: bool,
+    pub last_completed: Option<u32>,
+    pub peer_id_filter: Option<PeerIdFilter>,
+    /// the extensions we advertise, no extension protocol at all when empty
+    pub enabled_extensions: Vec<Extension>,
+    pub retry_policy: RetryPolicy,
+    pub piece_picker: Arc<dyn PiecePicker>,
 }
 
 #[derive(Debug)]
 pub struct Peers(Vec<Peer>);
 
 impl Peers {
-    pub fn new(
-        buf: &[u8],
-        task_queue: Arc<ArrayQueue<Task>>,
-        name: Arc<String>,
-        pb: ProgressBar,
-    ) -> Result<Self> {
-        let tracker_report: TrackerReport = serde_bencode::from_bytes(buf)?;
-        let buf = tracker_report.peers;
-
-        assert!(buf.len() % 6 == 0);
-        let peers: Vec<_> = (0..buf.len() / 6)
-            .map(|i| {
-                let offset = 6 * i;
-                let ip_bits = u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap());
-                let port = u16::from_be_bytes(buf[offset + 4..offset + 6].try_into().unwrap());
-                Peer {
-                    ip: Ipv4Addr::from(ip_bits),
-                    port,
-                    state: PeerState::Preparing,
-                    id: None,
-                    stream: None,
-                    bitfield: None,
-                    task_queue: task_queue.clone(),
-                    current_task: None,
-                    name: name.clone(),
-                    pb: pb.clone(),
-                    received_pieces: vec![],
-                }
-            })
-            .collect();
-        Ok(Self(peers))
+    pub fn new(addrs: &[SocketAddr], client: &TorrentClient) -> Self {
+        Self(
+            addrs
+                .iter()
+                .map(|addr| Peer::new(addr.ip(), addr.port(), client))
+                .collect(),
+        )
+    }
+
+    /// move peers in private, link-local or loopback ranges to the front
+    pub fn prioritize_local(&mut self) {
+        self.0.sort_by_key(|peer| !peer.is_local());
+    }
+
+    /// move peers we connected to before to the front and those which kept failing to the back
+    pub fn prioritize_known(&mut self, history: &HashMap<SocketAddr, PeerHistory>) {
+        self.0.sort_by_key(|peer| {
+            std::cmp::Reverse(history.get(&peer.addr()).map_or(0, PeerHistory::score))
+        });
+    }
+
+    /// add a peer unless one with the same address is in already
+    pub fn push(&mut self, peer: Peer) {
+        if !self.0.iter().any(|known| known.addr() == peer.addr()) {
+            self.0.push(peer);
+        }
     }
 
     #[allow(unused)]
@@ -104,27 +206,104 @@ impl IntoIterator for Peers {
     }
 }
 
+#[derive(Debug)]
 enum PeerEvent {
     Continue,
     Exit,
 }
 
+/// what woke the message loop of a peer up
+enum Wake {
+    Readable(std::io::Result<()>),
+    Interest,
+    KeepAlive,
+}
+
 impl Peer {
-    const BLOCK_SIZE: u32 = 2_u32.pow(14);
+    pub const BLOCK_SIZE: u32 = 2_u32.pow(14);
+    /// how often Interested is sent again to a peer which keeps choking us
+    const INTEREST_INTERVAL: Duration = Duration::from_secs(30);
+    /// larger requests are refused, as most clients do
+    const MAX_REQUEST_LENGTH: u32 = 2_u32.pow(17);
+    /// how long a piece may run before its rate is held against the minimum
+    const SNUB_GRACE: Duration = Duration::from_secs(5);
+
+    pub fn new(ip: IpAddr, port: u16, client: &TorrentClient) -> Self {
+        Self {
+            ip,
+            port,
+            state: PeerState::Preparing,
+            id: None,
+            info_hash: [0; 20],
+            stream: None,
+            bitfield: None,
+            task_queue: client.task_queue.clone(),
+            current_task: None,
+            name: client.name.clone(),
+            pb: client.pb.clone(),
+            assembly: client.assembly.clone(),
+            verifier: PieceVerifier::new(client),
+            verifications: vec![],
+            availability: client.availability.clone(),
+            shutdown: client.shutdown.subscribe(),
+            stats: client.peer_stats.clone(),
+            block_pool: client.block_pool.clone(),
+            piece_started: None,
+            max_piece_time: client.max_piece_time,
+            min_rate: client.min_peer_rate,
+            keep_alive_interval: client.keep_alive_interval,
+            idle_timeout: client.idle_timeout,
+            last_sent: Instant::now(),
+            last_useful: Instant::now(),
+            bandwidth: client.bandwidth.clone(),
+            socket_options: client.socket_options,
+            pending_requests: vec![],
+            events: client.events.clone(),
+            history: client.peer_history.clone(),
+            connect_permits: client.connect_permits.clone(),
+            extensions: None,
+            messages: MessageCounts::default(),
+            message_totals: client.message_totals.clone(),
+            uploaded: client.uploaded.clone(),
+            in_flight: vec![],
+            pipeline_depth: client.pipeline_depth,
+            slow_rate: client.slow_peer_rate,
+            window: client.pipeline_depth,
+            window_changed: None,
+            piece_affinity: client.piece_affinity,
+            last_completed: None,
+            peer_id_filter: client.peer_id_filter.clone(),
+            enabled_extensions: client.extensions.clone(),
+            retry_policy: client.retry_policy,
+            piece_picker: client.piece_picker.clone(),
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        SocketAddr::new(self.ip, self.port)
+    }
+
+    /// whether the peer lives on the local network (RFC 1918, unique local, link-local or loopback)
+    pub fn is_local(&self) -> bool {
+        match self.ip {
+            IpAddr::V4(ip) => ip.is_private() || ip.is_link_local() || ip.is_loopback(),
+            // unique local fc00::/7 and link-local fe80::/10
+            IpAddr::V6(ip) => {
+                ip.is_loopback()
+                    || (ip.segments()[0] & 0xfe00) == 0xfc00
+                    || (ip.segments()[0] & 0xffc0) == 0xfe80
+            }
+        }
+    }
 
     fn has_piece(&self, index: u32) -> bool {
         self.bitfield.as_ref().unwrap().has_piece(index)
     }
 
     fn is_current_task_done(&self) -> Option<bool> {
-        if self.current_task.is_some() {
-            Some(
-                self.received_pieces.len() as u32 * Self::BLOCK_SIZE
-                    >= self.current_task.as_ref().unwrap().piece_length,
-            )
-        } else {
-            None
-        }
+        self.current_task
+            .as_ref()
+            .map(|task| !self.assembly.contains(task.index))
     }
 
     /// if current task is done or none, fetch task from queue
@@ -132,17 +311,9 @@ impl Peer {
         match self.is_current_task_done() {
             // task exists and done
             Some(true) => {
-                self.save_pieces()?;
-                if let Err(err) = self.check_sum() {
-                    info!("{}", err);
-                    self.put_task_back();
-                } else {
-                    info!(
-                        "piece #{} downloaded successfully",
-                        self.current_task.as_ref().unwrap().index
-                    );
-                }
-                if let PeerEvent::Exit = self.fetch_task() {
+                // the piece is verifying already, whoever delivered its last block
+                self.last_completed = self.current_task.take().map(|task| task.index);
+                if let PeerEvent::Exit = self.fetch_task().await {
                     return Ok(PeerEvent::Exit);
                 };
                 self.request_piece().await?;
@@ -151,48 +322,181 @@ impl Peer {
             // task exists and not done
             Some(false) => Ok(PeerEvent::Continue),
             // task not exists
-            None => Ok(self.fetch_task()),
+            None => Ok(self.fetch_task().await),
         }
     }
 
-    fn fetch_task(&mut self) -> PeerEvent {
-        let task = match self.task_queue.pop() {
+    async fn fetch_task(&mut self) -> PeerEvent {
+        let next = match (self.piece_affinity, self.last_completed) {
+            (true, Some(last)) => self.task_queue.pop_preferring(last + 1),
+            _ => self.task_queue.pop_picked(|needed| {
+                let available = self.availability.lock().unwrap();
+                self.piece_picker
+                    .next_piece(self.bitfield.as_ref(), &available, needed)
+            }),
+        };
+        let task = match next {
             Some(task) => task,
             None => return PeerEvent::Exit,
         };
+        if !self.verifier.reserve_quota(&task, &mut self.shutdown).await {
+            self.task_queue.requeue(task);
+            return PeerEvent::Exit;
+        }
+        self.assembly.start(task);
         self.current_task = Some(task);
+        self.piece_started = Some(Instant::now());
+        self.plan_requests();
         PeerEvent::Continue
     }
 
+    /// whether `block` is one we requested: a block of the current piece at a block
+    /// boundary, holding exactly BLOCK_SIZE bytes or the rest of the piece
+    fn is_expected_block(&self, block: &Piece) -> bool {
+        let Some(task) = self.current_task.as_ref() else {
+            return false;
+        };
+        block.index == task.index
+            && block.begin.is_multiple_of(Self::BLOCK_SIZE)
+            && block.begin < task.piece_length
+            && block.piece.len() as u32 == Self::BLOCK_SIZE.min(task.piece_length - block.begin)
+    }
+
+    /// when the current piece has to be done by
+    fn piece_deadline(&self) -> Option<Instant> {
+        self.piece_started
+            .zip(self.max_piece_time)
+            .map(|(started, max)| started + max)
+    }
+
+    /// whether the peer delivers the current piece slower than the minimum rate
+    fn is_snubbed(&self) -> bool {
+        let (Some(rate), Some(min_rate)) = (self.piece_rate(), self.min_rate) else {
+            return false;
+        };
+        rate < min_rate as f64
+    }
+
+    /// bytes per second the current piece arrives at, once past the grace period
+    fn piece_rate(&self) -> Option<f64> {
+        let (started, task) = self.piece_started.zip(self.current_task)?;
+        let elapsed = started.elapsed();
+        if elapsed < Self::SNUB_GRACE {
+            return None;
+        }
+        Some(self.assembly.received(task.index) as f64 / elapsed.as_secs_f64())
+    }
+
+    /// halve the request window of a peer delivering below the slow rate, and grow
+    /// it back once it recovers, at most once per grace period
+    fn adjust_window(&mut self) {
+        let (Some(slow_rate), Some(rate)) = (self.slow_rate, self.piece_rate()) else {
+            return;
+        };
+        if self
+            .window_changed
+            .is_some_and(|changed| changed.elapsed() < Self::SNUB_GRACE)
+        {
+            return;
+        }
+        let window = match rate < slow_rate as f64 {
+            true => (self.window / 2).max(1),
+            false => (self.window * 2).min(self.pipeline_depth),
+        };
+        if window != self.window {
+            trace!(
+                "peer {} delivers {:.0} bytes/s, request window {} -> {}",
+                self.ip,
+                rate,
+                self.window,
+                window
+            );
+            self.window = window;
+            self.window_changed = Some(Instant::now());
+        }
+    }
+
     fn put_task_back(&mut self) {
-        self.task_queue
-            .push(self.current_task.take().unwrap())
-            .unwrap();
+        let task = self.current_task.take().unwrap();
+        self.verifier.release_quota(&task);
+        self.task_queue.requeue(task);
     }
 
     async fn try_connect(&mut self) -> Result<()> {
-        let stream = timeout(
-            Duration::from_secs(3),
-            TcpStream::connect(SocketAddrV4::new(self.ip, self.port)),
-        )
-        .await??;
+        let socket = match self.ip {
+            IpAddr::V4(_) => TcpSocket::new_v4()?,
+            IpAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        if let Some(size) = self.socket_options.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.socket_options.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        let stream = timeout(Duration::from_secs(3), socket.connect(self.addr())).await??;
+        stream.set_nodelay(self.socket_options.nodelay)?;
         self.stream = Some(stream);
         info!("peer connected: {}", self.ip);
+        let _ = self.events.send(Event::PeerConnected(self.addr()));
         Ok(())
     }
 
+    /// connect, retrying as the retry policy says unless shut down meanwhile
+    async fn connect_with_retries(&mut self) -> Result<()> {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let connected = {
+                // dial politely, only a few connection attempts may be in flight at once
+                let _permit = self.connect_permits.clone().acquire_owned().await?;
+                self.try_connect().await
+            };
+            let err = match connected {
+                Err(err) if self.retry_policy.retries_left(attempt) => err,
+                connected => return connected,
+            };
+            let delay = self.retry_policy.delay(attempt - 1);
+            trace!(
+                "connecting to peer {} failed: {}, retry in {:?}",
+                self.ip,
+                err,
+                delay
+            );
+            let mut shutdown = self.shutdown.clone();
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = shutdown.wait_for(|stop| *stop) => return Err(err),
+            }
+        }
+    }
+
     async fn send_message(&mut self, msg: Message) -> Result<()> {
         self.stream
             .as_mut()
             .unwrap()
             .write_all(&msg.as_bytes())
             .await?;
+        self.last_sent = Instant::now();
+        self.count_message(&msg, true);
         Ok(())
     }
 
+    fn count_message(&mut self, msg: &Message, sent: bool) {
+        let count = |counts: &mut MessageCounts| match sent {
+            true => counts.count_sent(msg.name()),
+            false => counts.count_received(msg.name()),
+        };
+        count(&mut self.messages);
+        if let Some(stats) = self.stats.lock().unwrap().get_mut(&self.addr()) {
+            count(&mut stats.messages);
+        }
+    }
+
     /// read and process message
     async fn read_message(&mut self) -> Result<PeerEvent> {
-        match Message::from_stream(self.stream.as_mut().unwrap()).await {
+        match Message::from_stream_pooled(self.stream.as_mut().unwrap(), Some(&self.block_pool))
+            .await
+        {
             Ok(msg) => self.process_msg(msg).await,
             Err(err) => {
                 info!("peer {} exit since: {}", self.ip, err);
@@ -201,90 +505,414 @@ impl Peer {
         }
     }
 
-    /// send request to peer
+    /// say we are interested again while choked by a peer which has the piece we
+    /// are after, some peers only unchoke on a fresh Interested. Peers choking us
+    /// for good are left to the stall detection, which looks for other peers
+    async fn reassert_interest(&mut self) -> Result<PeerEvent> {
+        let wanted = self
+            .current_task
+            .is_some_and(|task| self.bitfield.is_some() && self.has_piece(task.index));
+        if self.state != PeerState::Busy && wanted {
+            trace!("peer {} still chokes us, interested again", self.ip);
+            self.send_message(Message::Interested).await?;
+        }
+        Ok(PeerEvent::Continue)
+    }
+
+    /// top the requests in flight up to the pipeline depth, as long as we are unchoked
     async fn request_piece(&mut self) -> Result<()> {
-        trace!("send request to peer: {}", self.ip);
-        let mut offset = 0;
-        while offset < self.current_task.as_ref().unwrap().piece_length / Self::BLOCK_SIZE {
-            self.send_message(Message::Request(Request::new(
-                self.current_task.as_ref().unwrap().index,
-                offset * Self::BLOCK_SIZE,
-                Self::BLOCK_SIZE,
-            )))
-            .await?;
-            offset += 1;
+        if self.state != PeerState::Busy {
+            return Ok(());
+        }
+        let room = self
+            .window
+            .saturating_sub(self.in_flight.len())
+            .min(self.pending_requests.len());
+        if room == 0 {
+            return Ok(());
+        }
+        trace!("send {} requests to peer: {}", room, self.ip);
+        let mut buf = vec![];
+        let requests: Vec<_> = self.pending_requests.drain(..room).collect();
+        self.in_flight.extend(requests.iter().copied());
+        let requests: Vec<_> = requests.into_iter().map(Message::Request).collect();
+        for request in requests.iter() {
+            buf.extend_from_slice(&request.as_bytes());
+        }
+        self.stream.as_mut().unwrap().write_all(&buf).await?;
+        for request in requests.iter() {
+            self.count_message(request, true);
         }
         Ok(())
     }
 
+    /// work out the requests for the current task ahead of time, so they go out
+    /// the moment the peer unchokes us
+    fn plan_requests(&mut self) {
+        let task = *self.current_task.as_ref().unwrap();
+        self.pending_requests.clear();
+        self.in_flight.clear();
+        // the blocks of a short final piece end wherever the piece ends
+        let mut begin = 0;
+        while begin < task.piece_length {
+            let length = Self::BLOCK_SIZE.min(task.piece_length - begin);
+            self.pending_requests
+                .push(Request::new(task.index, begin, length));
+            begin += length;
+        }
+    }
+
     async fn process_msg(&mut self, msg: Message) -> Result<PeerEvent> {
+        self.count_message(&msg, false);
+        // nothing a peer says counts before it completed the handshake
+        if self.id.is_none() && !matches!(msg, Message::HandShake(_)) {
+            return Err(anyhow!(
+                "peer {} sent a message before the handshake",
+                self.ip
+            ));
+        }
         match msg {
             Message::HandShake(handshake) => {
+                // a peer serving another torrent on the same port would only hand us
+                // blocks failing the piece hash check
+                if handshake.info_hash != self.info_hash {
+                    warn!(
+                        "peer {} answered the handshake for another torrent",
+                        self.ip
+                    );
+                    return Ok(PeerEvent::Exit);
+                }
+                if self
+                    .peer_id_filter
+                    .as_ref()
+                    .is_some_and(|filter| !filter.allows(&handshake.peer_id))
+                {
+                    info!(
+                        "peer {} filtered out by its id, client {:?}",
+                        self.ip,
+                        client_name(&handshake.peer_id)
+                    );
+                    return Ok(PeerEvent::Exit);
+                }
                 self.id = Some(handshake.peer_id);
+                let mut stats = PeerStats::new(self.addr());
+                stats.client = client_name(&handshake.peer_id);
+                stats.messages = self.messages.clone();
+                self.stats.lock().unwrap().insert(self.addr(), stats);
                 trace!("handshake success with peer: {}", self.ip);
+                if handshake.supports_extension_protocol() && !self.enabled_extensions.is_empty() {
+                    let ours = ExtendedHandshake::advertising(&self.enabled_extensions).as_bytes();
+                    self.send_message(Message::Extended(Extended::new(
+                        Extended::HANDSHAKE_ID,
+                        &ours,
+                    )))
+                    .await?;
+                }
                 self.send_message(Message::UnChoke).await?;
             }
+            Message::Extended(ext) => return self.process_extended(ext).await,
+            Message::Request(request) => self.serve_block(request).await?,
             Message::Bitfield(bitfield) => {
                 trace!(
                     "get bitfield, length={}, from peer: {}",
                     bitfield.len(),
                     self.ip
                 );
+                let piece_count = self.availability.lock().unwrap().len() as u32;
+                if !bitfield.fits(piece_count) {
+                    warn!(
+                        "peer {} sent a bitfield of {} bytes with pieces beyond #{}",
+                        self.ip,
+                        bitfield.len(),
+                        piece_count
+                    );
+                    return Err(anyhow!(
+                        "peer {} sent a bitfield that does not fit the torrent",
+                        self.ip
+                    ));
+                }
+                {
+                    let mut availability = self.availability.lock().unwrap();
+                    for (index, count) in availability.iter_mut().enumerate() {
+                        if bitfield.has_piece(index as u32) {
+                            *count += 1;
+                        }
+                    }
+                }
                 self.bitfield = Some(bitfield);
                 if let Ok(PeerEvent::Exit) = self.try_fetch_task().await {
                     return Ok(PeerEvent::Exit);
                 }
-                loop {
-                    let index = self.current_task.as_ref().unwrap().index;
-                    if !self.has_piece(index) {
-                        self.put_task_back();
-                        if let PeerEvent::Exit = self.fetch_task() {
-                            return Ok(PeerEvent::Exit);
-                        }
-                    } else {
-                        self.send_message(Message::Interested).await?;
-                        break;
-                    }
+                if let PeerEvent::Exit = self.fetch_available_task().await {
+                    return Ok(PeerEvent::Exit);
                 }
+                self.send_message(Message::Interested).await?;
             }
             Message::Piece(piece) => {
+                if !self.is_expected_block(&piece) {
+                    return Err(anyhow!(
+                        "peer {} sent an unexpected block at {} of #{} with {} bytes",
+                        self.ip,
+                        piece.begin,
+                        piece.index,
+                        piece.piece.len()
+                    ));
+                }
                 trace!(
                     "download #{} block of #{} piece from peer: {}",
                     piece.begin / Self::BLOCK_SIZE,
                     piece.index,
                     self.ip
                 );
+                let served = |request: &Request| {
+                    request.index == piece.index && request.begin == piece.begin
+                };
+                self.in_flight.retain(|request| !served(request));
+                self.pending_requests.retain(|request| !served(request));
+                self.last_useful = Instant::now();
+                self.bandwidth.download.take(piece.piece.len() as u64).await;
                 self.pb.inc(piece.piece.len() as _);
-                self.received_pieces.push(piece);
+                if let Some(stats) = self.stats.lock().unwrap().get_mut(&self.addr()) {
+                    stats.downloaded += piece.piece.len() as u64;
+                }
+                // hash a finished piece in the background and move on right away
+                if let Some((task, blocks, sources)) = self.assembly.insert(piece, self.addr()) {
+                    let verifier = self.verifier.clone();
+                    self.verifications.push(spawn_blocking(move || {
+                        verifier.verify(task, blocks, &sources)
+                    }));
+                }
+                self.adjust_window();
                 if let Ok(PeerEvent::Exit) = self.try_fetch_task().await {
                     return Ok(PeerEvent::Exit);
                 }
+                self.request_piece().await?;
+            }
+            Message::Have(index) => {
+                let mut availability = self.availability.lock().unwrap();
+                if index as usize >= availability.len() {
+                    warn!(
+                        "peer {} has piece #{} of a torrent with {} pieces",
+                        self.ip,
+                        index,
+                        availability.len()
+                    );
+                    return Err(anyhow!(
+                        "peer {} sent have for piece #{} out of range",
+                        self.ip,
+                        index
+                    ));
+                }
+                let bitfield = self
+                    .bitfield
+                    .get_or_insert_with(|| Bitfield::new(availability.len() as u32));
+                if !bitfield.has_piece(index) {
+                    bitfield.set_piece(index);
+                    availability[index as usize] += 1;
+                }
             }
             Message::UnChoke => {
                 trace!("peer is unchoked: {}", self.ip);
                 if self.state == PeerState::Busy {
                     return Ok(PeerEvent::Continue);
                 }
-                self.request_piece().await?;
                 self.state = PeerState::Busy;
+                self.request_piece().await?;
+            }
+            Message::Choke => {
+                trace!("peer chokes us: {}", self.ip);
+                if self.state == PeerState::Busy {
+                    self.state = PeerState::Choked;
+                }
+                // a choking peer drops the requests it did not serve, ask again on unchoke
+                let dropped = std::mem::take(&mut self.in_flight);
+                self.pending_requests.splice(0..0, dropped);
             }
             _ => {}
         }
         Ok(PeerEvent::Continue)
     }
 
+    /// answer a request for a block of a verified piece, others are ignored
+    async fn serve_block(&mut self, request: Request) -> Result<()> {
+        if request.length > Self::MAX_REQUEST_LENGTH {
+            return Err(anyhow!(
+                "peer {} requested {} bytes at once",
+                self.ip,
+                request.length
+            ));
+        }
+        let verifier = self.verifier.clone();
+        let Some(block) = spawn_blocking(move || verifier.read_block(&request)).await?? else {
+            trace!(
+                "ignore request for #{} we do not have from peer: {}",
+                request.index,
+                self.ip
+            );
+            return Ok(());
+        };
+        let len = block.len() as u64;
+        self.bandwidth.upload.take(len).await;
+        self.send_message(Message::Piece(Piece::new(
+            request.index,
+            request.begin,
+            &block,
+        )))
+        .await?;
+        self.uploaded.fetch_add(len, Ordering::Relaxed);
+        self.last_useful = Instant::now();
+        Ok(())
+    }
+
+    async fn process_extended(&mut self, ext: Extended) -> Result<PeerEvent> {
+        if ext.id == Extended::HANDSHAKE_ID {
+            self.extensions = Some(ExtendedHandshake::from_bytes(&ext.payload)?);
+            return Ok(PeerEvent::Continue);
+        }
+        // an extension we did not advertise has no id on our side either
+        match Extension::from_id(ext.id).filter(|ext| self.enabled_extensions.contains(ext)) {
+            Some(Extension::DontHave) => {
+                let DontHave { index } = DontHave::from_bytes(&ext.payload)?;
+                self.forget_piece(index).await
+            }
+            None => {
+                trace!("unknown extended message {} from peer: {}", ext.id, self.ip);
+                Ok(PeerEvent::Continue)
+            }
+        }
+    }
+
+    /// the peer no longer has piece `index`, look for another one if it was ours
+    async fn forget_piece(&mut self, index: u32) -> Result<PeerEvent> {
+        let Some(bitfield) = self.bitfield.as_mut() else {
+            return Ok(PeerEvent::Continue);
+        };
+        if !bitfield.has_piece(index) {
+            return Ok(PeerEvent::Continue);
+        }
+        bitfield.clear_piece(index);
+        if let Some(count) = self.availability.lock().unwrap().get_mut(index as usize) {
+            *count = count.saturating_sub(1);
+        }
+        trace!("peer {} does not have #{} anymore", self.ip, index);
+        if self.current_task.is_some_and(|task| task.index == index) {
+            if let PeerEvent::Exit = self.fetch_available_task().await {
+                return Ok(PeerEvent::Exit);
+            }
+            if self.state == PeerState::Busy {
+                self.request_piece().await?;
+            }
+        }
+        Ok(PeerEvent::Continue)
+    }
+
+    /// swap the current task for the next one in the queue until the peer has its piece
+    async fn fetch_available_task(&mut self) -> PeerEvent {
+        loop {
+            let index = self.current_task.as_ref().unwrap().index;
+            if self.has_piece(index) {
+                return PeerEvent::Continue;
+            }
+            self.put_task_back();
+            if let PeerEvent::Exit = self.fetch_task().await {
+                return PeerEvent::Exit;
+            }
+        }
+    }
+
     pub async fn handshake(&mut self, info_hash: &[u8], peer_id: &[u8]) -> Result<()> {
         self.state = PeerState::Preparing;
-        self.send_message(Message::HandShake(HandShake::new(info_hash, peer_id)))
-            .await?;
+        let mut handshake = HandShake::new(info_hash, peer_id);
+        if !self.enabled_extensions.is_empty() {
+            handshake = handshake.with_extension_protocol();
+        }
+        self.info_hash = handshake.info_hash;
+        self.send_message(Message::HandShake(handshake)).await?;
         Ok(())
     }
 
+    /// take the pieces this peer advertised out of the availability counts
+    fn forget_availability(&mut self) {
+        let Some(bitfield) = self.bitfield.take() else {
+            return;
+        };
+        let mut availability = self.availability.lock().unwrap();
+        for (index, count) in availability.iter_mut().enumerate() {
+            if bitfield.has_piece(index as u32) {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+
     pub async fn try_download(mut self, info_hash: &[u8], peer_id: &[u8]) -> Result<()> {
-        self.try_connect().await?;
+        let connected = self.connect_with_retries().await;
+        {
+            let mut history = self.history.lock().unwrap();
+            let entry = history.entry(self.addr()).or_default();
+            match connected {
+                Ok(()) => entry.successes += 1,
+                Err(_) => entry.failures += 1,
+            }
+        }
+        connected?;
         self.handshake(info_hash, peer_id).await?;
+        self.last_useful = Instant::now();
+        let mut shutdown = self.shutdown.clone();
+        let mut interest = interval_at(
+            (Instant::now() + Self::INTEREST_INTERVAL).into(),
+            Self::INTEREST_INTERVAL,
+        );
+        // a peer which says nothing at all, not even a keep-alive, is dropped as a
+        // read would time out on it, handing back the piece it holds
+        let mut heard = Instant::now();
         loop {
-            match self.read_message().await {
+            let deadline = self.piece_deadline();
+            let keep_alive = self.last_sent + self.keep_alive_interval;
+            let idle = self.last_useful + self.idle_timeout;
+            // wait for the message to arrive rather than reading it here, a read cut
+            // short by a tick would lose the message half way. Peeking waits for actual
+            // data, readiness alone stays set until a read comes up empty
+            let mut probe = [0; 1];
+            let wake = tokio::select! {
+                peeked = self.stream.as_ref().unwrap().peek(&mut probe) => {
+                    Wake::Readable(peeked.map(|_| ()))
+                }
+                _ = interest.tick() => Wake::Interest,
+                _ = sleep_until((heard + Message::READ_TIMEOUT).into()) => {
+                    info!("peer {} silent for {:?}, disconnect", self.ip, Message::READ_TIMEOUT);
+                    break;
+                }
+                _ = sleep_until(keep_alive.into()) => Wake::KeepAlive,
+                _ = sleep_until(idle.into()) => {
+                    info!("peer {} idle for {:?}, disconnect", self.ip, self.idle_timeout);
+                    break;
+                }
+                _ = shutdown.wait_for(|stop| *stop) => {
+                    info!("peer {} disconnect on shutdown", self.ip);
+                    break;
+                }
+                _ = sleep_until(deadline.unwrap_or_else(Instant::now).into()), if deadline.is_some() => {
+                    info!("peer {} snubbed, piece took too long", self.ip);
+                    break;
+                }
+            };
+            let event = match wake {
+                Wake::Readable(Ok(())) => {
+                    heard = Instant::now();
+                    self.read_message().await
+                }
+                Wake::Readable(Err(err)) => Err(err.into()),
+                Wake::Interest => self.reassert_interest().await,
+                Wake::KeepAlive => self
+                    .send_message(Message::KeepAlive)
+                    .await
+                    .map(|_| PeerEvent::Continue),
+            };
+            if self.is_snubbed() {
+                info!("peer {} snubbed, below the minimum rate", self.ip);
+                break;
+            }
+            match event {
                 Ok(event) => match event {
                     PeerEvent::Continue => {}
                     PeerEvent::Exit => {
@@ -298,52 +926,856 @@ impl Peer {
                 }
             }
         }
+        // hand an unfinished piece back so another peer can pick it up, the blocks
+        // received so far stay in the assembly for it
+        if self.current_task.is_some() {
+            self.put_task_back();
+        }
+        self.leave();
+        for verification in self.verifications.drain(..) {
+            verification.await?;
+        }
+        Ok(())
+    }
+
+    /// take the peer out of the stats and the availability once disconnected
+    fn leave(&mut self) {
+        self.stats.lock().unwrap().remove(&self.addr());
+        self.message_totals.lock().unwrap().merge(&self.messages);
+        self.forget_availability();
+        let _ = self.events.send(Event::PeerDisconnected(self.addr()));
+    }
+
+    /// serve a peer which connected to us while seeding: answer a handshake for our
+    /// torrent with ours and the pieces we have, then hand out the blocks it requests
+    pub async fn serve(
+        mut self,
+        stream: TcpStream,
+        info_hash: &[u8],
+        peer_id: &[u8],
+        bitfield: Bitfield,
+    ) -> Result<()> {
+        stream.set_nodelay(self.socket_options.nodelay)?;
+        self.stream = Some(stream);
+        self.info_hash = HandShake::new(info_hash, peer_id).info_hash;
+        info!("peer connected to us: {}", self.ip);
+        let _ = self.events.send(Event::PeerConnected(self.addr()));
+        // the peer speaks first, a handshake for another torrent is left unanswered
+        let mut event = match Message::from_stream(self.stream.as_mut().unwrap()).await {
+            Ok(msg) => {
+                if matches!(&msg, Message::HandShake(handshake) if handshake.info_hash == self.info_hash)
+                {
+                    self.handshake(info_hash, peer_id).await?;
+                    self.send_message(Message::Bitfield(bitfield)).await?;
+                }
+                self.process_msg(msg).await
+            }
+            Err(err) => {
+                info!("peer {} exit since: {}", self.ip, err);
+                Ok(PeerEvent::Exit)
+            }
+        };
+        let mut shutdown = self.shutdown.clone();
+        while let Ok(PeerEvent::Continue) = event {
+            let keep_alive = self.last_sent + self.keep_alive_interval;
+            let idle = self.last_useful + self.idle_timeout;
+            let mut probe = [0; 1];
+            let wake = tokio::select! {
+                peeked = self.stream.as_ref().unwrap().peek(&mut probe) => {
+                    Wake::Readable(peeked.map(|_| ()))
+                }
+                _ = sleep_until(keep_alive.into()) => Wake::KeepAlive,
+                _ = sleep_until(idle.into()) => {
+                    info!("peer {} idle for {:?}, disconnect", self.ip, self.idle_timeout);
+                    break;
+                }
+                _ = shutdown.wait_for(|stop| *stop) => {
+                    info!("peer {} disconnect as seeding ends", self.ip);
+                    break;
+                }
+            };
+            event = match wake {
+                Wake::Readable(Ok(())) => self.read_upload_message().await,
+                Wake::Readable(Err(err)) => Err(err.into()),
+                Wake::Interest => Ok(PeerEvent::Continue),
+                Wake::KeepAlive => self
+                    .send_message(Message::KeepAlive)
+                    .await
+                    .map(|_| PeerEvent::Continue),
+            };
+        }
+        if let Err(err) = &event {
+            info!("peer {} disconnect cause of fatal error: {}", self.ip, err);
+        }
+        self.leave();
         Ok(())
     }
 
-    fn check_sum(&self) -> Result<()> {
-        let dir_path = PathBuf::from(format!("{}.cache", &*self.name));
-        let task = self.current_task.as_ref().unwrap();
-        let cache_path = dir_path.join(format!("{}-cache-{}", &self.name, task.index));
-        let mut cache_file = std::fs::OpenOptions::new().read(true).open(cache_path)?;
-        let mut buf = Vec::new();
-        cache_file.read_to_end(&mut buf)?;
-        let mut hasher = sha1::Sha1::new();
-        hasher.update(&buf);
-        let sum: [u8; 20] = hasher.finalize().into();
-        if task.piece_hash != sum {
-            Err(anyhow!(
-                "piece #{} has a wrong hash, expected: {:x?}, found: {:x?}",
-                task.index,
-                &task.piece_hash,
-                &sum
-            ))
-        } else {
+    /// read and process a message of a peer we only upload to, what it has or hands
+    /// out is of no use with every piece verified
+    async fn read_upload_message(&mut self) -> Result<PeerEvent> {
+        let msg = match Message::from_stream_pooled(
+            self.stream.as_mut().unwrap(),
+            Some(&self.block_pool),
+        )
+        .await
+        {
+            Ok(msg) => msg,
+            Err(err) => {
+                info!("peer {} exit since: {}", self.ip, err);
+                return Ok(PeerEvent::Exit);
+            }
+        };
+        match msg {
+            Message::Bitfield(_)
+            | Message::Have(_)
+            | Message::Piece(_)
+            | Message::Choke
+            | Message::UnChoke => {
+                self.count_message(&msg, false);
+                Ok(PeerEvent::Continue)
+            }
+            msg => self.process_msg(msg).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::{
+        storage::{MemoryStorage, Storage},
+        testutil::{self, SeedBehavior},
+    };
+
+    fn addrs(ips: &[&str]) -> Vec<SocketAddr> {
+        ips.iter()
+            .map(|ip| SocketAddr::new(ip.parse().unwrap(), 6881))
+            .collect()
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn socket_options_are_applied() {
+        use std::os::fd::AsRawFd;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = testutil::builder(&testutil::content(1000), 16384)
+            .set_tcp_nodelay(false)
+            .set_recv_buffer_size(256 * 1024)
+            .build()
+            .unwrap();
+        let mut peer = Peer::new(addr.ip(), addr.port(), &client);
+        peer.try_connect().await.unwrap();
+        let stream = peer.stream.as_ref().unwrap();
+        assert!(!stream.nodelay().unwrap());
+        let mut size: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        // SAFETY: the descriptor is owned by `stream` and `size` holds a c_int
+        let res = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_RCVBUF,
+                &mut size as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(res, 0);
+        // the kernel doubles the size for its bookkeeping
+        assert!(size >= 256 * 1024, "receive buffer of {} bytes", size);
+
+        let client = testutil::client(&testutil::content(1000), 16384);
+        let mut peer = Peer::new(addr.ip(), addr.port(), &client);
+        peer.try_connect().await.unwrap();
+        assert!(peer.stream.as_ref().unwrap().nodelay().unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failing_connects_back_off_as_the_retry_policy_says() {
+        // nothing listens there any more, so every attempt is refused right away
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let policy = RetryPolicy {
+            max_attempts: 4,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(3),
+            jitter: 0.0,
+        };
+        let client = testutil::builder(&testutil::content(1000), 16384)
+            .set_retry_policy(policy)
+            .build()
+            .unwrap();
+        let mut peer = Peer::new(addr.ip(), addr.port(), &client);
+        let start = tokio::time::Instant::now();
+        assert!(peer.connect_with_retries().await.is_err());
+        // 1s, 2s and then 3s at most, on the paused clock
+        assert_eq!(start.elapsed(), Duration::from_secs(6));
+        assert_eq!(
+            (0..4)
+                .map(|retry| policy.backoff(retry, 0.0))
+                .collect::<Vec<_>>(),
+            [1, 2, 3, 3].map(Duration::from_secs)
+        );
+    }
+
+    #[tokio::test]
+    async fn handshakes_for_another_torrent_are_not_served() {
+        let content = testutil::content(100_000);
+        let client = testutil::client(&content, 32768);
+        let (ours, mut theirs) = testutil::tcp_pair().await;
+        let addr = ours.peer_addr().unwrap();
+        let peer = Peer::new(addr.ip(), addr.port(), &client);
+        let serving = tokio::spawn({
+            let bitfield = client.bitfield.lock().unwrap().clone();
+            let (info_hash, peer_id) = (client.info_hash, client.id);
+            async move { peer.serve(ours, &info_hash, &peer_id, bitfield).await }
+        });
+        theirs
+            .write_all(&HandShake::new(&[7; 20], b"-LC0001-000000000000").as_bytes())
+            .await
+            .unwrap();
+        timeout(Duration::from_secs(1), serving)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        // hung up without a word, not even our handshake
+        let mut rest = vec![];
+        theirs.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+        assert!(client.peer_stats.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn interest_is_reasserted_while_choked() {
+        let content = testutil::content(100_000);
+        let client = testutil::client(&content, 32768);
+        let (ours, mut theirs) = testutil::tcp_pair().await;
+        let addr = ours.peer_addr().unwrap();
+        let mut peer = Peer::new(addr.ip(), addr.port(), &client);
+        peer.stream = Some(ours);
+        let mut bitfield = Bitfield::new(1);
+        (0..4).for_each(|index| bitfield.set_piece(index));
+        peer.bitfield = Some(bitfield);
+        peer.current_task = Some(Task::new(0, 32768, client.piece_hashes[0]));
+        let mut next = async || {
+            let mut message = [0; 5];
+            timeout(Duration::from_secs(1), theirs.read_exact(&mut message[..4]))
+                .await
+                .unwrap()
+                .unwrap();
+            if message[..4] != [0; 4] {
+                theirs.read_exact(&mut message[4..]).await.unwrap();
+            }
+            message
+        };
+        let interested = Message::Interested.as_bytes();
+        // every tick while choked, for the piece it has
+        for _ in 0..2 {
+            peer.reassert_interest().await.unwrap();
+            assert_eq!(next().await, interested[..]);
+        }
+        // not once unchoked, nor for a piece it does not have
+        peer.state = PeerState::Busy;
+        peer.reassert_interest().await.unwrap();
+        peer.state = PeerState::Choked;
+        peer.bitfield = Some(Bitfield::new(1));
+        peer.reassert_interest().await.unwrap();
+        peer.send_message(Message::KeepAlive).await.unwrap();
+        assert_eq!(next().await[..4], [0; 4]);
+    }
+
+    #[tokio::test]
+    async fn slow_peers_get_a_smaller_window_before_the_snub() {
+        const PIECE: u32 = 16 * Peer::BLOCK_SIZE;
+        let content = testutil::content(4 * PIECE as usize);
+        let client = testutil::builder(&content, PIECE)
+            .set_pipeline_depth(8)
+            .set_slow_peer_rate(100_000)
+            .set_min_peer_rate(1_000)
+            .build()
+            .unwrap();
+        let (ours, _theirs) = testutil::tcp_pair().await;
+        let addr = ours.peer_addr().unwrap();
+        let mut peer = Peer::new(addr.ip(), addr.port(), &client);
+        peer.stream = Some(ours);
+        peer.state = PeerState::Busy;
+        let task = Task::new(0, PIECE, client.piece_hashes[0]);
+        peer.assembly.start(task);
+        peer.current_task = Some(task);
+        peer.plan_requests();
+        peer.request_piece().await.unwrap();
+        assert_eq!(peer.in_flight.len(), 8);
+        // the peer serves `blocks` more of the requests in flight
+        let serve = |peer: &mut Peer, blocks: usize| {
+            for request in peer.in_flight.drain(..blocks).collect::<Vec<_>>() {
+                let block = &content[request.begin as usize..][..request.length as usize];
+                let block = Piece::new(request.index, request.begin, block);
+                assert!(peer.assembly.insert(block, addr).is_none());
+            }
+        };
+
+        // a block in ten seconds is slow, but above the minimum rate
+        peer.piece_started = Some(Instant::now() - Duration::from_secs(10));
+        serve(&mut peer, 1);
+        peer.adjust_window();
+        assert_eq!(peer.window, 4);
+        assert!(!peer.is_snubbed());
+        serve(&mut peer, 4);
+        peer.request_piece().await.unwrap();
+        assert_eq!(peer.in_flight.len(), 4);
+        // once per grace period, halving down to a single request
+        peer.adjust_window();
+        assert_eq!(peer.window, 4);
+        for window in [2, 1, 1] {
+            peer.window_changed = Some(Instant::now() - Peer::SNUB_GRACE);
+            peer.adjust_window();
+            assert_eq!(peer.window, window);
+        }
+        assert!(!peer.is_snubbed());
+        // and back up as it speeds up again
+        peer.slow_rate = Some(1_000);
+        peer.window_changed = None;
+        peer.adjust_window();
+        assert_eq!(peer.window, 2);
+    }
+
+    #[tokio::test]
+    async fn idle_peers_are_dropped_past_the_threshold() {
+        let content = testutil::content(100_000);
+        // a peer which has everything but never unchokes us, so no block goes either way
+        let run = |idle_timeout: Duration| {
+            let content = content.clone();
+            async move {
+                let client = testutil::builder(&content, 32768)
+                    .set_idle_timeout(idle_timeout)
+                    .set_keep_alive_interval(Duration::from_millis(100))
+                    .build()
+                    .unwrap();
+                client
+                    .task_queue
+                    .push(Task::new(0, 32768, client.piece_hashes[0]))
+                    .unwrap();
+                let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let addr = listener.local_addr().unwrap();
+                let peer = Peer::new(addr.ip(), addr.port(), &client);
+                let (info_hash, id) = (client.info_hash, client.id);
+                let download =
+                    tokio::spawn(async move { peer.try_download(&info_hash, &id).await });
+                let (mut theirs, _) = listener.accept().await.unwrap();
+                let mut handshake = [0; 68];
+                theirs.read_exact(&mut handshake).await.unwrap();
+                let mut bitfield = Bitfield::new(1);
+                (0..4).for_each(|index| bitfield.set_piece(index));
+                let greeting = [
+                    Message::HandShake(HandShake::new(&info_hash, &[1; 20])),
+                    Message::Bitfield(bitfield),
+                ];
+                for message in greeting {
+                    tokio::io::AsyncWriteExt::write_all(&mut theirs, &message.as_bytes())
+                        .await
+                        .unwrap();
+                }
+                (client, download, theirs)
+            }
+        };
+        let (_client, dropped, _theirs) = run(Duration::from_millis(500)).await;
+        let (_client, kept, mut theirs) = run(Duration::from_secs(60)).await;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(dropped.is_finished());
+        assert!(!kept.is_finished());
+        // kept alive meanwhile, skipping the Interested it sent first
+        loop {
+            let len = timeout(Duration::from_secs(1), theirs.read_u32())
+                .await
+                .unwrap()
+                .unwrap();
+            if len == 0 {
+                break;
+            }
+            theirs.read_exact(&mut vec![0; len as usize]).await.unwrap();
+        }
+        kept.abort();
+    }
+
+    #[tokio::test]
+    async fn requests_are_planned_while_choked_and_sent_on_unchoke() {
+        let content = testutil::content(100_000);
+        let client = testutil::client(&content, 32768);
+        let (ours, mut theirs) = testutil::tcp_pair().await;
+        let addr = ours.peer_addr().unwrap();
+        let mut peer = Peer::new(addr.ip(), addr.port(), &client);
+        peer.stream = Some(ours);
+        peer.id = Some([1; 20]);
+        client
+            .task_queue
+            .push(Task {
+                index: 0,
+                piece_hash: [0; 20],
+                piece_length: 32768,
+            })
+            .unwrap();
+        assert!(matches!(peer.fetch_task().await, PeerEvent::Continue));
+        // still choked, the requests wait without anything sent
+        peer.request_piece().await.unwrap();
+        let planned = [Request::new(0, 0, 16384), Request::new(0, 16384, 16384)];
+        let blocks = |requests: &[Request]| -> Vec<(u32, u32, u32)> {
+            requests
+                .iter()
+                .map(|request| (request.index, request.begin, request.length))
+                .collect()
+        };
+        assert_eq!(blocks(&peer.pending_requests), blocks(&planned));
+        assert!(peer.in_flight.is_empty());
+        peer.process_msg(Message::UnChoke).await.unwrap();
+        assert!(peer.pending_requests.is_empty());
+        let expected: Vec<u8> = planned
+            .iter()
+            .flat_map(|request| Message::Request(*request).as_bytes())
+            .collect();
+        let mut sent = vec![0; expected.len()];
+        timeout(Duration::from_secs(1), theirs.read_exact(&mut sent))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(sent, expected);
+    }
+
+    #[tokio::test]
+    async fn affinity_prefers_the_next_contiguous_piece() {
+        let content = testutil::content(5 * 16384);
+        let client = testutil::builder(&content, 16384)
+            .set_piece_affinity(true)
+            .build()
+            .unwrap();
+        for index in [0, 3, 1, 4] {
+            client
+                .task_queue
+                .push(Task::new(index, 16384, client.piece_hashes[index as usize]))
+                .unwrap();
+        }
+        let mut peer = Peer::new([127, 0, 0, 1].into(), 6881, &client);
+        peer.last_completed = Some(2);
+        assert!(matches!(peer.fetch_task().await, PeerEvent::Continue));
+        assert_eq!(peer.current_task.take().unwrap().index, 3);
+        peer.last_completed = Some(3);
+        assert!(matches!(peer.fetch_task().await, PeerEvent::Continue));
+        assert_eq!(peer.current_task.take().unwrap().index, 4);
+        // nothing follows the last piece, any queued one will do
+        peer.last_completed = Some(4);
+        assert!(matches!(peer.fetch_task().await, PeerEvent::Continue));
+        assert_eq!(peer.current_task.take().unwrap().index, 0);
+    }
+
+    #[tokio::test]
+    async fn oversized_blocks_are_rejected() {
+        let content = testutil::content(100_000);
+        let client = testutil::client(&content, 32768);
+        let (ours, _theirs) = testutil::tcp_pair().await;
+        let addr = ours.peer_addr().unwrap();
+        let mut peer = Peer::new(addr.ip(), addr.port(), &client);
+        peer.stream = Some(ours);
+        peer.id = Some([1; 20]);
+        let task = Task {
+            index: 0,
+            piece_hash: [0; 20],
+            piece_length: 32768,
+        };
+        client.task_queue.push(task).unwrap();
+        assert!(matches!(peer.fetch_task().await, PeerEvent::Continue));
+        let oversized = Piece::new(0, 0, &[0; 20000]);
+        let err = peer
+            .process_msg(Message::Piece(oversized))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("unexpected block"), "{}", err);
+        assert_eq!(client.assembly.received(0), 0);
+        assert_eq!(client.pb.position(), 0);
+    }
+
+    #[tokio::test]
+    async fn peers_which_connected_before_go_first() {
+        let client = testutil::client(&testutil::content(1000), 16384);
+        // nothing listens on a port freed right away
+        let refused = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let good = listener.local_addr().unwrap();
+        let peer = Peer::new(refused.ip(), refused.port(), &client);
+        assert!(peer
+            .try_download(&client.info_hash, &client.id)
+            .await
+            .is_err());
+        // the other one connects and waits for a handshake which never comes
+        let peer = Peer::new(good.ip(), good.port(), &client);
+        let (info_hash, id) = (client.info_hash, client.id);
+        let connection = tokio::spawn(async move { peer.try_download(&info_hash, &id).await });
+        let connected = || client.peer_history.lock().unwrap().contains_key(&good);
+        assert!(testutil::eventually(connected).await);
+        client.shutdown();
+        let _ = connection.await.unwrap();
+        let history = client.peer_history.lock().unwrap().clone();
+        assert_eq!(history[&refused].failures, 1);
+        assert_eq!(history[&good].successes, 1);
+        let unknown: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let mut peers = Peers::new(&[refused, unknown, good], &client);
+        peers.prioritize_known(&history);
+        let order: Vec<_> = peers.iter().map(Peer::addr).collect();
+        assert_eq!(order, vec![good, unknown, refused]);
+    }
+
+    #[tokio::test]
+    async fn pending_connects_are_capped() {
+        // never accepted, so once the listen queue is full further attempts hang
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let listener = socket.listen(1).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = testutil::builder(&testutil::content(1000), 16384)
+            .set_max_pending_connects(3)
+            .build()
+            .unwrap();
+        let finished = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let attempts: Vec<_> = (0..10)
+            .map(|_| {
+                let mut peer = Peer::new(addr.ip(), addr.port(), &client);
+                let finished = finished.clone();
+                tokio::spawn(async move {
+                    let res = peer.connect_with_retries().await;
+                    finished.fetch_add(1, Ordering::Relaxed);
+                    // keep a connection in the listen queue
+                    (peer, res)
+                })
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let queued = finished.load(Ordering::Relaxed);
+        assert!(queued < 10);
+        assert_eq!(client.connect_permits.available_permits(), 0);
+        // past the connect timeout only the three attempts in flight gave up
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        assert_eq!(finished.load(Ordering::Relaxed) - queued, 3);
+        attempts.iter().for_each(|attempt| attempt.abort());
+    }
+
+    #[tokio::test]
+    async fn messages_are_counted_by_kind() {
+        let content = testutil::content(100_000);
+        let client = testutil::client(&content, 32768);
+        let (ours, _theirs) = testutil::tcp_pair().await;
+        let addr = ours.peer_addr().unwrap();
+        let mut peer = Peer::new(addr.ip(), addr.port(), &client);
+        peer.stream = Some(ours);
+        peer.info_hash = client.info_hash;
+        let script = [
+            Message::HandShake(
+                HandShake::new(&client.info_hash, &[1; 20]).with_extension_protocol(),
+            ),
+            Message::Have(1),
+            Message::KeepAlive,
+            Message::Have(3),
+            Message::UnChoke,
+            Message::Choke,
+        ];
+        for msg in script {
+            peer.process_msg(msg).await.unwrap();
+        }
+        let counts = &peer.messages;
+        // the handshake is answered with ours for the extensions and an unchoke
+        assert_eq!(counts.sent("extended"), 1);
+        assert_eq!(counts.sent("unchoke"), 1);
+        assert_eq!(counts.sent.values().sum::<u64>(), 2);
+        assert_eq!(counts.received("handshake"), 1);
+        assert_eq!(counts.received("have"), 2);
+        assert_eq!(counts.received("keep-alive"), 1);
+        assert_eq!(counts.received("unchoke"), 1);
+        assert_eq!(counts.received("choke"), 1);
+        assert_eq!(counts.received("piece"), 0);
+        // the aggregate holds the connected peer's counts as well
+        assert_eq!(&client.message_counts(), counts);
+    }
+
+    #[tokio::test]
+    async fn short_final_blocks_are_requested() {
+        // pieces of 40000 bytes end on a block of 7232, the final piece of
+        // 20000 bytes on one of 3616
+        let content = testutil::content(100_000);
+        let behavior = SeedBehavior::default();
+        let requests = behavior.requests.clone();
+        let client = testutil::seeded(&content, 40000, behavior)
+            .await
+            .build()
+            .unwrap();
+        assert_eq!(client.download_to_vec().await.unwrap(), content);
+        let mut requested = requests.lock().unwrap().clone();
+        requested.sort_unstable();
+        requested.dedup();
+        assert_eq!(
+            requested,
+            [
+                (0, 0, 16384),
+                (0, 16384, 16384),
+                (0, 32768, 7232),
+                (1, 0, 16384),
+                (1, 16384, 16384),
+                (1, 32768, 7232),
+                (2, 0, 16384),
+                (2, 16384, 3616),
+            ]
+        );
+        // counted by the bytes received, not by blocks of the full size
+        assert_eq!(client.downloaded.load(Ordering::Relaxed), 100_000);
+    }
+
+    #[tokio::test]
+    async fn lt_donthave_is_advertised_and_honored() {
+        let content = testutil::content(100_000);
+        let client = testutil::client(&content, 32768);
+        let (ours, mut theirs) = testutil::tcp_pair().await;
+        let addr = ours.peer_addr().unwrap();
+        let mut peer = Peer::new(addr.ip(), addr.port(), &client);
+        peer.stream = Some(ours);
+        peer.info_hash = client.info_hash;
+        let handshake = HandShake::new(&client.info_hash, &[1; 20]).with_extension_protocol();
+        peer.process_msg(Message::HandShake(handshake))
+            .await
+            .unwrap();
+        // our extended handshake comes first, advertising lt_donthave
+        let len = timeout(Duration::from_secs(1), theirs.read_u32())
+            .await
+            .unwrap()
+            .unwrap();
+        let mut body = vec![0; len as usize];
+        theirs.read_exact(&mut body).await.unwrap();
+        assert_eq!(body[..2], [20, Extended::HANDSHAKE_ID]);
+        let advertised = ExtendedHandshake::from_bytes(&body[2..]).unwrap();
+        assert_eq!(
+            advertised.id_of("lt_donthave"),
+            Some(Extension::DontHave.id())
+        );
+
+        let mut bitfield = Bitfield::new(1);
+        (0..4).for_each(|index| bitfield.set_piece(index));
+        peer.process_msg(Message::Bitfield(bitfield)).await.unwrap();
+        assert_eq!(client.availability(), vec![1; 4]);
+        let donthave = DontHave { index: 2 };
+        let msg = Extended::new(Extension::DontHave.id(), &donthave.as_bytes());
+        peer.process_msg(Message::Extended(msg)).await.unwrap();
+        assert!(!peer.bitfield.as_ref().unwrap().has_piece(2));
+        assert_eq!(client.availability(), vec![1, 1, 0, 1]);
+        // said twice, it is counted once
+        let msg = Extended::new(Extension::DontHave.id(), &donthave.as_bytes());
+        peer.process_msg(Message::Extended(msg)).await.unwrap();
+        assert_eq!(client.availability(), vec![1, 1, 0, 1]);
+        // a payload which is not a piece index is an error
+        let msg = Extended::new(Extension::DontHave.id(), &[0, 2]);
+        assert!(peer.process_msg(Message::Extended(msg)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn disabled_extensions_are_neither_advertised_nor_honored() {
+        let content = testutil::content(100_000);
+        let client = testutil::builder(&content, 32768)
+            .set_extensions(vec![])
+            .build()
+            .unwrap();
+        let (ours, mut theirs) = testutil::tcp_pair().await;
+        let addr = ours.peer_addr().unwrap();
+        let mut peer = Peer::new(addr.ip(), addr.port(), &client);
+        peer.stream = Some(ours);
+        peer.handshake(&client.info_hash, &client.id).await.unwrap();
+        let mut sent = [0; 68];
+        theirs.read_exact(&mut sent).await.unwrap();
+        assert!(!HandShake::from_bytes(&sent)
+            .unwrap()
+            .supports_extension_protocol());
+        // they speak the extension protocol, we go on without an extended handshake
+        let handshake = HandShake::new(&client.info_hash, &[1; 20]).with_extension_protocol();
+        peer.process_msg(Message::HandShake(handshake))
+            .await
+            .unwrap();
+        let mut next = [0; 5];
+        timeout(Duration::from_secs(1), theirs.read_exact(&mut next))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(next, Message::UnChoke.as_bytes()[..]);
+        let mut bitfield = Bitfield::new(1);
+        (0..4).for_each(|index| bitfield.set_piece(index));
+        peer.process_msg(Message::Bitfield(bitfield)).await.unwrap();
+        let donthave = DontHave { index: 2 };
+        let msg = Extended::new(Extension::DontHave.id(), &donthave.as_bytes());
+        peer.process_msg(Message::Extended(msg)).await.unwrap();
+        assert!(peer.bitfield.as_ref().unwrap().has_piece(2));
+        assert_eq!(client.availability(), vec![1; 4]);
+    }
+
+    #[tokio::test]
+    async fn pieces_beyond_the_piece_count_drop_the_peer() {
+        let content = testutil::content(100_000);
+        let client = testutil::client(&content, 32768);
+        let (ours, _theirs) = testutil::tcp_pair().await;
+        let addr = ours.peer_addr().unwrap();
+        let mut peer = Peer::new(addr.ip(), addr.port(), &client);
+        peer.stream = Some(ours);
+        peer.id = Some([1; 20]);
+        peer.process_msg(Message::Bitfield(Bitfield::new(1)))
+            .await
+            .unwrap();
+        peer.process_msg(Message::Have(3)).await.unwrap();
+        let err = peer.process_msg(Message::Have(4)).await.unwrap_err();
+        assert!(err.to_string().contains("out of range"), "{}", err);
+        assert_eq!(client.availability(), vec![0, 0, 0, 1]);
+
+        // four pieces fit in one byte, not two
+        let mut peer = Peer::new(addr.ip(), addr.port(), &client);
+        peer.id = Some([1; 20]);
+        let err = peer
+            .process_msg(Message::Bitfield(Bitfield::new(2)))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("does not fit"), "{}", err);
+        // nor may the spare bits of the last byte be set
+        let mut bitfield = Bitfield::new(1);
+        bitfield.set_piece(4);
+        assert!(peer.process_msg(Message::Bitfield(bitfield)).await.is_err());
+        assert_eq!(client.availability(), vec![0, 0, 0, 1]);
+    }
+
+    #[tokio::test]
+    async fn bitfield_before_the_handshake_drops_the_peer() {
+        let content = testutil::content(100_000);
+        let client = testutil::client(&content, 32768);
+        let (ours, mut theirs) = testutil::tcp_pair().await;
+        let addr = ours.peer_addr().unwrap();
+        let mut peer = Peer::new(addr.ip(), addr.port(), &client);
+        peer.stream = Some(ours);
+        let mut bitfield = Bitfield::new(4);
+        (0..4).for_each(|index| bitfield.set_piece(index));
+        let bytes = Message::Bitfield(bitfield).as_bytes();
+        tokio::io::AsyncWriteExt::write_all(&mut theirs, &bytes)
+            .await
+            .unwrap();
+        let err = peer.read_message().await.unwrap_err();
+        assert!(err.to_string().contains("before the handshake"), "{}", err);
+        // nothing it said was taken in
+        assert!(peer.bitfield.is_none());
+        assert_eq!(client.availability(), vec![0; 4]);
+    }
+
+    #[test]
+    fn local_peers_go_first() {
+        let client = testutil::builder(&testutil::content(1000), 16384)
+            .set_prefer_local_peers(true)
+            .build()
+            .unwrap();
+        assert!(client.prefer_local_peers);
+        let mut peers = Peers::new(
+            &addrs(&[
+                "8.8.8.8",
+                "192.168.1.5",
+                "1.1.1.1",
+                "10.0.0.7",
+                "2001:db8::1",
+                "fe80::1",
+                "169.254.3.4",
+            ]),
+            &client,
+        );
+        peers.prioritize_local();
+        let order: Vec<_> = peers.iter().map(|peer| peer.ip.to_string()).collect();
+        // stable, local ones keep their tracker order too
+        assert_eq!(
+            order,
+            [
+                "192.168.1.5",
+                "10.0.0.7",
+                "fe80::1",
+                "169.254.3.4",
+                "8.8.8.8",
+                "1.1.1.1",
+                "2001:db8::1"
+            ]
+        );
+    }
+
+    #[test]
+    fn local_ranges() {
+        let client = testutil::client(&testutil::content(1000), 16384);
+        let is_local = |ip: &str| Peer::new(ip.parse().unwrap(), 6881, &client).is_local();
+        for ip in [
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.0.1",
+            "169.254.0.1",
+            "127.0.0.1",
+            "fd00::1",
+        ] {
+            assert!(is_local(ip), "{}", ip);
+        }
+        for ip in ["172.32.0.1", "8.8.8.8", "2001:db8::1", "11.0.0.1"] {
+            assert!(!is_local(ip), "{}", ip);
+        }
+    }
+
+    /// holds the write of piece 0 until the seed was asked for another piece
+    #[derive(Debug)]
+    struct GatedStorage {
+        inner: MemoryStorage,
+        requests: Arc<std::sync::Mutex<Vec<(u32, u32, u32)>>>,
+        overlapped: std::sync::atomic::AtomicBool,
+    }
+
+    impl Storage for GatedStorage {
+        fn write_piece(&self, index: u32, data: &[u8]) -> Result<()> {
+            if index == 0 {
+                let deadline = std::time::Instant::now() + Duration::from_secs(5);
+                while std::time::Instant::now() < deadline {
+                    if self.requests.lock().unwrap().iter().any(|r| r.0 != 0) {
+                        self.overlapped.store(true, Ordering::Relaxed);
+                        break;
+                    }
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+            }
+            self.inner.write_piece(index, data)
+        }
+
+        fn read_piece(&self, index: u32) -> Result<Option<Vec<u8>>> {
+            self.inner.read_piece(index)
+        }
+
+        fn has_piece(&self, index: u32) -> bool {
+            self.inner.has_piece(index)
+        }
+
+        fn finalize(&self) -> Result<()> {
             Ok(())
         }
     }
 
-    fn save_pieces(&mut self) -> Result<()> {
-        let dir_path = PathBuf::from(format!("{}.cache", &*self.name));
-        if !dir_path.is_dir() {
-            create_dir_all(&dir_path)?;
-        }
-        let cache_path = dir_path.join(format!(
-            "{}-cache-{}",
-            &self.name,
-            self.current_task.as_ref().unwrap().index
-        ));
-        let mut cache_file = std::fs::OpenOptions::new()
-            .create(true)
-            .truncate(true)
-            .write(true)
-            .open(cache_path)?;
-        let mut pieces = self.received_pieces.drain(0..).collect::<Vec<_>>();
-        pieces.sort_by_key(|piece| piece.begin);
-        pieces.into_iter().try_for_each(|piece| {
-            cache_file.write_all(&piece.piece)?;
-            anyhow::Ok(())
-        })?;
-        Ok(())
+    #[tokio::test]
+    async fn next_piece_is_requested_while_the_last_one_is_verified() {
+        let content = testutil::content(100_000);
+        let behavior = SeedBehavior::default();
+        let storage = Arc::new(GatedStorage {
+            inner: MemoryStorage::new(),
+            requests: behavior.requests.clone(),
+            overlapped: Default::default(),
+        });
+        let client = testutil::seeded(&content, 32 * 1024, behavior)
+            .await
+            .set_storage(storage.clone())
+            .build()
+            .unwrap();
+        assert!(client.download_to_vec().await.unwrap() == content);
+        assert!(storage.overlapped.load(Ordering::Relaxed));
     }
 }
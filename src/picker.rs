@@ -0,0 +1,103 @@
+use std::fmt;
+
+use crate::{message::Bitfield, torrent::random_unit};
+
+/// Decides which queued piece a peer downloads next, see
+/// [`crate::TorrentClientBuilder::set_piece_picker`].
+pub trait PiecePicker: Send + Sync + fmt::Debug {
+    /// one of `needed`, the queued pieces in queue order, or `None` to take the
+    /// first of them. `peer` is what the peer announced to have, `available` how
+    /// many connected peers have each piece
+    fn next_piece(&self, peer: Option<&Bitfield>, available: &[u16], needed: &[u32])
+        -> Option<u32>;
+}
+
+fn has(peer: Option<&Bitfield>, index: u32) -> bool {
+    peer.is_some_and(|bitfield| bitfield.has_piece(index))
+}
+
+/// The first queued piece the peer has, so the content fills up from the start.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sequential;
+
+impl PiecePicker for Sequential {
+    fn next_piece(&self, peer: Option<&Bitfield>, _: &[u16], needed: &[u32]) -> Option<u32> {
+        needed.iter().copied().find(|index| has(peer, *index))
+    }
+}
+
+/// The piece the peer has which the fewest other peers have, so rare pieces
+/// are not lost when their few holders leave.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RarestFirst;
+
+impl PiecePicker for RarestFirst {
+    fn next_piece(
+        &self,
+        peer: Option<&Bitfield>,
+        available: &[u16],
+        needed: &[u32],
+    ) -> Option<u32> {
+        needed
+            .iter()
+            .copied()
+            .filter(|index| has(peer, *index))
+            .min_by_key(|index| available.get(*index as usize).copied().unwrap_or(u16::MAX))
+    }
+}
+
+/// A random piece the peer has, so peers starting together download different ones.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RandomFirst;
+
+impl PiecePicker for RandomFirst {
+    fn next_piece(&self, peer: Option<&Bitfield>, _: &[u16], needed: &[u32]) -> Option<u32> {
+        let candidates: Vec<u32> = needed
+            .iter()
+            .copied()
+            .filter(|index| has(peer, *index))
+            .collect();
+        if candidates.is_empty() {
+            return None;
+        }
+        let unit = (random_unit() + 1.0) / 2.0;
+        let pos = ((unit * candidates.len() as f64) as usize).min(candidates.len() - 1);
+        Some(candidates[pos])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, SeedBehavior};
+
+    /// the last queued piece the peer has, the content fills up from the end
+    #[derive(Debug)]
+    struct Backwards;
+
+    impl PiecePicker for Backwards {
+        fn next_piece(&self, peer: Option<&Bitfield>, _: &[u16], needed: &[u32]) -> Option<u32> {
+            needed.iter().copied().rev().find(|index| has(peer, *index))
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_picker_decides_the_order() {
+        let content = testutil::content(100_000);
+        let behavior = SeedBehavior::default();
+        let requests = behavior.requests.clone();
+        let client = testutil::seeded(&content, 32768, behavior)
+            .await
+            .set_piece_picker(Backwards)
+            .build()
+            .unwrap();
+        assert_eq!(client.download_to_vec().await.unwrap(), content);
+        let mut order: Vec<u32> = vec![];
+        for (index, _, _) in requests.lock().unwrap().iter() {
+            if !order.contains(index) {
+                order.push(*index);
+            }
+        }
+        assert_eq!(order, vec![3, 2, 1, 0]);
+    }
+}
@@ -0,0 +1,184 @@
+use std::{
+    ops::{Deref, DerefMut},
+    sync::Arc,
+};
+
+use crossbeam::queue::ArrayQueue;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// A bounded pool of reusable block buffers.
+///
+/// At most `capacity` buffers are checked out at once, and returned buffers are
+/// kept for the next checkout instead of going back to the allocator.
+#[derive(Debug)]
+pub struct BlockPool {
+    buf_size: usize,
+    free: ArrayQueue<Vec<u8>>,
+    permits: Arc<Semaphore>,
+}
+
+impl BlockPool {
+    pub fn new(capacity: usize, buf_size: usize) -> Self {
+        Self {
+            buf_size,
+            free: ArrayQueue::new(capacity.max(1)),
+            permits: Arc::new(Semaphore::new(capacity)),
+        }
+    }
+
+    /// size of the buffers handed out, messages larger than that are not pooled
+    pub fn buf_size(&self) -> usize {
+        self.buf_size
+    }
+
+    /// number of buffers which may still be checked out
+    pub fn available(&self) -> usize {
+        self.permits.available_permits()
+    }
+
+    pub fn try_checkout(self: &Arc<Self>) -> Option<PooledBlock> {
+        let permit = self.permits.clone().try_acquire_owned().ok()?;
+        Some(self.block(permit))
+    }
+
+    /// check out a buffer, waiting for one to be returned while the pool is
+    /// exhausted, so reading slows down instead of memory growing. `None` once the
+    /// pool is closed
+    pub async fn checkout(self: &Arc<Self>) -> Option<PooledBlock> {
+        let permit = self.permits.clone().acquire_owned().await.ok()?;
+        Some(self.block(permit))
+    }
+
+    /// end every waiting and future checkout, e.g. on shutdown
+    pub fn close(&self) {
+        self.permits.close();
+    }
+
+    fn block(self: &Arc<Self>, permit: OwnedSemaphorePermit) -> PooledBlock {
+        let buf = self
+            .free
+            .pop()
+            .unwrap_or_else(|| Vec::with_capacity(self.buf_size));
+        PooledBlock {
+            buf,
+            start: 0,
+            pool: Some((self.clone(), permit)),
+        }
+    }
+}
+
+/// A buffer which goes back to its [`BlockPool`] when dropped.
+#[derive(Debug, Default)]
+pub struct PooledBlock {
+    buf: Vec<u8>,
+    /// bytes at the front of `buf` which are no longer part of the block
+    start: usize,
+    pool: Option<(Arc<BlockPool>, OwnedSemaphorePermit)>,
+}
+
+impl PooledBlock {
+    pub fn vec_mut(&mut self) -> &mut Vec<u8> {
+        self.buf.drain(..self.start);
+        self.start = 0;
+        &mut self.buf
+    }
+
+    /// drop the first `n` bytes, e.g. a message header, without moving the rest
+    pub fn skip(&mut self, n: usize) {
+        self.start = (self.start + n).min(self.buf.len());
+    }
+}
+
+impl From<Vec<u8>> for PooledBlock {
+    fn from(buf: Vec<u8>) -> Self {
+        Self {
+            buf,
+            start: 0,
+            pool: None,
+        }
+    }
+}
+
+impl Clone for PooledBlock {
+    /// a clone is a loose buffer and does not count against the pool
+    fn clone(&self) -> Self {
+        Self::from(self.to_vec())
+    }
+}
+
+impl Deref for PooledBlock {
+    type Target = [u8];
+    fn deref(&self) -> &Self::Target {
+        &self.buf[self.start..]
+    }
+}
+
+impl DerefMut for PooledBlock {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.buf[self.start..]
+    }
+}
+
+impl Drop for PooledBlock {
+    fn drop(&mut self) {
+        if let Some((pool, _permit)) = self.pool.take() {
+            let mut buf = std::mem::take(&mut self.buf);
+            buf.clear();
+            let _ = pool.free.push(buf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skipped_bytes_are_not_part_of_the_block() {
+        let mut block = PooledBlock::from(vec![1, 2, 3, 4, 5]);
+        block.skip(2);
+        assert_eq!(&*block, &[3, 4, 5]);
+        assert_eq!(&*block.clone(), &[3, 4, 5]);
+        block.vec_mut().push(6);
+        assert_eq!(&*block, &[3, 4, 5, 6]);
+        block.skip(10);
+        assert!(block.is_empty());
+    }
+
+    #[tokio::test]
+    async fn caps_outstanding_buffers_and_reuses_returned_ones() {
+        let pool = Arc::new(BlockPool::new(2, 16384));
+        let mut first = pool.checkout().await.unwrap();
+        let _second = pool.checkout().await.unwrap();
+        assert_eq!(pool.available(), 0);
+        assert!(pool.try_checkout().is_none());
+        let waiting = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.checkout().await.unwrap().vec_mut().as_ptr() as usize }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(!waiting.is_finished(), "a third buffer is not handed out");
+        first.vec_mut().extend_from_slice(&[1; 100]);
+        let reused = first.vec_mut().as_ptr() as usize;
+        drop(first);
+        assert_eq!(waiting.await.unwrap(), reused);
+        // cleared on return
+        let third = pool.checkout().await.unwrap();
+        assert!(third.is_empty());
+        assert!(third.buf.capacity() >= 16384);
+    }
+
+    #[tokio::test]
+    async fn close_ends_waiting_checkouts() {
+        let pool = Arc::new(BlockPool::new(1, 16));
+        let _held = pool.checkout().await.unwrap();
+        let waiting = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.checkout().await.is_none() }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        pool.close();
+        assert!(waiting.await.unwrap());
+        assert!(pool.checkout().await.is_none());
+    }
+}
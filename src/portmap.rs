@@ -0,0 +1,162 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use bytes::{Buf, BufMut, BytesMut};
+use log::info;
+use tokio::{net::UdpSocket, time::timeout};
+
+/// the port NAT-PMP gateways listen on
+pub const GATEWAY_PORT: u16 = 5351;
+/// how long a mapping is asked for, it is renewed once half of it passed
+pub const LIFETIME: Duration = Duration::from_secs(7200);
+const OPCODE_MAP_TCP: u8 = 2;
+const ATTEMPTS: u32 = 4;
+const FIRST_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// A TCP port forwarded by the gateway of the local network (NAT-PMP, RFC 6886),
+/// so peers behind the router can be reached on `external_port`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortMapping {
+    pub gateway: SocketAddr,
+    pub internal_port: u16,
+    pub external_port: u16,
+    pub lifetime: Duration,
+    pub mapped_at: Instant,
+}
+
+impl PortMapping {
+    /// ask `gateway` to forward `internal_port`, preferably as the same external port
+    pub async fn request(gateway: SocketAddr, internal_port: u16) -> Result<Self> {
+        let (external_port, lifetime) =
+            map(gateway, internal_port, internal_port, LIFETIME).await?;
+        info!(
+            "gateway {} maps port {} to external port {}",
+            gateway, internal_port, external_port
+        );
+        Ok(Self {
+            gateway,
+            internal_port,
+            external_port,
+            lifetime,
+            mapped_at: Instant::now(),
+        })
+    }
+
+    /// whether half of the lifetime passed, time to ask again
+    pub fn needs_renewal(&self) -> bool {
+        self.mapped_at.elapsed() >= self.lifetime / 2
+    }
+
+    /// ask again for the same external port, keeping the mapping alive
+    pub async fn renew(&self) -> Result<Self> {
+        let (external_port, lifetime) = map(
+            self.gateway,
+            self.internal_port,
+            self.external_port,
+            LIFETIME,
+        )
+        .await?;
+        Ok(Self {
+            external_port,
+            lifetime,
+            mapped_at: Instant::now(),
+            ..*self
+        })
+    }
+
+    /// remove the mapping, a request with a lifetime of zero
+    pub async fn remove(&self) -> Result<()> {
+        map(self.gateway, self.internal_port, 0, Duration::ZERO).await?;
+        info!(
+            "gateway {} removed the mapping of port {}",
+            self.gateway, self.internal_port
+        );
+        Ok(())
+    }
+}
+
+/// send a mapping request, returning the external port and lifetime granted
+async fn map(
+    gateway: SocketAddr,
+    internal_port: u16,
+    external_port: u16,
+    lifetime: Duration,
+) -> Result<(u16, Duration)> {
+    let bind: SocketAddr = match gateway {
+        SocketAddr::V4(_) => ([0u8; 4], 0).into(),
+        SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(gateway).await?;
+    let mut buf = BytesMut::with_capacity(12);
+    buf.put_u8(0); // version
+    buf.put_u8(OPCODE_MAP_TCP);
+    buf.put_u16(0); // reserved
+    buf.put_u16(internal_port);
+    buf.put_u16(external_port);
+    buf.put_u32(lifetime.as_secs() as u32);
+
+    // the gateway answers over UDP too, so retry with a doubling timeout
+    let mut wait = FIRST_TIMEOUT;
+    let mut res = [0; 16];
+    for _ in 0..ATTEMPTS {
+        socket.send(&buf).await?;
+        let Ok(received) = timeout(wait, socket.recv(&mut res)).await else {
+            wait *= 2;
+            continue;
+        };
+        let len = received?;
+        let mut res = &res[..len];
+        if res.remaining() < 16 {
+            return Err(anyhow!("gateway {} sent a short response", gateway));
+        }
+        let _version = res.get_u8();
+        let opcode = res.get_u8();
+        let result = res.get_u16();
+        let _epoch = res.get_u32();
+        let _internal = res.get_u16();
+        let external = res.get_u16();
+        let granted = res.get_u32();
+        if opcode != 128 + OPCODE_MAP_TCP {
+            return Err(anyhow!(
+                "gateway {} answered with opcode {}",
+                gateway,
+                opcode
+            ));
+        }
+        if result != 0 {
+            return Err(anyhow!(
+                "gateway {} refused the mapping with result code {}",
+                gateway,
+                result
+            ));
+        }
+        return Ok((external, Duration::from_secs(granted as u64)));
+    }
+    Err(anyhow!("gateway {} did not answer", gateway))
+}
+
+/// the default gateway of this host, where NAT-PMP requests go
+pub fn default_gateway() -> Result<SocketAddr> {
+    #[cfg(target_os = "linux")]
+    {
+        // Iface Destination Gateway ..., addresses in little endian hex
+        let routes = std::fs::read_to_string("/proc/net/route")?;
+        for line in routes.lines().skip(1) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.get(1) != Some(&"00000000") {
+                continue;
+            }
+            if let Some(gateway) = fields.get(2).and_then(|g| u32::from_str_radix(g, 16).ok()) {
+                let ip = std::net::Ipv4Addr::from(gateway.swap_bytes());
+                return Ok(SocketAddr::new(ip.into(), GATEWAY_PORT));
+            }
+        }
+    }
+    Err(anyhow!(
+        "no default gateway found, set one for the port mapping"
+    ))
+}
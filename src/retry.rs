@@ -0,0 +1,57 @@
+use std::time::Duration;
+
+use crate::torrent::{jittered, random_unit};
+
+/// How failing tracker announces and peer connections are retried, see
+/// [`crate::TorrentClientBuilder::set_retry_policy`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// attempts in total, the first one included
+    pub max_attempts: u32,
+    /// delay before the first retry, doubled on every further retry
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// up to this fraction of a delay is added or taken away at random
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            jitter: 0.0,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// try once, never retry
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Self::default()
+        }
+    }
+
+    /// retries left after `attempt` attempts failed
+    pub fn retries_left(&self, attempt: u32) -> bool {
+        attempt < self.max_attempts
+    }
+
+    /// delay before retry number `retry`, counting from 0, with `unit` within
+    /// -1.0..=1.0 picking the jitter
+    pub fn backoff(&self, retry: u32, unit: f64) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_delay);
+        jittered(delay, self.jitter, unit)
+    }
+
+    /// [`RetryPolicy::backoff`] with random jitter
+    pub fn delay(&self, retry: u32) -> Duration {
+        self.backoff(retry, random_unit())
+    }
+}
@@ -0,0 +1,254 @@
+use std::{
+    collections::HashMap,
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::Instant,
+};
+
+use anyhow::Result;
+use log::info;
+use tokio::sync::Semaphore;
+
+use crate::{
+    error::RbitError,
+    torrent::{TorrentClient, TorrentState},
+};
+
+/// What to do when a torrent is added to a session which already manages it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicatePolicy {
+    /// fail with [`RbitError::DuplicateTorrent`]
+    #[default]
+    Reject,
+    /// add the trackers of the new torrent to the managed one and hand that out
+    MergeTrackers,
+}
+
+/// A line of [`Session::summary`], one per torrent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TorrentSummary {
+    pub info_hash: [u8; 20],
+    pub name: String,
+    /// share of verified pieces, 0.0 to 100.0
+    pub completion: f64,
+    /// bytes per second since the previous summary
+    pub download_rate: f64,
+    pub upload_rate: f64,
+    pub peers: usize,
+    pub state: TorrentState,
+}
+
+/// counters of a torrent at the previous summary, to derive rates from
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    at: Instant,
+    downloaded: u64,
+    uploaded: u64,
+}
+
+/// A set of torrents managed together, at most one download per info hash.
+#[derive(Debug)]
+pub struct Session {
+    torrents: Mutex<HashMap<[u8; 20], Arc<TorrentClient>>>,
+    duplicate_policy: DuplicatePolicy,
+    samples: Mutex<HashMap<[u8; 20], Sample>>,
+    verify_limit: Arc<Semaphore>,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self {
+            torrents: Default::default(),
+            duplicate_policy: Default::default(),
+            samples: Default::default(),
+            verify_limit: Arc::new(Semaphore::new(Self::DEFAULT_VERIFY_LIMIT)),
+        }
+    }
+}
+
+impl Session {
+    /// how many pieces the torrents of a session hash on resume at once by default
+    const DEFAULT_VERIFY_LIMIT: usize = 2;
+
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// the verify limit shared by the torrents of the session, hand it to
+    /// [`crate::TorrentClientBuilder::set_verify_limit`] for each torrent
+    pub fn verify_limit(&self) -> Arc<Semaphore> {
+        self.verify_limit.clone()
+    }
+
+    pub fn set_verify_limit(mut self, permits: usize) -> Self {
+        self.verify_limit = Arc::new(Semaphore::new(permits.max(1)));
+        self
+    }
+
+    pub fn set_duplicate_policy(mut self, policy: DuplicatePolicy) -> Self {
+        self.duplicate_policy = policy;
+        self
+    }
+
+    /// manage `client`, returning the torrent to download with.
+    /// A torrent already in the session is handled by the duplicate policy
+    pub fn add_torrent(&self, client: TorrentClient) -> Result<Arc<TorrentClient>> {
+        let mut torrents = self.torrents.lock().unwrap();
+        if let Some(existing) = torrents.get(&client.info_hash) {
+            return match self.duplicate_policy {
+                DuplicatePolicy::Reject => {
+                    Err(RbitError::DuplicateTorrent(client.info_hash).into())
+                }
+                DuplicatePolicy::MergeTrackers => {
+                    info!("merge trackers of {} into the managed torrent", client.name);
+                    existing.add_trackers(client.trackers())?;
+                    Ok(existing.clone())
+                }
+            };
+        }
+        let client = Arc::new(client);
+        torrents.insert(client.info_hash, client.clone());
+        Ok(client)
+    }
+
+    pub fn get(&self, info_hash: &[u8; 20]) -> Option<Arc<TorrentClient>> {
+        self.torrents.lock().unwrap().get(info_hash).cloned()
+    }
+
+    /// stop managing a torrent, it is shut down if still downloading
+    pub fn remove(&self, info_hash: &[u8; 20]) -> Option<Arc<TorrentClient>> {
+        let client = self.torrents.lock().unwrap().remove(info_hash)?;
+        client.shutdown();
+        Some(client)
+    }
+
+    pub fn torrents(&self) -> Vec<Arc<TorrentClient>> {
+        self.torrents.lock().unwrap().values().cloned().collect()
+    }
+
+    /// the state of every torrent, sorted by name, with rates measured since the
+    /// previous call. The first summary of a torrent reports rates of zero
+    pub fn summary(&self) -> Vec<TorrentSummary> {
+        let now = Instant::now();
+        let mut samples = self.samples.lock().unwrap();
+        let mut summary: Vec<_> = self
+            .torrents()
+            .into_iter()
+            .map(|client| {
+                let sample = Sample {
+                    at: now,
+                    downloaded: client.downloaded.load(Ordering::Relaxed),
+                    uploaded: client.uploaded.load(Ordering::Relaxed),
+                };
+                let (download_rate, upload_rate) = match samples.insert(client.info_hash, sample) {
+                    Some(prev) if now > prev.at => {
+                        let secs = (now - prev.at).as_secs_f64();
+                        (
+                            sample.downloaded.saturating_sub(prev.downloaded) as f64 / secs,
+                            sample.uploaded.saturating_sub(prev.uploaded) as f64 / secs,
+                        )
+                    }
+                    _ => (0.0, 0.0),
+                };
+                let total = client.piece_hashes.len().max(1) as f64;
+                let verified = client.bitfield.lock().unwrap().count() as f64;
+                TorrentSummary {
+                    info_hash: client.info_hash,
+                    name: client.name.to_string(),
+                    completion: (verified / total * 100.0).min(100.0),
+                    download_rate,
+                    upload_rate,
+                    peers: client.peer_stats.lock().unwrap().len(),
+                    state: client.state(),
+                }
+            })
+            .collect();
+        summary.sort_by(|a, b| a.name.cmp(&b.name));
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    #[test]
+    fn duplicate_torrents_are_recognized() {
+        let content = testutil::content(40_000);
+        let session = Session::new();
+        let first = session
+            .add_torrent(testutil::client(&content, 16384))
+            .unwrap();
+        let err = session
+            .add_torrent(testutil::client(&content, 16384))
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<RbitError>(),
+            Some(RbitError::DuplicateTorrent(info_hash)) if *info_hash == first.info_hash
+        ));
+        assert_eq!(session.torrents().len(), 1);
+
+        let session = Session::new().set_duplicate_policy(DuplicatePolicy::MergeTrackers);
+        let first = session
+            .add_torrent(testutil::client(&content, 16384))
+            .unwrap();
+        let second = testutil::builder(&content, 16384)
+            .add_trackers(vec!["http://127.0.0.1:1/extra".to_string()])
+            .build()
+            .unwrap();
+        let merged = session.add_torrent(second).unwrap();
+        assert!(Arc::ptr_eq(&first, &merged));
+        assert_eq!(session.torrents().len(), 1);
+        assert!(first
+            .trackers()
+            .contains(&"http://127.0.0.1:1/extra".to_string()));
+    }
+
+    #[tokio::test]
+    async fn summary_reports_each_torrent() {
+        let content = testutil::content(40_000);
+        let session = Session::new();
+        let complete = session
+            .add_torrent(
+                testutil::seeded(&content, 16384, Default::default())
+                    .await
+                    .set_assemble(false)
+                    .build()
+                    .unwrap(),
+            )
+            .unwrap();
+        let other = testutil::content(30_000);
+        let paused = session
+            .add_torrent(
+                testutil::builder_of(&testutil::torrent_of(testutil::info(
+                    "rbt-test-paused",
+                    &other,
+                    16384,
+                )))
+                .build()
+                .unwrap(),
+            )
+            .unwrap();
+
+        let before = session.summary();
+        assert_eq!(before.len(), 2);
+        assert!(before
+            .iter()
+            .all(|line| line.state == TorrentState::Idle && line.completion == 0.0));
+
+        complete.send_request().await.unwrap();
+        paused.shutdown();
+        assert!(paused.send_request().await.is_err());
+
+        let summary = session.summary();
+        assert_eq!(summary[0].name, "rbt-test-memory");
+        assert_eq!(summary[0].info_hash, complete.info_hash);
+        assert_eq!(summary[0].state, TorrentState::Complete);
+        assert_eq!(summary[0].completion, 100.0);
+        assert!(summary[0].download_rate > 0.0);
+        assert_eq!(summary[1].name, "rbt-test-paused");
+        assert_eq!(summary[1].state, TorrentState::Paused);
+        assert_eq!(summary[1].completion, 0.0);
+        assert_eq!(summary[1].download_rate, 0.0);
+    }
+}
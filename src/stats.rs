@@ -0,0 +1,383 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::TorrentState;
+
+/// What we know about a connected peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerStats {
+    pub addr: SocketAddr,
+    /// client software decoded from the peer id, see [`client_name`]
+    pub client: String,
+    /// bytes of blocks received from the peer
+    pub downloaded: u64,
+    pub messages: MessageCounts,
+}
+
+impl PeerStats {
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            client: "unknown".to_string(),
+            downloaded: 0,
+            messages: MessageCounts::default(),
+        }
+    }
+}
+
+/// How many messages of each kind went each way, keyed by
+/// [`crate::message::Message::name`]. Meant for debugging the peer wire protocol.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MessageCounts {
+    pub sent: BTreeMap<&'static str, u64>,
+    pub received: BTreeMap<&'static str, u64>,
+}
+
+impl MessageCounts {
+    pub fn count_sent(&mut self, name: &'static str) {
+        *self.sent.entry(name).or_default() += 1;
+    }
+
+    pub fn count_received(&mut self, name: &'static str) {
+        *self.received.entry(name).or_default() += 1;
+    }
+
+    pub fn sent(&self, name: &str) -> u64 {
+        self.sent.get(name).copied().unwrap_or_default()
+    }
+
+    pub fn received(&self, name: &str) -> u64 {
+        self.received.get(name).copied().unwrap_or_default()
+    }
+
+    /// add the counts of `other` to these
+    pub fn merge(&mut self, other: &Self) {
+        for (name, count) in other.sent.iter() {
+            *self.sent.entry(name).or_default() += count;
+        }
+        for (name, count) in other.received.iter() {
+            *self.received.entry(name).or_default() += count;
+        }
+    }
+}
+
+/// How the last announce to a tracker went.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AnnounceResult {
+    Success,
+    /// the tracker did not answer in time
+    Timeout,
+    Failure(String),
+}
+
+/// What we know about a tracker, see [`crate::TorrentClient::tracker_stats`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackerStats {
+    pub result: AnnounceResult,
+    /// peers the last successful announce returned
+    pub peers: usize,
+    pub announces: u32,
+    pub last_announce: Instant,
+}
+
+/// Outcome of [`crate::TorrentClient::quick_verify`], which hashes only a few pieces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuickVerify {
+    /// the pieces hashed, the first and the last one and the random sample
+    pub checked: Vec<u32>,
+    pub corrupt: Vec<u32>,
+    pub piece_count: u32,
+}
+
+impl QuickVerify {
+    /// whether every piece checked matches, the output is probably the right one
+    pub fn is_match(&self) -> bool {
+        self.corrupt.is_empty()
+    }
+
+    /// how much of the output backs a match, the share of the pieces checked.
+    /// Zero once a piece is corrupt, the output is certainly not the right one
+    pub fn confidence(&self) -> f64 {
+        if !self.is_match() || self.piece_count == 0 {
+            return 0.0;
+        }
+        self.checked.len() as f64 / self.piece_count as f64
+    }
+}
+
+/// A snapshot of a torrent for monitoring, see [`crate::TorrentClient::status_json`].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TorrentStatus {
+    pub name: String,
+    /// hex encoded
+    pub info_hash: String,
+    pub state: TorrentState,
+    /// share of the content verified, within 0.0..=1.0
+    pub progress: f64,
+    pub downloaded: u64,
+    pub uploaded: u64,
+    pub left: u64,
+    /// bytes per second over the recent window
+    pub download_rate: f64,
+    pub eta_secs: Option<u64>,
+    pub pieces: u32,
+    pub pieces_verified: u32,
+    /// connected peers
+    pub peers: usize,
+    pub trackers: Vec<TrackerStatus>,
+}
+
+/// [`TrackerStats`] of one tracker as part of a [`TorrentStatus`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TrackerStatus {
+    pub url: String,
+    /// `success`, `timeout` or `failure`
+    pub result: String,
+    /// the reason of a failure
+    pub error: Option<String>,
+    pub peers: usize,
+    pub announces: u32,
+    pub secs_since_announce: u64,
+}
+
+impl TrackerStatus {
+    pub fn new(url: String, stats: &TrackerStats) -> Self {
+        let (result, error) = match &stats.result {
+            AnnounceResult::Success => ("success", None),
+            AnnounceResult::Timeout => ("timeout", None),
+            AnnounceResult::Failure(reason) => ("failure", Some(reason.clone())),
+        };
+        Self {
+            url,
+            result: result.into(),
+            error,
+            peers: stats.peers,
+            announces: stats.announces,
+            secs_since_announce: stats.last_announce.elapsed().as_secs(),
+        }
+    }
+}
+
+/// How connecting to a peer went so far, across re-announces.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeerHistory {
+    pub successes: u32,
+    pub failures: u32,
+}
+
+impl PeerHistory {
+    /// higher for peers which connected more often than they failed
+    pub fn score(&self) -> i64 {
+        self.successes as i64 - self.failures as i64
+    }
+}
+
+/// Progress of a download as a whole, see [`crate::TorrentClient::stats`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DownloadStats {
+    pub downloaded: u64,
+    pub uploaded: u64,
+    /// bytes of pieces which are not verified yet
+    pub left: u64,
+    /// bytes per second over the recent window
+    pub average_speed: f64,
+    /// time until `left` is downloaded at `average_speed`, `None` while stalled
+    pub eta: Option<Duration>,
+}
+
+impl DownloadStats {
+    pub fn new(downloaded: u64, uploaded: u64, left: u64, average_speed: f64) -> Self {
+        Self {
+            downloaded,
+            uploaded,
+            left,
+            average_speed,
+            eta: eta(left, average_speed),
+        }
+    }
+}
+
+/// time to download `left` bytes at `rate` bytes per second, `None` for a zero rate
+pub fn eta(left: u64, rate: f64) -> Option<Duration> {
+    if left == 0 {
+        return Some(Duration::ZERO);
+    }
+    if !rate.is_finite() || rate <= 0.0 {
+        return None;
+    }
+    Duration::try_from_secs_f64(left as f64 / rate).ok()
+}
+
+/// Samples of a byte counter over a sliding window, to average the recent rate.
+#[derive(Debug, Clone)]
+pub struct RateWindow {
+    window: Duration,
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl Default for RateWindow {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_WINDOW)
+    }
+}
+
+impl RateWindow {
+    pub const DEFAULT_WINDOW: Duration = Duration::from_secs(20);
+
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// record the counter at `at` and return the bytes per second since the
+    /// oldest sample still inside the window
+    pub fn sample(&mut self, at: Instant, total: u64) -> f64 {
+        while self
+            .samples
+            .front()
+            .is_some_and(|(first, _)| at.saturating_duration_since(*first) > self.window)
+        {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((at, total));
+        let (first_at, first_total) = self.samples[0];
+        let secs = at.saturating_duration_since(first_at).as_secs_f64();
+        if secs == 0.0 {
+            return 0.0;
+        }
+        total.saturating_sub(first_total) as f64 / secs
+    }
+}
+
+/// Azureus-style client codes, as in `-qB4500-`
+const CLIENTS: &[(&str, &str)] = &[
+    ("AZ", "Vuze"),
+    ("BC", "BitComet"),
+    ("BT", "BitTorrent"),
+    ("DE", "Deluge"),
+    ("FD", "Free Download Manager"),
+    ("KT", "KTorrent"),
+    ("LT", "libtorrent"),
+    ("lt", "rTorrent"),
+    ("qB", "qBittorrent"),
+    ("RT", "rbittorrent"),
+    ("TL", "Tribler"),
+    ("TR", "Transmission"),
+    ("UT", "µTorrent"),
+    ("UM", "µTorrent Mac"),
+    ("WW", "WebTorrent"),
+    ("XL", "Xunlei"),
+];
+
+/// decode the client software and version from a peer id, e.g. `qBittorrent 4.5.0`
+pub fn client_name(id: &[u8; 20]) -> String {
+    // Azureus style: '-' two letter client code, four version characters, '-'
+    if id[0] == b'-' && id[7] == b'-' {
+        let code = std::str::from_utf8(&id[1..3]).unwrap_or_default();
+        if let Some((_, name)) = CLIENTS.iter().find(|(known, _)| *known == code) {
+            let version = id[3..6]
+                .iter()
+                .map(|c| (*c as char).to_string())
+                .collect::<Vec<_>>()
+                .join(".");
+            return format!("{} {}", name, version);
+        }
+    }
+    // mainline style: 'M' followed by a dash separated version, as in `M7-4-3--`
+    if id[0] == b'M' {
+        if let Ok(prefix) = std::str::from_utf8(&id[1..8]) {
+            let version = prefix
+                .split('-')
+                .filter(|part| !part.is_empty())
+                .collect::<Vec<_>>();
+            if !version.is_empty() && version.iter().all(|part| part.parse::<u8>().is_ok()) {
+                return format!("BitTorrent {}", version.join("."));
+            }
+        }
+    }
+    "unknown".to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{self, SeedBehavior};
+
+    fn id(prefix: &[u8]) -> [u8; 20] {
+        let mut id = [b'0'; 20];
+        id[..prefix.len()].copy_from_slice(prefix);
+        id
+    }
+
+    #[test]
+    fn decodes_well_known_clients() {
+        assert_eq!(client_name(&id(b"-qB4500-")), "qBittorrent 4.5.0");
+        assert_eq!(client_name(&id(b"-TR3000-")), "Transmission 3.0.0");
+        assert_eq!(client_name(&id(b"-UT355S-")), "µTorrent 3.5.5");
+        assert_eq!(client_name(&id(b"-lt0D60-")), "rTorrent 0.D.6");
+        assert_eq!(client_name(&id(b"M7-4-3--")), "BitTorrent 7.4.3");
+    }
+
+    #[test]
+    fn unknown_clients() {
+        assert_eq!(client_name(&id(b"-ZZ1000-")), "unknown");
+        assert_eq!(client_name(&[0; 20]), "unknown");
+        assert_eq!(client_name(&id(b"Mx-y----")), "unknown");
+        assert_eq!(client_name(&id(b"-qB4500x")), "unknown");
+    }
+
+    #[test]
+    fn eta_follows_a_steady_rate() {
+        let start = Instant::now();
+        let mut window = RateWindow::new(Duration::from_secs(20));
+        let length = 10_000_000;
+        let mut stats = DownloadStats::new(0, 0, length, 0.0);
+        // 100 kB every second for ten seconds
+        for second in 0..=10 {
+            let downloaded = second * 100_000;
+            let speed = window.sample(start + Duration::from_secs(second), downloaded);
+            stats = DownloadStats::new(downloaded, 0, length - downloaded, speed);
+        }
+        assert_eq!(stats.average_speed, 100_000.0);
+        assert_eq!(stats.eta, Some(Duration::from_secs(90)));
+
+        // stalled long enough for the window to hold no progress
+        let speed = window.sample(start + Duration::from_secs(40), 1_000_000);
+        assert_eq!(speed, 0.0);
+        assert_eq!(DownloadStats::new(1_000_000, 0, 9_000_000, speed).eta, None);
+        assert_eq!(eta(0, 0.0), Some(Duration::ZERO));
+        assert_eq!(eta(100, f64::NAN), None);
+    }
+
+    #[tokio::test]
+    async fn peer_stats_name_the_client() {
+        let content = testutil::content(100_000);
+        let behavior = SeedBehavior {
+            peer_id: Some(id(b"-TR4060-")),
+            choke: true,
+            ..Default::default()
+        };
+        let client = std::sync::Arc::new(
+            testutil::seeded(&content, 32768, behavior)
+                .await
+                .build()
+                .unwrap(),
+        );
+        let download = tokio::spawn({
+            let client = client.clone();
+            async move { client.send_request().await }
+        });
+        // stats are kept while the peer is connected
+        assert!(testutil::eventually(|| !client.peer_stats().is_empty()).await);
+        assert_eq!(client.peer_stats()[0].client, "Transmission 4.0.6");
+        client.shutdown();
+        let _ = download.await;
+    }
+}
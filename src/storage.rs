@@ -0,0 +1,1054 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs::{create_dir_all, remove_dir_all, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use tokio::sync::watch;
+
+use crate::files::TorrentFile;
+
+/// Where verified pieces are kept until the download is finished.
+pub trait Storage: Send + Sync + std::fmt::Debug {
+    /// persist the data of a verified piece
+    fn write_piece(&self, index: u32, data: &[u8]) -> Result<()>;
+
+    /// read back a stored piece, `None` if it is not stored (anymore)
+    fn read_piece(&self, index: u32) -> Result<Option<Vec<u8>>>;
+
+    fn has_piece(&self, index: u32) -> bool;
+
+    /// make a written piece durable, it only counts as verified once this succeeded
+    fn sync_piece(&self, _index: u32) -> Result<()> {
+        Ok(())
+    }
+
+    /// drop a stored piece which turned out corrupt, so it is downloaded again cleanly
+    fn discard_piece(&self, _index: u32) -> Result<()> {
+        Ok(())
+    }
+
+    /// turn the stored pieces into the final output once every piece is in
+    fn finalize(&self) -> Result<()>;
+
+    /// bytes the storage takes up on disk
+    fn disk_usage(&self) -> u64 {
+        0
+    }
+}
+
+/// Keeps every piece in its own `{name}-cache-{index}` file under `{name}.cache`
+/// and concatenates them into `name` when finalized, or splits them across the
+/// files of a multi-file torrent. Once finalized, pieces are read back from the
+/// output if the piece length is known.
+#[derive(Debug)]
+pub struct CacheStorage {
+    name: Arc<String>,
+    piece_num: u32,
+    piece_length: Option<u32>,
+    length: Option<u64>,
+    files: Vec<TorrentFile>,
+    on_missing: MissingPiece,
+    order: AssemblyOrder,
+}
+
+/// The order [`CacheStorage`] assembles the output in. Every piece is written at
+/// its own offset, so the order must not change the output, a different one is
+/// there to check exactly that.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AssemblyOrder {
+    #[default]
+    Ascending,
+    Descending,
+}
+
+/// What assembling the output does about a piece which is not stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MissingPiece {
+    /// fail, the output would be shorter or misaligned
+    #[default]
+    Error,
+    /// write zeros in its place with a warning, the piece fails its hash later
+    ZeroFill,
+}
+
+impl CacheStorage {
+    pub fn new(name: Arc<String>, piece_num: u32) -> Self {
+        Self {
+            name,
+            piece_num,
+            piece_length: None,
+            length: None,
+            files: Vec::new(),
+            on_missing: MissingPiece::default(),
+            order: AssemblyOrder::default(),
+        }
+    }
+
+    /// assemble a single-file output in `order`, which takes the piece length
+    pub fn with_assembly_order(mut self, order: AssemblyOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// the content length, so a zero filled last piece gets its actual length
+    pub fn with_length(mut self, length: u64) -> Self {
+        self.length = Some(length);
+        self
+    }
+
+    pub fn with_missing_piece(mut self, on_missing: MissingPiece) -> Self {
+        self.on_missing = on_missing;
+        self
+    }
+
+    /// split the content across `files` instead of writing it to `name`
+    pub fn with_files(mut self, files: Vec<TorrentFile>) -> Self {
+        self.files = files;
+        self
+    }
+
+    /// lets pieces be read back from the assembled output
+    pub fn with_piece_length(mut self, piece_length: u32) -> Self {
+        self.piece_length = Some(piece_length);
+        self
+    }
+
+    fn dir(&self) -> PathBuf {
+        PathBuf::from(format!("{}.cache", &self.name))
+    }
+
+    fn cache_path(&self, index: u32) -> PathBuf {
+        self.dir().join(format!("{}-cache-{}", &self.name, index))
+    }
+
+    /// where the piece lies in the assembled output, if there is one holding it
+    fn output_span(&self, index: u32) -> Option<(u64, u64)> {
+        let piece_length = self.piece_length? as u64;
+        let len = if self.files.is_empty() {
+            std::fs::metadata(&*self.name).ok()?.len()
+        } else {
+            // only a complete set of files is an output
+            self.files
+                .iter()
+                .all(|file| {
+                    std::fs::metadata(&file.path).is_ok_and(|meta| meta.len() == file.length)
+                })
+                .then(|| self.files.iter().map(|file| file.length).sum())?
+        };
+        let offset = index as u64 * piece_length;
+        (index < self.piece_num && offset < len).then(|| (offset, (len - offset).min(piece_length)))
+    }
+}
+
+impl Storage for CacheStorage {
+    fn write_piece(&self, index: u32, data: &[u8]) -> Result<()> {
+        if let Some(expected) = self.piece_len(index) {
+            check_piece_len(index, data, expected)?;
+        }
+        let dir_path = self.dir();
+        if !dir_path.is_dir() {
+            create_dir_all(&dir_path)?;
+        }
+        std::fs::write(self.cache_path(index), data)?;
+        Ok(())
+    }
+
+    fn read_piece(&self, index: u32) -> Result<Option<Vec<u8>>> {
+        let path = self.cache_path(index);
+        if path.is_file() {
+            return Ok(Some(std::fs::read(path)?));
+        }
+        let Some((offset, len)) = self.output_span(index) else {
+            return Ok(None);
+        };
+        let mut data = vec![0; len as usize];
+        if self.files.is_empty() {
+            let mut file = File::open(&*self.name)?;
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut data)?;
+            return Ok(Some(data));
+        }
+        let span = offset..offset + len;
+        for file in self.files.iter() {
+            let range = file.range();
+            let (start, end) = (span.start.max(range.start), span.end.min(range.end));
+            if start >= end {
+                continue;
+            }
+            let mut out = File::open(&file.path)?;
+            out.seek(SeekFrom::Start(start - range.start))?;
+            out.read_exact(&mut data[(start - offset) as usize..(end - offset) as usize])?;
+        }
+        Ok(Some(data))
+    }
+
+    fn has_piece(&self, index: u32) -> bool {
+        self.cache_path(index).is_file() || self.output_span(index).is_some()
+    }
+
+    fn sync_piece(&self, index: u32) -> Result<()> {
+        File::open(self.cache_path(index))?.sync_all()?;
+        Ok(())
+    }
+
+    /// only the cache file goes, a corrupt piece of the assembled output is overwritten
+    /// when the output is assembled again
+    fn discard_piece(&self, index: u32) -> Result<()> {
+        let path = self.cache_path(index);
+        if path.is_file() {
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    fn disk_usage(&self) -> u64 {
+        (0..self.piece_num)
+            .filter_map(|index| std::fs::metadata(self.cache_path(index)).ok())
+            .map(|meta| meta.len())
+            .sum()
+    }
+
+    fn finalize(&self) -> Result<()> {
+        // nothing cached means the output is assembled already, keep it as it is
+        if !self.dir().is_dir() {
+            return Ok(());
+        }
+        if !self.files.is_empty() {
+            return self.split_into_files();
+        }
+        // pieces verified from an earlier output are taken from it, so assemble
+        // next to it and replace it at the end
+        let part = PathBuf::from(format!("{}.part", &self.name));
+        let mut file = File::create(&part)?;
+        let indices: Vec<u32> = match self.order {
+            AssemblyOrder::Ascending => (0..self.piece_num).collect(),
+            AssemblyOrder::Descending => (0..self.piece_num).rev().collect(),
+        };
+        for index in indices {
+            // place each piece at its offset rather than relying on the order
+            match self.piece_length {
+                Some(piece_length) => {
+                    file.seek(SeekFrom::Start(index as u64 * piece_length as u64))?
+                }
+                None if self.order == AssemblyOrder::Ascending => file.stream_position()?,
+                None => {
+                    return Err(anyhow!(
+                        "assembling {} out of order takes the piece length",
+                        self.name
+                    ))
+                }
+            };
+            let path = self.cache_path(index);
+            if path.is_file() {
+                std::io::copy(&mut File::open(path)?, &mut file)?;
+            } else if let Some(data) = self.read_piece(index)? {
+                file.write_all(&data)?;
+            } else {
+                file.write_all(&self.missing_piece(index)?)?;
+            }
+        }
+        if let Some(length) = self.length {
+            let assembled = file.metadata()?.len();
+            if assembled != length {
+                return Err(anyhow!(
+                    "assembled {} bytes of {} instead of {}",
+                    assembled,
+                    self.name,
+                    length
+                ));
+            }
+        }
+        file.sync_all()?;
+        std::fs::rename(part, &*self.name)?;
+        sync_parent(Path::new(&*self.name))?;
+        remove_dir_all(self.dir())?;
+        Ok(())
+    }
+}
+
+impl CacheStorage {
+    /// the exact length of a piece, the final one is shorter unless the length is a
+    /// multiple of the piece length
+    fn piece_len(&self, index: u32) -> Option<u64> {
+        let (length, piece_length) = (self.length?, self.piece_length? as u64);
+        Some(
+            length
+                .saturating_sub(index as u64 * piece_length)
+                .min(piece_length),
+        )
+    }
+
+    /// stand-in for a piece which is neither cached nor in the output, skipping it
+    /// would shift everything after it
+    fn missing_piece(&self, index: u32) -> Result<Vec<u8>> {
+        let missing = || {
+            anyhow!(
+                "piece #{} of {} is missing from the cache",
+                index,
+                self.name
+            )
+        };
+        match (self.on_missing, self.piece_length) {
+            (MissingPiece::ZeroFill, Some(piece_length)) => {
+                warn!(
+                    "piece #{} of {} is missing from the cache, filled with zeros",
+                    index, self.name
+                );
+                let length = self.piece_len(index).unwrap_or(piece_length as u64);
+                Ok(vec![0; length as usize])
+            }
+            _ => Err(missing()),
+        }
+    }
+
+    /// write each file from the pieces overlapping it. A piece crossing a file
+    /// boundary ends one file and starts the next, so it is kept for the next file
+    fn split_into_files(&self) -> Result<()> {
+        let piece_length = self
+            .piece_length
+            .ok_or_else(|| anyhow!("the piece length of {} is unknown", self.name))?
+            as u64;
+        let mut piece: Option<(u32, Vec<u8>)> = None;
+        let mut parts = Vec::with_capacity(self.files.len());
+        for file in self.files.iter() {
+            if let Some(dir) = file.path.parent() {
+                create_dir_all(dir)?;
+            }
+            let mut part_path = file.path.clone().into_os_string();
+            part_path.push(".part");
+            let part_path = PathBuf::from(part_path);
+            let mut part = File::create(&part_path)?;
+            let range = file.range();
+            for index in file.pieces(piece_length as u32) {
+                if piece.as_ref().is_none_or(|(cached, _)| *cached != index) {
+                    let data = match self.read_piece(index)? {
+                        Some(data) => data,
+                        None => self.missing_piece(index)?,
+                    };
+                    piece = Some((index, data));
+                }
+                let data = &piece.as_ref().unwrap().1;
+                let begin = index as u64 * piece_length;
+                let start = range.start.max(begin) - begin;
+                let end = range.end.min(begin + data.len() as u64) - begin;
+                part.write_all(&data[start as usize..end as usize])?;
+            }
+            part.sync_all()?;
+            parts.push((part_path, &file.path));
+        }
+        for (part, path) in parts {
+            std::fs::rename(part, path)?;
+            sync_parent(path)?;
+        }
+        remove_dir_all(self.dir())?;
+        Ok(())
+    }
+}
+
+/// Writes every piece straight to its place in the output file, which is
+/// preallocated to the full length up front so a full disk shows right away.
+/// The default for single-file torrents, nothing is left to assemble at the end.
+#[derive(Debug)]
+pub struct FileStorage {
+    path: PathBuf,
+    piece_length: u32,
+    length: u64,
+    file: Mutex<File>,
+    /// whether the file had content before, only then may it hold earlier pieces
+    existed: bool,
+    written: Mutex<HashSet<u32>>,
+}
+
+impl FileStorage {
+    pub fn new<T>(path: T, piece_length: u32, length: u64) -> Result<Self>
+    where
+        T: AsRef<Path>,
+    {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(&path)?;
+        let existed = file.metadata()?.len() > 0;
+        preallocate(&file, length)?;
+        Ok(Self {
+            path,
+            piece_length,
+            length,
+            file: Mutex::new(file),
+            existed,
+            written: Mutex::new(HashSet::new()),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// byte offset and length of a piece in the file
+    fn span(&self, index: u32) -> Option<(u64, u64)> {
+        let offset = index as u64 * self.piece_length as u64;
+        (offset < self.length)
+            .then(|| (offset, (self.length - offset).min(self.piece_length as u64)))
+    }
+}
+
+/// Writes every piece straight to the files of a multi-file torrent, each with
+/// its own handle, so pieces of different files are written in parallel. A piece
+/// crossing a file boundary is split between the handles. The default for
+/// multi-file torrents, the files are preallocated like [`FileStorage`] does.
+#[derive(Debug)]
+pub struct MultiFileStorage {
+    files: Vec<TorrentFile>,
+    handles: Vec<Mutex<File>>,
+    piece_length: u32,
+    length: u64,
+    /// whether each file had content before, only then may it hold earlier pieces
+    existed: Vec<bool>,
+    written: Mutex<HashSet<u32>>,
+}
+
+impl MultiFileStorage {
+    pub fn new(files: Vec<TorrentFile>, piece_length: u32, length: u64) -> Result<Self> {
+        let mut handles = Vec::with_capacity(files.len());
+        let mut existed = Vec::with_capacity(files.len());
+        for file in files.iter() {
+            if let Some(dir) = file.path.parent() {
+                create_dir_all(dir)?;
+            }
+            let handle = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .read(true)
+                .write(true)
+                .open(&file.path)?;
+            existed.push(handle.metadata()?.len() > 0);
+            preallocate(&handle, file.length)?;
+            handles.push(Mutex::new(handle));
+        }
+        Ok(Self {
+            files,
+            handles,
+            piece_length,
+            length,
+            existed,
+            written: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// the parts of a piece, as the file they go to, the offset within that file
+    /// and the range of the piece data
+    fn parts(&self, index: u32) -> Vec<(usize, u64, Range<usize>)> {
+        let begin = index as u64 * self.piece_length as u64;
+        if begin >= self.length {
+            return Vec::new();
+        }
+        let end = (begin + self.piece_length as u64).min(self.length);
+        self.files
+            .iter()
+            .enumerate()
+            .filter_map(|(at, file)| {
+                let range = file.range();
+                let (start, stop) = (begin.max(range.start), end.min(range.end));
+                (start < stop).then(|| {
+                    let data = (start - begin) as usize..(stop - begin) as usize;
+                    (at, start - range.start, data)
+                })
+            })
+            .collect()
+    }
+}
+
+impl Storage for MultiFileStorage {
+    fn write_piece(&self, index: u32, data: &[u8]) -> Result<()> {
+        let parts = self.parts(index);
+        let Some(len) = parts.last().map(|(_, _, range)| range.end) else {
+            return Err(anyhow!("piece #{} is beyond the end of the content", index));
+        };
+        check_piece_len(index, data, len as u64)?;
+        for (at, offset, range) in parts {
+            let mut file = self.handles[at].lock().unwrap();
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(&data[range])?;
+        }
+        self.written.lock().unwrap().insert(index);
+        Ok(())
+    }
+
+    fn read_piece(&self, index: u32) -> Result<Option<Vec<u8>>> {
+        let parts = self.parts(index);
+        let Some(len) = parts.last().map(|(_, _, range)| range.end) else {
+            return Ok(None);
+        };
+        let mut data = vec![0; len];
+        for (at, offset, range) in parts {
+            let mut file = self.handles[at].lock().unwrap();
+            if file.metadata()?.len() < offset + range.len() as u64 {
+                return Ok(None);
+            }
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut data[range])?;
+        }
+        Ok(Some(data))
+    }
+
+    /// like [`FileStorage::has_piece`], every file the piece lies in has to have
+    /// had content before unless the piece was written since
+    fn has_piece(&self, index: u32) -> bool {
+        let parts = self.parts(index);
+        !parts.is_empty()
+            && (parts.iter().all(|(at, _, _)| self.existed[*at])
+                || self.written.lock().unwrap().contains(&index))
+    }
+
+    fn sync_piece(&self, index: u32) -> Result<()> {
+        for (at, _, _) in self.parts(index) {
+            self.handles[at].lock().unwrap().sync_data()?;
+        }
+        Ok(())
+    }
+
+    fn finalize(&self) -> Result<()> {
+        for (file, handle) in self.files.iter().zip(self.handles.iter()) {
+            handle.lock().unwrap().sync_all()?;
+            sync_parent(&file.path)?;
+        }
+        Ok(())
+    }
+
+    /// bytes of the pieces written so far, the rest of the preallocated files
+    /// does not count against a quota
+    fn disk_usage(&self) -> u64 {
+        let written = self.written.lock().unwrap();
+        written
+            .iter()
+            .filter_map(|index| {
+                self.parts(*index)
+                    .last()
+                    .map(|(_, _, range)| range.end as u64)
+            })
+            .sum()
+    }
+}
+
+/// a piece is written with its exact length, anything else would shift or
+/// lengthen the output
+fn check_piece_len(index: u32, data: &[u8], expected: u64) -> Result<()> {
+    if data.len() as u64 != expected {
+        return Err(anyhow!(
+            "piece #{} has {} bytes instead of {}",
+            index,
+            data.len(),
+            expected
+        ));
+    }
+    Ok(())
+}
+
+/// make the directory entry of `path` durable, so a created or renamed file
+/// survives a crash along with its data. Only Unix can sync a directory
+fn sync_parent(path: &Path) -> Result<()> {
+    #[cfg(unix)]
+    {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// reserve `length` bytes of disk for `file`, failing early if they are not available
+fn preallocate(file: &File, length: u64) -> Result<()> {
+    if file.metadata()?.len() >= length {
+        return Ok(());
+    }
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+        // SAFETY: the descriptor is owned by `file` and stays open for the call
+        let res = unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, length as libc::off_t) };
+        match res {
+            0 => return Ok(()),
+            // the file system cannot allocate without writing, fall back to set_len
+            libc::EOPNOTSUPP | libc::EINVAL => {}
+            errno => return Err(std::io::Error::from_raw_os_error(errno).into()),
+        }
+    }
+    file.set_len(length)?;
+    Ok(())
+}
+
+impl Storage for FileStorage {
+    fn write_piece(&self, index: u32, data: &[u8]) -> Result<()> {
+        let (offset, len) = self
+            .span(index)
+            .ok_or_else(|| anyhow!("piece #{} is beyond the end of {:?}", index, self.path))?;
+        check_piece_len(index, data, len)?;
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(data)?;
+        self.written.lock().unwrap().insert(index);
+        Ok(())
+    }
+
+    fn read_piece(&self, index: u32) -> Result<Option<Vec<u8>>> {
+        let Some((offset, len)) = self.span(index) else {
+            return Ok(None);
+        };
+        let mut file = self.file.lock().unwrap();
+        if file.metadata()?.len() < offset + len {
+            return Ok(None);
+        }
+        let mut data = vec![0; len as usize];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut data)?;
+        Ok(Some(data))
+    }
+
+    /// the file is preallocated, so this only tells whether the piece lies within
+    /// a file which had content before or was written since, whether it holds the
+    /// right data takes hashing it. A freshly created file is not hashed for nothing
+    fn has_piece(&self, index: u32) -> bool {
+        self.span(index).is_some()
+            && (self.existed || self.written.lock().unwrap().contains(&index))
+    }
+
+    fn sync_piece(&self, _index: u32) -> Result<()> {
+        self.file.lock().unwrap().sync_data()?;
+        Ok(())
+    }
+
+    fn finalize(&self) -> Result<()> {
+        self.file.lock().unwrap().sync_all()?;
+        sync_parent(&self.path)?;
+        Ok(())
+    }
+
+    /// bytes of the pieces written so far, like [`MultiFileStorage`] counts them
+    fn disk_usage(&self) -> u64 {
+        let written = self.written.lock().unwrap();
+        written
+            .iter()
+            .filter_map(|index| self.span(*index).map(|(_, len)| len))
+            .sum()
+    }
+}
+
+/// A disk job for the writer thread of a [`SerialWriter`], answered once done.
+#[derive(Debug)]
+enum WriteJob {
+    Write(u32, Vec<u8>, mpsc::Sender<Result<()>>),
+    Sync(u32, mpsc::Sender<Result<()>>),
+    Discard(u32, mpsc::Sender<Result<()>>),
+}
+
+/// Hands every write of a storage to one dedicated thread over a channel, so
+/// pieces completed by many peers at once reach the disk one after the other in
+/// the order they were completed. Reads go to the storage directly.
+#[derive(Debug)]
+pub struct SerialWriter {
+    storage: Arc<dyn Storage>,
+    jobs: mpsc::Sender<WriteJob>,
+}
+
+impl SerialWriter {
+    /// start the writer thread, it stops once the writer is dropped
+    pub fn new(storage: Arc<dyn Storage>) -> Result<Self> {
+        let (jobs, queue) = mpsc::channel();
+        let inner = storage.clone();
+        std::thread::Builder::new()
+            .name("rbt-writer".into())
+            .spawn(move || {
+                for job in queue {
+                    let _ = match job {
+                        WriteJob::Write(index, data, done) => {
+                            done.send(inner.write_piece(index, &data))
+                        }
+                        WriteJob::Sync(index, done) => done.send(inner.sync_piece(index)),
+                        WriteJob::Discard(index, done) => done.send(inner.discard_piece(index)),
+                    };
+                }
+            })?;
+        Ok(Self { storage, jobs })
+    }
+
+    /// queue a job and wait for the writer thread to finish it
+    fn run(&self, job: impl FnOnce(mpsc::Sender<Result<()>>) -> WriteJob) -> Result<()> {
+        let (done, result) = mpsc::channel();
+        self.jobs
+            .send(job(done))
+            .map_err(|_| anyhow!("the writer thread is gone"))?;
+        result
+            .recv()
+            .map_err(|_| anyhow!("the writer thread is gone"))?
+    }
+}
+
+impl Storage for SerialWriter {
+    fn write_piece(&self, index: u32, data: &[u8]) -> Result<()> {
+        self.run(|done| WriteJob::Write(index, data.to_vec(), done))
+    }
+
+    fn read_piece(&self, index: u32) -> Result<Option<Vec<u8>>> {
+        self.storage.read_piece(index)
+    }
+
+    fn has_piece(&self, index: u32) -> bool {
+        self.storage.has_piece(index)
+    }
+
+    fn sync_piece(&self, index: u32) -> Result<()> {
+        self.run(|done| WriteJob::Sync(index, done))
+    }
+
+    fn discard_piece(&self, index: u32) -> Result<()> {
+        self.run(|done| WriteJob::Discard(index, done))
+    }
+
+    fn finalize(&self) -> Result<()> {
+        self.storage.finalize()
+    }
+
+    fn disk_usage(&self) -> u64 {
+        self.storage.disk_usage()
+    }
+}
+
+/// Caps the disk space of a storage, counting pieces in flight as taken so that
+/// writing them can never exceed the limit.
+#[derive(Debug)]
+pub struct DiskQuota {
+    limit: u64,
+    storage: Arc<dyn Storage>,
+    reserved: Mutex<u64>,
+}
+
+impl DiskQuota {
+    const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+    pub fn new(limit: u64, storage: Arc<dyn Storage>) -> Self {
+        Self {
+            limit,
+            storage,
+            reserved: Mutex::new(0),
+        }
+    }
+
+    pub fn try_reserve(&self, bytes: u64) -> bool {
+        let mut reserved = self.reserved.lock().unwrap();
+        if self.storage.disk_usage() + *reserved + bytes > self.limit {
+            return false;
+        }
+        *reserved += bytes;
+        true
+    }
+
+    /// wait until `bytes` fit into the quota and take them, false if shut down first
+    pub async fn reserve(&self, bytes: u64, shutdown: &mut watch::Receiver<bool>) -> bool {
+        let mut warned = false;
+        while !self.try_reserve(bytes) {
+            if !warned {
+                info!("disk quota of {} bytes reached, pausing", self.limit);
+                warned = true;
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(Self::POLL_INTERVAL) => {}
+                _ = shutdown.wait_for(|stop| *stop) => return false,
+            }
+        }
+        true
+    }
+
+    /// give back a reservation once its piece is written or dropped
+    pub fn release(&self, bytes: u64) {
+        let mut reserved = self.reserved.lock().unwrap();
+        *reserved = reserved.saturating_sub(bytes);
+    }
+}
+
+/// Keeps only the most recent `capacity` pieces in memory, evicting the oldest
+/// ones. Meant for streaming content that does not fit on disk, evicted pieces
+/// are fetched again with [`crate::TorrentClient::ensure_piece`] on a seek back.
+#[derive(Debug)]
+pub struct RingStorage {
+    capacity: usize,
+    pieces: Mutex<VecDeque<(u32, Vec<u8>)>>,
+}
+
+impl RingStorage {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            pieces: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// indexes of the pieces currently held, oldest first
+    pub fn window(&self) -> Vec<u32> {
+        self.pieces
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(index, _)| *index)
+            .collect()
+    }
+}
+
+impl Storage for RingStorage {
+    fn write_piece(&self, index: u32, data: &[u8]) -> Result<()> {
+        let mut pieces = self.pieces.lock().unwrap();
+        pieces.retain(|(stored, _)| *stored != index);
+        while pieces.len() >= self.capacity.max(1) {
+            pieces.pop_front();
+        }
+        pieces.push_back((index, data.to_vec()));
+        Ok(())
+    }
+
+    fn discard_piece(&self, index: u32) -> Result<()> {
+        self.pieces
+            .lock()
+            .unwrap()
+            .retain(|(stored, _)| *stored != index);
+        Ok(())
+    }
+
+    fn read_piece(&self, index: u32) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .pieces
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(stored, _)| *stored == index)
+            .map(|(_, data)| data.clone()))
+    }
+
+    fn has_piece(&self, index: u32) -> bool {
+        self.pieces
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|(stored, _)| *stored == index)
+    }
+
+    fn finalize(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Keeps every piece in memory, for small torrents which never need to touch the disk.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    pieces: Mutex<HashMap<u32, Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn write_piece(&self, index: u32, data: &[u8]) -> Result<()> {
+        self.pieces.lock().unwrap().insert(index, data.to_vec());
+        Ok(())
+    }
+
+    fn read_piece(&self, index: u32) -> Result<Option<Vec<u8>>> {
+        Ok(self.pieces.lock().unwrap().get(&index).cloned())
+    }
+
+    fn has_piece(&self, index: u32) -> bool {
+        self.pieces.lock().unwrap().contains_key(&index)
+    }
+
+    fn discard_piece(&self, index: u32) -> Result<()> {
+        self.pieces.lock().unwrap().remove(&index);
+        Ok(())
+    }
+
+    fn finalize(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    #[test]
+    fn outputs_are_preallocated_up_front() {
+        let scratch = testutil::Scratch::dir("prealloc");
+        let _storage = FileStorage::new(scratch.join("single"), 16384, 100_000).unwrap();
+        assert_eq!(
+            std::fs::metadata(scratch.join("single")).unwrap().len(),
+            100_000
+        );
+        let files = vec![
+            TorrentFile {
+                path: scratch.join("dir/a"),
+                offset: 0,
+                length: 30000,
+            },
+            TorrentFile {
+                path: scratch.join("dir/b"),
+                offset: 30000,
+                length: 0,
+            },
+            TorrentFile {
+                path: scratch.join("dir/c"),
+                offset: 30000,
+                length: 70000,
+            },
+        ];
+        let _storage = MultiFileStorage::new(files, 16384, 100_000).unwrap();
+        for (name, length) in [("dir/a", 30000), ("dir/b", 0), ("dir/c", 70000)] {
+            assert_eq!(std::fs::metadata(scratch.join(name)).unwrap().len(), length);
+        }
+        // content from before is kept for the resume to hash
+        std::fs::write(scratch.join("partial"), [7; 1000]).unwrap();
+        let storage = FileStorage::new(scratch.join("partial"), 16384, 20000).unwrap();
+        assert_eq!(
+            std::fs::metadata(scratch.join("partial")).unwrap().len(),
+            20000
+        );
+        let piece = storage.read_piece(0).unwrap().unwrap();
+        assert_eq!(piece[..1000], [7; 1000]);
+        assert!(piece[1000..].iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn preallocated_files_count_written_pieces() {
+        let scratch = testutil::Scratch::dir("usage");
+        let storage = FileStorage::new(scratch.join("out"), 16384, 40000).unwrap();
+        assert_eq!(std::fs::metadata(scratch.join("out")).unwrap().len(), 40000);
+        assert_eq!(storage.disk_usage(), 0);
+        storage.write_piece(2, &[1; 7232]).unwrap();
+        assert_eq!(storage.disk_usage(), 7232);
+        storage.write_piece(0, &[1; 16384]).unwrap();
+        assert_eq!(storage.disk_usage(), 16384 + 7232);
+
+        let files = vec![
+            TorrentFile {
+                path: scratch.join("a"),
+                offset: 0,
+                length: 20000,
+            },
+            TorrentFile {
+                path: scratch.join("b"),
+                offset: 20000,
+                length: 20000,
+            },
+        ];
+        let storage = MultiFileStorage::new(files, 16384, 40000).unwrap();
+        assert_eq!(storage.disk_usage(), 0);
+        storage.write_piece(1, &[1; 16384]).unwrap();
+        assert_eq!(storage.disk_usage(), 16384);
+    }
+
+    #[test]
+    fn pieces_crossing_files_are_split_between_handles() {
+        let scratch = testutil::Scratch::dir("split");
+        let content = testutil::content(40000);
+        let files = vec![
+            TorrentFile {
+                path: scratch.join("a"),
+                offset: 0,
+                length: 20000,
+            },
+            TorrentFile {
+                path: scratch.join("b"),
+                offset: 20000,
+                length: 20000,
+            },
+        ];
+        let storage = MultiFileStorage::new(files, 16384, 40000).unwrap();
+        // the second piece ends the first file and starts the second
+        storage.write_piece(1, &content[16384..32768]).unwrap();
+        let a = std::fs::read(scratch.join("a")).unwrap();
+        let b = std::fs::read(scratch.join("b")).unwrap();
+        assert_eq!(a[16384..], content[16384..20000]);
+        assert_eq!(b[..12768], content[20000..32768]);
+        assert!(a[..16384].iter().chain(&b[12768..]).all(|byte| *byte == 0));
+        assert_eq!(
+            storage.read_piece(1).unwrap().unwrap(),
+            content[16384..32768]
+        );
+        assert!(storage.has_piece(1));
+        assert!(!storage.has_piece(0));
+    }
+
+    #[test]
+    fn missing_cache_pieces_are_not_skipped() {
+        let content = testutil::content(40_000);
+        let cache = |name: &str, on_missing| {
+            let storage = CacheStorage::new(Arc::new(name.to_string()), 3)
+                .with_piece_length(16384)
+                .with_length(content.len() as u64)
+                .with_missing_piece(on_missing);
+            // the piece in the middle never made it into the cache
+            for index in [0, 2] {
+                let start = index as usize * 16384;
+                let end = (start + 16384).min(content.len());
+                storage.write_piece(index, &content[start..end]).unwrap();
+            }
+            storage
+        };
+        let name = testutil::unique_name("missing");
+        let _output = testutil::Scratch::output(&name);
+        let err = cache(&name, MissingPiece::Error).finalize().unwrap_err();
+        assert!(err.to_string().contains("piece #1"), "{}", err);
+        assert!(!Path::new(&name).exists());
+
+        let name = testutil::unique_name("zero-fill");
+        let _output = testutil::Scratch::output(&name);
+        cache(&name, MissingPiece::ZeroFill).finalize().unwrap();
+        let output = std::fs::read(&name).unwrap();
+        assert_eq!(output.len(), content.len());
+        assert_eq!(output[..16384], content[..16384]);
+        assert!(output[16384..32768].iter().all(|byte| *byte == 0));
+        assert_eq!(output[32768..], content[32768..]);
+    }
+
+    #[test]
+    fn ring_evicts_the_oldest_pieces() {
+        let ring = RingStorage::new(3);
+        for index in 0..5 {
+            ring.write_piece(index, &[index as u8; 4]).unwrap();
+        }
+        assert_eq!(ring.window(), vec![2, 3, 4]);
+        assert!(!ring.has_piece(0) && !ring.has_piece(1));
+        assert_eq!(ring.read_piece(1).unwrap(), None);
+        assert_eq!(ring.read_piece(3).unwrap(), Some(vec![3; 4]));
+        // a piece fetched again after a seek back is the newest one
+        ring.write_piece(1, &[9; 4]).unwrap();
+        assert_eq!(ring.window(), vec![3, 4, 1]);
+        assert_eq!(ring.read_piece(1).unwrap(), Some(vec![9; 4]));
+    }
+
+    #[test]
+    fn ring_rewrite_keeps_one_copy() {
+        let ring = RingStorage::new(2);
+        ring.write_piece(0, &[1]).unwrap();
+        ring.write_piece(1, &[2]).unwrap();
+        ring.write_piece(1, &[3]).unwrap();
+        assert_eq!(ring.window(), vec![0, 1]);
+        assert_eq!(ring.read_piece(1).unwrap(), Some(vec![3]));
+        ring.discard_piece(0).unwrap();
+        assert_eq!(ring.window(), vec![1]);
+    }
+}
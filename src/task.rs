@@ -1,3 +1,5 @@
+use std::{collections::VecDeque, sync::Mutex};
+
 #[derive(Debug, Clone, Copy)]
 pub struct Task {
     pub index: u32,
@@ -14,3 +16,127 @@ impl Task {
         }
     }
 }
+
+/// number of pieces `length` bytes take. A length which is a multiple of the
+/// piece length has no short final piece, and no empty one either
+pub fn piece_count(length: u32, piece_length: u32) -> u32 {
+    length.div_ceil(piece_length)
+}
+
+/// length of piece `index`, only the final piece may be shorter
+pub fn piece_length_at(length: u32, piece_length: u32, index: u32) -> u32 {
+    piece_length.min(length - index * piece_length)
+}
+
+/// The pieces waiting for a peer or web seed, handed out in order. Shaped like
+/// the bounded queue it replaced, and additionally lets a peer pick a piece.
+#[derive(Debug)]
+pub struct TaskQueue {
+    capacity: usize,
+    tasks: Mutex<VecDeque<Task>>,
+}
+
+impl TaskQueue {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tasks: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// hand back a task taken from the queue, e.g. a piece a peer gave up or which
+    /// failed its hash. It was queued before, so it goes in even if the queue is full
+    pub fn requeue(&self, task: Task) {
+        self.tasks.lock().unwrap().push_back(task);
+    }
+
+    /// queue `task`, handing it back if the queue is full
+    pub fn push(&self, task: Task) -> Result<(), Task> {
+        let mut tasks = self.tasks.lock().unwrap();
+        if tasks.len() >= self.capacity {
+            return Err(task);
+        }
+        tasks.push_back(task);
+        Ok(())
+    }
+
+    pub fn pop(&self) -> Option<Task> {
+        self.tasks.lock().unwrap().pop_front()
+    }
+
+    /// the task of piece `index` if it is queued, otherwise the next one
+    pub fn pop_preferring(&self, index: u32) -> Option<Task> {
+        let mut tasks = self.tasks.lock().unwrap();
+        match tasks.iter().position(|task| task.index == index) {
+            Some(pos) => tasks.remove(pos),
+            None => tasks.pop_front(),
+        }
+    }
+
+    /// the task of the piece `pick` chooses among the queued ones, otherwise the next one
+    pub fn pop_picked<F>(&self, pick: F) -> Option<Task>
+    where
+        F: FnOnce(&[u32]) -> Option<u32>,
+    {
+        let mut tasks = self.tasks.lock().unwrap();
+        let indices: Vec<u32> = tasks.iter().map(|task| task.index).collect();
+        match pick(&indices).and_then(|index| indices.iter().position(|i| *i == index)) {
+            Some(pos) => tasks.remove(pos),
+            None => tasks.pop_front(),
+        }
+    }
+
+    /// how many tasks `push` accepts at most
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn contains(&self, index: u32) -> bool {
+        self.tasks
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|task| task.index == index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.tasks.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tasks.lock().unwrap().is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact_multiples_have_no_extra_piece() {
+        assert_eq!(piece_count(65536, 16384), 4);
+        assert_eq!(piece_length_at(65536, 16384, 3), 16384);
+    }
+
+    #[test]
+    fn a_remainder_is_one_short_piece() {
+        assert_eq!(piece_count(65537, 16384), 5);
+        assert_eq!(piece_length_at(65537, 16384, 3), 16384);
+        assert_eq!(piece_length_at(65537, 16384, 4), 1);
+    }
+
+    #[test]
+    fn requeues_never_fail_on_a_full_queue() {
+        let queue = TaskQueue::new(2);
+        let task = |index| Task::new(index, 16384, [0; 20]);
+        queue.push(task(0)).unwrap();
+        queue.push(task(1)).unwrap();
+        assert_eq!(queue.push(task(2)).unwrap_err().index, 2);
+        // handed back by many peers at once
+        for _ in 0..1000 {
+            queue.requeue(task(3));
+        }
+        assert_eq!(queue.len(), 1002);
+        assert_eq!(queue.pop().unwrap().index, 0);
+    }
+}
@@ -0,0 +1,423 @@
+//! Fixtures shared by the unit tests: torrents, scratch paths and a seeding peer.
+
+#![allow(dead_code)]
+
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+};
+
+use bytes::Bytes;
+use sha1::Digest;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    bencode::{BencodeFile, BencodeInfo, BencodeTorrent},
+    message::{Bitfield, HandShake, Message, Piece},
+    storage::MemoryStorage,
+    Torrent, TorrentClient, TorrentClientBuilder,
+};
+
+/// an address nothing listens on, announces to it fail right away
+pub const DEAD_TRACKER: &str = "http://127.0.0.1:9/announce";
+
+/// `len` bytes which differ from piece to piece
+pub fn content(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+}
+
+pub fn piece_hashes(content: &[u8], piece_length: u32) -> Vec<u8> {
+    content
+        .chunks(piece_length as usize)
+        .flat_map(|piece| <[u8; 20]>::from(sha1::Sha1::digest(piece)))
+        .collect()
+}
+
+pub fn info(name: &str, content: &[u8], piece_length: u32) -> BencodeInfo {
+    BencodeInfo {
+        pieces: Bytes::from(piece_hashes(content, piece_length)),
+        piece_length,
+        length: Some(content.len() as u32),
+        files: None,
+        name: name.to_string(),
+        private: None,
+    }
+}
+
+/// a multi-file info dictionary, the files laid out one after another in `content`
+pub fn multi_info(
+    name: &str,
+    files: &[(&str, usize)],
+    content: &[u8],
+    piece_length: u32,
+) -> BencodeInfo {
+    BencodeInfo {
+        pieces: Bytes::from(piece_hashes(content, piece_length)),
+        piece_length,
+        length: None,
+        files: Some(
+            files
+                .iter()
+                .map(|(path, length)| BencodeFile {
+                    length: *length as u32,
+                    path: path.split('/').map(String::from).collect(),
+                })
+                .collect(),
+        ),
+        name: name.to_string(),
+        private: None,
+    }
+}
+
+pub fn torrent_of(info: BencodeInfo) -> BencodeTorrent {
+    BencodeTorrent {
+        announce: DEAD_TRACKER.to_string(),
+        announce_list: None,
+        info,
+        url_list: None,
+        httpseeds: None,
+        comment: None,
+        comment_utf8: None,
+        created_by: None,
+        created_by_utf8: None,
+        creation_date: None,
+    }
+}
+
+/// a single-file `.torrent` of `content`
+pub fn torrent_bytes(name: &str, content: &[u8], piece_length: u32) -> Vec<u8> {
+    serde_bencode::to_bytes(&torrent_of(info(name, content, piece_length))).unwrap()
+}
+
+pub fn torrent(name: &str, content: &[u8], piece_length: u32) -> Torrent {
+    Torrent::from_bytes(&torrent_bytes(name, content, piece_length)).unwrap()
+}
+
+/// a builder for a single-file torrent of `content`, kept in memory and announcing
+/// to a tracker which is not there, so only the peers added to it are used
+pub fn builder(content: &[u8], piece_length: u32) -> TorrentClientBuilder {
+    builder_of(&torrent_of(info("rbt-test-memory", content, piece_length)))
+}
+
+/// like [`builder`], for a torrent of its own
+pub fn builder_of(torrent: &BencodeTorrent) -> TorrentClientBuilder {
+    TorrentClientBuilder::new()
+        .add_torrent_bytes(&serde_bencode::to_bytes(torrent).unwrap())
+        .unwrap()
+        .set_storage(Arc::new(MemoryStorage::new()))
+        .set_retry_policy(crate::RetryPolicy::none())
+}
+
+pub fn client(content: &[u8], piece_length: u32) -> TorrentClient {
+    builder(content, piece_length).build().unwrap()
+}
+
+/// a [`builder`] which knows one [`seed`] of `content`, behaving as `behavior` says
+pub async fn seeded(
+    content: &[u8],
+    piece_length: u32,
+    behavior: SeedBehavior,
+) -> TorrentClientBuilder {
+    let info_hash = torrent("rbt-test-memory", content, piece_length).info_hash;
+    let seed = seed(content.to_vec(), piece_length, info_hash, behavior).await;
+    builder(content, piece_length).add_peers(vec![seed])
+}
+
+/// poll `check` for up to five seconds, whether it came true
+pub async fn eventually<F: Fn() -> bool>(check: F) -> bool {
+    for _ in 0..100 {
+        if check() {
+            return true;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    false
+}
+
+/// a name no other test uses, for outputs created in the working directory
+pub fn unique_name(tag: &str) -> String {
+    static NEXT: AtomicU32 = AtomicU32::new(0);
+    format!(
+        "rbt-test-{}-{}-{}",
+        tag,
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// A scratch path, removed with everything below it when dropped.
+#[derive(Debug)]
+pub struct Scratch(PathBuf);
+
+impl Scratch {
+    /// a path in the temporary directory, created as a directory
+    pub fn dir(tag: &str) -> Self {
+        let path = std::env::temp_dir().join(unique_name(tag));
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    /// an output of the client in the working directory, named after its torrent
+    pub fn output(name: &str) -> Self {
+        Self(PathBuf::from(name))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join(&self, path: &str) -> PathBuf {
+        self.0.join(path)
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+        let _ = std::fs::remove_file(&self.0);
+        for suffix in [".rbt", ".rbt.tmp", ".part", ".cache"] {
+            let mut path = self.0.clone().into_os_string();
+            path.push(suffix);
+            let _ = std::fs::remove_dir_all(&path);
+            let _ = std::fs::remove_file(&path);
+        }
+    }
+}
+
+/// How a [`seed`] behaves towards the client.
+#[derive(Debug, Clone, Default)]
+pub struct SeedBehavior {
+    /// pieces served with flipped bytes
+    pub corrupt: Vec<u32>,
+    /// hang up after serving this many blocks
+    pub blocks: Option<usize>,
+    /// sent in place of the full bitfield
+    pub bitfield: Option<Vec<u8>>,
+    /// messages sent right after the handshake
+    pub greeting: Vec<Message>,
+    /// never unchoke the client
+    pub choke: bool,
+    /// wait this long before serving each block
+    pub delay: Option<std::time::Duration>,
+    /// peer id in the handshake
+    pub peer_id: Option<[u8; 20]>,
+    /// info hash in the handshake, the torrent's by default
+    pub info_hash: Option<[u8; 20]>,
+    /// hang up this long after the handshake
+    pub hang_up: Option<std::time::Duration>,
+    /// every request received, as (index, begin, length), shared by the clones
+    pub requests: Arc<std::sync::Mutex<Vec<(u32, u32, u32)>>>,
+}
+
+/// a peer listening on localhost which has every piece of `content`, serving
+/// each connection as `behavior` says
+pub async fn seed(
+    content: Vec<u8>,
+    piece_length: u32,
+    info_hash: [u8; 20],
+    behavior: SeedBehavior,
+) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let content = Arc::new(content);
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let (content, behavior) = (content.clone(), behavior.clone());
+            tokio::spawn(async move {
+                let _ = serve(stream, &content, piece_length, info_hash, &behavior).await;
+            });
+        }
+    });
+    addr
+}
+
+async fn serve(
+    mut stream: TcpStream,
+    content: &[u8],
+    piece_length: u32,
+    info_hash: [u8; 20],
+    behavior: &SeedBehavior,
+) -> anyhow::Result<()> {
+    let mut handshake = [0; 68];
+    stream.read_exact(&mut handshake).await?;
+    let ours = HandShake::new(
+        &behavior.info_hash.unwrap_or(info_hash),
+        &behavior.peer_id.unwrap_or(*b"-SD0001-000000000000"),
+    );
+    stream.write_all(&ours.as_bytes()).await?;
+    let piece_num = content.len().div_ceil(piece_length as usize);
+    let bitfield = behavior.bitfield.clone().unwrap_or_else(|| {
+        let mut bitfield = Bitfield::new(piece_num.div_ceil(8) as u32);
+        (0..piece_num as u32).for_each(|index| bitfield.set_piece(index));
+        bitfield.as_bytes().to_vec()
+    });
+    stream
+        .write_all(&Message::Bitfield(Bitfield::from(&bitfield)).as_bytes())
+        .await?;
+    for message in behavior.greeting.iter() {
+        stream.write_all(&message.as_bytes()).await?;
+    }
+    if !behavior.choke {
+        stream.write_all(&Message::UnChoke.as_bytes()).await?;
+    }
+    let mut served = 0;
+    let deadline = behavior
+        .hang_up
+        .map(|after| tokio::time::Instant::now() + after);
+    loop {
+        let length = match deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline, stream.read_u32()).await {
+                Ok(length) => length?,
+                Err(_) => return Ok(()),
+            },
+            None => stream.read_u32().await?,
+        } as usize;
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).await?;
+        if body.first() != Some(&6) {
+            continue;
+        }
+        if behavior.blocks.is_some_and(|blocks| served >= blocks) {
+            return Ok(());
+        }
+        let field = |at: usize| u32::from_be_bytes(body[at..at + 4].try_into().unwrap());
+        let (index, begin, len) = (field(1), field(5), field(9));
+        behavior.requests.lock().unwrap().push((index, begin, len));
+        let start = index as usize * piece_length as usize + begin as usize;
+        let mut block = content[start..start + len as usize].to_vec();
+        if behavior.corrupt.contains(&index) {
+            block.iter_mut().for_each(|byte| *byte = !*byte);
+        }
+        if let Some(delay) = behavior.delay {
+            tokio::time::sleep(delay).await;
+        }
+        stream
+            .write_all(&Message::Piece(Piece::new(index, begin, &block)).as_bytes())
+            .await?;
+        served += 1;
+    }
+}
+
+/// An HTTP server on localhost answering each request as its handler says,
+/// keeping the request lines it got.
+#[derive(Debug, Clone)]
+pub struct HttpStub {
+    pub addr: SocketAddr,
+    pub requests: Arc<std::sync::Mutex<Vec<String>>>,
+}
+
+/// status, extra header lines and body of a [`HttpStub`] response
+pub type HttpResponse = (u16, String, Vec<u8>);
+
+impl HttpStub {
+    /// answer every request with `status` and `body`
+    pub async fn new(status: u16, content_type: &str, body: Vec<u8>) -> Self {
+        Self::with_headers(status, &format!("Content-Type: {}\r\n", content_type), body).await
+    }
+
+    pub async fn with_headers(status: u16, headers: &str, body: Vec<u8>) -> Self {
+        let headers = headers.to_string();
+        Self::with_handler(move |_| (status, headers.clone(), body.clone())).await
+    }
+
+    /// serve `files` by path, honouring `Range` headers like a plain file server
+    pub async fn files(files: Vec<(&str, Vec<u8>)>) -> Self {
+        let files: std::collections::HashMap<String, Vec<u8>> = files
+            .into_iter()
+            .map(|(path, data)| (path.to_string(), data))
+            .collect();
+        Self::with_handler(move |request| {
+            let path = request.split(' ').nth(1).unwrap_or_default();
+            let Some(data) = files.get(path) else {
+                return (404, String::new(), vec![]);
+            };
+            let range = request.lines().find_map(|line| {
+                let (from, to) = line
+                    .to_ascii_lowercase()
+                    .strip_prefix("range: bytes=")?
+                    .split_once('-')
+                    .map(|(from, to)| (from.parse::<usize>(), to.parse::<usize>()))?;
+                Some((from.ok()?, to.ok()?))
+            });
+            match range {
+                Some((from, to)) if from <= to && to < data.len() => {
+                    (206, String::new(), data[from..=to].to_vec())
+                }
+                Some(_) => (416, String::new(), vec![]),
+                None => (200, String::new(), data.clone()),
+            }
+        })
+        .await
+    }
+
+    /// answer each request, given as its head, with what `handler` returns
+    pub async fn with_handler<F>(handler: F) -> Self
+    where
+        F: Fn(&str) -> HttpResponse + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests: Arc<std::sync::Mutex<Vec<String>>> = Default::default();
+        let log = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = vec![0; 8192];
+                let mut read = 0;
+                while !buf[..read].windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut buf[read..]).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => read += n,
+                    }
+                }
+                let request = String::from_utf8_lossy(&buf[..read]).into_owned();
+                log.lock()
+                    .unwrap()
+                    .push(request.lines().next().unwrap_or_default().to_string());
+                let (status, headers, body) = handler(&request);
+                let head = format!(
+                    "HTTP/1.1 {} X\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    headers,
+                    body.len()
+                );
+                let _ = stream.write_all(head.as_bytes()).await;
+                let _ = stream.write_all(&body).await;
+            }
+        });
+        Self { addr, requests }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+/// a port nothing listens on right now, for a client which has to listen on its own
+pub fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// both ends of a localhost connection
+pub async fn tcp_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (server, _) = listener.accept().await.unwrap();
+    (client, server)
+}
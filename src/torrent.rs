@@ -1,120 +1,2726 @@
-use std::{fs::remove_dir_all, path::PathBuf, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    future::Future,
+    io::{Read, Seek, SeekFrom, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
-use anyhow::Result;
-use crossbeam::{queue::ArrayQueue, sync::WaitGroup};
+use anyhow::{anyhow, Result};
+use futures_util::{stream, Stream};
 use indicatif::ProgressBar;
-use log::info;
+use log::{info, warn};
+use serde::Serialize;
+use tokio::{
+    net::TcpListener,
+    sync::{
+        broadcast::{self, error::RecvError},
+        watch, Semaphore,
+    },
+    task::{spawn_blocking, JoinSet},
+    time::sleep_until,
+};
 
-use crate::{message::Bitfield, peer::Peers, task::Task};
+use crate::{
+    assembly::PieceAssembly,
+    bandwidth::{Bandwidth, BandwidthSchedule, RateLimits},
+    control::ControlFile,
+    error::RbitError,
+    event::Event,
+    files::{self, TorrentFile},
+    message::{Bitfield, Extension},
+    peer::{Peer, PeerIdFilter, Peers, SocketOptions},
+    picker::PiecePicker,
+    pool::BlockPool,
+    portmap::{self, PortMapping},
+    retry::RetryPolicy,
+    stats::{
+        AnnounceResult, DownloadStats, MessageCounts, PeerHistory, PeerStats, QuickVerify,
+        RateWindow, TorrentStatus, TrackerStats, TrackerStatus,
+    },
+    storage::{DiskQuota, Storage},
+    task::{self, Task, TaskQueue},
+    tracker::{self, AnnounceEvent, AnnounceRequest, TrackerFilter},
+    verifier::PieceVerifier,
+    webseed::{WebSeed, WebSeedPolicy},
+};
 
 #[derive(Debug)]
 pub struct TorrentClient {
     pub announce: String,
+    pub trackers: Mutex<Vec<String>>,
     pub info_hash: [u8; 20],
     pub piece_hashes: Vec<[u8; 20]>,
     pub piece_length: u32,
     pub length: u32,
+    pub files: Vec<TorrentFile>,
     pub name: Arc<String>,
     pub id: [u8; 20],
     pub port: u16,
-    pub task_queue: Arc<ArrayQueue<Task>>,
-    pub bitfield: Bitfield,
+    pub task_queue: Arc<TaskQueue>,
+    pub bitfield: Arc<Mutex<Bitfield>>,
     pub pb: ProgressBar,
+    pub downloaded: Arc<AtomicU64>,
+    pub uploaded: Arc<AtomicU64>,
+    pub control_file: bool,
+    pub reannounce_attempts: u32,
+    pub prefer_local_peers: bool,
+    pub web_seeds: Vec<String>,
+    pub http_seeds: Vec<String>,
+    pub webseed_policy: WebSeedPolicy,
+    pub webseed_timeout: Duration,
+    pub availability: Arc<Mutex<Vec<u16>>>,
+    pub blocks_completed: Arc<AtomicU64>,
+    pub pieces_checked: Arc<AtomicU64>,
+    pub shutdown: watch::Sender<bool>,
+    pub storage: Arc<dyn Storage>,
+    pub retry_policy: RetryPolicy,
+    pub peer_stats: Arc<Mutex<HashMap<SocketAddr, PeerStats>>>,
+    pub block_pool: Arc<BlockPool>,
+    pub seed_ratio_limit: Option<f64>,
+    pub seed_time_limit: Option<Duration>,
+    pub assemble: bool,
+    pub max_piece_time: Option<Duration>,
+    pub min_peer_rate: Option<u64>,
+    pub slow_peer_rate: Option<u64>,
+    pub keep_alive_interval: Duration,
+    pub idle_timeout: Duration,
+    pub bandwidth: Arc<Bandwidth>,
+    pub bandwidth_schedule: Option<BandwidthSchedule>,
+    pub socket_options: SocketOptions,
+    pub assembly: PieceAssembly,
+    pub events: broadcast::Sender<Event>,
+    pub prefer_known_peers: bool,
+    pub peer_history: Arc<Mutex<HashMap<SocketAddr, PeerHistory>>>,
+    pub disk_quota: Option<Arc<DiskQuota>>,
+    pub connect_permits: Arc<Semaphore>,
+    pub external_ip: Arc<Mutex<Option<IpAddr>>>,
+    pub announce_interval: Mutex<Duration>,
+    pub announce_jitter: f64,
+    pub state: Mutex<TorrentState>,
+    pub verify_limit: Arc<Semaphore>,
+    pub private: bool,
+    /// the comment of the torrent file, absent for magnet links
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    pub initial_peers: Vec<SocketAddr>,
+    pub announce_param_order: Option<Vec<String>>,
+    pub rate_window: Mutex<RateWindow>,
+    pub tracker_filter: Option<TrackerFilter>,
+    pub peer_id_filter: Option<PeerIdFilter>,
+    pub tracker_stats: Mutex<HashMap<String, TrackerStats>>,
+    pub extensions: Vec<Extension>,
+    pub stall_window: Option<Duration>,
+    /// how often each piece failed its hash check
+    pub piece_failures: Arc<Mutex<HashMap<u32, u32>>>,
+    pub max_piece_failures: u32,
+    pub piece_picker: Arc<dyn PiecePicker>,
+    /// where the output is moved once complete, see
+    /// [`crate::TorrentClientBuilder::set_completed_dir`]
+    pub completed_dir: Option<PathBuf>,
+    pub message_totals: Arc<Mutex<MessageCounts>>,
+    pub metadata_only: bool,
+    pub pipeline_depth: usize,
+    pub piece_affinity: bool,
+    pub port_mapping: bool,
+    pub gateway: Option<SocketAddr>,
+    pub mapping: Mutex<Option<PortMapping>>,
+    pub max_response_size: usize,
+}
+
+/// Where a torrent is in its life.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TorrentState {
+    /// not started yet
+    #[default]
+    Idle,
+    Downloading,
+    /// complete and staying in the swarm until a seed limit is reached
+    Seeding,
+    /// shut down before it was complete
+    Paused,
+    /// gave up as no tracker knew any peer, see [`RbitError::NoPeers`]
+    NoPeers,
+    Complete,
+    /// stopped with the error it failed on
+    Error(String),
 }
 
 impl TorrentClient {
+    /// announce to the trackers in order until one of them answers
     pub async fn look_for_peers(&self, peer_id: [u8; 20], port: u16) -> Result<Peers> {
-        let info_hash_query = format!(
-            "info_hash={}",
-            url::form_urlencoded::byte_serialize(&self.info_hash[..]).collect::<String>()
-        );
-        let peer_id_query = format!(
-            "peer_id={}",
-            url::form_urlencoded::byte_serialize(&peer_id[..]).collect::<String>()
-        );
-
-        let mut url = url::Url::parse(&self.announce)?;
-        url.set_query(Some(&format!("{}&{}", info_hash_query, peer_id_query)));
-
-        let res = reqwest::ClientBuilder::new()
-            .user_agent("rbittorrent/0.1.0")
-            .build()?
-            .get(url)
-            .query(&[
-                ("port", &port.to_string()),
-                ("uploaded", &"0".to_string()),
-                ("downloaded", &"0".to_string()),
-                ("compact", &"1".to_string()),
-                ("left", &self.length.to_string()),
-            ])
-            .send()
-            .await
-            .unwrap();
-
-        Peers::new(
-            &res.bytes().await?,
-            self.task_queue.clone(),
-            self.name.clone(),
-            self.pb.clone(),
+        let request = AnnounceRequest {
+            info_hash: self.info_hash,
+            peer_id,
+            port,
+            uploaded: self.uploaded.load(Ordering::Relaxed),
+            downloaded: self.downloaded.load(Ordering::Relaxed),
+            left: self.remaining_bytes(),
+            event: None,
+            param_order: self.announce_param_order.clone(),
+            max_response_size: self.max_response_size,
+        };
+        let mut last_err = None;
+        let trackers = self.trackers();
+        for tracker in trackers.iter() {
+            if let Err(err) = url::Url::parse(tracker) {
+                warn!("skip unparseable announce url {}: {}", tracker, err);
+                continue;
+            }
+            match request.send_with_retries(tracker, &self.retry_policy).await {
+                Ok(report) => {
+                    if report.interval > 0 {
+                        *self.announce_interval.lock().unwrap() =
+                            Duration::from_secs(report.interval as u64);
+                    }
+                    if let Some(ip) = report.external_ip() {
+                        *self.external_ip.lock().unwrap() = Some(ip);
+                    }
+                    let peers: Vec<_> = report
+                        .resolve_peers()
+                        .await
+                        .into_iter()
+                        .filter(|addr| {
+                            let is_self = self.is_self(addr);
+                            if is_self {
+                                info!("skip peer {}, it is ourselves", addr);
+                            }
+                            !is_self
+                        })
+                        .collect();
+                    self.record_announce(tracker, AnnounceResult::Success, peers.len());
+                    let _ = self.events.send(Event::TrackerAnnounced {
+                        tracker: tracker.clone(),
+                        peers: peers.len(),
+                    });
+                    return Ok(Peers::new(&peers, self));
+                }
+                Err(err) => {
+                    info!("tracker {} failed: {}", tracker, err);
+                    let result = if tracker::is_timeout(&err) {
+                        AnnounceResult::Timeout
+                    } else {
+                        AnnounceResult::Failure(err.to_string())
+                    };
+                    self.record_announce(tracker, result, 0);
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err
+            .unwrap_or_else(|| anyhow!("no valid announce url among: {}", trackers.join(", "))))
+    }
+
+    fn record_announce(&self, tracker: &str, result: AnnounceResult, peers: usize) {
+        let mut stats = self.tracker_stats.lock().unwrap();
+        let stats = stats
+            .entry(tracker.to_string())
+            .or_insert_with(|| TrackerStats {
+                result: AnnounceResult::Success,
+                peers: 0,
+                announces: 0,
+                last_announce: Instant::now(),
+            });
+        if result == AnnounceResult::Success {
+            stats.peers = peers;
+        }
+        stats.result = result;
+        stats.announces += 1;
+        stats.last_announce = Instant::now();
+    }
+
+    /// how the last announce to each tracker asked so far went, e.g. to prune dead ones
+    pub fn tracker_stats(&self) -> HashMap<String, TrackerStats> {
+        self.tracker_stats.lock().unwrap().clone()
+    }
+
+    fn spawn_peer(&self, workers: &mut JoinSet<()>, peer: Peer) {
+        let info_hash = self.info_hash;
+        let peer_id = self.id;
+        workers.spawn(async move {
+            if let Err(err) = peer.try_download(&info_hash, &peer_id).await {
+                info!("{}", err);
+            }
+        });
+    }
+
+    /// the tracker interval with up to `announce_jitter` of it added or taken away,
+    /// so that clients which started together do not keep announcing together
+    pub fn next_announce_delay(&self) -> Duration {
+        let interval = *self.announce_interval.lock().unwrap();
+        jittered(interval, self.announce_jitter, random_unit())
+    }
+
+    /// snapshot of the trackers announced to, in order
+    pub fn trackers(&self) -> Vec<String> {
+        self.trackers.lock().unwrap().clone()
+    }
+
+    /// announce to `trackers` as well from the next announce on. A private torrent
+    /// only uses the trackers it names, so it refuses any others
+    pub fn add_trackers(&self, trackers: Vec<String>) -> Result<()> {
+        if self.private {
+            return Err(anyhow!(
+                "{} is a private torrent, it cannot use other trackers",
+                self.name
+            ));
+        }
+        let mut current = self.trackers.lock().unwrap();
+        for tracker in trackers {
+            if self
+                .tracker_filter
+                .as_ref()
+                .is_some_and(|filter| !filter.allows(&tracker))
+            {
+                info!("skip filtered tracker {}", tracker);
+                continue;
+            }
+            if !current.contains(&tracker) {
+                current.push(tracker);
+            }
+        }
+        Ok(())
+    }
+
+    /// whether `addr` is our own listen address, so connecting would reach ourselves
+    pub fn is_self(&self, addr: &SocketAddr) -> bool {
+        addr.port() == self.port
+            && (addr.ip().is_loopback()
+                || addr.ip().is_unspecified()
+                || Some(addr.ip()) == *self.external_ip.lock().unwrap())
+    }
+
+    fn task(&self, index: u32) -> Task {
+        Task::new(
+            index,
+            self.piece_length_of(index),
+            self.piece_hashes[index as usize],
         )
     }
 
-    fn assign_tasks(&self) -> Result<()> {
-        for index in 0..self.length / self.piece_length {
-            if !self.bitfield.has_piece(index) {
-                let task = Task::new(index, self.piece_length, self.piece_hashes[index as usize]);
-                self.task_queue.push(task).unwrap();
+    /// length of piece `index`, the final piece is shorter unless the length
+    /// is a multiple of the piece length
+    pub fn piece_length_of(&self, index: u32) -> u32 {
+        task::piece_length_at(self.length, self.piece_length, index)
+    }
+
+    /// pick up the progress of an earlier run of the same torrent.
+    ///
+    /// The control file is trusted as long as it was written for the same info hash
+    /// and layout, so a torrent with other trackers resumes right away. Anything else
+    /// means the content may have changed, e.g. a new version of the torrent with
+    /// another length, so the control file is dropped and every cached piece is
+    /// hashed again.
+    pub async fn resume(&self) -> Result<()> {
+        let verifier = PieceVerifier::new(self);
+        match ControlFile::load(self.control_file_path()) {
+            Ok(control)
+                if control.info_hash == self.info_hash
+                    && control.piece_length == self.piece_length
+                    && control.length == self.length =>
+            {
+                info!("resume from control file {:?}", self.control_file_path());
+                for index in 0..self.piece_num() {
+                    if control.bitfield.has_piece(index) && self.storage.has_piece(index) {
+                        let task = self.task(index);
+                        verifier.mark_verified(&task);
+                        self.pb.inc(task.piece_length as _);
+                    }
+                }
+                self.downloaded.store(control.downloaded, Ordering::Relaxed);
+                self.uploaded.store(control.uploaded, Ordering::Relaxed);
+            }
+            Ok(control) => {
+                info!(
+                    "control file {:?} is stale, it was written for {} bytes in pieces of {}",
+                    self.control_file_path(),
+                    control.length,
+                    control.piece_length
+                );
+                std::fs::remove_file(self.control_file_path())?;
+                self.verify_existing().await?
+            }
+            Err(_) => self.verify_existing().await?,
+        }
+        Ok(())
+    }
+
+    /// hash every cached piece and mark the intact ones as verified.
+    ///
+    /// Progress is counted in [`TorrentClient::verify_progress`], and a shutdown
+    /// stops the pass, leaving the unchecked pieces to be downloaded. Every piece
+    /// takes a permit of the verify limit, so verifying in the background never
+    /// takes more than its share of threads and disk from active downloads.
+    pub async fn verify_existing(&self) -> Result<()> {
+        let verifier = PieceVerifier::new(self);
+        self.pieces_checked.store(0, Ordering::Relaxed);
+        for index in 0..self.piece_num() {
+            if self.is_shutdown() {
+                info!(
+                    "verification of {} cancelled at piece #{}",
+                    self.name, index
+                );
+                return Ok(());
+            }
+            let task = self.task(index);
+            let stored = {
+                let _permit = self.verify_limit.acquire().await?;
+                let verifier = verifier.clone();
+                spawn_blocking(move || verifier.is_stored(&task)).await?
+            };
+            self.pieces_checked.fetch_add(1, Ordering::Relaxed);
+            if stored {
+                verifier.mark_verified(&task);
+                self.pb.inc(task.piece_length as _);
+            }
+            tokio::task::yield_now().await;
+        }
+        Ok(())
+    }
+
+    fn assign_tasks(&self, pieces: Range<u32>) -> Result<()> {
+        let bitfield = self.bitfield.lock().unwrap();
+        for index in pieces {
+            // a piece queued by an earlier download of an overlapping range stays once
+            if !bitfield.has_piece(index) && !self.task_queue.contains(index) {
+                self.task_queue.push(self.task(index)).map_err(|task| {
+                    anyhow!(
+                        "task queue of {} is full at {} tasks, cannot queue #{}",
+                        self.name,
+                        self.task_queue.capacity(),
+                        task.index
+                    )
+                })?;
             }
         }
         Ok(())
     }
 
+    /// download the torrent and assemble it, once this returns `Ok` the output and
+    /// its directory entry are synced to disk
     pub async fn send_request(&self) -> Result<()> {
-        self.assign_tasks()?;
-        let peers = self.look_for_peers(self.info_hash, self.port).await?;
-        let wg = WaitGroup::new();
-        for peer in peers.into_iter() {
-            tokio::spawn({
-                let wg = wg.clone();
-                let info_hash = self.info_hash;
-                let peer_id = self.id;
-                async move {
-                    if let Err(err) = peer.try_download(&info_hash, &peer_id).await {
+        if self.metadata_only {
+            info!("resolved the metadata of {}, not downloading it", self.name);
+            return Ok(());
+        }
+        self.set_state(TorrentState::Downloading);
+        let res = self.download(0..self.piece_num()).await.and_then(|_| {
+            if self.assemble {
+                self.assemble()?;
+            }
+            Ok(())
+        });
+        if let Err(err) = &res {
+            self.remove_port_mapping().await;
+            self.set_state(if self.is_shutdown() {
+                TorrentState::Paused
+            } else if let Some(RbitError::NoPeers) = err.downcast_ref() {
+                TorrentState::NoPeers
+            } else {
+                TorrentState::Error(err.to_string())
+            });
+            return res;
+        }
+        let _ = self.events.send(Event::DownloadComplete);
+        if self.seed_ratio_limit.is_some() || self.seed_time_limit.is_some() {
+            self.set_state(TorrentState::Seeding);
+            self.seed().await;
+            // the upload total carries over to the next run, e.g. for a private tracker
+            if self.control_file {
+                if let Err(err) = self.save_control_file() {
+                    warn!("failed to save the control file of {}: {}", self.name, err);
+                }
+            }
+        }
+        self.remove_port_mapping().await;
+        // without assembling there is no output to move yet
+        if let (true, Some(dir)) = (self.assemble, &self.completed_dir) {
+            match files::move_into(Path::new(self.name.as_str()), dir) {
+                Ok(dest) => info!("moved {} to {:?}", self.name, dest),
+                Err(err) => {
+                    self.set_state(TorrentState::Error(err.to_string()));
+                    return Err(err);
+                }
+            }
+        }
+        self.set_state(TorrentState::Complete);
+        Ok(())
+    }
+
+    /// set the bandwidth to the limits the schedule has for now, if there is one
+    pub fn apply_bandwidth_schedule(&self) -> Option<RateLimits> {
+        let limits = self.bandwidth_schedule.as_ref()?.current_limits();
+        if self.bandwidth.limits() != limits {
+            info!("bandwidth of {} limited to {:?}", self.name, limits);
+            self.bandwidth.apply(limits);
+        }
+        Some(limits)
+    }
+
+    pub fn state(&self) -> TorrentState {
+        self.state.lock().unwrap().clone()
+    }
+
+    fn set_state(&self, state: TorrentState) {
+        *self.state.lock().unwrap() = state;
+    }
+
+    /// download the whole torrent and return its content instead of assembling it.
+    /// Together with [`crate::storage::MemoryStorage`] nothing is written to disk
+    pub async fn download_to_vec(&self) -> Result<Vec<u8>> {
+        self.download(0..self.piece_num()).await?;
+        let mut content = Vec::with_capacity(self.length as usize);
+        for index in 0..self.piece_num() {
+            let data = self
+                .storage
+                .read_piece(index)?
+                .ok_or_else(|| anyhow!("piece #{} is missing from the storage", index))?;
+            content.extend_from_slice(&data);
+        }
+        let _ = self.events.send(Event::DownloadComplete);
+        Ok(content)
+    }
+
+    /// download only the pieces holding the file at `path` and write it to `dest`
+    pub async fn download_file<T>(&self, path: &str, dest: T) -> Result<()>
+    where
+        T: AsRef<Path>,
+    {
+        let file = self
+            .files()
+            .into_iter()
+            .find(|file| file.path == Path::new(path))
+            .ok_or_else(|| anyhow!("no file {} in torrent {}", path, self.name))?;
+        let pieces = file.pieces(self.piece_length);
+        self.download(pieces.start..pieces.end.min(self.piece_num()))
+            .await?;
+        let mut out = std::fs::File::create(dest)?;
+        for index in pieces {
+            let data = self
+                .storage
+                .read_piece(index)?
+                .ok_or_else(|| anyhow!("piece #{} is missing from the storage", index))?;
+            let begin = index as u64 * self.piece_length as u64;
+            let range = file.range();
+            let start = range.start.max(begin) - begin;
+            let end = range.end.min(begin + data.len() as u64) - begin;
+            out.write_all(&data[start as usize..end as usize])?;
+        }
+        Ok(())
+    }
+
+    /// files of the torrent in the order they are laid out in the content
+    pub fn files(&self) -> Vec<TorrentFile> {
+        self.files.clone()
+    }
+
+    /// whether the content is a directory of files rather than a single file
+    pub fn is_multi_file(&self) -> bool {
+        self.files.len() > 1 || self.files[0].path != Path::new(self.name.as_str())
+    }
+
+    /// per file its path, the bytes covered by verified pieces and its length.
+    /// A piece spanning several files credits each of them with its share
+    pub fn file_progress(&self) -> Vec<(PathBuf, u64, u64)> {
+        let bitfield = self.bitfield.lock().unwrap();
+        let piece_length = self.piece_length as u64;
+        self.files()
+            .into_iter()
+            .map(|file| {
+                let range = file.range();
+                let completed = file
+                    .pieces(self.piece_length)
+                    .filter(|index| bitfield.has_piece(*index))
+                    .map(|index| {
+                        let begin = index as u64 * piece_length;
+                        let end = (begin + piece_length).min(self.length as u64);
+                        range.end.min(end) - range.start.max(begin)
+                    })
+                    .sum();
+                (file.path, completed, file.length)
+            })
+            .collect()
+    }
+
+    /// fetch `pieces` from the swarm and web seeds until they are all verified
+    async fn download(&self, pieces: Range<u32>) -> Result<()> {
+        if self.metadata_only {
+            return Err(anyhow!(
+                "{} only resolves the metadata, it downloads no content",
+                self.name
+            ));
+        }
+        if self.control_file {
+            self.resume().await?;
+        } else if pieces.clone().any(|index| self.storage.has_piece(index)) {
+            self.verify_existing().await?;
+        }
+        // e.g. a re-run of a finished download, no need to bother any peer
+        if self.missing_pieces(&pieces) == 0 {
+            info!("{} is complete already", self.name);
+            self.pb.finish();
+            return Ok(());
+        }
+        self.assign_tasks(pieces.clone())?;
+        let mut attempts = 0;
+        loop {
+            let mut peers = match self
+                .look_for_peers(self.id, self.announce_port().await)
+                .await
+            {
+                Ok(peers) => peers,
+                // known peers or web seeds alone are enough to get going
+                Err(err) if attempts == 0 && !self.initial_peers.is_empty() => {
+                    info!("announce failed, using the known peers only: {}", err);
+                    Peers::new(&[], self)
+                }
+                Err(err)
+                    if attempts == 0
+                        && !(self.web_seeds.is_empty() && self.http_seeds.is_empty()) =>
+                {
+                    info!("announce failed, using the web seeds only: {}", err);
+                    Peers::new(&[], self)
+                }
+                // the peers left and no others can be found, say so rather than
+                // surfacing only the tracker error
+                Err(err) if attempts > 0 => {
+                    info!("re-announce failed: {}", err);
+                    self.pb.abandon();
+                    if self.control_file {
+                        self.save_control_file()?;
+                    }
+                    let remaining = self.missing_pieces(&pieces);
+                    return Err(RbitError::Incomplete { remaining }.into());
+                }
+                Err(err) => return Err(err),
+            };
+            if attempts == 0 {
+                for addr in self.initial_peers.iter() {
+                    peers.push(Peer::new(addr.ip(), addr.port(), self));
+                }
+            }
+            if self.prefer_known_peers {
+                peers.prioritize_known(&self.peer_history.lock().unwrap());
+            }
+            if self.prefer_local_peers {
+                peers.prioritize_local();
+            }
+            let mut workers = JoinSet::new();
+            let mut dialed = HashSet::new();
+            for peer in peers.into_iter() {
+                dialed.insert(peer.addr());
+                self.spawn_peer(&mut workers, peer);
+            }
+            let seeds = self
+                .web_seeds
+                .iter()
+                .map(|url| WebSeed::new(url, self))
+                .chain(
+                    self.http_seeds
+                        .iter()
+                        .map(|url| WebSeed::http_seed(url, self)),
+                )
+                .map(|seed| seed.with_pieces(pieces.clone()));
+            let mut seeded = 0;
+            for seed in seeds {
+                seeded += 1;
+                let policy = self.webseed_policy;
+                let fallback_timeout = self.webseed_timeout;
+                workers.spawn(async move {
+                    if let Err(err) = seed.run(policy, fallback_timeout).await {
                         info!("{}", err);
                     }
-                    drop(wg)
+                });
+            }
+            // announce again on the tracker interval while peers are busy, joining new ones
+            let mut next_announce = Instant::now() + self.next_announce_delay();
+            let mut progress = (self.downloaded.load(Ordering::Relaxed), Instant::now());
+            let stall_window = self.stall_window.unwrap_or_default();
+            let mut next_schedule = Instant::now();
+            loop {
+                tokio::select! {
+                    _ = sleep_until(next_schedule.into()), if self.bandwidth_schedule.is_some() => {
+                        self.apply_bandwidth_schedule();
+                        next_schedule = Instant::now() + BandwidthSchedule::CHECK_INTERVAL;
+                    },
+                    joined = workers.join_next() => if joined.is_none() {
+                        break;
+                    },
+                    _ = sleep_until((progress.1 + stall_window).into()), if self.stall_window.is_some() => {
+                        let downloaded = self.downloaded.load(Ordering::Relaxed);
+                        if downloaded == progress.0 {
+                            // the peers choke us or lack what we need, look for others
+                            // and give those which dropped out another chance
+                            info!(
+                                "{} stalled for {:?}, re-announcing",
+                                self.name, stall_window
+                            );
+                            let connected = self.peer_stats.lock().unwrap();
+                            dialed.retain(|addr| connected.contains_key(addr));
+                            next_announce = Instant::now();
+                        }
+                        progress = (downloaded, Instant::now());
+                    },
+                    _ = sleep_until(next_announce.into()) => {
+                        next_announce = Instant::now() + self.next_announce_delay();
+                        match self.look_for_peers(self.id, self.announce_port().await).await {
+                            Ok(peers) => {
+                                for peer in peers.into_iter() {
+                                    if dialed.insert(peer.addr()) {
+                                        self.spawn_peer(&mut workers, peer);
+                                    }
+                                }
+                            }
+                            Err(err) => info!("re-announce failed: {}", err),
+                        }
+                    }
                 }
-            });
+            }
+
+            let remaining = self.missing_pieces(&pieces);
+            if remaining == 0 {
+                break;
+            }
+            if let Some((index, attempts)) = self.abandoned_piece() {
+                self.pb.abandon();
+                if self.control_file {
+                    self.save_control_file()?;
+                }
+                return Err(RbitError::PieceFailed { index, attempts }.into());
+            }
+            // an empty swarm is not a download which fell apart half way
+            let no_peers = dialed.is_empty() && seeded == 0;
+            if attempts >= self.reannounce_attempts || self.is_shutdown() {
+                self.pb.abandon();
+                if self.control_file {
+                    self.save_control_file()?;
+                }
+                if no_peers && !self.is_shutdown() {
+                    return Err(RbitError::NoPeers.into());
+                }
+                return Err(RbitError::Incomplete { remaining }.into());
+            }
+            attempts += 1;
+            match no_peers {
+                true => info!(
+                    "no peers for {}, re-announcing ({}/{})",
+                    self.name, attempts, self.reannounce_attempts
+                ),
+                false => info!(
+                    "all peers disconnected with {} pieces remaining, re-announcing ({}/{})",
+                    remaining, attempts, self.reannounce_attempts
+                ),
+            }
         }
-        wg.wait();
         self.pb.finish();
-        self.concat_cache()?;
+        if self.control_file {
+            self.save_control_file()?;
+        }
+        Ok(())
+    }
+
+    /// turn the verified pieces into the final output, e.g. split the cache files of a
+    /// multi-file torrent
+    pub fn assemble(&self) -> Result<()> {
+        self.storage.finalize()
+    }
+
+    /// uploaded / downloaded, counting a download without any transfer as the whole torrent
+    pub fn seed_ratio(&self) -> f64 {
+        let downloaded = match self.downloaded.load(Ordering::Relaxed) {
+            0 => self.length as u64,
+            downloaded => downloaded,
+        };
+        self.uploaded.load(Ordering::Relaxed) as f64 / downloaded as f64
+    }
+
+    /// whether the seed ratio or seed time limit is reached after seeding for `seeding_for`
+    pub fn seed_limit_reached(&self, seeding_for: Duration) -> bool {
+        self.seed_ratio_limit
+            .is_some_and(|limit| self.seed_ratio() >= limit)
+            || self
+                .seed_time_limit
+                .is_some_and(|limit| seeding_for >= limit)
+    }
+
+    /// stay in the swarm until a seed limit is reached, then tell the trackers we stopped.
+    /// A shutdown ends seeding as well, the stopped event is then left to the caller.
+    async fn seed(&self) {
+        info!("seeding {}", self.name);
+        let start = Instant::now();
+        let mut shutdown = self.shutdown.subscribe();
+        let mut tick = tokio::time::interval(Duration::from_secs(1));
+        // without a listener seeding goes on until the time limit, nothing is uploaded
+        let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, self.port))
+            .await
+            .inspect_err(|err| warn!("cannot listen on port {} to seed: {}", self.port, err))
+            .ok();
+        // the peers we upload to stop once seeding ends, not only on shutdown
+        let (seed_end, _) = watch::channel(false);
+        let mut uploads = JoinSet::new();
+        let stopped = loop {
+            tokio::select! {
+                _ = tick.tick() => {}
+                accepted = async { listener.as_ref().unwrap().accept().await }, if listener.is_some() => {
+                    match accepted {
+                        Ok((stream, addr)) => {
+                            let mut peer = Peer::new(addr.ip(), addr.port(), self);
+                            peer.shutdown = seed_end.subscribe();
+                            let bitfield = self.bitfield.lock().unwrap().clone();
+                            let (info_hash, peer_id) = (self.info_hash, self.id);
+                            uploads.spawn(async move {
+                                peer.serve(stream, &info_hash, &peer_id, bitfield).await
+                            });
+                        }
+                        Err(err) => warn!("accepting a peer of {} failed: {}", self.name, err),
+                    }
+                }
+                _ = shutdown.wait_for(|stop| *stop) => break true,
+            }
+            if self.seed_limit_reached(start.elapsed()) {
+                break false;
+            }
+        };
+        let _ = seed_end.send(true);
+        while uploads.join_next().await.is_some() {}
+        if stopped {
+            return;
+        }
+        info!(
+            "seed limit reached for {} with ratio {:.2}",
+            self.name,
+            self.seed_ratio()
+        );
+        self.announce_stopped().await;
+    }
+
+    /// a piece which failed its hash check as often as allowed, with its failures
+    fn abandoned_piece(&self) -> Option<(u32, u32)> {
+        let max = self.max_piece_failures;
+        self.piece_failures
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, attempts)| **attempts >= max)
+            .map(|(index, attempts)| (*index, *attempts))
+            .min()
+    }
+
+    /// ask every peer and web seed to stop, the running download then returns
+    pub fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+        // peers waiting for a block buffer would never see the shutdown otherwise
+        self.block_pool.close();
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    /// download until done or until `signal` resolves, in which case the download is
+    /// shut down gracefully: progress is flushed and the trackers are told we stopped
+    pub async fn run_until<F>(&self, signal: F) -> Result<()>
+    where
+        F: Future<Output = ()>,
+    {
+        let download = self.send_request();
+        tokio::pin!(download);
+        tokio::select! {
+            res = &mut download => return res,
+            _ = signal => info!("shutting down {}", self.name),
+        }
+        self.shutdown();
+        let res = download.await;
+        if self.control_file {
+            self.save_control_file()?;
+        }
+        self.announce_stopped().await;
+        match res {
+            Err(err) if err.downcast_ref::<RbitError>().is_some() => Ok(()),
+            res => res,
+        }
+    }
+
+    /// like [`TorrentClient::run_until`], stopping on SIGINT or SIGTERM
+    pub async fn run_until_signal(&self) -> Result<()> {
+        self.run_until(async {
+            #[cfg(unix)]
+            {
+                use tokio::signal::unix::{signal, SignalKind};
+                match signal(SignalKind::terminate()) {
+                    Ok(mut terminate) => {
+                        tokio::select! {
+                            _ = tokio::signal::ctrl_c() => {}
+                            _ = terminate.recv() => {}
+                        }
+                    }
+                    Err(_) => {
+                        let _ = tokio::signal::ctrl_c().await;
+                    }
+                }
+            }
+            #[cfg(not(unix))]
+            {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        })
+        .await
+    }
+
+    /// the port to announce: the external one of the port mapping if enabled, which
+    /// is requested on the first announce and renewed once half its lifetime passed.
+    /// Without a mapping, e.g. as the gateway does not speak NAT-PMP, the listen port
+    async fn announce_port(&self) -> u16 {
+        if !self.port_mapping {
+            return self.port;
+        }
+        let current = *self.mapping.lock().unwrap();
+        let mapped = match current {
+            Some(mapping) if !mapping.needs_renewal() => return mapping.external_port,
+            Some(mapping) => mapping.renew().await,
+            None => match self.gateway.map_or_else(portmap::default_gateway, Ok) {
+                Ok(gateway) => PortMapping::request(gateway, self.port).await,
+                Err(err) => Err(err),
+            },
+        };
+        match mapped {
+            Ok(mapping) => {
+                *self.mapping.lock().unwrap() = Some(mapping);
+                mapping.external_port
+            }
+            Err(err) => {
+                info!("port mapping for {} failed: {}", self.name, err);
+                current.map_or(self.port, |mapping| mapping.external_port)
+            }
+        }
+    }
+
+    /// the external port of the current port mapping, or the listen port
+    pub fn mapped_port(&self) -> u16 {
+        self.mapping
+            .lock()
+            .unwrap()
+            .map_or(self.port, |mapping| mapping.external_port)
+    }
+
+    async fn remove_port_mapping(&self) {
+        let mapping = self.mapping.lock().unwrap().take();
+        if let Some(mapping) = mapping {
+            if let Err(err) = mapping.remove().await {
+                info!("removing the port mapping of {} failed: {}", self.name, err);
+            }
+        }
+    }
+
+    async fn announce_stopped(&self) {
+        let request = AnnounceRequest {
+            info_hash: self.info_hash,
+            peer_id: self.id,
+            port: self.mapped_port(),
+            uploaded: self.uploaded.load(Ordering::Relaxed),
+            downloaded: self.downloaded.load(Ordering::Relaxed),
+            left: self.remaining_bytes(),
+            event: Some(AnnounceEvent::Stopped),
+            param_order: self.announce_param_order.clone(),
+            max_response_size: self.max_response_size,
+        };
+        for tracker in self.trackers().iter() {
+            if let Err(err) = request.send(tracker).await {
+                info!("tracker {} failed on stop: {}", tracker, err);
+            }
+        }
+    }
+
+    /// queue a verified piece again if the storage no longer holds it, e.g.
+    /// after [`crate::storage::RingStorage`] evicted it and the player seeks back
+    pub fn ensure_piece(&self, index: u32) -> Result<()> {
+        if self.storage.has_piece(index) {
+            return Ok(());
+        }
+        let mut bitfield = self.bitfield.lock().unwrap();
+        if bitfield.has_piece(index) {
+            // a piece going back into the queue must not be lost to its capacity
+            self.task_queue.requeue(self.task(index));
+            bitfield.clear_piece(index);
+        }
         Ok(())
     }
 
+    /// pieces hashed by the last [`TorrentClient::verify_existing`] pass, out of all pieces
+    pub fn verify_progress(&self) -> (u32, u32) {
+        (
+            self.pieces_checked.load(Ordering::Relaxed) as u32,
+            self.piece_num(),
+        )
+    }
+
+    /// events of the download from now on. A subscriber falling too far behind skips
+    /// the events it missed
+    pub fn events(&self) -> impl Stream<Item = Event> {
+        stream::unfold(self.events.subscribe(), |mut events| async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => return Some((event, events)),
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+
+    /// whether piece `index` is downloaded and verified
+    pub fn has_piece(&self, index: u32) -> bool {
+        index < self.piece_num() && self.bitfield.lock().unwrap().has_piece(index)
+    }
+
+    /// resolve once piece `index` is verified, right away if it is already
+    pub async fn wait_for_piece(&self, index: u32) -> Result<()> {
+        if index >= self.piece_num() {
+            return Err(anyhow!("no piece #{} in torrent {}", index, self.name));
+        }
+        // subscribe before looking, so a piece verified in between is not missed
+        let mut events = self.events.subscribe();
+        // pieces found intact on resume are marked without an event, look now and then
+        let mut tick = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            if self.has_piece(index) {
+                return Ok(());
+            }
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok(Event::PieceVerified(verified)) if verified == index => return Ok(()),
+                    Ok(_) | Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => {
+                        return Err(anyhow!("{} stopped before piece #{}", self.name, index))
+                    }
+                },
+                _ = tick.tick() => {}
+            }
+        }
+    }
+
+    /// hash the stored data of one piece again, false if it is missing or corrupt.
+    /// Nothing about the download changes, a corrupt piece stays marked as verified
+    pub fn recheck_piece(&self, index: u32) -> Result<bool> {
+        let piece_hash = *self
+            .piece_hashes
+            .get(index as usize)
+            .ok_or_else(|| anyhow!("no piece #{} in torrent {}", index, self.name))?;
+        let piece_length = self.piece_length_of(index);
+        let Some(data) = self.storage.read_piece(index)? else {
+            return Ok(false);
+        };
+        let task = Task::new(index, piece_length, piece_hash);
+        Ok(data.len() == piece_length as usize && PieceVerifier::new(self).is_intact(&task, &data))
+    }
+
+    /// hash the assembled output piece by piece in index order, reading each piece
+    /// from its offset in the files rather than trusting the order pieces were
+    /// written in. Returns the indices of the pieces which do not match their hash
+    pub fn verify_output(&self) -> Result<Vec<u32>> {
+        self.verify_pieces(0..self.piece_num())
+    }
+
+    /// a quick "is this the right output?" check before a full [`Self::verify_output`],
+    /// hashing only the first and the last piece and `samples` random ones
+    pub fn quick_verify(&self, samples: u32) -> Result<QuickVerify> {
+        let piece_count = self.piece_num();
+        let mut checked = vec![0, piece_count.saturating_sub(1)];
+        for _ in 0..samples.min(piece_count) {
+            let unit = (random_unit() + 1.0) / 2.0;
+            checked.push(((unit * piece_count as f64) as u32).min(piece_count - 1));
+        }
+        checked.sort_unstable();
+        checked.dedup();
+        checked.retain(|index| *index < piece_count);
+        let corrupt = self.verify_pieces(checked.iter().copied())?;
+        Ok(QuickVerify {
+            checked,
+            corrupt,
+            piece_count,
+        })
+    }
+
+    /// hash the pieces `indices` of the output, returning the ones which do not match
+    fn verify_pieces(&self, indices: impl IntoIterator<Item = u32>) -> Result<Vec<u32>> {
+        let verifier = PieceVerifier::new(self);
+        let mut open: Option<(usize, File)> = None;
+        let mut corrupt = Vec::new();
+        for index in indices {
+            let task = self.task(index);
+            let begin = index as u64 * self.piece_length as u64;
+            let mut data = vec![0; task.piece_length as usize];
+            for (i, file) in self.files.iter().enumerate() {
+                let range = file.range();
+                let start = range.start.max(begin);
+                let end = range.end.min(begin + data.len() as u64);
+                if start >= end {
+                    continue;
+                }
+                let handle = match &mut open {
+                    Some((opened, handle)) if *opened == i => handle,
+                    _ => &mut open.insert((i, File::open(&file.path)?)).1,
+                };
+                handle.seek(SeekFrom::Start(start - range.start))?;
+                handle.read_exact(&mut data[(start - begin) as usize..(end - begin) as usize])?;
+            }
+            if !verifier.is_intact(&task, &data) {
+                corrupt.push(index);
+            }
+        }
+        Ok(corrupt)
+    }
+
+    /// progress of the download, the speed is averaged since the calls in the
+    /// last [`RateWindow::DEFAULT_WINDOW`], so poll it regularly
+    pub fn stats(&self) -> DownloadStats {
+        let downloaded = self.downloaded.load(Ordering::Relaxed);
+        let average_speed = self
+            .rate_window
+            .lock()
+            .unwrap()
+            .sample(Instant::now(), downloaded);
+        DownloadStats::new(
+            downloaded,
+            self.uploaded.load(Ordering::Relaxed),
+            self.remaining_bytes(),
+            average_speed,
+        )
+    }
+
+    /// progress, rates, peers, trackers and state in one snapshot. Takes a sample
+    /// of the download rate like [`Self::stats`]
+    pub fn status(&self) -> TorrentStatus {
+        let stats = self.stats();
+        let pieces = self.piece_num();
+        let mut trackers: Vec<TrackerStatus> = self
+            .tracker_stats()
+            .into_iter()
+            .map(|(url, stats)| TrackerStatus::new(url, &stats))
+            .collect();
+        trackers.sort_by(|a, b| a.url.cmp(&b.url));
+        TorrentStatus {
+            name: self.name.to_string(),
+            info_hash: self
+                .info_hash
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect(),
+            state: self.state(),
+            progress: match self.length {
+                0 => 1.0,
+                length => 1.0 - stats.left as f64 / length as f64,
+            },
+            downloaded: stats.downloaded,
+            uploaded: stats.uploaded,
+            left: stats.left,
+            download_rate: stats.average_speed,
+            eta_secs: stats.eta.map(|eta| eta.as_secs()),
+            pieces,
+            pieces_verified: self.bitfield.lock().unwrap().count(),
+            peers: self.peer_stats.lock().unwrap().len(),
+            trackers,
+        }
+    }
+
+    /// [`Self::status`] as JSON, for a supervising process to poll
+    pub fn status_json(&self) -> String {
+        serde_json::to_string(&self.status()).expect("the status serializes to JSON")
+    }
+
+    /// stats of the currently connected peers
+    pub fn peer_stats(&self) -> Vec<PeerStats> {
+        self.peer_stats.lock().unwrap().values().cloned().collect()
+    }
+
+    /// messages exchanged with every peer so far, connected or not. The counts
+    /// of a single peer are in its [`PeerStats::messages`]
+    pub fn message_counts(&self) -> MessageCounts {
+        let mut counts = self.message_totals.lock().unwrap().clone();
+        for stats in self.peer_stats.lock().unwrap().values() {
+            counts.merge(&stats.messages);
+        }
+        counts
+    }
+
+    /// pieces waiting for a peer or web seed
+    pub fn queued_pieces(&self) -> usize {
+        self.task_queue.len()
+    }
+
+    /// for each piece, how many connected peers have it
+    pub fn availability(&self) -> Vec<u16> {
+        self.availability.lock().unwrap().clone()
+    }
+
+    /// number of blocks the whole torrent is requested in, counting the short
+    /// final block of every piece and the short final piece
+    pub fn total_blocks(&self) -> u64 {
+        (0..self.piece_num())
+            .map(|index| self.piece_length_of(index).div_ceil(Peer::BLOCK_SIZE) as u64)
+            .sum()
+    }
+
+    /// number of blocks belonging to verified pieces
+    pub fn blocks_completed(&self) -> u64 {
+        self.blocks_completed.load(Ordering::Relaxed)
+    }
+
+    /// number of `pieces` which are not verified yet
+    fn missing_pieces(&self, pieces: &Range<u32>) -> u32 {
+        let bitfield = self.bitfield.lock().unwrap();
+        pieces
+            .clone()
+            .filter(|index| !bitfield.has_piece(*index))
+            .count() as u32
+    }
+
+    /// number of pieces which are not verified yet
+    pub fn remaining_pieces(&self) -> u32 {
+        let bitfield = self.bitfield.lock().unwrap();
+        (0..self.piece_num())
+            .filter(|index| !bitfield.has_piece(*index))
+            .count() as u32
+    }
+
+    /// number of bytes in pieces which are not verified yet
+    pub fn remaining_bytes(&self) -> u64 {
+        let bitfield = self.bitfield.lock().unwrap();
+        (0..self.piece_num())
+            .filter(|index| !bitfield.has_piece(*index))
+            .map(|index| self.piece_length_of(index) as u64)
+            .sum()
+    }
+
+    /// whether the torrent uses a power-of-two piece length
+    pub fn is_standard_piece_length(&self) -> bool {
+        self.piece_length.is_power_of_two()
+    }
+
+    /// path of the control file kept next to the output
+    pub fn control_file_path(&self) -> PathBuf {
+        PathBuf::from(format!("{}.rbt", &self.name))
+    }
+
+    /// snapshot the current progress into a control file
+    pub fn control(&self) -> ControlFile {
+        ControlFile {
+            info_hash: self.info_hash,
+            piece_length: self.piece_length,
+            length: self.length,
+            downloaded: self.downloaded.load(Ordering::Relaxed),
+            uploaded: self.uploaded.load(Ordering::Relaxed),
+            bitfield: self.bitfield.lock().unwrap().clone(),
+        }
+    }
+
+    pub fn save_control_file(&self) -> Result<()> {
+        self.control().save(self.control_file_path())
+    }
+
     #[inline]
     fn piece_num(&self) -> u32 {
-        self.length / self.piece_length
+        task::piece_count(self.length, self.piece_length)
     }
+}
 
-    fn concat_cache(&self) -> Result<()> {
-        let mut file = std::fs::OpenOptions::new()
-            .create(true)
-            .truncate(true)
-            .write(true)
-            .open(&*self.name)?;
-        let dir = PathBuf::from(format!("{}.cache", &self.name));
-        (0..self.piece_num())
-            .map(|index| format!("{}-cache-{}", &self.name, index))
-            .map(PathBuf::from)
-            .map(|path| dir.join(path))
-            .filter(|path| path.is_file())
-            .flat_map(|path| std::fs::OpenOptions::new().read(true).open(path))
-            .try_for_each(|mut cache| {
-                std::io::copy(&mut cache, &mut file)?;
-                Ok::<(), anyhow::Error>(())
-            })?;
-        remove_dir_all(dir)?;
-        Ok(())
+/// `interval` scaled by `1 + jitter * unit`, `unit` being within -1.0..=1.0
+pub fn jittered(interval: Duration, jitter: f64, unit: f64) -> Duration {
+    interval.mul_f64((1.0 + jitter.clamp(0.0, 1.0) * unit.clamp(-1.0, 1.0)).max(0.0))
+}
+
+/// a random number within -1.0..=1.0, good enough to spread announces
+pub(crate) fn random_unit() -> f64 {
+    use std::hash::{BuildHasher, RandomState};
+    let bits = RandomState::new().hash_one(Instant::now());
+    (bits as f64 / u64::MAX as f64) * 2.0 - 1.0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{atomic::AtomicUsize, Arc},
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::{
+        bencode::BencodeTorrent,
+        storage::{CacheStorage, FileStorage, MemoryStorage, Storage},
+        testutil::{self, SeedBehavior},
+        RbitError,
+    };
+
+    const PIECE: u32 = 32 * 1024;
+
+    #[tokio::test]
+    async fn downloads_from_a_seed() {
+        let content = testutil::content(100_000);
+        let client = testutil::seeded(&content, PIECE, SeedBehavior::default())
+            .await
+            .build()
+            .unwrap();
+        assert!(client.download_to_vec().await.unwrap() == content);
+    }
+
+    #[tokio::test]
+    async fn peers_leaving_early_is_incomplete() {
+        let content = testutil::content(100_000);
+        let behavior = SeedBehavior {
+            blocks: Some(2),
+            ..Default::default()
+        };
+        let client = testutil::seeded(&content, PIECE, behavior)
+            .await
+            .set_reannounce_attempts(1)
+            .build()
+            .unwrap();
+        let err = client.send_request().await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<RbitError>(),
+            Some(&RbitError::Incomplete { remaining: 3 })
+        );
+    }
+
+    #[tokio::test]
+    async fn pieces_failing_past_the_cap_fail_the_download() {
+        let content = testutil::content(100_000);
+        let behavior = SeedBehavior {
+            corrupt: vec![2],
+            ..Default::default()
+        };
+        let requests = behavior.requests.clone();
+        let info_hash = testutil::torrent("rbt-test-memory", &content, PIECE).info_hash;
+        let seed = testutil::seed(content.clone(), PIECE, info_hash, behavior).await;
+        // the peer leaves once nothing is left to fetch, the tracker knows it again
+        let mut body = b"d8:intervali60e5:peers6:".to_vec();
+        body.extend([127, 0, 0, 1]);
+        body.extend(seed.port().to_be_bytes());
+        body.push(b'e');
+        let tracker = testutil::HttpStub::new(200, "text/plain", body).await;
+        let client = testutil::builder(&content, PIECE)
+            .add_trackers(vec![tracker.url("/announce")])
+            .set_max_piece_failures(3)
+            .set_reannounce_attempts(u32::MAX)
+            .build()
+            .unwrap();
+        let err = tokio::time::timeout(Duration::from_secs(20), client.send_request())
+            .await
+            .expect("the download keeps fetching the corrupt piece")
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<RbitError>(),
+            Some(&RbitError::PieceFailed {
+                index: 2,
+                attempts: 3
+            })
+        );
+        // fetched exactly as often as allowed, the intact pieces once
+        let fetched = |index| {
+            let requests = requests.lock().unwrap();
+            requests.iter().filter(|r| r.0 == index && r.1 == 0).count()
+        };
+        assert_eq!(fetched(2), 3);
+        assert_eq!(fetched(0), 1);
+    }
+
+    #[tokio::test]
+    async fn filtered_peer_ids_are_dropped_after_the_handshake() {
+        let content = testutil::content(100_000);
+        let info_hash = testutil::torrent("rbt-test-memory", &content, PIECE).info_hash;
+        let filtered = SeedBehavior {
+            peer_id: Some(*b"-XL0019-000000000000"),
+            ..Default::default()
+        };
+        let seed = testutil::seed(content.clone(), PIECE, info_hash, filtered.clone()).await;
+        let client = testutil::seeded(&content, PIECE, SeedBehavior::default())
+            .await
+            .add_peers(vec![seed])
+            .set_peer_id_filter(|peer_id| !peer_id.starts_with(b"-XL"))
+            .build()
+            .unwrap();
+        assert_eq!(client.download_to_vec().await.unwrap(), content);
+        // dropped before it was asked for anything
+        assert!(filtered.requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn slow_peers_are_snubbed() {
+        // the rate is only judged past the grace period, so the piece has to take longer
+        let piece = 4 * Peer::BLOCK_SIZE;
+        let content = testutil::content(100_000);
+        let behavior = SeedBehavior {
+            delay: Some(Duration::from_secs(2)),
+            ..Default::default()
+        };
+        let client = testutil::seeded(&content, piece, behavior)
+            .await
+            .set_min_peer_rate(1_000_000)
+            .set_reannounce_attempts(0)
+            .build()
+            .unwrap();
+        let start = Instant::now();
+        let err = client.send_request().await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<RbitError>(),
+            Some(&RbitError::Incomplete { remaining: 2 })
+        );
+        // dropped long before the piece would have been complete
+        assert!(start.elapsed() < Duration::from_secs(8));
+        assert!(client.task_queue.contains(0));
+    }
+
+    #[tokio::test]
+    async fn download_a_single_file() {
+        let content = testutil::content(100_000);
+        let files = [("a.bin", 40000), ("sub/b.bin", 30000), ("c.bin", 30000)];
+        let torrent = testutil::torrent_of(testutil::multi_info("multi", &files, &content, PIECE));
+        let info_hash = crate::Torrent::from_bytes(&serde_bencode::to_bytes(&torrent).unwrap())
+            .unwrap()
+            .info_hash;
+        let behavior = SeedBehavior::default();
+        let requests = behavior.requests.clone();
+        let seed = testutil::seed(content.clone(), PIECE, info_hash, behavior).await;
+        let client = testutil::builder_of(&torrent)
+            .add_peers(vec![seed])
+            .build()
+            .unwrap();
+        let scratch = testutil::Scratch::dir("single-file");
+        let dest = scratch.join("b.bin");
+        client
+            .download_file("multi/sub/b.bin", &dest)
+            .await
+            .unwrap();
+        assert!(std::fs::read(&dest).unwrap() == content[40000..70000]);
+        // only the pieces overlapping the file are fetched
+        let mut pieces: Vec<u32> = requests.lock().unwrap().iter().map(|r| r.0).collect();
+        pieces.sort();
+        pieces.dedup();
+        assert_eq!(pieces, vec![1, 2]);
+        assert!(!client.bitfield.lock().unwrap().has_piece(0));
+    }
+
+    /// a storage taking its time to read each piece back
+    #[derive(Debug)]
+    struct SlowStorage(MemoryStorage);
+
+    impl Storage for SlowStorage {
+        fn write_piece(&self, index: u32, data: &[u8]) -> Result<()> {
+            self.0.write_piece(index, data)
+        }
+
+        fn read_piece(&self, index: u32) -> Result<Option<Vec<u8>>> {
+            std::thread::sleep(Duration::from_millis(50));
+            self.0.read_piece(index)
+        }
+
+        fn has_piece(&self, index: u32) -> bool {
+            self.0.has_piece(index)
+        }
+
+        fn finalize(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    /// a storage logging its writes and the final sync of the output
+    #[derive(Debug, Default)]
+    struct SyncLog(MemoryStorage, std::sync::Mutex<Vec<String>>);
+
+    impl Storage for SyncLog {
+        fn write_piece(&self, index: u32, data: &[u8]) -> Result<()> {
+            self.1.lock().unwrap().push(format!("write {}", index));
+            self.0.write_piece(index, data)
+        }
+
+        fn read_piece(&self, index: u32) -> Result<Option<Vec<u8>>> {
+            self.0.read_piece(index)
+        }
+
+        fn has_piece(&self, index: u32) -> bool {
+            self.0.has_piece(index)
+        }
+
+        fn finalize(&self) -> Result<()> {
+            self.1.lock().unwrap().push("finalize".to_string());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn output_is_synced_before_success() {
+        let content = testutil::content(100_000);
+        let storage = Arc::new(SyncLog::default());
+        let client = testutil::seeded(&content, PIECE, SeedBehavior::default())
+            .await
+            .set_storage(storage.clone())
+            .build()
+            .unwrap();
+        client.send_request().await.unwrap();
+        let log = storage.1.lock().unwrap().clone();
+        assert_eq!(
+            log.iter()
+                .filter(|entry| entry.starts_with("write"))
+                .count(),
+            4
+        );
+        // synced once, after the last piece was written
+        assert_eq!(log.last().map(String::as_str), Some("finalize"));
+        assert_eq!(log.iter().filter(|entry| *entry == "finalize").count(), 1);
+    }
+
+    #[tokio::test]
+    async fn verification_reports_progress_and_stops_on_shutdown() {
+        let content = testutil::content(20 * 16384);
+        let storage = SlowStorage(MemoryStorage::new());
+        for (index, piece) in content.chunks(16384).enumerate() {
+            storage.write_piece(index as u32, piece).unwrap();
+        }
+        let client = Arc::new(
+            testutil::builder(&content, 16384)
+                .set_storage(Arc::new(storage))
+                .build()
+                .unwrap(),
+        );
+        let verify = tokio::spawn({
+            let client = client.clone();
+            async move { client.verify_existing().await }
+        });
+        assert!(testutil::eventually(|| client.verify_progress().0 >= 3).await);
+        client.shutdown();
+        verify.await.unwrap().unwrap();
+        let (checked, total) = client.verify_progress();
+        assert_eq!(total, 20);
+        assert!(checked < total, "stopped midway");
+        assert_eq!(client.remaining_pieces(), 20 - checked);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(client.verify_progress().0, checked);
+    }
+
+    /// a [`SlowStorage`] which records how many reads ran at once at most
+    #[derive(Debug)]
+    struct CountingStorage {
+        slow: SlowStorage,
+        reading: Arc<AtomicUsize>,
+        peak: Arc<AtomicUsize>,
+    }
+
+    impl Storage for CountingStorage {
+        fn write_piece(&self, index: u32, data: &[u8]) -> Result<()> {
+            self.slow.write_piece(index, data)
+        }
+
+        fn read_piece(&self, index: u32) -> Result<Option<Vec<u8>>> {
+            let reading = self.reading.fetch_add(1, Ordering::SeqCst) + 1;
+            self.peak.fetch_max(reading, Ordering::SeqCst);
+            let data = self.slow.read_piece(index);
+            self.reading.fetch_sub(1, Ordering::SeqCst);
+            data
+        }
+
+        fn has_piece(&self, index: u32) -> bool {
+            self.slow.has_piece(index)
+        }
+
+        fn finalize(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn downloads_progress_while_verification_is_bounded() {
+        let session = crate::session::Session::new().set_verify_limit(1);
+        let cached = testutil::content(20 * 16384);
+        let reading = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let verifying: Vec<_> = (0..2)
+            .map(|_| {
+                let storage = CountingStorage {
+                    slow: SlowStorage(MemoryStorage::new()),
+                    reading: reading.clone(),
+                    peak: peak.clone(),
+                };
+                for (index, piece) in cached.chunks(16384).enumerate() {
+                    storage.write_piece(index as u32, piece).unwrap();
+                }
+                Arc::new(
+                    testutil::builder(&cached, 16384)
+                        .set_storage(Arc::new(storage))
+                        .set_verify_limit(session.verify_limit())
+                        .build()
+                        .unwrap(),
+                )
+            })
+            .collect();
+        let verifications: Vec<_> = verifying
+            .iter()
+            .map(|client| {
+                let client = client.clone();
+                tokio::spawn(async move { client.verify_existing().await })
+            })
+            .collect();
+
+        let content = testutil::content(100_000);
+        let downloading = testutil::seeded(&content, PIECE, SeedBehavior::default())
+            .await
+            .set_verify_limit(session.verify_limit())
+            .build()
+            .unwrap();
+        assert_eq!(downloading.download_to_vec().await.unwrap(), content);
+        // the download finished long before the forty slow reads could have
+        let checked: u32 = verifying
+            .iter()
+            .map(|client| client.verify_progress().0)
+            .sum();
+        assert!(checked < 40, "verification was done first");
+
+        for verification in verifications {
+            verification.await.unwrap().unwrap();
+        }
+        assert!(verifying
+            .iter()
+            .all(|client| client.remaining_pieces() == 0));
+        assert_eq!(peak.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn verified_pieces_show_up_as_events() {
+        use futures_util::StreamExt;
+        let content = testutil::content(100_000);
+        let client = testutil::seeded(&content, PIECE, SeedBehavior::default())
+            .await
+            .build()
+            .unwrap();
+        let events = client.events();
+        client.send_request().await.unwrap();
+        let events: Vec<Event> = events
+            .take_while(|event| std::future::ready(*event != Event::DownloadComplete))
+            .collect()
+            .await;
+        let mut verified: Vec<u32> = events
+            .iter()
+            .filter_map(|event| match event {
+                Event::PieceVerified(index) => Some(*index),
+                _ => None,
+            })
+            .collect();
+        verified.sort_unstable();
+        assert_eq!(verified, vec![0, 1, 2, 3]);
+        assert!(matches!(events.first(), Some(Event::PeerConnected(_))));
+    }
+
+    #[test]
+    fn file_progress_credits_pieces_across_files() {
+        let content = testutil::content(100_000);
+        let files = [("a.bin", 40000), ("b.bin", 30000), ("c.bin", 30000)];
+        let torrent = testutil::torrent_of(testutil::multi_info("multi", &files, &content, PIECE));
+        let client = testutil::builder_of(&torrent).build().unwrap();
+        let progress = |client: &TorrentClient| -> Vec<(u64, u64)> {
+            client
+                .file_progress()
+                .into_iter()
+                .map(|(_, completed, total)| (completed, total))
+                .collect()
+        };
+        assert_eq!(progress(&client), vec![(0, 40000), (0, 30000), (0, 30000)]);
+        client.bitfield.lock().unwrap().set_piece(0);
+        client.bitfield.lock().unwrap().set_piece(2);
+        // piece 2 holds the end of b.bin and most of c.bin
+        assert_eq!(
+            progress(&client),
+            vec![(32768, 40000), (4464, 30000), (28304, 30000)]
+        );
+        client.bitfield.lock().unwrap().set_piece(3);
+        assert_eq!(progress(&client)[2], (30000, 30000));
+        let paths: Vec<_> = client
+            .file_progress()
+            .into_iter()
+            .map(|(path, ..)| path)
+            .collect();
+        assert_eq!(paths[1], PathBuf::from("multi/b.bin"));
+    }
+
+    #[tokio::test]
+    async fn tight_disk_quota_pauses_the_download() {
+        let name = testutil::unique_name("tight-quota");
+        let _output = testutil::Scratch::output(&name);
+        let content = testutil::content(100_000);
+        let storage = Arc::new(
+            CacheStorage::new(Arc::new(name.clone()), 4)
+                .with_length(content.len() as u64)
+                .with_piece_length(PIECE),
+        );
+        let quota = 2 * PIECE as u64;
+        let client = Arc::new(
+            testutil::seeded(&content, PIECE, SeedBehavior::default())
+                .await
+                .set_storage(storage.clone())
+                .set_disk_quota(quota)
+                .build()
+                .unwrap(),
+        );
+        let download = tokio::spawn({
+            let client = client.clone();
+            async move { client.send_request().await }
+        });
+        assert!(testutil::eventually(|| client.remaining_pieces() == 2).await);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        // paused rather than going over
+        assert_eq!(client.remaining_pieces(), 2);
+        assert!(storage.disk_usage() <= quota);
+        assert!(!download.is_finished());
+        client.shutdown();
+        assert!(download.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn download_to_vec_stays_off_the_disk() {
+        use futures_util::StreamExt;
+        let name = testutil::unique_name("to-vec");
+        let _output = testutil::Scratch::output(&name);
+        // one full piece and a short final one
+        let content = testutil::content(PIECE as usize + 123);
+        let torrent = testutil::torrent_of(testutil::info(&name, &content, PIECE));
+        let info_hash = crate::Torrent::from_bytes(&serde_bencode::to_bytes(&torrent).unwrap())
+            .unwrap()
+            .info_hash;
+        let seed = testutil::seed(content.clone(), PIECE, info_hash, SeedBehavior::default()).await;
+        let client = testutil::builder_of(&torrent)
+            .add_peers(vec![seed])
+            .build()
+            .unwrap();
+        let events = client.events();
+        assert!(client.download_to_vec().await.unwrap() == content);
+        for suffix in ["", ".rbt", ".cache", ".part"] {
+            assert!(!Path::new(&format!("{}{}", name, suffix)).exists());
+        }
+        let mut complete = std::pin::pin!(
+            events.filter(|event| std::future::ready(*event == Event::DownloadComplete))
+        );
+        assert!(
+            tokio::time::timeout(Duration::from_secs(1), complete.next())
+                .await
+                .unwrap()
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn recheck_single_pieces() {
+        let content = testutil::content(100_000);
+        let client = testutil::client(&content, PIECE);
+        let piece = |index: usize| {
+            let start = index * PIECE as usize;
+            &content[start..(start + PIECE as usize).min(content.len())]
+        };
+        client.storage.write_piece(0, piece(0)).unwrap();
+        let mut corrupt = piece(1).to_vec();
+        corrupt[100] ^= 0xff;
+        client.storage.write_piece(1, &corrupt).unwrap();
+        // the short final piece
+        client.storage.write_piece(3, piece(3)).unwrap();
+        assert!(client.recheck_piece(0).unwrap());
+        assert!(!client.recheck_piece(1).unwrap());
+        assert!(!client.recheck_piece(2).unwrap(), "missing");
+        assert!(client.recheck_piece(3).unwrap());
+        assert!(client.recheck_piece(4).is_err());
+        // a final piece stored with the length of a whole one is not intact
+        client.storage.write_piece(3, &[0; PIECE as usize]).unwrap();
+        assert!(!client.recheck_piece(3).unwrap());
+        assert_eq!(client.remaining_pieces(), 4);
+    }
+
+    #[tokio::test]
+    async fn availability_counts_peer_bitfields() {
+        let content = testutil::content(100_000);
+        let info_hash = testutil::torrent("rbt-test-memory", &content, PIECE).info_hash;
+        let mut peers = vec![];
+        for bitfield in [0b1100_0000, 0b0110_0000, 0b1111_0000] {
+            let behavior = SeedBehavior {
+                bitfield: Some(vec![bitfield]),
+                choke: true,
+                ..Default::default()
+            };
+            peers.push(testutil::seed(content.clone(), PIECE, info_hash, behavior).await);
+        }
+        let client = Arc::new(
+            testutil::builder(&content, PIECE)
+                .add_peers(peers)
+                .set_reannounce_attempts(0)
+                .build()
+                .unwrap(),
+        );
+        assert_eq!(client.availability(), vec![0; 4]);
+        let download = tokio::spawn({
+            let client = client.clone();
+            async move { client.send_request().await }
+        });
+        assert!(testutil::eventually(|| client.availability() == vec![2, 3, 2, 1]).await);
+        client.shutdown();
+        assert!(download.await.unwrap().is_err());
+        // peers which left no longer count
+        assert_eq!(client.availability(), vec![0; 4]);
+    }
+
+    #[tokio::test]
+    async fn availability_drops_when_a_peer_leaves() {
+        let content = testutil::content(100_000);
+        let info_hash = testutil::torrent("rbt-test-memory", &content, PIECE).info_hash;
+        let mut peers = vec![];
+        for (bitfield, hang_up) in [
+            (0b1100_0000, None),
+            (0b0110_0000, None),
+            (0b1111_0000, Some(Duration::from_secs(1))),
+        ] {
+            let behavior = SeedBehavior {
+                bitfield: Some(vec![bitfield]),
+                choke: true,
+                hang_up,
+                ..Default::default()
+            };
+            peers.push(testutil::seed(content.clone(), PIECE, info_hash, behavior).await);
+        }
+        let client = Arc::new(
+            testutil::builder(&content, PIECE)
+                .add_peers(peers)
+                .set_reannounce_attempts(0)
+                .build()
+                .unwrap(),
+        );
+        let download = tokio::spawn({
+            let client = client.clone();
+            async move { client.send_request().await }
+        });
+        assert!(testutil::eventually(|| client.availability() == vec![2, 3, 2, 1]).await);
+        // back to what the two remaining peers have
+        assert!(testutil::eventually(|| client.availability() == vec![1, 2, 1, 0]).await);
+        client.shutdown();
+        assert!(download.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn seeding_stops_at_the_ratio_limit() {
+        use crate::message::{HandShake, Message, Request};
+        use tokio::{io::AsyncWriteExt, net::TcpStream};
+
+        let stub = testutil::HttpStub::new(200, "text/plain", b"d8:intervali1800ee".to_vec()).await;
+        let content = testutil::content(100_000);
+        let info_hash = testutil::torrent("seed-ratio", &content, PIECE).info_hash;
+        let seed = testutil::seed(content.clone(), PIECE, info_hash, SeedBehavior::default()).await;
+        let mut torrent = testutil::torrent_of(testutil::info("seed-ratio", &content, PIECE));
+        torrent.announce = stub.url("/announce");
+        let port = testutil::free_port();
+        let client = Arc::new(
+            testutil::builder_of(&torrent)
+                .add_peers(vec![seed])
+                .set_port(port)
+                .set_seed_ratio_limit(1.0)
+                .set_seed_time_limit(Duration::from_secs(60))
+                .build()
+                .unwrap(),
+        );
+        let download = tokio::spawn({
+            let client = client.clone();
+            async move { client.send_request().await }
+        });
+        assert!(testutil::eventually(|| client.state() == TorrentState::Seeding).await);
+        let mut leecher = None;
+        for _ in 0..50 {
+            if let Ok(stream) = TcpStream::connect(("127.0.0.1", port)).await {
+                leecher = Some(stream);
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        let mut leecher = leecher.expect("the seeding client listens on its port");
+        leecher
+            .write_all(&HandShake::new(&info_hash, b"-LC0001-000000000000").as_bytes())
+            .await
+            .unwrap();
+        let answer = Message::from_stream(&mut leecher).await.unwrap();
+        assert!(matches!(answer, Message::HandShake(ours) if ours.info_hash == info_hash));
+        let have = Message::from_stream(&mut leecher).await.unwrap();
+        assert!(matches!(have, Message::Bitfield(bitfield) if bitfield.count() == 4));
+        assert!(matches!(
+            Message::from_stream(&mut leecher).await.unwrap(),
+            Message::UnChoke
+        ));
+        let stopped = || {
+            stub.requests()
+                .into_iter()
+                .filter(|request| request.contains("event=stopped"))
+                .collect::<Vec<_>>()
+        };
+        let block = Peer::BLOCK_SIZE as usize;
+        for (at, expected) in content.chunks(block).enumerate() {
+            let begin = at * block;
+            let (index, begin) = (begin / PIECE as usize, begin % PIECE as usize);
+            let request = Request::new(index as u32, begin as u32, expected.len() as u32);
+            leecher
+                .write_all(&Message::Request(request).as_bytes())
+                .await
+                .unwrap();
+            let Message::Piece(piece) = Message::from_stream(&mut leecher).await.unwrap() else {
+                panic!("a block answers the request for it");
+            };
+            assert_eq!(&piece.piece[..], expected);
+            // three blocks uploaded are short of the ratio
+            if at == 2 {
+                tokio::time::sleep(Duration::from_millis(1500)).await;
+                assert!(!download.is_finished());
+                assert!(stopped().is_empty());
+            }
+        }
+        tokio::time::timeout(Duration::from_secs(3), download)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let stopped = stopped();
+        assert_eq!(stopped.len(), 1);
+        assert!(stopped[0].contains("uploaded=100000"));
+        assert!(stopped[0].contains("left=0"));
+        assert_eq!(client.uploaded.load(Ordering::Relaxed), 100_000);
+    }
+
+    #[tokio::test]
+    async fn unassembled_pieces_stay_in_the_cache() {
+        use sha1::{Digest, Sha1};
+        let name = testutil::unique_name("unassembled");
+        let output = testutil::Scratch::output(&name);
+        let content = testutil::content(100_000);
+        let storage = CacheStorage::new(Arc::new(name.clone()), 4)
+            .with_length(content.len() as u64)
+            .with_piece_length(PIECE);
+        let client = testutil::seeded(&content, PIECE, SeedBehavior::default())
+            .await
+            .set_storage(Arc::new(storage))
+            .set_assemble(false)
+            .build()
+            .unwrap();
+        client.send_request().await.unwrap();
+        assert!(!output.path().exists());
+        for (index, piece) in content.chunks(PIECE as usize).enumerate() {
+            let cached = std::fs::read(format!("{0}.cache/{0}-cache-{1}", name, index)).unwrap();
+            assert_eq!(Sha1::digest(&cached), Sha1::digest(piece));
+        }
+        client.assemble().unwrap();
+        assert!(std::fs::read(output.path()).unwrap() == content);
+    }
+
+    #[tokio::test]
+    async fn our_own_address_is_skipped() {
+        // the tracker tells us we are 10.0.0.9 and hands out that address with our port
+        let mut body = b"d11:external ip4:".to_vec();
+        body.extend([10, 0, 0, 9]);
+        body.extend(b"8:intervali60e5:peers18:");
+        body.extend([10, 0, 0, 9, 0x1a, 0xe1]);
+        body.extend([127, 0, 0, 1, 0x1a, 0xe1]);
+        body.extend([10, 0, 0, 9, 0x1a, 0xe2]);
+        body.push(b'e');
+        let stub = testutil::HttpStub::new(200, "text/plain", body).await;
+        let content = testutil::content(1000);
+        let mut torrent = testutil::torrent_of(testutil::info("self", &content, PIECE));
+        torrent.announce = stub.url("/announce");
+        let client = testutil::builder_of(&torrent)
+            .set_port(6881)
+            .build()
+            .unwrap();
+        assert!(!client.is_self(&"10.0.0.9:6881".parse().unwrap()));
+        let peers = client.look_for_peers(client.id, 6881).await.unwrap();
+        let addrs: Vec<_> = peers.iter().map(|peer| peer.addr()).collect();
+        // the same host on another port is someone else
+        assert_eq!(addrs, vec!["10.0.0.9:6882".parse().unwrap()]);
+        assert!(client.is_self(&"10.0.0.9:6881".parse().unwrap()));
+        assert!(client.is_self(&"0.0.0.0:6881".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn complete_output_needs_no_peers() {
+        let name = testutil::unique_name("complete");
+        let _output = testutil::Scratch::output(&name);
+        let content = testutil::content(100_000);
+        std::fs::write(&name, &content).unwrap();
+        let stub =
+            testutil::HttpStub::new(200, "text/plain", b"d8:intervali60e5:peers0:e".to_vec()).await;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut torrent = testutil::torrent_of(testutil::info(&name, &content, PIECE));
+        torrent.announce = stub.url("/announce");
+        let client = testutil::builder_of(&torrent)
+            .set_storage(Arc::new(
+                FileStorage::new(&name, PIECE, content.len() as u64).unwrap(),
+            ))
+            .add_peers(vec![listener.local_addr().unwrap()])
+            .build()
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(5), client.send_request())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(client.remaining_pieces(), 0);
+        assert_eq!(client.state(), TorrentState::Complete);
+        assert!(stub.requests().is_empty());
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), listener.accept())
+                .await
+                .is_err(),
+            "a peer was contacted"
+        );
+        assert_eq!(std::fs::read(&name).unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn empty_swarm_reports_no_peers() {
+        let stub =
+            testutil::HttpStub::new(200, "text/plain", b"d8:intervali60e5:peers0:e".to_vec()).await;
+        let content = testutil::content(100_000);
+        let mut torrent = testutil::torrent_of(testutil::info("empty", &content, PIECE));
+        torrent.announce = stub.url("/announce");
+        let client = testutil::builder_of(&torrent)
+            .set_reannounce_attempts(1)
+            .build()
+            .unwrap();
+        let err = tokio::time::timeout(Duration::from_secs(10), client.send_request())
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<RbitError>(), Some(&RbitError::NoPeers));
+        assert_eq!(client.state(), TorrentState::NoPeers);
+        // asked again before giving up
+        assert_eq!(stub.requests().len(), 2);
+        assert_eq!(client.remaining_pieces(), 4);
+    }
+
+    #[tokio::test]
+    async fn mapped_port_is_announced() {
+        // a NAT-PMP gateway mapping every port to 40000, remembering the requests
+        let gateway = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let gateway_addr = gateway.local_addr().unwrap();
+        let mappings = Arc::new(std::sync::Mutex::new(vec![]));
+        let log = mappings.clone();
+        tokio::spawn(async move {
+            let mut req = [0; 12];
+            while let Ok((12, from)) = gateway.recv_from(&mut req).await {
+                let internal = u16::from_be_bytes([req[4], req[5]]);
+                let lifetime = u32::from_be_bytes([req[8], req[9], req[10], req[11]]);
+                log.lock().unwrap().push((internal, lifetime));
+                let mut res = vec![0, 128 + req[1], 0, 0, 0, 0, 0, 1];
+                res.extend(internal.to_be_bytes());
+                res.extend(40000u16.to_be_bytes());
+                res.extend(lifetime.to_be_bytes());
+                gateway.send_to(&res, from).await.unwrap();
+            }
+        });
+        let stub =
+            testutil::HttpStub::new(200, "text/plain", b"d8:intervali60e5:peers0:e".to_vec()).await;
+        let content = testutil::content(1000);
+        let mut torrent = testutil::torrent_of(testutil::info("mapped", &content, PIECE));
+        torrent.announce = stub.url("/announce");
+        let client = testutil::builder_of(&torrent)
+            .set_port(6881)
+            .set_port_mapping(true)
+            .set_gateway(gateway_addr)
+            .set_reannounce_attempts(0)
+            .build()
+            .unwrap();
+        assert!(client.send_request().await.is_err());
+        let requests = stub.requests();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].contains("port=40000"), "{}", requests[0]);
+        // asked for on the announce and removed once done
+        let lifetime = portmap::LIFETIME.as_secs() as u32;
+        assert_eq!(*mappings.lock().unwrap(), vec![(6881, lifetime), (6881, 0)]);
+        assert_eq!(client.mapped_port(), 6881);
+    }
+
+    #[test]
+    fn pieces_written_in_reverse_verify_in_order() {
+        let name = testutil::unique_name("reverse");
+        let _output = testutil::Scratch::output(&name);
+        let content = testutil::content(100_000);
+        let storage = Arc::new(FileStorage::new(&name, PIECE, content.len() as u64).unwrap());
+        let torrent = testutil::torrent_of(testutil::info(&name, &content, PIECE));
+        let client = testutil::builder_of(&torrent)
+            .set_storage(storage.clone())
+            .build()
+            .unwrap();
+        for (index, piece) in content.chunks(PIECE as usize).enumerate().rev() {
+            storage.write_piece(index as u32, piece).unwrap();
+        }
+        client.assemble().unwrap();
+        assert_eq!(client.verify_output().unwrap(), Vec::<u32>::new());
+        assert_eq!(std::fs::read(&name).unwrap(), content);
+
+        // a piece landing at the offset of another is caught
+        storage.write_piece(1, &content[..PIECE as usize]).unwrap();
+        assert_eq!(client.verify_output().unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn evicted_pieces_are_requeued_on_a_full_queue() {
+        let content = testutil::content(100_000);
+        let client = testutil::builder(&content, PIECE)
+            .set_task_queue_capacity(1)
+            .build()
+            .unwrap();
+        client.task_queue.push(client.task(0)).unwrap();
+        client.bitfield.lock().unwrap().set_piece(2);
+        // verified once, but the storage no longer holds it
+        client.ensure_piece(2).unwrap();
+        assert!(client.task_queue.contains(2));
+        assert!(!client.has_piece(2));
+        assert_eq!(client.task_queue.len(), 2);
+        // a piece which is not verified is left to the download
+        client.ensure_piece(3).unwrap();
+        assert_eq!(client.task_queue.len(), 2);
+    }
+
+    #[test]
+    fn quick_verify_checks_the_first_and_the_last_piece() {
+        let name = testutil::unique_name("quick");
+        let _output = testutil::Scratch::output(&name);
+        let mut content = testutil::content(100_000);
+        let torrent = testutil::torrent_of(testutil::info(&name, &content, PIECE));
+        let client = testutil::builder_of(&torrent).build().unwrap();
+        std::fs::write(&name, &content).unwrap();
+        let quick = client.quick_verify(0).unwrap();
+        assert_eq!(quick.checked, vec![0, 3]);
+        assert!(quick.is_match());
+        assert_eq!(quick.confidence(), 0.5);
+        // random samples add to the pieces checked
+        let sampled = client.quick_verify(2).unwrap();
+        assert!(sampled.is_match() && sampled.checked.len() >= 2);
+
+        *content.last_mut().unwrap() ^= 0xff;
+        std::fs::write(&name, &content).unwrap();
+        let quick = client.quick_verify(0).unwrap();
+        assert_eq!(quick.corrupt, vec![3]);
+        assert!(!quick.is_match());
+        assert_eq!(quick.confidence(), 0.0);
+    }
+
+    #[test]
+    fn status_json_reports_a_download_in_progress() {
+        let content = testutil::content(100_000);
+        let client = testutil::client(&content, PIECE);
+        client.set_state(TorrentState::Downloading);
+        {
+            let mut bitfield = client.bitfield.lock().unwrap();
+            bitfield.set_piece(0);
+            bitfield.set_piece(1);
+        }
+        client.downloaded.store(65536, Ordering::Relaxed);
+        client.uploaded.store(1000, Ordering::Relaxed);
+        let peer: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        client
+            .peer_stats
+            .lock()
+            .unwrap()
+            .insert(peer, crate::stats::PeerStats::new(peer));
+        client.tracker_stats.lock().unwrap().insert(
+            "http://tracker.test/announce".into(),
+            TrackerStats {
+                result: AnnounceResult::Failure("unregistered torrent".into()),
+                peers: 0,
+                announces: 2,
+                last_announce: Instant::now(),
+            },
+        );
+        let status: serde_json::Value = serde_json::from_str(&client.status_json()).unwrap();
+        assert_eq!(status["name"], "rbt-test-memory");
+        let info_hash: String = client
+            .info_hash
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        assert_eq!(status["info_hash"], info_hash);
+        assert_eq!(status["state"], "downloading");
+        let progress = status["progress"].as_f64().unwrap();
+        assert!((progress - 0.65536).abs() < 1e-9, "{}", progress);
+        assert_eq!(status["downloaded"], 65536);
+        assert_eq!(status["uploaded"], 1000);
+        assert_eq!(status["left"], 100_000 - 65536);
+        assert!(status["download_rate"].is_number());
+        assert_eq!(status["pieces"], 4);
+        assert_eq!(status["pieces_verified"], 2);
+        assert_eq!(status["peers"], 1);
+        let tracker = &status["trackers"][0];
+        assert_eq!(tracker["url"], "http://tracker.test/announce");
+        assert_eq!(tracker["result"], "failure");
+        assert_eq!(tracker["error"], "unregistered torrent");
+        assert_eq!(tracker["announces"], 2);
+        assert_eq!(tracker["secs_since_announce"], 0);
+    }
+
+    #[tokio::test]
+    async fn stalled_downloads_re_announce() {
+        let content = testutil::content(100_000);
+        let info_hash = testutil::torrent("stall", &content, PIECE).info_hash;
+        let choking = SeedBehavior {
+            choke: true,
+            ..Default::default()
+        };
+        let seed = testutil::seed(content.clone(), PIECE, info_hash, choking).await;
+        let mut body = b"d8:intervali1800e5:peers6:".to_vec();
+        body.extend([127, 0, 0, 1]);
+        body.extend(seed.port().to_be_bytes());
+        body.push(b'e');
+        let stub = testutil::HttpStub::new(200, "text/plain", body).await;
+        let mut torrent = testutil::torrent_of(testutil::info("stall", &content, PIECE));
+        torrent.announce = stub.url("/announce");
+        let client = Arc::new(
+            testutil::builder_of(&torrent)
+                .set_stall_window(Duration::from_millis(300))
+                .build()
+                .unwrap(),
+        );
+        let download = tokio::spawn({
+            let client = client.clone();
+            async move { client.send_request().await }
+        });
+        // long before the interval of half an hour
+        assert!(testutil::eventually(|| stub.requests().len() >= 3).await);
+        assert_eq!(client.downloaded.load(Ordering::Relaxed), 0);
+        client.shutdown();
+        assert!(download.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn completed_output_moves_to_the_completed_dir() {
+        let name = testutil::unique_name("completed");
+        let _output = testutil::Scratch::output(&name);
+        let completed = testutil::Scratch::dir("completed-dir");
+        // somebody else's file of the same name stays where it is
+        std::fs::write(completed.join(&name), b"taken").unwrap();
+        let content = testutil::content(100_000);
+        let torrent = testutil::torrent_of(testutil::info(&name, &content, PIECE));
+        let info_hash = crate::Torrent::from_bytes(&serde_bencode::to_bytes(&torrent).unwrap())
+            .unwrap()
+            .info_hash;
+        let seed = testutil::seed(content.clone(), PIECE, info_hash, SeedBehavior::default()).await;
+        let client = testutil::builder_of(&torrent)
+            .set_storage(Arc::new(
+                FileStorage::new(&name, PIECE, content.len() as u64).unwrap(),
+            ))
+            .add_peers(vec![seed])
+            .set_completed_dir(completed.path())
+            .build()
+            .unwrap();
+        client.send_request().await.unwrap();
+        assert!(!std::path::Path::new(&name).exists());
+        assert_eq!(std::fs::read(completed.join(&name)).unwrap(), b"taken");
+        let moved = completed.join(&format!("{} (1)", name));
+        assert_eq!(std::fs::read(moved).unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn wait_for_piece_resolves_once_verified() {
+        let content = testutil::content(100_000);
+        let client = Arc::new(
+            testutil::seeded(&content, PIECE, SeedBehavior::default())
+                .await
+                .build()
+                .unwrap(),
+        );
+        assert!(!client.has_piece(3));
+        let waiter = tokio::spawn({
+            let client = client.clone();
+            async move { client.wait_for_piece(3).await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!waiter.is_finished());
+        assert_eq!(client.download_to_vec().await.unwrap(), content);
+        tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(client.has_piece(3));
+        // present already, nothing to wait for
+        tokio::time::timeout(Duration::from_millis(10), client.wait_for_piece(0))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(client.wait_for_piece(4).await.is_err());
+        assert!(!client.has_piece(4));
+    }
+
+    #[test]
+    fn tasks_follow_the_piece_count() {
+        for (length, count, last) in [(65536, 4, 16384), (65537, 5, 1)] {
+            let client = testutil::client(&testutil::content(length), 16384);
+            assert_eq!(client.piece_num(), count);
+            assert_eq!(client.piece_hashes.len(), count as usize);
+            assert_eq!(client.task(count - 1).piece_length, last);
+            assert!(client.assign_tasks(0..count).is_ok());
+            assert_eq!(client.task_queue.len(), count as usize);
+        }
+    }
+
+    #[tokio::test]
+    async fn filtered_trackers_are_never_contacted() {
+        let stub = testutil::HttpStub::new(500, "text/plain", b"down".to_vec()).await;
+        let content = testutil::content(1000);
+        let mut torrent = testutil::torrent_of(testutil::info("filter", &content, PIECE));
+        torrent.announce = stub.url("/logging/announce");
+        torrent.announce_list = Some(vec![
+            vec![stub.url("/logging/announce"), stub.url("/a/announce")],
+            vec![stub.url("/logging/other")],
+        ]);
+        let client = testutil::builder_of(&torrent)
+            .set_tracker_filter(|url: &str| !url.contains("/logging/"))
+            .build()
+            .unwrap();
+        client
+            .add_trackers(vec![stub.url("/logging/extra"), stub.url("/b/announce")])
+            .unwrap();
+        assert!(client.look_for_peers(client.id, 6881).await.is_err());
+        let paths: Vec<_> = stub
+            .requests()
+            .iter()
+            .map(|line| line.split(['?', ' ']).nth(1).unwrap().to_string())
+            .collect();
+        assert_eq!(paths, ["/a/announce", "/b/announce"]);
+    }
+
+    #[tokio::test]
+    async fn added_trackers_join_the_rotation() {
+        let mut body = b"d8:intervali60e5:peers6:".to_vec();
+        body.extend([10, 0, 0, 7, 0x1a, 0xe1]);
+        body.push(b'e');
+        let stub = testutil::HttpStub::new(200, "text/plain", body).await;
+        let content = testutil::content(1000);
+        let client = testutil::client(&content, PIECE);
+        client.add_trackers(vec![stub.url("/extra")]).unwrap();
+        // the dead tracker of the torrent fails, the added one answers
+        assert_eq!(client.trackers().len(), 2);
+        let peers = client.look_for_peers(client.id, 6881).await.unwrap();
+        let addrs: Vec<_> = peers.iter().map(|peer| peer.addr()).collect();
+        assert_eq!(addrs, vec!["10.0.0.7:6881".parse().unwrap()]);
+        assert_eq!(stub.requests().len(), 1);
+
+        let mut info = testutil::info("private", &content, PIECE);
+        info.private = Some(1);
+        let torrent = testutil::torrent_of(info);
+        let client = testutil::builder_of(&torrent).build().unwrap();
+        assert!(client.add_trackers(vec![stub.url("/extra")]).is_err());
+        assert_eq!(client.trackers(), torrent.trackers());
+        assert!(testutil::builder_of(&torrent)
+            .add_trackers(vec![stub.url("/extra")])
+            .build()
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn tracker_stats_record_each_announce() {
+        let mut body = b"d8:intervali60e5:peers12:".to_vec();
+        body.extend([10, 0, 0, 7, 0x1a, 0xe1, 10, 0, 0, 8, 0x1a, 0xe1]);
+        body.push(b'e');
+        let working = testutil::HttpStub::new(200, "text/plain", body).await;
+        let failing = testutil::HttpStub::new(500, "text/plain", b"down".to_vec()).await;
+        let client = testutil::client(&testutil::content(1000), PIECE);
+        client
+            .add_trackers(vec![failing.url("/announce"), working.url("/announce")])
+            .unwrap();
+        for _ in 0..2 {
+            client.look_for_peers(client.id, 6881).await.unwrap();
+        }
+        let stats = client.tracker_stats();
+        assert_eq!(stats.len(), 3);
+        let working = &stats[&working.url("/announce")];
+        assert_eq!(working.result, AnnounceResult::Success);
+        assert_eq!((working.peers, working.announces), (2, 2));
+        for tracker in [testutil::DEAD_TRACKER.to_string(), failing.url("/announce")] {
+            let stats = &stats[&tracker];
+            assert!(
+                matches!(stats.result, AnnounceResult::Failure(_)),
+                "{:?}",
+                stats.result
+            );
+            assert_eq!((stats.peers, stats.announces), (0, 2));
+        }
+        // a tracker which does not answer is told apart from one which fails
+        let timed_out =
+            std::io::Error::new(std::io::ErrorKind::TimedOut, "udp tracker did not answer");
+        assert!(tracker::is_timeout(&timed_out.into()));
+        assert!(!tracker::is_timeout(&anyhow!("tracker returned failure")));
+    }
+
+    /// a storage logging the thread of each write and how many overlapped at most
+    #[derive(Debug, Default)]
+    struct WriterLog {
+        pieces: MemoryStorage,
+        threads: std::sync::Mutex<Vec<String>>,
+        writing: AtomicUsize,
+        overlapped: AtomicUsize,
+    }
+
+    impl Storage for WriterLog {
+        fn write_piece(&self, index: u32, data: &[u8]) -> Result<()> {
+            let writing = self.writing.fetch_add(1, Ordering::SeqCst) + 1;
+            self.overlapped.fetch_max(writing, Ordering::SeqCst);
+            let thread = std::thread::current()
+                .name()
+                .unwrap_or_default()
+                .to_string();
+            self.threads.lock().unwrap().push(thread);
+            // long enough for the other peers to complete pieces meanwhile
+            std::thread::sleep(Duration::from_millis(20));
+            let written = self.pieces.write_piece(index, data);
+            self.writing.fetch_sub(1, Ordering::SeqCst);
+            written
+        }
+
+        fn read_piece(&self, index: u32) -> Result<Option<Vec<u8>>> {
+            self.pieces.read_piece(index)
+        }
+
+        fn has_piece(&self, index: u32) -> bool {
+            self.pieces.has_piece(index)
+        }
+
+        fn finalize(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn serial_writes_go_through_the_writer_thread() {
+        let content = testutil::content(300_000);
+        let info_hash = testutil::torrent("rbt-test-memory", &content, PIECE).info_hash;
+        let mut seeds = Vec::new();
+        let mut served = Vec::new();
+        for _ in 0..3 {
+            let behavior = SeedBehavior::default();
+            served.push(behavior.requests.clone());
+            seeds.push(testutil::seed(content.clone(), PIECE, info_hash, behavior).await);
+        }
+        let log = Arc::new(WriterLog::default());
+        let client = testutil::builder(&content, PIECE)
+            .set_storage(log.clone())
+            .set_serial_writes(true)
+            .add_peers(seeds)
+            .build()
+            .unwrap();
+        assert!(client.download_to_vec().await.unwrap() == content);
+        // pieces completed by several peers at once, written one after the other
+        let serving = served
+            .iter()
+            .filter(|requests| !requests.lock().unwrap().is_empty())
+            .count();
+        assert!(serving >= 2, "{} seeds served", serving);
+        let threads = log.threads.lock().unwrap();
+        assert_eq!(threads.len(), 10);
+        assert!(
+            threads.iter().all(|thread| thread == "rbt-writer"),
+            "{:?}",
+            threads
+        );
+        assert_eq!(log.overlapped.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn resume_state_of_another_layout_is_discarded() {
+        let name = testutil::unique_name("layout");
+        let _output = testutil::Scratch::output(&name);
+        let old = testutil::content(100_000);
+        let old_torrent = testutil::torrent_of(testutil::info(&name, &old, PIECE));
+        let old_client = testutil::builder_of(&old_torrent)
+            .set_control_file(true)
+            .build()
+            .unwrap();
+        (0..4).for_each(|index| old_client.bitfield.lock().unwrap().set_piece(index));
+        old_client.downloaded.store(100_000, Ordering::Relaxed);
+        old_client.save_control_file().unwrap();
+
+        // a new version of the torrent, with a piece more, over the same cache
+        let new: Vec<u8> = testutil::content(150_000).iter().map(|b| b ^ 1).collect();
+        let storage = Arc::new(MemoryStorage::new());
+        storage.write_piece(0, &new[..PIECE as usize]).unwrap();
+        storage
+            .write_piece(1, &old[PIECE as usize..2 * PIECE as usize])
+            .unwrap();
+        storage.write_piece(3, &old[3 * PIECE as usize..]).unwrap();
+        let client =
+            testutil::builder_of(&testutil::torrent_of(testutil::info(&name, &new, PIECE)))
+                .set_storage(storage)
+                .set_control_file(true)
+                .build()
+                .unwrap();
+        client.resume().await.unwrap();
+        assert!(!client.control_file_path().exists());
+        // every piece hashed again, only the one matching the new version kept
+        assert_eq!(client.verify_progress(), (5, 5));
+        let bitfield = client.bitfield.lock().unwrap();
+        assert!(bitfield.has_piece(0));
+        assert!((1..5).all(|index| !bitfield.has_piece(index)));
+        assert_eq!(client.downloaded.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn short_final_blocks_are_not_padded() {
+        // the final piece ends in a block of a thousand bytes
+        let content = testutil::content(3 * PIECE as usize + Peer::BLOCK_SIZE as usize + 1000);
+        for piece_cache in [false, true] {
+            let name = testutil::unique_name("short-block");
+            let _output = testutil::Scratch::output(&name);
+            let bytes = testutil::torrent_bytes(&name, &content, PIECE);
+            let info_hash = crate::Torrent::from_bytes(&bytes).unwrap().info_hash;
+            let seed =
+                testutil::seed(content.clone(), PIECE, info_hash, SeedBehavior::default()).await;
+            let client = crate::TorrentClientBuilder::new()
+                .add_torrent_bytes(&bytes)
+                .unwrap()
+                .set_retry_policy(crate::RetryPolicy::none())
+                .set_piece_cache(piece_cache)
+                .add_peers(vec![seed])
+                .build()
+                .unwrap();
+            client.send_request().await.unwrap();
+            let output = std::fs::read(&name).unwrap();
+            assert_eq!(output.len(), content.len(), "piece cache: {}", piece_cache);
+            assert!(output == content);
+            // a final piece padded to full blocks is refused rather than written
+            let mut padded = content[3 * PIECE as usize..].to_vec();
+            padded.resize(2 * Peer::BLOCK_SIZE as usize, 0);
+            let err = client.storage.write_piece(3, &padded).unwrap_err();
+            assert!(err.to_string().contains("instead of 17384"), "{}", err);
+        }
+    }
+
+    #[tokio::test]
+    async fn transfer_totals_survive_a_restart_and_are_announced() {
+        let name = testutil::unique_name("totals");
+        let _output = testutil::Scratch::output(&name);
+        let stub =
+            testutil::HttpStub::new(200, "text/plain", b"d8:intervali60e5:peers0:e".to_vec()).await;
+        let content = testutil::content(100_000);
+        let mut torrent = testutil::torrent_of(testutil::info(&name, &content, PIECE));
+        torrent.announce = stub.url("/announce");
+        let storage = Arc::new(MemoryStorage::new());
+        storage.write_piece(0, &content[..PIECE as usize]).unwrap();
+        let builder = || {
+            testutil::builder_of(&torrent)
+                .set_storage(storage.clone())
+                .set_control_file(true)
+                .set_peer_id(*b"-RB0001-abcdefghijkl")
+                .set_reannounce_attempts(0)
+        };
+        let first = builder().build().unwrap();
+        first.bitfield.lock().unwrap().set_piece(0);
+        first.downloaded.store(1 << 33, Ordering::Relaxed);
+        first.uploaded.store(5000, Ordering::Relaxed);
+        first.save_control_file().unwrap();
+        drop(first);
+
+        let second = builder().build().unwrap();
+        assert_eq!(second.uploaded.load(Ordering::Relaxed), 0);
+        // nobody in the swarm, but the announce went out with the totals
+        assert!(second.send_request().await.is_err());
+        assert_eq!(second.downloaded.load(Ordering::Relaxed), 1 << 33);
+        assert_eq!(second.uploaded.load(Ordering::Relaxed), 5000);
+        let requests = stub.requests();
+        let announce = &requests[0];
+        for param in [
+            "peer_id=-RB0001-abcdefghijkl",
+            "uploaded=5000",
+            "downloaded=8589934592",
+            "left=67232",
+        ] {
+            assert!(announce.contains(param), "{} in {}", param, announce);
+        }
+    }
+
+    #[test]
+    fn announces_fall_within_the_jitter_window() {
+        let interval = Duration::from_secs(1800);
+        let (low, high) = (interval.mul_f64(0.9), interval.mul_f64(1.1));
+        // a seeded linear congruential generator, the same units on every run
+        let mut state: u64 = 42;
+        let mut unit = || {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (state >> 11) as f64 / (1_u64 << 53) as f64 * 2.0 - 1.0
+        };
+        let delays: Vec<_> = (0..1000).map(|_| jittered(interval, 0.1, unit())).collect();
+        assert!(delays.iter().all(|delay| (low..=high).contains(delay)));
+        // spread over the window rather than bunched up at the interval
+        assert!(delays.iter().any(|delay| *delay < interval.mul_f64(0.92)));
+        assert!(delays.iter().any(|delay| *delay > interval.mul_f64(1.08)));
+        assert_eq!(jittered(interval, 0.1, -1.0), low);
+        assert_eq!(jittered(interval, 0.0, 0.7), interval);
+
+        let client = testutil::client(&testutil::content(1000), PIECE);
+        *client.announce_interval.lock().unwrap() = interval;
+        for _ in 0..100 {
+            assert!((low..=high).contains(&client.next_announce_delay()));
+        }
+        let client = testutil::builder(&testutil::content(1000), PIECE)
+            .set_announce_jitter(0.0)
+            .build()
+            .unwrap();
+        *client.announce_interval.lock().unwrap() = interval;
+        assert_eq!(client.next_announce_delay(), interval);
+    }
+
+    #[tokio::test]
+    async fn unparseable_trackers_are_skipped() {
+        let mut body = b"d8:intervali1800e5:peers6:".to_vec();
+        body.extend([10, 0, 0, 1, 0x1a, 0xe1]);
+        body.push(b'e');
+        let stub = testutil::HttpStub::new(200, "text/plain", body).await;
+        let content = testutil::content(1000);
+        let mut torrent = testutil::torrent_of(testutil::info("trackers", &content, PIECE));
+        torrent.announce = "not a url".to_string();
+        torrent.announce_list = Some(vec![
+            vec!["not a url".to_string()],
+            vec!["http://[::1".to_string(), stub.url("/announce")],
+        ]);
+        let client = testutil::builder_of(&torrent).build().unwrap();
+        let peers = client.look_for_peers(client.id, 6881).await.unwrap();
+        let addrs: Vec<_> = peers.iter().map(|peer| peer.addr()).collect();
+        assert_eq!(addrs, vec!["10.0.0.1:6881".parse().unwrap()]);
+        assert_eq!(stub.requests().len(), 1);
+        assert_eq!(
+            client.tracker_stats().into_keys().collect::<Vec<_>>(),
+            vec![stub.url("/announce")]
+        );
+    }
+
+    #[tokio::test]
+    async fn no_valid_tracker_names_them() {
+        let content = testutil::content(1000);
+        let mut torrent = testutil::torrent_of(testutil::info("trackers", &content, PIECE));
+        torrent.announce = "not a url".to_string();
+        let client = testutil::builder_of(&torrent).build().unwrap();
+        let err = client.look_for_peers(client.id, 6881).await.err().unwrap();
+        assert_eq!(err.to_string(), "no valid announce url among: not a url");
+    }
+
+    #[tokio::test]
+    async fn total_blocks_count_short_final_blocks() {
+        let content = testutil::content(100_000);
+        // three 32 KiB pieces of 2 blocks and a short one of 1696 bytes
+        assert_eq!(testutil::client(&content, PIECE).total_blocks(), 7);
+        // pieces of 40000 bytes end in a short block, the final piece of 20000 too
+        let client = testutil::seeded(&content, 40_000, SeedBehavior::default())
+            .await
+            .build()
+            .unwrap();
+        let per_piece: u64 = (0..client.piece_hashes.len() as u32)
+            .map(|index| client.piece_length_of(index).div_ceil(16384) as u64)
+            .sum();
+        assert_eq!(client.total_blocks(), per_piece);
+        assert_eq!(client.total_blocks(), 3 + 3 + 2);
+        assert_eq!(client.blocks_completed(), 0);
+        assert!(client.download_to_vec().await.unwrap() == content);
+        assert_eq!(client.blocks_completed(), client.total_blocks());
+    }
+
+    #[tokio::test]
+    async fn resume_is_keyed_on_the_info_hash() {
+        let name = testutil::unique_name("resume");
+        let _scratch = testutil::Scratch::output(&name);
+        let content = testutil::content(100_000);
+        let storage = Arc::new(MemoryStorage::new());
+        for (index, piece) in content.chunks(PIECE as usize).enumerate() {
+            storage.write_piece(index as u32, piece).unwrap();
+        }
+        let client_of = |torrent: &BencodeTorrent| {
+            testutil::builder_of(torrent)
+                .set_storage(storage.clone())
+                .set_control_file(true)
+                .build()
+                .unwrap()
+        };
+        let original = testutil::torrent_of(testutil::info(&name, &content, PIECE));
+        let first = client_of(&original);
+        first.verify_existing().await.unwrap();
+        first.save_control_file().unwrap();
+
+        // other trackers and a comment, but the same info dictionary
+        let mut cosmetic = testutil::torrent_of(testutil::info(&name, &content, PIECE));
+        cosmetic.announce = "http://127.0.0.1:9/other".to_string();
+        cosmetic.comment = Some(bytes::Bytes::from_static(b"re-released"));
+        let resumed = client_of(&cosmetic);
+        assert_eq!(resumed.info_hash, first.info_hash);
+        resumed.resume().await.unwrap();
+        assert_eq!(resumed.verify_progress().0, 0, "nothing is hashed again");
+        assert_eq!(resumed.remaining_pieces(), 0);
+
+        // same name and layout, other content
+        let mut changed = content.clone();
+        changed[0] ^= 1;
+        let changed = client_of(&testutil::torrent_of(testutil::info(
+            &name, &changed, PIECE,
+        )));
+        changed.resume().await.unwrap();
+        assert_eq!(
+            changed.verify_progress(),
+            (4, 4),
+            "everything is hashed again"
+        );
+        assert!(!changed.has_piece(0));
+        assert!((1..4).all(|index| changed.has_piece(index)));
+    }
+
+    /// a client named `name` with a seed which never unchokes it
+    async fn stalled_client(name: &str, control_file: bool) -> TorrentClient {
+        let content = testutil::content(100_000);
+        let torrent = testutil::torrent_of(testutil::info(name, &content, PIECE));
+        let info_hash = testutil::torrent(name, &content, PIECE).info_hash;
+        let behavior = SeedBehavior {
+            choke: true,
+            ..Default::default()
+        };
+        let seed = testutil::seed(content, PIECE, info_hash, behavior).await;
+        testutil::builder_of(&torrent)
+            .add_peers(vec![seed])
+            .set_control_file(control_file)
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn run_until_a_signal_saves_the_control_file() {
+        let name = testutil::unique_name("signal");
+        let _scratch = testutil::Scratch::output(&name);
+        let client = stalled_client(&name, true).await;
+        let (signal, signaled) = tokio::sync::oneshot::channel::<()>();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            let _ = signal.send(());
+        });
+        client
+            .run_until(async {
+                let _ = signaled.await;
+            })
+            .await
+            .unwrap();
+        assert!(client.is_shutdown());
+        let control = ControlFile::load(client.control_file_path()).unwrap();
+        assert_eq!(control.info_hash, client.info_hash);
+    }
+
+    #[tokio::test]
+    async fn run_until_a_signal_leaves_no_control_file_when_disabled() {
+        let name = testutil::unique_name("signal");
+        let _scratch = testutil::Scratch::output(&name);
+        let client = stalled_client(&name, false).await;
+        client
+            .run_until(tokio::time::sleep(Duration::from_millis(200)))
+            .await
+            .unwrap();
+        assert!(client.is_shutdown());
+        assert!(!client.control_file_path().exists());
     }
 }
@@ -0,0 +1,656 @@
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{net::lookup_host, time::timeout};
+
+use crate::{bencode, error::RbitError, http, retry::RetryPolicy};
+
+mod udp;
+
+/// Decides by url which trackers are announced to, see
+/// [`crate::TorrentClientBuilder::set_tracker_filter`].
+#[derive(Clone)]
+pub struct TrackerFilter(Arc<dyn Fn(&str) -> bool + Send + Sync>);
+
+impl TrackerFilter {
+    pub fn new<F>(filter: F) -> Self
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        Self(Arc::new(filter))
+    }
+
+    pub fn allows(&self, tracker: &str) -> bool {
+        (self.0)(tracker)
+    }
+}
+
+impl fmt::Debug for TrackerFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("TrackerFilter")
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TrackerReport {
+    #[serde(rename = "failure reason")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    failure_reason: Option<String>,
+    #[serde(default)]
+    pub interval: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    peers: Option<PeerList>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    peers6: Option<PeerList>,
+    #[serde(rename = "external ip")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    external_ip: Option<Bytes>,
+}
+
+/// `peers` and `peers6` come either compact or as a list of dictionaries (BEP 3),
+/// anything else is ignored rather than failing the whole announce
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum PeerList {
+    Compact(Bytes),
+    Dict(Vec<PeerEntry>),
+    #[serde(skip_serializing)]
+    Other(serde::de::IgnoredAny),
+}
+
+#[derive(Serialize, Deserialize)]
+struct PeerEntry {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ip: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    port: Option<u16>,
+}
+
+impl PeerList {
+    /// `addr_len` is the size of a compact address, 4 for `peers` and 16 for `peers6`
+    fn addrs(&self, addr_len: usize) -> Vec<SocketAddr> {
+        match self {
+            Self::Compact(buf) => buf
+                .chunks_exact(addr_len + 2)
+                .map(|chunk| {
+                    let ip = match addr_len {
+                        4 => IpAddr::from(<[u8; 4]>::try_from(&chunk[..4]).unwrap()),
+                        _ => IpAddr::from(<[u8; 16]>::try_from(&chunk[..16]).unwrap()),
+                    };
+                    let port = u16::from_be_bytes([chunk[addr_len], chunk[addr_len + 1]]);
+                    SocketAddr::new(ip, port)
+                })
+                .collect(),
+            Self::Dict(entries) => entries
+                .iter()
+                .filter_map(|entry| match (entry.ip.as_deref(), entry.port) {
+                    (Some(ip), Some(port)) => Some(SocketAddr::new(ip.parse().ok()?, port)),
+                    _ => {
+                        warn!("skip malformed peer entry {:?}:{:?}", entry.ip, entry.port);
+                        None
+                    }
+                })
+                .collect(),
+            Self::Other(_) => vec![],
+        }
+    }
+
+    /// dictionary entries naming their peer by host name instead of address
+    fn hosts(&self) -> Vec<(String, u16)> {
+        let Self::Dict(entries) = self else {
+            return vec![];
+        };
+        entries
+            .iter()
+            .filter_map(|entry| Some((entry.ip.clone()?, entry.port?)))
+            .filter(|(ip, _)| ip.parse::<IpAddr>().is_err())
+            .collect()
+    }
+}
+
+impl TrackerReport {
+    /// how much of a non-bencode body ends up in the error message
+    const SNIPPET_LEN: usize = 128;
+    const RESOLVE_TIMEOUT: Duration = Duration::from_secs(3);
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self> {
+        if buf.first() != Some(&b'd') || bencode::value_len(buf).is_err() {
+            let snippet = String::from_utf8_lossy(&buf[..buf.len().min(Self::SNIPPET_LEN)]);
+            return Err(anyhow!(
+                "tracker returned non-bencode response: {}",
+                snippet.trim()
+            ));
+        }
+        let report: Self = serde_bencode::from_bytes(buf)?;
+        if let Some(reason) = report.failure_reason {
+            return Err(RbitError::TrackerFailure(reason).into());
+        }
+        Ok(report)
+    }
+
+    /// our address as seen by the tracker (BEP 24), if it told us
+    pub fn external_ip(&self) -> Option<IpAddr> {
+        let buf = self.external_ip.as_deref()?;
+        match buf.len() {
+            4 => Some(IpAddr::from(<[u8; 4]>::try_from(buf).ok()?)),
+            16 => Some(IpAddr::from(<[u8; 16]>::try_from(buf).ok()?)),
+            _ => None,
+        }
+    }
+
+    /// like [`Self::peers`], with the host names of dictionary entries resolved.
+    /// A name which does not resolve is skipped
+    pub async fn resolve_peers(&self) -> Vec<SocketAddr> {
+        let mut peers = self.peers();
+        let hosts = self
+            .peers
+            .iter()
+            .chain(self.peers6.iter())
+            .flat_map(PeerList::hosts);
+        for (host, port) in hosts {
+            match timeout(Self::RESOLVE_TIMEOUT, lookup_host((host.as_str(), port))).await {
+                Ok(Ok(mut addrs)) => peers.extend(addrs.next()),
+                Ok(Err(err)) => warn!("skip peer {}:{}, it does not resolve: {}", host, port, err),
+                Err(_) => warn!("skip peer {}:{}, resolving it timed out", host, port),
+            }
+        }
+        peers
+    }
+
+    /// every peer of `peers` and `peers6`, whichever form they come in
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        let mut peers = vec![];
+        if let Some(list) = &self.peers {
+            peers.extend(list.addrs(4));
+        }
+        if let Some(list) = &self.peers6 {
+            peers.extend(list.addrs(16));
+        }
+        peers
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnounceEvent {
+    Started,
+    Completed,
+    Stopped,
+}
+
+impl AnnounceEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Started => "started",
+            Self::Completed => "completed",
+            Self::Stopped => "stopped",
+        }
+    }
+}
+
+/// The parameters sent to a tracker on announce.
+#[derive(Debug, Clone)]
+pub struct AnnounceRequest {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: Option<AnnounceEvent>,
+    /// order of the query parameters for trackers picky about it, `None` for the usual one
+    pub param_order: Option<Vec<String>>,
+    /// the largest response body read from an HTTP tracker
+    pub max_response_size: usize,
+}
+
+impl AnnounceRequest {
+    const TIMEOUT: Duration = Duration::from_secs(15);
+    const MAX_REDIRECTS: usize = 5;
+
+    /// announce to `announce`, over UDP for `udp://` trackers. Over HTTP, redirects are
+    /// followed by ourselves, so a redirect to `https://` or `udp://` goes on over that
+    /// scheme and one to anything else is reported instead of failing somewhere inside
+    /// the HTTP client
+    pub async fn send(&self, announce: &str) -> Result<TrackerReport> {
+        let url = url::Url::parse(announce)
+            .map_err(|err| anyhow!("invalid announce url {}: {}", announce, err))?;
+        if url.scheme() == "udp" {
+            return udp::announce(self, &url).await;
+        }
+        let client = http::builder()?
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+        let mut announce = announce.to_string();
+        for _ in 0..=Self::MAX_REDIRECTS {
+            let res = client
+                .get(self.announce_url(&announce))
+                .timeout(Self::TIMEOUT)
+                .send()
+                .await?;
+            if !res.status().is_redirection() {
+                return TrackerReport::from_bytes(
+                    &http::read_body(res, self.max_response_size).await?,
+                );
+            }
+            let location = res
+                .headers()
+                .get(reqwest::header::LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .ok_or_else(|| anyhow!("tracker {} redirected without a location", announce))?;
+            let mut next = url::Url::parse(&announce)?.join(location).map_err(|err| {
+                anyhow!("tracker {} redirected to {}: {}", announce, location, err)
+            })?;
+            match next.scheme() {
+                "http" | "https" => {}
+                "udp" => {
+                    info!("tracker {} redirected to {}", announce, next);
+                    return udp::announce(self, &next).await;
+                }
+                scheme => {
+                    return Err(anyhow!(
+                        "tracker {} redirected to {}, {} trackers are not supported",
+                        announce,
+                        next,
+                        scheme
+                    ))
+                }
+            }
+            info!("tracker {} redirected to {}", announce, next);
+            // the announce parameters are added again for the new location
+            next.set_query(None);
+            announce = next.into();
+        }
+        Err(anyhow!("tracker {} redirected too many times", announce))
+    }
+
+    /// `announce` exactly as given with the announce parameters appended, so the
+    /// path and any query of its own (e.g. a passkey) reach the tracker untouched
+    pub fn announce_url(&self, announce: &str) -> String {
+        let mut params: Vec<(&str, String)> = vec![
+            (
+                "info_hash",
+                url::form_urlencoded::byte_serialize(&self.info_hash[..]).collect(),
+            ),
+            (
+                "peer_id",
+                url::form_urlencoded::byte_serialize(&self.peer_id[..]).collect(),
+            ),
+            ("port", self.port.to_string()),
+            ("uploaded", self.uploaded.to_string()),
+            ("downloaded", self.downloaded.to_string()),
+            ("compact", "1".to_string()),
+            ("left", self.left.to_string()),
+        ];
+        if let Some(event) = self.event {
+            params.push(("event", event.as_str().to_string()));
+        }
+        if let Some(order) = &self.param_order {
+            // listed parameters first and in that order, the rest as they are
+            params.sort_by_key(|(key, _)| {
+                order
+                    .iter()
+                    .position(|listed| listed == key)
+                    .unwrap_or(order.len())
+            });
+        }
+        let (base, fragment) = match announce.split_once('#') {
+            Some((base, fragment)) => (base, Some(fragment)),
+            None => (announce, None),
+        };
+        let mut url = base.to_string();
+        for (key, value) in params {
+            let sep = match url.find('?') {
+                None => "?",
+                Some(pos) if pos + 1 == url.len() || url.ends_with('&') => "",
+                Some(_) => "&",
+            };
+            url.push_str(sep);
+            url.push_str(key);
+            url.push('=');
+            url.push_str(&value);
+        }
+        if let Some(fragment) = fragment {
+            url.push('#');
+            url.push_str(fragment);
+        }
+        url
+    }
+
+    /// send the announce, retrying transient failures as `policy` says.
+    /// A `failure reason` from the tracker is permanent and returned right away.
+    pub async fn send_with_retries(
+        &self,
+        announce: &str,
+        policy: &RetryPolicy,
+    ) -> Result<TrackerReport> {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            match self.send(announce).await {
+                Ok(report) => return Ok(report),
+                Err(err) if err.downcast_ref::<RbitError>().is_some() => return Err(err),
+                Err(err) if !policy.retries_left(attempt) => return Err(err),
+                Err(err) => {
+                    let delay = policy.delay(attempt - 1);
+                    info!(
+                        "tracker {} failed: {}, retry {}/{} in {:?}",
+                        announce,
+                        err,
+                        attempt,
+                        policy.max_attempts - 1,
+                        delay
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+    }
+}
+
+/// whether an announce failed as the tracker did not answer in time
+pub fn is_timeout(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause
+            .downcast_ref::<reqwest::Error>()
+            .is_some_and(reqwest::Error::is_timeout)
+            || cause
+                .downcast_ref::<std::io::Error>()
+                .is_some_and(|err| err.kind() == std::io::ErrorKind::TimedOut)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::HttpStub;
+
+    fn request() -> AnnounceRequest {
+        AnnounceRequest {
+            info_hash: [1; 20],
+            peer_id: [2; 20],
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 100,
+            event: None,
+            param_order: None,
+            max_response_size: 1 << 20,
+        }
+    }
+
+    #[tokio::test]
+    async fn announce_url_keeps_path_and_query() {
+        let mut request = request();
+        let url = request.announce_url("http://t.example/a/announce/?passkey=Ab%2Fc&x=1");
+        assert!(url.starts_with("http://t.example/a/announce/?passkey=Ab%2Fc&x=1&info_hash="));
+        assert!(url.ends_with("&compact=1&left=100"));
+
+        request.param_order = Some(vec!["port".to_string(), "info_hash".to_string()]);
+        let url = request.announce_url("http://t.example/announce?");
+        assert!(url.starts_with("http://t.example/announce?port=6881&info_hash="));
+        let url = request.announce_url("http://t.example/announce#frag");
+        assert!(url.ends_with("&left=100#frag"));
+
+        // and so it reaches the tracker
+        let stub = HttpStub::new(200, "text/plain", b"d8:intervali60e5:peers0:e".to_vec()).await;
+        request
+            .send(&stub.url("/pk/Ab%2Fc/announce/?key=%7E1"))
+            .await
+            .unwrap();
+        let line = stub.requests().remove(0);
+        assert!(line.starts_with("GET /pk/Ab%2Fc/announce/?key=%7E1&port=6881&info_hash="));
+    }
+
+    #[tokio::test]
+    async fn oversized_responses_are_rejected_early() {
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // a tracker streaming an endless body, without saying how long it is
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let sent = Arc::new(AtomicUsize::new(0));
+        let counter = sent.clone();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let _ = stream.read(&mut [0; 4096]).await;
+            let head = "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nConnection: close\r\n\r\n";
+            stream.write_all(head.as_bytes()).await.unwrap();
+            let chunk = vec![b'x'; 64 * 1024];
+            while counter.load(Ordering::Relaxed) < 256 << 20 {
+                if stream.write_all(&chunk).await.is_err() {
+                    break;
+                }
+                counter.fetch_add(chunk.len(), Ordering::Relaxed);
+            }
+        });
+        let err = request()
+            .send(&format!("http://{}/announce", addr))
+            .await
+            .err()
+            .unwrap();
+        assert!(
+            err.to_string().contains("larger than 1048576 bytes"),
+            "{}",
+            err
+        );
+        // given up on long before the server was done
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(sent.load(Ordering::Relaxed) < 32 << 20);
+
+        // a declared length beyond the limit is not read at all
+        let stub = HttpStub::new(200, "text/plain", vec![b'x'; 2 << 20]).await;
+        let err = request().send(&stub.url("/announce")).await.err().unwrap();
+        assert!(err.to_string().contains("larger than"), "{}", err);
+    }
+
+    #[test]
+    fn non_bencode_body_is_quoted() {
+        let err = TrackerReport::from_bytes(b"<html><body>502 Bad Gateway</body></html>")
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "tracker returned non-bencode response: <html><body>502 Bad Gateway</body></html>"
+        );
+        let long = vec![b'x'; 1000];
+        let err = TrackerReport::from_bytes(&long).err().unwrap().to_string();
+        assert!(err.ends_with(&"x".repeat(TrackerReport::SNIPPET_LEN)));
+        assert!(!err.ends_with(&"x".repeat(TrackerReport::SNIPPET_LEN + 1)));
+    }
+
+    #[test]
+    fn peers_and_peers6_are_combined() {
+        let mut body = b"d8:intervali60e5:peers12:".to_vec();
+        body.extend([10, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0x1a, 0xe2]);
+        body.extend(b"6:peers618:");
+        body.extend(std::net::Ipv6Addr::LOCALHOST.octets());
+        body.extend([0x1a, 0xe3]);
+        body.push(b'e');
+        let report = TrackerReport::from_bytes(&body).unwrap();
+        let expected: Vec<SocketAddr> = ["10.0.0.1:6881", "10.0.0.2:6882", "[::1]:6883"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        assert_eq!(report.peers(), expected);
+    }
+
+    #[test]
+    fn odd_peer_lists_are_tolerated() {
+        // dictionary peers next to an integer where the compact peers6 should be
+        let body =
+            b"d8:intervali60e5:peersld2:ip8:10.0.0.74:porti6881eed2:ip3:bad4:porti1eee6:peers6i0ee";
+        let report = TrackerReport::from_bytes(body).unwrap();
+        assert_eq!(report.peers(), vec!["10.0.0.7:6881".parse().unwrap()]);
+        let hosts = report.peers.as_ref().unwrap().hosts();
+        assert_eq!(hosts, vec![("bad".to_string(), 1)]);
+    }
+
+    #[tokio::test]
+    async fn html_error_page_from_a_tracker() {
+        let stub = HttpStub::new(
+            503,
+            "text/html",
+            b"<html>tracker overloaded, try later</html>".to_vec(),
+        )
+        .await;
+        let err = request()
+            .send(&stub.url("/announce"))
+            .await
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(
+            err.contains("non-bencode response: <html>tracker overloaded"),
+            "{}",
+            err
+        );
+    }
+
+    /// a tracker answering every announce with a redirect to `location`
+    async fn redirecting_tracker(location: String) -> HttpStub {
+        HttpStub::with_headers(301, &format!("Location: {}\r\n", location), vec![]).await
+    }
+
+    #[tokio::test]
+    async fn redirect_to_https_is_followed() {
+        // no TLS in the tests, so look for the client hello arriving at the new location
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let https = format!("https://{}/announce", listener.local_addr().unwrap());
+        let tracker = redirecting_tracker(https).await;
+        let hello = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut record = [0; 1];
+            tokio::io::AsyncReadExt::read_exact(&mut stream, &mut record)
+                .await
+                .unwrap();
+            record[0]
+        });
+        let err = request()
+            .send(&tracker.url("/announce"))
+            .await
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(!err.contains("redirected"), "{}", err);
+        // a TLS handshake record
+        assert_eq!(hello.await.unwrap(), 0x16);
+        assert_eq!(tracker.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn redirect_to_udp_announces_over_udp() {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let udp = format!("udp://{}/announce", socket.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut buf = [0; 2048];
+            // connect, then announce, answering with the transaction id of each
+            for action in [0_u32, 1] {
+                let (_, from) = socket.recv_from(&mut buf).await.unwrap();
+                let mut res = action.to_be_bytes().to_vec();
+                // both requests carry the transaction id after 12 bytes
+                res.extend_from_slice(&buf[12..16]);
+                match action {
+                    0 => res.extend(7_u64.to_be_bytes()),
+                    _ => {
+                        res.extend(60_u32.to_be_bytes());
+                        res.extend([0; 8]);
+                        res.extend([10, 0, 0, 3, 0x1a, 0xe1]);
+                    }
+                }
+                socket.send_to(&res, from).await.unwrap();
+            }
+        });
+        let tracker = redirecting_tracker(udp).await;
+        let report = request().send(&tracker.url("/announce")).await.unwrap();
+        assert_eq!(report.peers(), vec!["10.0.0.3:6881".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn redirect_to_an_unknown_scheme_is_reported() {
+        let tracker = redirecting_tracker("wss://tracker.example/announce".into()).await;
+        let err = request()
+            .send(&tracker.url("/announce"))
+            .await
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("wss trackers are not supported"), "{}", err);
+    }
+
+    fn policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(50),
+            jitter: 0.0,
+        }
+    }
+
+    /// a tracker answering 503 to its first `failures` announces, then with a peer
+    async fn flaky_tracker(failures: usize) -> HttpStub {
+        let count = std::sync::atomic::AtomicUsize::new(0);
+        HttpStub::with_handler(move |_| {
+            if count.fetch_add(1, std::sync::atomic::Ordering::Relaxed) < failures {
+                return (503, String::new(), b"overloaded".to_vec());
+            }
+            let mut body = b"d8:intervali60e5:peers6:".to_vec();
+            body.extend([10, 0, 0, 2, 0x1a, 0xe1]);
+            body.push(b'e');
+            (200, String::new(), body)
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn transient_failure_is_retried() {
+        let tracker = flaky_tracker(1).await;
+        let report = request()
+            .send_with_retries(&tracker.url("/announce"), &policy(3))
+            .await
+            .unwrap();
+        assert_eq!(report.peers(), vec!["10.0.0.2:6881".parse().unwrap()]);
+        assert_eq!(tracker.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn retries_run_out() {
+        let tracker = flaky_tracker(5).await;
+        let result = request()
+            .send_with_retries(&tracker.url("/announce"), &policy(3))
+            .await;
+        assert!(result.is_err());
+        assert_eq!(tracker.requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn failure_reason_is_not_retried() {
+        let tracker = HttpStub::new(
+            200,
+            "text/plain",
+            b"d14:failure reason12:unregisterede".to_vec(),
+        )
+        .await;
+        let err = request()
+            .send_with_retries(&tracker.url("/announce"), &policy(3))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.downcast_ref::<RbitError>(),
+            Some(&RbitError::TrackerFailure("unregistered".to_string()))
+        );
+        assert_eq!(tracker.requests().len(), 1);
+    }
+}
@@ -0,0 +1,151 @@
+use std::{
+    hash::{BuildHasher, RandomState},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use log::info;
+use tokio::{net::UdpSocket, time::timeout};
+
+use super::{AnnounceEvent, AnnounceRequest, PeerList, TrackerReport};
+use crate::error::RbitError;
+
+/// magic of the connect request
+const PROTOCOL_ID: u64 = 0x41727101980;
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_ERROR: u32 = 3;
+const ATTEMPTS: u32 = 4;
+const FIRST_TIMEOUT: Duration = Duration::from_secs(3);
+const MAX_PACKET: usize = 2048;
+
+/// announce over the UDP tracker protocol (BEP 15): a connect round trip for a
+/// connection id, then the announce itself. Packets get lost, so each round trip
+/// is retried with a growing timeout
+pub async fn announce(request: &AnnounceRequest, url: &url::Url) -> Result<TrackerReport> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow!("udp tracker {} has no host", url))?;
+    let port = url
+        .port()
+        .ok_or_else(|| anyhow!("udp tracker {} has no port", url))?;
+    let addr = tokio::net::lookup_host((host, port))
+        .await?
+        .next()
+        .ok_or_else(|| anyhow!("udp tracker {} does not resolve", url))?;
+    let bind: SocketAddr = match addr {
+        SocketAddr::V4(_) => ([0u8; 4], 0).into(),
+        SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(addr).await?;
+
+    let connection_id = {
+        let transaction_id = transaction_id();
+        let mut buf = BytesMut::with_capacity(16);
+        buf.put_u64(PROTOCOL_ID);
+        buf.put_u32(ACTION_CONNECT);
+        buf.put_u32(transaction_id);
+        let mut res = round_trip(&socket, &buf, ACTION_CONNECT, transaction_id).await?;
+        if res.remaining() < 8 {
+            return Err(anyhow!("udp tracker {} sent a short connect response", url));
+        }
+        res.get_u64()
+    };
+
+    let transaction_id = transaction_id();
+    let mut buf = BytesMut::with_capacity(98);
+    buf.put_u64(connection_id);
+    buf.put_u32(ACTION_ANNOUNCE);
+    buf.put_u32(transaction_id);
+    buf.put_slice(&request.info_hash);
+    buf.put_slice(&request.peer_id);
+    buf.put_u64(request.downloaded);
+    buf.put_u64(request.left);
+    buf.put_u64(request.uploaded);
+    buf.put_u32(match request.event {
+        None => 0,
+        Some(AnnounceEvent::Completed) => 1,
+        Some(AnnounceEvent::Started) => 2,
+        Some(AnnounceEvent::Stopped) => 3,
+    });
+    buf.put_u32(0); // ip, the one the packet comes from
+    buf.put_u32(transaction_id); // key
+    buf.put_i32(-1); // num_want, as many as the tracker likes
+    buf.put_u16(request.port);
+    let mut res = round_trip(&socket, &buf, ACTION_ANNOUNCE, transaction_id).await?;
+    if res.remaining() < 12 {
+        return Err(anyhow!(
+            "udp tracker {} sent a short announce response",
+            url
+        ));
+    }
+    let interval = res.get_u32();
+    let _leechers = res.get_u32();
+    let _seeders = res.get_u32();
+    // peers come in the address family the tracker was reached with
+    let peers = Some(PeerList::Compact(res));
+    let (peers, peers6) = match addr {
+        SocketAddr::V4(_) => (peers, None),
+        SocketAddr::V6(_) => (None, peers),
+    };
+    Ok(TrackerReport {
+        failure_reason: None,
+        interval: interval as i64,
+        peers,
+        peers6,
+        external_ip: None,
+    })
+}
+
+/// send `packet` until a response with `transaction_id` arrives and return what
+/// follows its header. Responses to other transactions are stale and skipped
+async fn round_trip(
+    socket: &UdpSocket,
+    packet: &[u8],
+    action: u32,
+    transaction_id: u32,
+) -> Result<Bytes> {
+    let mut wait = FIRST_TIMEOUT;
+    for attempt in 1..=ATTEMPTS {
+        socket.send(packet).await?;
+        let deadline = Instant::now() + wait;
+        loop {
+            let mut buf = vec![0; MAX_PACKET];
+            let left = deadline.saturating_duration_since(Instant::now());
+            let Ok(received) = timeout(left, socket.recv(&mut buf)).await else {
+                info!(
+                    "udp tracker did not answer in {:?} ({}/{})",
+                    wait, attempt, ATTEMPTS
+                );
+                break;
+            };
+            buf.truncate(received?);
+            if buf.len() < 8 {
+                continue;
+            }
+            let mut res = Bytes::from(buf);
+            let got_action = res.get_u32();
+            if res.get_u32() != transaction_id {
+                continue;
+            }
+            return match got_action {
+                ACTION_ERROR => Err(RbitError::TrackerFailure(
+                    String::from_utf8_lossy(&res).into_owned(),
+                )
+                .into()),
+                got if got == action => Ok(res),
+                got => Err(anyhow!("udp tracker answered action {} to {}", got, action)),
+            };
+        }
+        wait *= 2;
+    }
+    Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "udp tracker did not answer").into())
+}
+
+/// a random transaction id, good enough to tell responses apart
+fn transaction_id() -> u32 {
+    RandomState::new().hash_one(Instant::now()) as u32
+}
@@ -0,0 +1,296 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use sha1::Digest;
+use tokio::sync::{broadcast, watch};
+
+use crate::{
+    event::Event,
+    message::{Bitfield, Piece, Request},
+    peer::Peer,
+    storage::{DiskQuota, Storage},
+    task::{Task, TaskQueue},
+    torrent::TorrentClient,
+};
+
+/// Checks and stores completed pieces off the peer task, so a peer can keep
+/// downloading while the previous piece is still being hashed.
+#[derive(Debug, Clone)]
+pub struct PieceVerifier {
+    pub storage: Arc<dyn Storage>,
+    pub task_queue: Arc<TaskQueue>,
+    pub verified: Arc<Mutex<Bitfield>>,
+    pub downloaded: Arc<AtomicU64>,
+    pub blocks_completed: Arc<AtomicU64>,
+    pub events: broadcast::Sender<Event>,
+    pub disk_quota: Option<Arc<DiskQuota>>,
+    /// how often each piece failed so far
+    pub failures: Arc<Mutex<HashMap<u32, u32>>>,
+    pub max_failures: u32,
+}
+
+impl PieceVerifier {
+    pub fn new(client: &TorrentClient) -> Self {
+        Self {
+            storage: client.storage.clone(),
+            task_queue: client.task_queue.clone(),
+            verified: client.bitfield.clone(),
+            downloaded: client.downloaded.clone(),
+            blocks_completed: client.blocks_completed.clone(),
+            events: client.events.clone(),
+            disk_quota: client.disk_quota.clone(),
+            failures: client.piece_failures.clone(),
+            max_failures: client.max_piece_failures,
+        }
+    }
+
+    /// check the blocks of a piece and store it, or put the task back on a bad hash.
+    /// `sources` are the peers which delivered the blocks, reported when it fails
+    pub fn verify(&self, task: Task, mut blocks: Vec<Piece>, sources: &[SocketAddr]) -> bool {
+        blocks.sort_unstable_by_key(|piece| piece.begin);
+        let mut data = Vec::with_capacity(task.piece_length as usize);
+        for block in blocks {
+            data.extend_from_slice(&block.piece);
+        }
+        let result = self
+            .check_sum(&task, &data)
+            .and_then(|_| self.storage.write_piece(task.index, &data))
+            .and_then(|_| self.storage.sync_piece(task.index));
+        self.release_quota(&task);
+        match result {
+            Ok(()) => {
+                self.mark_verified(&task);
+                self.downloaded
+                    .fetch_add(task.piece_length as u64, Ordering::Relaxed);
+                info!("piece #{} downloaded successfully", task.index);
+                let _ = self.events.send(Event::PieceVerified(task.index));
+                true
+            }
+            Err(err) => {
+                info!("{}", err);
+                if !sources.is_empty() {
+                    warn!(
+                        "piece #{} failed, its blocks came from {:?}",
+                        task.index, sources
+                    );
+                }
+                let _ = self.events.send(Event::PieceFailed {
+                    index: task.index,
+                    peers: sources.to_vec(),
+                });
+                let attempts = {
+                    let mut failures = self.failures.lock().unwrap();
+                    let attempts = failures.entry(task.index).or_default();
+                    *attempts += 1;
+                    *attempts
+                };
+                // a piece which never verifies is left out, the download then fails
+                // instead of fetching it forever
+                if attempts >= self.max_failures {
+                    warn!(
+                        "piece #{} failed {} times, giving up on it",
+                        task.index, attempts
+                    );
+                } else {
+                    self.task_queue.requeue(task);
+                }
+                false
+            }
+        }
+    }
+
+    /// whether the piece is verified or failed too often to be tried again
+    pub fn is_settled(&self, index: u32) -> bool {
+        self.verified.lock().unwrap().has_piece(index)
+            || self
+                .failures
+                .lock()
+                .unwrap()
+                .get(&index)
+                .is_some_and(|attempts| *attempts >= self.max_failures)
+    }
+
+    /// hold disk space for a piece about to be downloaded, false if shut down meanwhile
+    pub async fn reserve_quota(&self, task: &Task, shutdown: &mut watch::Receiver<bool>) -> bool {
+        match &self.disk_quota {
+            Some(quota) => quota.reserve(task.piece_length as u64, shutdown).await,
+            None => true,
+        }
+    }
+
+    pub fn release_quota(&self, task: &Task) {
+        if let Some(quota) = &self.disk_quota {
+            quota.release(task.piece_length as u64);
+        }
+    }
+
+    /// whether the piece is already stored with the right hash. A stored piece of
+    /// the wrong length or hash is discarded, so it is downloaded again cleanly
+    pub fn is_stored(&self, task: &Task) -> bool {
+        let Ok(Some(data)) = self.storage.read_piece(task.index) else {
+            return false;
+        };
+        let result = match data.len() == task.piece_length as usize {
+            true => self.check_sum(task, &data),
+            false => Err(anyhow!(
+                "piece #{} is stored with {} bytes instead of {}",
+                task.index,
+                data.len(),
+                task.piece_length
+            )),
+        };
+        let Err(err) = result else {
+            return true;
+        };
+        info!("discard stored piece: {}", err);
+        if let Err(err) = self.storage.discard_piece(task.index) {
+            warn!("failed to discard piece #{}: {}", task.index, err);
+        }
+        false
+    }
+
+    /// the block `request` asks for, if its piece is verified and holds it
+    pub fn read_block(&self, request: &Request) -> Result<Option<Vec<u8>>> {
+        if !self.verified.lock().unwrap().has_piece(request.index) {
+            return Ok(None);
+        }
+        let Some(data) = self.storage.read_piece(request.index)? else {
+            return Ok(None);
+        };
+        let begin = request.begin as usize;
+        let end = begin + request.length as usize;
+        Ok(data.get(begin..end).map(<[u8]>::to_vec))
+    }
+
+    /// whether `data` hashes to the piece hash of `task`
+    pub fn is_intact(&self, task: &Task, data: &[u8]) -> bool {
+        self.check_sum(task, data).is_ok()
+    }
+
+    pub fn mark_verified(&self, task: &Task) {
+        self.verified.lock().unwrap().set_piece(task.index);
+        self.blocks_completed.fetch_add(
+            task.piece_length.div_ceil(Peer::BLOCK_SIZE) as u64,
+            Ordering::Relaxed,
+        );
+    }
+
+    fn check_sum(&self, task: &Task, data: &[u8]) -> Result<()> {
+        // a short final piece is stored as it is, never padded to a full block
+        if data.len() != task.piece_length as usize {
+            return Err(anyhow!(
+                "piece #{} has {} bytes instead of {}",
+                task.index,
+                data.len(),
+                task.piece_length
+            ));
+        }
+        let mut hasher = sha1::Sha1::new();
+        hasher.update(data);
+        let sum: [u8; 20] = hasher.finalize().into();
+        if task.piece_hash != sum {
+            Err(anyhow!(
+                "piece #{} has a wrong hash, expected: {:x?}, found: {:x?}",
+                task.index,
+                &task.piece_hash,
+                &sum
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assembly::PieceAssembly, storage::MemoryStorage, testutil};
+
+    /// a storage whose flush of piece #0 always fails
+    #[derive(Debug)]
+    struct FailingSync(MemoryStorage);
+
+    impl Storage for FailingSync {
+        fn write_piece(&self, index: u32, data: &[u8]) -> Result<()> {
+            self.0.write_piece(index, data)
+        }
+
+        fn read_piece(&self, index: u32) -> Result<Option<Vec<u8>>> {
+            self.0.read_piece(index)
+        }
+
+        fn has_piece(&self, index: u32) -> bool {
+            self.0.has_piece(index)
+        }
+
+        fn sync_piece(&self, index: u32) -> Result<()> {
+            match index {
+                0 => Err(anyhow!("flush of piece #0 failed")),
+                _ => Ok(()),
+            }
+        }
+
+        fn finalize(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn pieces_which_fail_to_flush_are_not_verified() {
+        let content = testutil::content(40_000);
+        let client = testutil::builder(&content, 16384)
+            .set_storage(Arc::new(FailingSync(MemoryStorage::new())))
+            .build()
+            .unwrap();
+        let verifier = PieceVerifier::new(&client);
+        for index in 0..2 {
+            let data = &content[index * 16384..(index + 1) * 16384];
+            let task = Task::new(index as u32, 16384, client.piece_hashes[index]);
+            let stored = verifier.verify(task, vec![Piece::new(index as u32, 0, data)], &[]);
+            assert_eq!(stored, index == 1);
+        }
+        let control = client.control();
+        assert!(!control.bitfield.has_piece(0));
+        assert!(control.bitfield.has_piece(1));
+        assert_eq!(control.downloaded, 16384);
+        // the piece is fetched again instead
+        assert!(client.task_queue.contains(0));
+    }
+
+    #[test]
+    fn failed_pieces_name_the_peers_which_delivered_them() {
+        let content = testutil::content(40_000);
+        let client = testutil::client(&content, 32768);
+        let mut events = client.events.subscribe();
+        let verifier = PieceVerifier::new(&client);
+        let (first, second): (SocketAddr, SocketAddr) = (
+            "10.0.0.1:6881".parse().unwrap(),
+            "10.0.0.2:6881".parse().unwrap(),
+        );
+        let assembly = PieceAssembly::default();
+        assembly.start(Task::new(0, 32768, client.piece_hashes[0]));
+        assert!(assembly
+            .insert(Piece::new(0, 0, &content[..16384]), first)
+            .is_none());
+        // the second half is garbage
+        let (task, blocks, sources) = assembly
+            .insert(Piece::new(0, 16384, &[0; 16384]), second)
+            .unwrap();
+        assert!(!verifier.verify(task, blocks, &sources));
+        match events.try_recv().unwrap() {
+            Event::PieceFailed { index, peers } => {
+                assert_eq!(index, 0);
+                assert_eq!(peers, vec![first, second]);
+            }
+            event => panic!("unexpected {:?}", event),
+        }
+    }
+}
@@ -0,0 +1,403 @@
+use std::{ops::Range, path::Path, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Result};
+use indicatif::ProgressBar;
+use log::info;
+use tokio::{sync::watch, task::spawn_blocking};
+
+use crate::{
+    files::TorrentFile,
+    http,
+    message::Piece,
+    task::{Task, TaskQueue},
+    torrent::TorrentClient,
+    verifier::PieceVerifier,
+};
+
+/// When web seeds are allowed to take pieces from the task queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WebSeedPolicy {
+    /// download from web seeds alongside peers from the start
+    Always,
+    /// only take pieces peers have not picked up within the web seed timeout
+    #[default]
+    Fallback,
+}
+
+/// How pieces are requested from a web seed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebSeedKind {
+    /// BEP 19 (GetRight-style), a plain file server asked for byte ranges of the file
+    GetRight,
+    /// BEP 17 (Hoffman-style), a script asked for pieces by info hash and index
+    Hoffman,
+}
+
+/// A web seed serving the torrent content over HTTP.
+#[derive(Debug)]
+pub struct WebSeed {
+    pub url: String,
+    pub kind: WebSeedKind,
+    pub info_hash: [u8; 20],
+    pub piece_length: u32,
+    /// the files of the torrent, a multi-file torrent is fetched file by file
+    pub files: Vec<TorrentFile>,
+    pub multi_file: bool,
+    /// the pieces being downloaded, the seed stays around until they are settled
+    pub pieces: Range<u32>,
+    pub task_queue: Arc<TaskQueue>,
+    pub verifier: PieceVerifier,
+    pub pb: ProgressBar,
+    pub shutdown: watch::Receiver<bool>,
+}
+
+impl WebSeed {
+    /// give up on a web seed after this many pieces failed in a row
+    const MAX_FAILURES: u32 = 3;
+    /// how often an idle seed looks for pieces peers gave back
+    const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+    pub fn new(url: &str, client: &TorrentClient) -> Self {
+        Self {
+            url: url.to_string(),
+            kind: WebSeedKind::GetRight,
+            info_hash: client.info_hash,
+            piece_length: client.piece_length,
+            files: client.files(),
+            multi_file: client.is_multi_file(),
+            pieces: 0..client.piece_hashes.len() as u32,
+            task_queue: client.task_queue.clone(),
+            verifier: PieceVerifier::new(client),
+            pb: client.pb.clone(),
+            shutdown: client.shutdown.subscribe(),
+        }
+    }
+
+    pub fn with_pieces(mut self, pieces: Range<u32>) -> Self {
+        self.pieces = pieces;
+        self
+    }
+
+    /// a BEP 17 seed from the `httpseeds` key, the url points at the seeding script
+    pub fn http_seed(url: &str, client: &TorrentClient) -> Self {
+        Self {
+            url: url.to_string(),
+            kind: WebSeedKind::Hoffman,
+            ..Self::new("", client)
+        }
+    }
+
+    /// the urls holding the piece of `task` (BEP 19) and the byte range of each.
+    /// The url of a multi-file torrent is the directory holding the torrent's
+    /// directory, pieces crossing files are fetched from each of them in turn
+    fn ranges(&self, task: &Task) -> Result<Vec<(String, Range<u64>)>> {
+        let begin = task.index as u64 * self.piece_length as u64;
+        let end = begin + task.piece_length as u64;
+        if !self.multi_file {
+            // a url ending with a slash names the directory holding the file
+            let url = match self.url.ends_with('/') {
+                true => join_path(&self.url, &self.files[0].path)?,
+                false => self.url.clone(),
+            };
+            return Ok(vec![(url, begin..end)]);
+        }
+        self.files
+            .iter()
+            .filter(|file| file.length > 0)
+            .filter(|file| file.offset < end && begin < file.offset + file.length)
+            .map(|file| {
+                let range = file.range();
+                let start = begin.max(range.start) - file.offset;
+                let stop = end.min(range.end) - file.offset;
+                Ok((join_path(&self.url, &file.path)?, start..stop))
+            })
+            .collect()
+    }
+
+    async fn fetch_range(
+        &self,
+        client: &reqwest::Client,
+        url: &str,
+        range: Range<u64>,
+    ) -> Result<Vec<u8>> {
+        let res = client
+            .get(url)
+            .header(
+                reqwest::header::RANGE,
+                format!("bytes={}-{}", range.start, range.end - 1),
+            )
+            .send()
+            .await?
+            .error_for_status()?;
+        let bytes = res.bytes().await?;
+        if bytes.len() as u64 != range.end - range.start {
+            return Err(anyhow!(
+                "web seed {} returned {} bytes for the range {:?}",
+                url,
+                bytes.len(),
+                range
+            ));
+        }
+        Ok(bytes.to_vec())
+    }
+
+    async fn fetch_piece(&self, client: &reqwest::Client, task: &Task) -> Result<Vec<u8>> {
+        let request = match self.kind {
+            WebSeedKind::GetRight => {
+                let mut data = Vec::with_capacity(task.piece_length as usize);
+                for (url, range) in self.ranges(task)? {
+                    data.extend(self.fetch_range(client, &url, range).await?);
+                }
+                return Ok(data);
+            }
+            WebSeedKind::Hoffman => {
+                // ranges are inclusive and relative to the piece
+                let mut url = url::Url::parse(&self.url)?;
+                let query = format!(
+                    "info_hash={}&piece={}&ranges=0-{}",
+                    url::form_urlencoded::byte_serialize(&self.info_hash).collect::<String>(),
+                    task.index,
+                    task.piece_length - 1
+                );
+                match url.query().filter(|q| !q.is_empty()) {
+                    Some(existing) => url.set_query(Some(&format!("{}&{}", existing, query))),
+                    None => url.set_query(Some(&query)),
+                }
+                client.get(url)
+            }
+        };
+        let res = request.send().await?.error_for_status()?;
+        let bytes = res.bytes().await?;
+        if bytes.len() != task.piece_length as usize {
+            return Err(anyhow!(
+                "web seed returned {} bytes for piece #{}, expected {}",
+                bytes.len(),
+                task.index,
+                task.piece_length
+            ));
+        }
+        Ok(bytes.to_vec())
+    }
+
+    /// pull tasks from the queue until it is empty or the seed keeps failing
+    pub async fn run(self, policy: WebSeedPolicy, fallback_timeout: Duration) -> Result<()> {
+        let mut shutdown = self.shutdown.clone();
+        if policy == WebSeedPolicy::Fallback {
+            tokio::select! {
+                _ = tokio::time::sleep(fallback_timeout) => {}
+                _ = shutdown.wait_for(|stop| *stop) => return Ok(()),
+            }
+        }
+        let client = http::client()?;
+        let mut failures = 0;
+        loop {
+            let Some(task) = self.task_queue.pop() else {
+                // peers may hold pieces they never deliver, those come back to the
+                // queue once the peers give up on them
+                if self
+                    .pieces
+                    .clone()
+                    .all(|index| self.verifier.is_settled(index))
+                {
+                    break;
+                }
+                tokio::select! {
+                    _ = tokio::time::sleep(Self::POLL_INTERVAL) => continue,
+                    _ = shutdown.wait_for(|stop| *stop) => break,
+                }
+            };
+            if *shutdown.borrow() || !self.verifier.reserve_quota(&task, &mut shutdown).await {
+                self.task_queue.requeue(task);
+                break;
+            }
+            let verified = match self.fetch_piece(&client, &task).await {
+                Ok(data) => {
+                    self.pb.inc(data.len() as _);
+                    let verifier = self.verifier.clone();
+                    let blocks = vec![Piece::new(task.index, 0, &data)];
+                    spawn_blocking(move || verifier.verify(task, blocks, &[])).await?
+                }
+                Err(err) => {
+                    info!("web seed {} failed: {}", self.url, err);
+                    self.verifier.release_quota(&task);
+                    self.task_queue.requeue(task);
+                    false
+                }
+            };
+            failures = if verified { 0 } else { failures + 1 };
+            if failures >= Self::MAX_FAILURES {
+                return Err(anyhow!("web seed {} keeps failing, giving up", self.url));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `base` with the components of `path` appended, each percent-encoded
+fn join_path(base: &str, path: &Path) -> Result<String> {
+    let mut url = url::Url::parse(base)?;
+    url.path_segments_mut()
+        .map_err(|_| anyhow!("web seed {} cannot hold a path", base))?
+        .pop_if_empty()
+        .extend(path.iter().map(|part| part.to_string_lossy()));
+    Ok(url.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::{
+        bencode::UrlList,
+        testutil::{self, HttpStub, SeedBehavior},
+        TorrentClientBuilder,
+    };
+
+    const PIECE: u32 = 32 * 1024;
+
+    fn multi_file_builder(content: &[u8], url: String) -> TorrentClientBuilder {
+        let files = [("a.bin", 40000), ("sub/b c.bin", 30000), ("c.bin", 30000)];
+        let mut torrent =
+            testutil::torrent_of(testutil::multi_info("multi", &files, content, PIECE));
+        torrent.url_list = Some(UrlList::One(url));
+        testutil::builder_of(&torrent).set_webseed_policy(WebSeedPolicy::Always)
+    }
+
+    #[test]
+    fn pieces_map_to_file_ranges() {
+        let content = testutil::content(100_000);
+        let client = multi_file_builder(&content, "http://seed/root/".into())
+            .build()
+            .unwrap();
+        let seed = WebSeed::new("http://seed/root/", &client);
+        let task = |index: u32| Task {
+            index,
+            piece_hash: [0; 20],
+            piece_length: client.piece_length_of(index),
+        };
+        assert_eq!(
+            seed.ranges(&task(1)).unwrap(),
+            vec![
+                ("http://seed/root/multi/a.bin".to_string(), 32768..40000),
+                ("http://seed/root/multi/sub/b%20c.bin".to_string(), 0..25536),
+            ]
+        );
+        assert_eq!(
+            seed.ranges(&task(3)).unwrap(),
+            vec![("http://seed/root/multi/c.bin".to_string(), 28304..30000)]
+        );
+        // without the slash the url still names the directory
+        let seed = WebSeed::new("http://seed/root", &client);
+        assert_eq!(
+            seed.ranges(&task(0)).unwrap()[0].0,
+            "http://seed/root/multi/a.bin"
+        );
+    }
+
+    #[test]
+    fn single_file_url() {
+        let content = testutil::content(1000);
+        let client = testutil::client(&content, PIECE);
+        let task = Task {
+            index: 0,
+            piece_hash: [0; 20],
+            piece_length: 1000,
+        };
+        let ranges = WebSeed::new("http://seed/files/", &client).ranges(&task);
+        assert_eq!(
+            ranges.unwrap(),
+            vec![("http://seed/files/rbt-test-memory".to_string(), 0..1000)]
+        );
+        let ranges = WebSeed::new("http://seed/x.iso", &client).ranges(&task);
+        assert_eq!(ranges.unwrap()[0].0, "http://seed/x.iso");
+    }
+
+    #[tokio::test]
+    async fn multi_file_download_from_a_web_seed() {
+        let content = testutil::content(100_000);
+        let stub = HttpStub::files(vec![
+            ("/root/multi/a.bin", content[..40000].to_vec()),
+            ("/root/multi/sub/b%20c.bin", content[40000..70000].to_vec()),
+            ("/root/multi/c.bin", content[70000..].to_vec()),
+        ])
+        .await;
+        let client = multi_file_builder(&content, stub.url("/root/"))
+            .build()
+            .unwrap();
+        assert!(client.download_to_vec().await.unwrap() == content);
+    }
+
+    #[tokio::test]
+    async fn pieces_from_a_bep17_http_seed() {
+        let content = testutil::content(100_000);
+        let mut torrent = testutil::torrent_of(testutil::info("rbt-test-memory", &content, PIECE));
+        let info_hash = testutil::torrent("rbt-test-memory", &content, PIECE).info_hash;
+        let served = content.clone();
+        let stub = HttpStub::with_handler(move |request| {
+            let target = request.split(' ').nth(1).unwrap_or_default();
+            let url = url::Url::parse(&format!("http://seed{}", target)).unwrap();
+            let param = |name: &str| {
+                url.query_pairs()
+                    .find(|(key, _)| key == name)
+                    .map(|(_, value)| value.into_owned())
+            };
+            let piece: usize = param("piece").unwrap().parse().unwrap();
+            let (first, last) = param("ranges")
+                .unwrap()
+                .split_once('-')
+                .map(|(a, b)| (a.parse::<usize>().unwrap(), b.parse::<usize>().unwrap()))
+                .unwrap();
+            let start = piece * PIECE as usize + first;
+            let end = (piece * PIECE as usize + last + 1).min(served.len());
+            (200, String::new(), served[start..end].to_vec())
+        })
+        .await;
+        torrent.httpseeds = Some(vec![stub.url("/seed.php?key=1")]);
+        let client = testutil::builder_of(&torrent)
+            .set_webseed_policy(WebSeedPolicy::Always)
+            .build()
+            .unwrap();
+        assert!(client.download_to_vec().await.unwrap() == content);
+        let requests = stub.requests();
+        assert_eq!(requests.len(), 4);
+        let info_hash: String = url::form_urlencoded::byte_serialize(&info_hash).collect();
+        assert!(requests.iter().all(|request| request.starts_with(&format!(
+            "GET /seed.php?key=1&info_hash={}&piece=",
+            info_hash
+        ))));
+        assert!(requests
+            .iter()
+            .any(|request| request.contains("piece=3&ranges=0-1695 ")));
+    }
+
+    #[tokio::test]
+    async fn fallback_fetches_what_peers_do_not_deliver() {
+        let content = testutil::content(100_000);
+        let stub = HttpStub::files(vec![("/file", content.clone())]).await;
+        let mut torrent = testutil::torrent_of(testutil::info("rbt-test-memory", &content, PIECE));
+        torrent.url_list = Some(UrlList::One(stub.url("/file")));
+        // a peer which has everything but never unchokes
+        let seed = testutil::seed(
+            content.clone(),
+            PIECE,
+            testutil::torrent("rbt-test-memory", &content, PIECE).info_hash,
+            SeedBehavior {
+                choke: true,
+                ..Default::default()
+            },
+        )
+        .await;
+        let timeout = Duration::from_millis(500);
+        let client = testutil::builder_of(&torrent)
+            .set_webseed_policy(WebSeedPolicy::Fallback)
+            .set_webseed_timeout(timeout)
+            .add_peers(vec![seed])
+            .build()
+            .unwrap();
+        let start = Instant::now();
+        assert!(client.download_to_vec().await.unwrap() == content);
+        assert!(start.elapsed() >= timeout);
+        assert_eq!(stub.requests().len(), 4);
+    }
+}